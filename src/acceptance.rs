@@ -0,0 +1,305 @@
+use crate::results::RunResult;
+use chrono::{DateTime, Utc};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// Each phase of an acceptance run lasts at least this long, a circuit has to
+// hold its speed rather than burst to it
+pub static MIN_PHASE_SECS: u64 = 30;
+
+// How one assertion fared across every run of the battery
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct AssertionSummary {
+    pub assertion: String,
+    pub runs_passed: usize,
+    pub runs: usize,
+    // every run happened and passed
+    pub passed: bool,
+}
+
+// Everything the technician hands over, and what the seal covers
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct AcceptanceReport {
+    pub tool: String,
+    // missing if the system clock wasn't set
+    pub generated_at: Option<DateTime<Utc>>,
+    pub runs: Vec<RunResult>,
+    // runs that couldn't be completed, and why
+    pub failed_runs: Vec<String>,
+    pub assertions: Vec<AssertionSummary>,
+    pub passed: bool,
+}
+
+impl AcceptanceReport {
+    pub fn new(outcomes: Vec<std::result::Result<RunResult, String>>) -> Self {
+        let attempted = outcomes.len();
+        let (runs, failed_runs): (Vec<_>, Vec<_>) = outcomes.into_iter().partition(|r| r.is_ok());
+        let runs: Vec<RunResult> = runs.into_iter().flatten().collect();
+        let failed_runs: Vec<String> = failed_runs.into_iter().filter_map(|r| r.err()).collect();
+
+        // every run checked the same assertions, in the same order
+        let assertions: Vec<AssertionSummary> = runs
+            .first()
+            .map(|run| run.assertions.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .enumerate()
+            .map(|(i, outcome)| {
+                let runs_passed = runs
+                    .iter()
+                    .filter(|run| run.assertions.get(i).is_some_and(|o| o.passed))
+                    .count();
+                AssertionSummary {
+                    assertion: outcome.assertion.clone(),
+                    runs_passed,
+                    runs: attempted,
+                    passed: runs_passed == attempted,
+                }
+            })
+            .collect();
+
+        let passed = failed_runs.is_empty()
+            && !runs.is_empty()
+            && assertions.iter().all(|summary| summary.passed);
+
+        Self {
+            tool: format!("cf_speedtest {}", env!("CARGO_PKG_VERSION")),
+            generated_at: crate::clock::now(),
+            runs,
+            failed_runs,
+            assertions,
+            passed,
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/* What makes a report tamper-evident: the SHA-256 of its JSON, and with a
+   key an Ed25519 signature over the same bytes. Whoever has the public key
+   (the customer, say) can check that the results came from the holder of
+   the private key unchanged, e.g.
+
+     openssl genpkey -algorithm ed25519 -out key.pem
+     openssl pkey -in key.pem -pubout -out public.pem
+     openssl pkeyutl -verify -pubin -inkey public.pem -rawin \
+       -in acceptance-report.json -sigfile acceptance-report.json.sig
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Seal {
+    pub sha256: String,
+    pub signature: Option<Signature>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+    pub ed25519: Vec<u8>,
+    pub public_key: Vec<u8>,
+}
+
+// An Ed25519 private key as openssl writes it: PKCS#8, in PEM or DER
+pub fn load_key(bytes: &[u8]) -> Result<Ed25519KeyPair> {
+    use base64::Engine;
+
+    let der = match std::str::from_utf8(bytes) {
+        Ok(pem) if pem.trim_start().starts_with("-----BEGIN") => {
+            let body: String = pem
+                .lines()
+                .filter(|line| !line.starts_with("-----"))
+                .collect();
+            base64::engine::general_purpose::STANDARD
+                .decode(body.trim())
+                .map_err(|err| format!("the key isn't valid PEM: {err}"))?
+        }
+        _ => bytes.to_vec(),
+    };
+
+    Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der).map_err(|err| {
+        format!("the key isn't an Ed25519 private key ({err}), make one with `openssl genpkey -algorithm ed25519`").into()
+    })
+}
+
+// The key in the file at `path`, see `load_key`
+pub fn read_key(path: &Path) -> Result<Ed25519KeyPair> {
+    let bytes =
+        std::fs::read(path).map_err(|err| format!("couldn't read {}: {err}", path.display()))?;
+    load_key(&bytes).map_err(|err| format!("{}: {err}", path.display()).into())
+}
+
+// Where `write` puts the results for a report at `path`: never `path`
+// itself, even if the report is named .json
+pub fn payload_path(path: &Path) -> PathBuf {
+    path.with_extension("payload.json")
+}
+
+pub fn seal(payload: &[u8], key: Option<&Ed25519KeyPair>) -> Seal {
+    Seal {
+        sha256: hex(ring::digest::digest(&ring::digest::SHA256, payload).as_ref()),
+        signature: key.map(|key| Signature {
+            ed25519: key.sign(payload).as_ref().to_vec(),
+            public_key: key.public_key().as_ref().to_vec(),
+        }),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn mbit(bps: Option<f64>) -> String {
+    bps.map_or("-".to_owned(), crate::format_bits_per_sec)
+}
+
+fn millis(ms: Option<f64>) -> String {
+    ms.map_or("-".to_owned(), |ms| format!("{ms:.2} ms"))
+}
+
+// A self-contained page, meant to be printed (or saved as PDF) and signed off
+pub fn render_html(report: &AcceptanceReport, payload_name: &str, seal: &Seal) -> String {
+    let verdict = if report.passed { "PASSED" } else { "FAILED" };
+    let mut html = String::new();
+
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+        <title>Circuit acceptance report</title>\
+        <style>body{{font-family:sans-serif;max-width:60em;margin:2em auto}}\
+        table{{border-collapse:collapse}}td,th{{border:1px solid #999;padding:.3em .6em}}\
+        .pass{{color:#060}}.fail{{color:#a00}}code{{word-break:break-all}}</style>\
+        </head><body>\n<h1>Circuit acceptance report: <span class=\"{}\">{verdict}</span></h1>\n",
+        if report.passed { "pass" } else { "fail" }
+    );
+
+    let location = report.runs.first().map(|run| {
+        [run.city.as_deref(), run.country.as_deref()]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(", ")
+    });
+    let _ = writeln!(
+        html,
+        "<p>Generated by {} at {}.<br>Location: {}, Cloudflare colo {}.</p>",
+        escape(&report.tool),
+        report
+            .generated_at
+            .map_or("an unknown time (system clock not set)".to_owned(), |at| {
+                at.to_rfc3339()
+            }),
+        escape(&location.unwrap_or_default()),
+        escape(
+            report
+                .runs
+                .first()
+                .and_then(|run| run.colo.as_deref())
+                .unwrap_or("unknown")
+        ),
+    );
+
+    html +=
+        "<h2>Assertions</h2>\n<table><tr><th>Assertion</th><th>Runs passed</th><th></th></tr>\n";
+    for summary in &report.assertions {
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{} of {}</td><td class=\"{}\">{}</td></tr>",
+            escape(&summary.assertion),
+            summary.runs_passed,
+            summary.runs,
+            if summary.passed { "pass" } else { "fail" },
+            if summary.passed { "PASS" } else { "FAIL" },
+        );
+    }
+    html += "</table>\n";
+
+    html += "<h2>Runs</h2>\n<table><tr><th>#</th><th>Download</th><th>Upload</th>\
+        <th>Latency</th><th>Loaded latency</th><th>RPM</th><th>Loss</th></tr>\n";
+    for (i, run) in report.runs.iter().enumerate() {
+        let loaded = [&run.download_loaded_latency, &run.upload_loaded_latency]
+            .into_iter()
+            .flatten();
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            i + 1,
+            mbit(run.download.as_ref().map(|p| p.median_bps)),
+            mbit(run.upload.as_ref().map(|p| p.median_bps)),
+            millis(run.latency_ms),
+            millis(loaded.clone().map(|l| l.median_ms).reduce(f64::max)),
+            loaded
+                .filter_map(|l| l.responsiveness.as_ref())
+                .map(|r| r.rpm)
+                .reduce(f64::min)
+                .map_or("-".to_owned(), |rpm| format!("{rpm:.0}")),
+            run.sockets
+                .as_ref()
+                .and_then(|s| s.retransmit_percent())
+                .map_or("-".to_owned(), |loss| format!("{loss:.2}%")),
+        );
+    }
+    html += "</table>\n";
+
+    for failure in &report.failed_runs {
+        let _ = writeln!(
+            html,
+            "<p class=\"fail\">A run could not be completed: {}</p>",
+            escape(failure)
+        );
+    }
+
+    let signature =
+        seal.signature
+            .as_ref()
+            .map_or("not signed, no key given".to_owned(), |signature| {
+                format!(
+                    "<code>{}</code> (in <code>{}.sig</code>)<br>\
+                Ed25519 public key: <code>{}</code>",
+                    hex(&signature.ed25519),
+                    escape(payload_name),
+                    hex(&signature.public_key)
+                )
+            });
+    let _ = writeln!(
+        html,
+        "<h2>Seal</h2>\n<p>The full results are in <code>{}</code>.<br>\
+        SHA-256: <code>{}</code><br>Ed25519 signature: {signature}</p>",
+        escape(payload_name),
+        seal.sha256,
+    );
+    html += "<p>Technician: ______________________ &nbsp; Customer: ______________________</p>\n";
+    html += "</body></html>\n";
+
+    html
+}
+
+// Write the report as `path` (HTML) and its results next to it as JSON
+// (see `payload_path`), signed with the key in `key_path` if there is one,
+// the signature next to that as .payload.json.sig. Returns the JSON's path.
+pub fn write(report: &AcceptanceReport, path: &Path, key_path: Option<&Path>) -> Result<PathBuf> {
+    let payload = serde_json::to_vec_pretty(report)?;
+    let key = key_path.map(read_key).transpose()?;
+    let seal = seal(&payload, key.as_ref());
+
+    let payload_path = payload_path(path);
+    std::fs::write(&payload_path, &payload)?;
+    if let Some(signature) = &seal.signature {
+        let mut signature_path = payload_path.clone().into_os_string();
+        signature_path.push(".sig");
+        std::fs::write(signature_path, &signature.ed25519)?;
+    }
+    let payload_name = payload_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    std::fs::write(path, render_html(report, &payload_name, &seal))?;
+
+    Ok(payload_path)
+}
@@ -0,0 +1,136 @@
+/* daemon --alert-drop: tell someone when the line gets slower than it has
+   been, rather than slower than a fixed figure that a variable link (Wi-Fi,
+   cellular, a shared cable segment) crosses every evening. Each run's
+   download and upload are compared with their average over the runs before
+   it, up to --alert-window of them, and a drop of --alert-drop percent or
+   more raises an alert. A direction that's raised one stays quiet until a
+   run is back within the threshold, which is reported as recovered, so a
+   slow spell makes two notifications rather than one per run. The runs of
+   a slow spell don't count towards the average, or a drop lasting longer
+   than the window would become the new normal and report itself as
+   recovered while the line is as slow as ever.
+*/
+use crate::results::RunResult;
+use crate::throughput::Direction;
+use std::collections::VecDeque;
+
+static DIRECTIONS: [Direction; 2] = [Direction::Download, Direction::Upload];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alert {
+    pub direction: Direction,
+    pub bps: f64,
+    pub average_bps: f64,
+    // how far below the average, negative if above
+    pub drop_percent: f64,
+    // back within the threshold after an alert
+    pub recovered: bool,
+}
+
+impl Alert {
+    pub fn message(&self) -> String {
+        let (bps, average) = (
+            crate::format_bits_per_sec(self.bps),
+            crate::format_bits_per_sec(self.average_bps),
+        );
+        if self.recovered {
+            format!(
+                "{} has recovered: {bps} against an average of {average}",
+                self.direction.name()
+            )
+        } else {
+            format!(
+                "{} dropped {:.0}%: {bps} against an average of {average}",
+                self.direction.name(),
+                self.drop_percent
+            )
+        }
+    }
+}
+
+pub struct DropAlerts {
+    threshold_percent: f64,
+    window: usize,
+    // median download and upload of the latest runs, oldest first
+    recent: [VecDeque<f64>; 2],
+    alerting: [bool; 2],
+}
+
+impl DropAlerts {
+    pub fn new(threshold_percent: f64, window: usize) -> Self {
+        Self {
+            threshold_percent,
+            window,
+            recent: [VecDeque::new(), VecDeque::new()],
+            alerting: [false; 2],
+        }
+    }
+
+    // The runs before the daemon started, e.g. from --history, oldest first
+    pub fn seed(&mut self, runs: &[RunResult]) {
+        for run in runs {
+            self.remember(run);
+        }
+    }
+
+    fn remember(&mut self, run: &RunResult) {
+        for (i, direction) in DIRECTIONS.into_iter().enumerate() {
+            if let Some(bps) = median_bps(run, direction) {
+                self.remember_bps(i, bps);
+            }
+        }
+    }
+
+    fn remember_bps(&mut self, i: usize, bps: f64) {
+        let recent = &mut self.recent[i];
+        recent.push_back(bps);
+        while recent.len() > self.window {
+            recent.pop_front();
+        }
+    }
+
+    // What `run` raises, then it counts towards the average for the next
+    // unless it's part of a slow spell. Nothing is compared until there's
+    // a full window to compare with.
+    pub fn observe(&mut self, run: &RunResult) -> Vec<Alert> {
+        let mut alerts = vec![];
+
+        for (i, direction) in DIRECTIONS.into_iter().enumerate() {
+            let Some(bps) = median_bps(run, direction) else {
+                continue;
+            };
+            let before = &self.recent[i];
+            if before.len() < self.window {
+                self.remember_bps(i, bps);
+                continue;
+            }
+
+            let average_bps = before.iter().sum::<f64>() / before.len() as f64;
+            let drop_percent = (1.0 - bps / average_bps) * 100.0;
+            let dropped = drop_percent >= self.threshold_percent;
+            if dropped != self.alerting[i] {
+                self.alerting[i] = dropped;
+                alerts.push(Alert {
+                    direction,
+                    bps,
+                    average_bps,
+                    drop_percent,
+                    recovered: !dropped,
+                });
+            }
+            if !dropped {
+                self.remember_bps(i, bps);
+            }
+        }
+
+        alerts
+    }
+}
+
+fn median_bps(run: &RunResult, direction: Direction) -> Option<f64> {
+    let phase = match direction {
+        Direction::Download => &run.download,
+        Direction::Upload => &run.upload,
+    };
+    phase.as_ref().map(|phase| phase.median_bps)
+}
@@ -0,0 +1,271 @@
+use crate::results::RunResult;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// What a metric is measured in, and so which suffixes its thresholds take
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Unit {
+    BitsPerSecond,
+    Millis,
+    Percent,
+    Rpm,
+}
+
+impl Unit {
+    // How many of our base unit one of `suffix` is. Rates take what --limit
+    // does.
+    fn scale(self, suffix: &str) -> Option<f64> {
+        let suffix = suffix.to_ascii_lowercase();
+        let suffix = suffix.trim_end_matches("/s");
+        match self {
+            Self::BitsPerSecond => format!("1{suffix}")
+                .parse::<crate::pacing::Rate>()
+                .ok()
+                .map(|rate| rate.bits_per_sec),
+            Self::Millis => match suffix {
+                "" | "ms" => Some(1.0),
+                "us" => Some(1e-3),
+                "s" => Some(1e3),
+                _ => None,
+            },
+            Self::Percent => matches!(suffix, "" | "%").then_some(1.0),
+            Self::Rpm => matches!(suffix, "" | "rpm").then_some(1.0),
+        }
+    }
+
+    fn format(self, value: f64) -> String {
+        match self {
+            Self::BitsPerSecond => crate::format_bits_per_sec(value),
+            Self::Millis => format!("{value:.2}ms"),
+            Self::Percent => format!("{value:.2}%"),
+            Self::Rpm => format!("{value:.0} RPM"),
+        }
+    }
+}
+
+// Where to find a metric in a run
+type Figure = fn(&RunResult) -> Option<f64>;
+
+// Everything an assertion can be about
+static METRICS: [(&str, Unit, Figure); 10] = [
+    ("download", Unit::BitsPerSecond, |run| {
+        run.download.as_ref().map(|p| p.median_bps)
+    }),
+    ("download_p90", Unit::BitsPerSecond, |run| {
+        run.download.as_ref().map(|p| p.p90_bps)
+    }),
+    ("upload", Unit::BitsPerSecond, |run| {
+        run.upload.as_ref().map(|p| p.median_bps)
+    }),
+    ("upload_p90", Unit::BitsPerSecond, |run| {
+        run.upload.as_ref().map(|p| p.p90_bps)
+    }),
+    ("latency", Unit::Millis, |run| run.latency_ms),
+    ("jitter", Unit::Millis, |run| {
+        run.idle_latency.as_ref().map(|l| l.jitter_ms)
+    }),
+    // the worse of the two phases
+    ("loaded_latency", Unit::Millis, |run| {
+        loaded(run, |l| l.median_ms).into_iter().reduce(f64::max)
+    }),
+    ("loaded_latency_increase", Unit::Millis, |run| {
+        loaded(run, |l| l.delta_ms).into_iter().reduce(f64::max)
+    }),
+    // retransmits of what we sent, which is next to nothing without an
+    // upload, so then it isn't measured
+    ("loss", Unit::Percent, |run| {
+        run.upload.as_ref()?;
+        run.sockets.as_ref().and_then(|s| s.retransmit_percent())
+    }),
+    ("rpm", Unit::Rpm, |run| {
+        loaded(run, |l| l.responsiveness.as_ref().map(|r| r.rpm))
+            .into_iter()
+            .flatten()
+            .reduce(f64::min)
+    }),
+];
+
+// `figure` of each phase's loaded latency
+fn loaded<T>(run: &RunResult, figure: impl Fn(&crate::latency::LoadedLatency) -> T) -> Vec<T> {
+    [&run.download_loaded_latency, &run.upload_loaded_latency]
+        .into_iter()
+        .flatten()
+        .map(figure)
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Comparison {
+    AtLeast,
+    AtMost,
+    Above,
+    Below,
+}
+
+impl Comparison {
+    fn holds(self, actual: f64, threshold: f64) -> bool {
+        match self {
+            Self::AtLeast => actual >= threshold,
+            Self::AtMost => actual <= threshold,
+            Self::Above => actual > threshold,
+            Self::Below => actual < threshold,
+        }
+    }
+}
+
+// One line of an assertions file, e.g. "download >= 800Mbit"
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assertion {
+    text: String,
+    metric: usize,
+    comparison: Comparison,
+    // in the metric's base unit: bits/s, ms, percent or RPM
+    threshold: f64,
+}
+
+impl std::str::FromStr for Assertion {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = |why: &str| format!("invalid assertion '{s}': {why}");
+
+        let op_start = s
+            .find(['<', '>'])
+            .ok_or_else(|| invalid("expected <, <=, > or >="))?;
+        let (name, rest) = s.split_at(op_start);
+        let (comparison, value) = match rest.split_at(1) {
+            (">", value) if value.starts_with('=') => (Comparison::AtLeast, &value[1..]),
+            ("<", value) if value.starts_with('=') => (Comparison::AtMost, &value[1..]),
+            (">", value) => (Comparison::Above, value),
+            (_, value) => (Comparison::Below, value),
+        };
+
+        let name = name.trim();
+        let metric = METRICS
+            .iter()
+            .position(|(metric, _, _)| *metric == name)
+            .ok_or_else(|| {
+                let names: Vec<_> = METRICS.iter().map(|(metric, _, _)| *metric).collect();
+                invalid(&format!(
+                    "unknown metric, expected one of {}",
+                    names.join(", ")
+                ))
+            })?;
+
+        let value = value.trim();
+        let number_end = value
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(value.len());
+        let (number, suffix) = value.split_at(number_end);
+        let number: f64 = number
+            .parse()
+            .map_err(|_| invalid("expected a number after the comparison"))?;
+        let unit = METRICS[metric].1;
+        let scale = unit
+            .scale(suffix.trim())
+            .ok_or_else(|| invalid(&format!("unknown unit '{}'", suffix.trim())))?;
+
+        Ok(Self {
+            text: s.trim().to_owned(),
+            metric,
+            comparison,
+            threshold: number * scale,
+        })
+    }
+}
+
+// How one assertion fared against a run
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AssertionOutcome {
+    pub assertion: String,
+    // in the metric's base unit, missing if the run didn't measure it
+    pub actual: Option<f64>,
+    pub passed: bool,
+}
+
+impl AssertionOutcome {
+    // e.g. "PASS  download >= 800Mbit (912.30 Mbit/s)"
+    pub fn describe(&self) -> String {
+        let unit = self
+            .assertion
+            .parse::<Assertion>()
+            .map(|assertion| METRICS[assertion.metric].1)
+            .ok();
+        let actual = match (self.actual, unit) {
+            (Some(actual), Some(unit)) => unit.format(actual),
+            _ => "not measured".to_owned(),
+        };
+
+        format!(
+            "{}  {} ({actual})",
+            if self.passed { "PASS" } else { "FAIL" },
+            self.assertion
+        )
+    }
+}
+
+impl Assertion {
+    // As it was written, which its outcome is known by
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn metric(&self) -> &'static str {
+        METRICS[self.metric].0
+    }
+
+    // As a Nagios threshold range in units of `scale` (1e6 for Mbit/s),
+    // which alerts outside it: "100:" for "download >= 100Mbit", "50" for
+    // "latency <= 50ms"
+    pub fn nagios_range(&self, scale: f64) -> String {
+        let threshold = self.threshold / scale;
+        match self.comparison {
+            Comparison::AtLeast | Comparison::Above => format!("{threshold}:"),
+            Comparison::AtMost | Comparison::Below => format!("{threshold}"),
+        }
+    }
+
+    // A metric the run didn't measure fails, an acceptance test can't pass
+    // on something nobody checked
+    pub fn evaluate(&self, run: &RunResult) -> AssertionOutcome {
+        let actual = (METRICS[self.metric].2)(run);
+
+        AssertionOutcome {
+            assertion: self.text.clone(),
+            actual,
+            passed: actual.is_some_and(|actual| self.comparison.holds(actual, self.threshold)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AssertionsFile {
+    assertions: Vec<String>,
+}
+
+/* An assertions file is TOML with a list of them:
+
+     assertions = [
+       "download >= 800Mbit",
+       "loaded_latency_increase <= 50ms",
+       "loss <= 0.5%",
+     ]
+*/
+pub fn parse(text: &str) -> Result<Vec<Assertion>> {
+    let file: AssertionsFile = toml::from_str(text)?;
+
+    Ok(file
+        .assertions
+        .iter()
+        .map(|assertion| assertion.parse())
+        .collect::<std::result::Result<_, _>>()?)
+}
+
+pub fn load(path: &Path) -> Result<Vec<Assertion>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("couldn't read {}: {err}", path.display()))?;
+    parse(&text).map_err(|err| format!("{}: {err}", path.display()).into())
+}
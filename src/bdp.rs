@@ -0,0 +1,173 @@
+/* The bandwidth-delay product advisor: whether it was the OS's socket
+   buffers that held one connection back. A TCP connection can have at most
+   a window of data in flight, so it moves at most a window per round trip;
+   turned around, what the fastest connection moved times the round trip is
+   the window it must have had. A window up against the most the OS lets a
+   buffer grow to is being capped by the buffers, and raising the limit
+   speeds single downloads up. The threads share the link, so a connection
+   that fell well short of its share of the phase with a window well short
+   of the limit points elsewhere, at loss or per-flow shaping. One that got
+   its share with room to spare is fine and gets no advice. The round trip
+   is the kernel's smoothed one under load where it tells us (Linux), else
+   the idle latency.
+*/
+use crate::results::RunResult;
+use crate::throughput::Direction;
+
+// One connection short of this much of its thread's share of the phase
+// was held back. Its rate is over its whole life, handshake and slow start
+// included, so it's never quite the share.
+static FILLED_SHARE: f64 = 0.8;
+// Linux keeps up to half of a buffer for its own bookkeeping, so a window
+// past this share of the limit is as big as it could get
+static LIMIT_SHARE: f64 = 0.4;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BdpAdvice {
+    pub direction: Direction,
+    pub stream_bps: f64,
+    pub rtt_ms: f64,
+    // what the fastest connection had in flight, stream_bps × rtt
+    pub window_bytes: f64,
+    // the most a buffer may grow to, when we could find out
+    pub limit_bytes: Option<u64>,
+    pub capped: bool,
+}
+
+impl BdpAdvice {
+    /* For each direction of `run` where the fastest connection was held
+       back or capped, what its window was and whether it was the buffers.
+       `limit` is the buffer limit for a direction, see `local_limit`. A
+       phase that doesn't say how many threads it ran is left alone.
+    */
+    pub fn from_run(run: &RunResult, limit: impl Fn(Direction) -> Option<u64>) -> Vec<Self> {
+        let Some(sockets) = &run.sockets else {
+            return vec![];
+        };
+        let Some(rtt_ms) = sockets
+            .rtt_ms
+            .as_ref()
+            .map(|rtt| rtt.median)
+            .or(run.latency_ms)
+            .filter(|rtt| *rtt > 0.0)
+        else {
+            return vec![];
+        };
+
+        [
+            (
+                Direction::Download,
+                &run.download,
+                sockets.stream_download_bps,
+            ),
+            (Direction::Upload, &run.upload, sockets.stream_upload_bps),
+        ]
+        .into_iter()
+        .filter_map(|(direction, phase, stream_bps)| {
+            let (phase, stream_bps) = (phase.as_ref()?, stream_bps?);
+            let share_bps = phase.median_bps / f64::from(phase.threads?.max(1));
+            let window_bytes = stream_bps / 8.0 * rtt_ms / 1000.0;
+            let limit_bytes = limit(direction);
+            let capped =
+                limit_bytes.is_some_and(|limit| window_bytes >= limit as f64 * LIMIT_SHARE);
+            if stream_bps >= share_bps * FILLED_SHARE && !capped {
+                return None;
+            }
+
+            Some(Self {
+                direction,
+                stream_bps,
+                rtt_ms,
+                window_bytes,
+                limit_bytes,
+                capped,
+            })
+        })
+        .collect()
+    }
+
+    pub fn describe(&self) -> String {
+        let found = format!(
+            "one connection reached {} at {:.0} ms, a window of {}",
+            crate::format_bits_per_sec(self.stream_bps),
+            self.rtt_ms,
+            kib(self.window_bytes)
+        );
+        let settings = settings(self.direction);
+        match self.limit_bytes {
+            Some(limit) if self.capped => format!(
+                "{found}, up against the buffer limit of {}: raise {settings} to speed up single connections",
+                kib(limit as f64)
+            ),
+            Some(limit) => format!(
+                "{found}, well within the buffer limit of {}, so loss or per-flow shaping held it back rather than the buffers",
+                kib(limit as f64)
+            ),
+            None => format!(
+                "{found}; if that's near the buffer limit, raise {settings} to speed up single connections"
+            ),
+        }
+    }
+}
+
+fn kib(bytes: f64) -> String {
+    format!("{:.0} KiB", bytes / 1024.0)
+}
+
+// The settings that limit the receive (download) or send (upload) buffers
+pub fn settings(direction: Direction) -> &'static str {
+    let download = direction == Direction::Download;
+    if cfg!(any(target_os = "linux", target_os = "android")) {
+        if download {
+            "net.ipv4.tcp_rmem (its last value) and net.core.rmem_max"
+        } else {
+            "net.ipv4.tcp_wmem (its last value) and net.core.wmem_max"
+        }
+    } else if cfg!(target_os = "macos") {
+        if download {
+            "kern.ipc.maxsockbuf and net.inet.tcp.autorcvbufmax"
+        } else {
+            "kern.ipc.maxsockbuf and net.inet.tcp.autosndbufmax"
+        }
+    } else if cfg!(target_os = "freebsd") {
+        if download {
+            "kern.ipc.maxsockbuf and net.inet.tcp.recvbuf_max"
+        } else {
+            "kern.ipc.maxsockbuf and net.inet.tcp.sendbuf_max"
+        }
+    } else if cfg!(windows) {
+        "the autotuninglevel in netsh interface tcp show global (normal or experimental)"
+    } else {
+        "the OS's socket buffer limits"
+    }
+}
+
+// The last of net.ipv4.tcp_rmem's "min default max"
+pub fn parse_tcp_mem(values: &str) -> Option<u64> {
+    values.split_whitespace().nth(2)?.parse().ok()
+}
+
+// The most this machine lets a buffer autotune up to, where it tells us
+pub fn local_limit(direction: Direction) -> Option<u64> {
+    let download = direction == Direction::Download;
+    if cfg!(any(target_os = "linux", target_os = "android")) {
+        let path = if download {
+            "/proc/sys/net/ipv4/tcp_rmem"
+        } else {
+            "/proc/sys/net/ipv4/tcp_wmem"
+        };
+        parse_tcp_mem(&std::fs::read_to_string(path).ok()?)
+    } else if cfg!(target_os = "macos") {
+        let name = if download {
+            "net.inet.tcp.autorcvbufmax"
+        } else {
+            "net.inet.tcp.autosndbufmax"
+        };
+        crate::host::command_output("sysctl", &["-n", name])?
+            .trim()
+            .parse()
+            .ok()
+    } else {
+        None
+    }
+}
@@ -0,0 +1,171 @@
+/* --pattern bursty: traffic the way a web page or an app makes it rather
+   than a bulk download, short bursts with nothing in between. One kept
+   alive connection downloads a burst, then sits idle for IDLE_MILLIS,
+   long enough for a Wi-Fi or cellular radio to drop into power save and
+   for a shaper's token bucket to fill up again. Each burst is timed from
+   the request to its last byte, and for how long it took to get up to
+   speed after the gap: the end of the first WINDOW_MILLIS window that
+   moved at least RAMP_SHARE of what the burst's fastest window did.
+   Bursts are sized to last about BURST_MILLIS at the speed of the one
+   before, so they're short on any link.
+*/
+use crate::throughput::{Direction, Target, ThroughputTarget};
+use crate::Speedtest;
+use serde::Serialize;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+static BURSTS: usize = 10;
+static IDLE_MILLIS: u64 = 2000;
+static BURST_MILLIS: u64 = 250;
+static FIRST_BURST_BYTES: usize = 1024 * 1024;
+static MIN_BURST_BYTES: usize = 256 * 1024;
+static MAX_BURST_BYTES: usize = 50 * 1024 * 1024;
+static WINDOW_MILLIS: u64 = 10;
+static RAMP_SHARE: f64 = 0.9;
+
+// How --pattern has the test move data
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrafficPattern {
+    // the usual phases, every thread transferring for the whole duration
+    Bulk,
+    Bursty,
+}
+
+impl std::str::FromStr for TrafficPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "bulk" => Ok(Self::Bulk),
+            "bursty" => Ok(Self::Bursty),
+            _ => Err(format!("unknown --pattern '{s}', expected bulk or bursty")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Burst {
+    pub bytes: usize,
+    // from the request
+    pub first_byte: Duration,
+    pub last_byte: Duration,
+    // None for a burst too short to tell
+    pub ramp: Option<Duration>,
+}
+
+impl Burst {
+    // What the burst achieved, the wait for its first byte included
+    pub fn bits_per_sec(&self) -> f64 {
+        self.bytes as f64 * 8.0 / self.last_byte.as_secs_f64().max(f64::EPSILON)
+    }
+
+    // From when each read returned, since the request, and how much it read
+    pub fn from_reads(reads: &[(Duration, usize)]) -> Option<Self> {
+        let (first_byte, _) = *reads.first()?;
+        let (last_byte, _) = *reads.last()?;
+        let window = Duration::from_millis(WINDOW_MILLIS);
+        let index = |at: Duration| ((at - first_byte).as_nanos() / window.as_nanos()) as usize;
+
+        let mut windows = vec![0; index(last_byte) + 1];
+        for &(at, bytes) in reads {
+            windows[index(at)] += bytes;
+        }
+        // the last window ends with the burst, not a full window
+        windows.pop();
+        let peak = windows.iter().max().copied().unwrap_or_default();
+        let ramp = windows
+            .iter()
+            .position(|&bytes| peak > 0 && bytes as f64 >= peak as f64 * RAMP_SHARE)
+            .map(|i| first_byte + window * (i as u32 + 1));
+
+        Some(Self {
+            bytes: reads.iter().map(|&(_, bytes)| bytes).sum(),
+            first_byte,
+            last_byte,
+            ramp,
+        })
+    }
+}
+
+fn download_burst(
+    target: &dyn ThroughputTarget,
+    agent: &ureq::Agent,
+    bytes: usize,
+) -> Result<Burst> {
+    let start = Instant::now();
+    let response = target.build_request(agent, bytes).call()?;
+    target.validate_response(&response)?;
+
+    let mut reader = response.into_reader();
+    let mut buf = vec![0; 64 * 1024];
+    let mut reads = vec![];
+    loop {
+        match reader.read(&mut buf)? {
+            0 => break,
+            n => reads.push((start.elapsed(), n)),
+        }
+    }
+
+    Burst::from_reads(&reads).ok_or_else(|| "the server sent nothing".into())
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct BurstSummary {
+    pub bursts: usize,
+    pub failures: usize,
+    pub idle_ms: u64,
+    // medians, None if every burst failed
+    pub burst_bps: Option<f64>,
+    pub first_byte_ms: Option<f64>,
+    pub ramp_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+impl BurstSummary {
+    pub fn new(bursts: &[std::result::Result<Burst, String>]) -> Self {
+        let ok: Vec<&Burst> = bursts.iter().filter_map(|b| b.as_ref().ok()).collect();
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+
+        Self {
+            bursts: bursts.len(),
+            failures: bursts.len() - ok.len(),
+            idle_ms: IDLE_MILLIS,
+            burst_bps: crate::median(ok.iter().map(|b| b.bits_per_sec()).collect()),
+            first_byte_ms: crate::median(ok.iter().map(|b| ms(b.first_byte)).collect()),
+            ramp_ms: crate::median(ok.iter().filter_map(|b| b.ramp).map(ms).collect()),
+            last_error: bursts.iter().rev().find_map(|b| b.clone().err()),
+        }
+    }
+}
+
+// BURSTS downloads with the idle gap before each, stopping early if
+// `speedtest` is interrupted
+pub fn run(speedtest: &Speedtest) -> BurstSummary {
+    // bursts are HTTP requests, see UserArgs::validate
+    let Target::Requests(target) = speedtest.target(Direction::Download) else {
+        return BurstSummary::new(&[Err("bursts need HTTP/1.1 requests".to_owned())]);
+    };
+    let agent = crate::build_agent(&speedtest.connect);
+    let mut bytes = FIRST_BURST_BYTES;
+    let mut bursts = vec![];
+
+    for _ in 0..BURSTS {
+        std::thread::sleep(Duration::from_millis(IDLE_MILLIS));
+        if speedtest.interrupt.is_triggered() {
+            break;
+        }
+
+        let burst = download_burst(target.as_ref(), &agent, bytes);
+        if let Ok(burst) = &burst {
+            let next = burst.bits_per_sec() / 8.0 * BURST_MILLIS as f64 / 1000.0;
+            bytes = (next as usize).clamp(MIN_BURST_BYTES, MAX_BURST_BYTES);
+        }
+        bursts.push(burst.map_err(|err| err.to_string()));
+    }
+
+    BurstSummary::new(&bursts)
+}
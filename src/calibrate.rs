@@ -0,0 +1,194 @@
+/* --calibrate: the whole measuring engine against a server on loopback that
+   sends and takes data at exactly the rate asked for, so whatever we
+   measure beyond that is the tool's own error. At very high rates it's our
+   own CPU that runs out first, and this shows where that happens on the
+   machine at hand.
+*/
+use crate::pacing::{Rate, TokenBucket};
+use crate::{Measured, Observer, Speedtest};
+use serde::Serialize;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// Measured within this much of the source's rate, the results can be trusted
+pub static TOLERANCE_PERCENT: f64 = 5.0;
+
+// The most the source writes or reads at once
+static CHUNK_BYTES: usize = 64 * 1024;
+
+/* A server on loopback answering GET /down?bytes=N with N bytes and taking
+   POST /up bodies (with a length or chunked, as ureq sends them), both paced
+   to `rate` across all its connections. It runs until the process exits.
+*/
+pub fn start_source(rate: Rate) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let sending = Arc::new(TokenBucket::new(rate, None));
+    let receiving = Arc::new(TokenBucket::new(rate, None));
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let (sending, receiving) = (Arc::clone(&sending), Arc::clone(&receiving));
+            std::thread::spawn(move || {
+                // a connection that goes away mid-request is the client's
+                // phase ending, nothing to report
+                let _ = serve(stream, &sending, &receiving);
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+// Answer requests on one keep-alive connection until the client closes it
+fn serve(stream: TcpStream, sending: &TokenBucket, receiving: &TokenBucket) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line)? == 0 {
+            return Ok(());
+        }
+
+        let mut content_length = None;
+        let mut chunked = false;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header)?;
+            let header = header.trim_end().to_ascii_lowercase();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(length) = header.strip_prefix("content-length:") {
+                content_length = length.trim().parse().ok();
+            }
+            if header.starts_with("transfer-encoding:") && header.contains("chunked") {
+                chunked = true;
+            }
+        }
+
+        if chunked {
+            loop {
+                let mut size = String::new();
+                reader.read_line(&mut size)?;
+                let size = usize::from_str_radix(size.trim(), 16).unwrap_or(0);
+                take(&mut reader, size, receiving)?;
+                // the CRLF after each chunk, the last one after the empty chunk
+                take(&mut reader, 2, receiving)?;
+                if size == 0 {
+                    break;
+                }
+            }
+        } else {
+            take(&mut reader, content_length.unwrap_or(0), receiving)?;
+        }
+
+        let bytes = request_line
+            .split_once("bytes=")
+            .and_then(|(_, rest)| rest.split([' ', '&']).next())
+            .and_then(|bytes| bytes.parse().ok())
+            .unwrap_or(0);
+        write!(writer, "HTTP/1.1 200 OK\r\nContent-Length: {bytes}\r\n\r\n")?;
+        let chunk = vec![0; CHUNK_BYTES.min(sending.max_chunk())];
+        let mut sent = 0;
+        while sent < bytes {
+            let len = chunk.len().min(bytes - sent);
+            sending.take(len);
+            writer.write_all(&chunk[..len])?;
+            sent += len;
+        }
+    }
+}
+
+// Read and throw away `bytes` of a request body, at the bucket's pace
+fn take(reader: &mut impl Read, bytes: usize, bucket: &TokenBucket) -> std::io::Result<()> {
+    let mut buf = vec![0; CHUNK_BYTES.min(bucket.max_chunk())];
+    let mut left = bytes;
+    while left > 0 {
+        let len = buf.len().min(left);
+        reader.read_exact(&mut buf[..len])?;
+        bucket.take(len);
+        left -= len;
+    }
+    Ok(())
+}
+
+// How far one phase's measurement was from the source's rate
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct PhaseError {
+    pub median_bps: f64,
+    pub average_bps: f64,
+    // of the median, negative where we measured less than was sent
+    pub error_percent: f64,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct CalibrationReport {
+    pub rate_bps: f64,
+    pub download: Option<PhaseError>,
+    pub upload: Option<PhaseError>,
+}
+
+impl CalibrationReport {
+    pub fn from_measured(rate: Rate, measured: &Measured) -> Self {
+        let error = |phase: &Option<crate::results::PhaseSummary>| {
+            phase.as_ref().map(|phase| PhaseError {
+                median_bps: phase.median_bps,
+                average_bps: phase.average_bps,
+                error_percent: (phase.median_bps - rate.bits_per_sec) / rate.bits_per_sec * 100.0,
+            })
+        };
+
+        Self {
+            rate_bps: rate.bits_per_sec,
+            download: error(&measured.download),
+            upload: error(&measured.upload),
+        }
+    }
+
+    // Whether every phase that ran measured within TOLERANCE_PERCENT
+    pub fn within_tolerance(&self) -> bool {
+        [&self.download, &self.upload]
+            .into_iter()
+            .flatten()
+            .all(|phase| phase.error_percent.abs() <= TOLERANCE_PERCENT)
+    }
+
+    pub fn verdict(&self) -> String {
+        let rate = crate::format_bits_per_sec(self.rate_bps);
+        if self.within_tolerance() {
+            format!("measurements are within {TOLERANCE_PERCENT}% at {rate}, results up to it can be trusted")
+        } else {
+            format!(
+                "measurements are off by more than {TOLERANCE_PERCENT}% at {rate}, \
+                likely this machine running out of CPU; treat results near it with caution"
+            )
+        }
+    }
+}
+
+// Run `speedtest`'s phases against a source sending at `rate` instead of
+// its provider, and report how far off they measured
+pub fn calibrate(
+    speedtest: &Speedtest,
+    rate: Rate,
+    observer: &mut dyn Observer,
+) -> Result<CalibrationReport> {
+    let addr = start_source(rate)?;
+    let speedtest = Speedtest {
+        download_url: Some(format!("http://{addr}/down?bytes={{bytes}}")),
+        upload_url: Some(format!("http://{addr}/up")),
+        connect: crate::net::ConnectOptions::default(),
+        http_version: crate::throughput::HttpVersion::Http1,
+        limit: None,
+        ..speedtest.clone()
+    };
+    let measured = speedtest.run_phases(Duration::ZERO, observer);
+
+    Ok(CalibrationReport::from_measured(rate, &measured))
+}
@@ -0,0 +1,217 @@
+/* --capture-spikes-ms: while a phase loads the link, tcpdump keeps a rolling
+   capture of packet headers in a ring of a few files, the oldest overwritten
+   as it goes. When a round trip under load takes longer than the threshold,
+   the ring is kept: the seconds leading up to the spike and a few after it,
+   for a closer look in Wireshark. Without a spike the ring is deleted when
+   the phase ends. It needs tcpdump installed and the right to capture (root
+   or CAP_NET_RAW); without them the spike is still noted, with why there's
+   no capture. Only the first spike of a run is captured, and the ring's
+   size is fixed, so a bad run can't fill the disk.
+*/
+use crate::throughput::EventLog;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// How long after the spike to keep capturing
+static CAPTURE_SECS: u64 = 5;
+// The ring: this many files of this many million bytes (tcpdump's -C unit)
+static RING_FILES: u32 = 4;
+static RING_FILE_MB: u32 = 16;
+// Enough for the IP and TCP headers, options included
+static SNAPLEN_BYTES: u32 = 128;
+
+// Rings started by this process, to name them apart
+static RINGS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Debug)]
+pub struct SpikeCapture {
+    pub threshold: Duration,
+    pub dir: PathBuf,
+    // capture on this interface rather than all of them, e.g. with
+    // --via-interface
+    pub interface: Option<String>,
+    // what to run as tcpdump
+    pub program: PathBuf,
+    // shared by clones, so the whole run gets one capture
+    taken: Arc<AtomicBool>,
+}
+
+impl SpikeCapture {
+    pub fn new(threshold: Duration, dir: PathBuf, interface: Option<String>) -> Self {
+        Self {
+            threshold,
+            dir,
+            interface,
+            program: PathBuf::from("tcpdump"),
+            taken: Arc::default(),
+        }
+    }
+
+    // The tcpdump arguments for a ring of files named `path` and a number.
+    // -U writes each packet as it comes, so stopping tcpdump loses none.
+    pub fn tcpdump_args(&self, path: &Path) -> Vec<String> {
+        vec![
+            "-i".to_owned(),
+            self.interface.clone().unwrap_or_else(|| "any".to_owned()),
+            "-s".to_owned(),
+            SNAPLEN_BYTES.to_string(),
+            "-U".to_owned(),
+            "-C".to_owned(),
+            RING_FILE_MB.to_string(),
+            "-W".to_owned(),
+            RING_FILES.to_string(),
+            "-w".to_owned(),
+            path.display().to_string(),
+            "tcp".to_owned(),
+        ]
+    }
+
+    // Start the ring for a phase, spikes recorded in `events`. There's none
+    // once the run's spike has been captured.
+    pub fn start(&self, events: &EventLog) -> Option<Ring> {
+        if self.taken.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        let path = self.dir.join(format!(
+            "cf_speedtest_ring_{}_{}.pcap",
+            std::process::id(),
+            RINGS.fetch_add(1, Ordering::SeqCst)
+        ));
+        let child = Command::new(&self.program)
+            .args(self.tcpdump_args(&path))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| format!("couldn't run tcpdump: {err}"));
+
+        Some(Ring {
+            capture: self.clone(),
+            events: events.clone(),
+            path,
+            child,
+            spike: None,
+        })
+    }
+}
+
+// A phase's rolling capture, see `SpikeCapture::start`
+pub struct Ring {
+    capture: SpikeCapture,
+    events: EventLog,
+    // the ring's files are this and a number
+    path: PathBuf,
+    // or why tcpdump isn't running
+    child: Result<Child, String>,
+    // when the spike was, and what its capture is called
+    spike: Option<(Instant, String)>,
+}
+
+impl Ring {
+    // Called with each round trip measured under load. A spike is recorded
+    // as a latency_spike, with where its packets went.
+    pub fn check(&mut self, round_trip: Duration) {
+        if self
+            .spike
+            .as_ref()
+            .is_some_and(|(at, _)| at.elapsed() >= Duration::from_secs(CAPTURE_SECS))
+        {
+            self.stop();
+        }
+        if round_trip < self.capture.threshold || self.capture.taken.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let round_trip_ms = round_trip.as_secs_f64() * 1000.0;
+        // tcpdump refusing (e.g. no permission) only shows once it exits
+        if let Err(why) = self.failure() {
+            self.events.record(
+                "latency_spike",
+                None,
+                Some(format!(
+                    "{round_trip_ms:.0}ms round trip, not captured: {why}"
+                )),
+            );
+            return;
+        }
+
+        let name = format!(
+            "cf_speedtest_spike_{}_{}",
+            std::process::id(),
+            chrono::Utc::now().timestamp()
+        );
+        self.events.record(
+            "latency_spike",
+            None,
+            Some(format!(
+                "{round_trip_ms:.0}ms round trip, capturing to {}",
+                self.capture.dir.join(format!("{name}_*.pcap")).display()
+            )),
+        );
+        self.spike = Some((Instant::now(), name));
+    }
+
+    // Whether tcpdump is still capturing, or why not
+    fn failure(&mut self) -> Result<(), String> {
+        let child = self.child.as_mut().map_err(|why| why.clone())?;
+        match child.try_wait() {
+            Ok(None) => Ok(()),
+            Ok(Some(_)) => {
+                let mut stderr = String::new();
+                if let Some(pipe) = child.stderr.as_mut() {
+                    let _ = pipe.read_to_string(&mut stderr);
+                }
+                Err(stderr
+                    .lines()
+                    .last()
+                    .unwrap_or("tcpdump stopped")
+                    .to_owned())
+            }
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Ok(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    // The files of the ring that tcpdump got to, oldest first
+    fn files(&self) -> Vec<PathBuf> {
+        let prefix = self.path.file_name().unwrap_or_default().to_string_lossy();
+        let mut files: Vec<_> = std::fs::read_dir(&self.capture.dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&*prefix))
+            .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+            .collect();
+        files.sort();
+        files.into_iter().map(|(_, path)| path).collect()
+    }
+
+    // At the end of the phase: keep the ring as the spike's capture if
+    // there was one, delete it if there wasn't
+    pub fn finish(mut self) {
+        self.stop();
+        for (i, file) in self.files().into_iter().enumerate() {
+            let kept = match &self.spike {
+                Some((_, name)) => {
+                    let to = self.capture.dir.join(format!("{name}_{i}.pcap"));
+                    std::fs::rename(&file, to)
+                }
+                None => std::fs::remove_file(&file),
+            };
+            if let Err(err) = kept {
+                tracing::warn!("Couldn't tidy up {}: {err}", file.display());
+            }
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// A hanging endpoint is exactly what we're looking for, so don't wait forever
+static CHECK_TIMEOUT_MILLIS: u64 = 9600;
+
+// Outcome of poking a single endpoint
+#[derive(Serialize)]
+pub struct EndpointCheck {
+    pub name: &'static str,
+    pub url: &'static str,
+    pub passed: bool,
+    pub status: Option<u16>,
+    pub latency_ms: Option<f64>,
+    pub error: Option<String>,
+}
+
+// POST `body` if given, otherwise GET
+fn check_endpoint(
+    name: &'static str,
+    url: &'static str,
+    request: ureq::Request,
+    body: Option<&str>,
+) -> EndpointCheck {
+    let request = request.set("User-Agent", crate::OUR_USER_AGENT);
+
+    let start = Instant::now();
+    let response = match body {
+        Some(body) => request.send_string(body),
+        None => request.call(),
+    };
+
+    let (passed, status, error) = match response {
+        // make sure the body actually arrives, not just the headers
+        Ok(resp) => {
+            let status = resp.status();
+            match std::io::copy(&mut resp.into_reader(), &mut std::io::sink()) {
+                Ok(_) => (true, Some(status), None),
+                Err(err) => (false, Some(status), Some(err.to_string())),
+            }
+        }
+        Err(ureq::Error::Status(status, resp)) => {
+            (false, Some(status), Some(resp.status_text().to_owned()))
+        }
+        Err(ureq::Error::Transport(err)) => (false, None, Some(err.to_string())),
+    };
+
+    EndpointCheck {
+        name,
+        url,
+        passed,
+        status,
+        latency_ms: status.map(|_| start.elapsed().as_secs_f64() * 1000.0),
+        error,
+    }
+}
+
+// Check every endpoint a test run depends on, using the same TLS setup as the test
+pub fn check_endpoints() -> Vec<EndpointCheck> {
+    let agent = ureq::AgentBuilder::new()
+        .tls_connector(Arc::new(crate::tls::InterceptingTlsConnector::new()))
+        .timeout(Duration::from_millis(CHECK_TIMEOUT_MILLIS))
+        .redirects(0)
+        .build();
+
+    vec![
+        check_endpoint(
+            "trace",
+            crate::CLOUDFLARE_SPEEDTEST_CGI_URL,
+            agent.get(crate::CLOUDFLARE_SPEEDTEST_CGI_URL),
+            None,
+        ),
+        check_endpoint(
+            "download",
+            crate::CLOUDFLARE_SPEEDTEST_SERVER_URL,
+            agent.get(crate::CLOUDFLARE_SPEEDTEST_SERVER_URL),
+            None,
+        ),
+        check_endpoint(
+            "upload",
+            crate::CLOUDFLARE_SPEEDTEST_UPLOAD_URL,
+            agent
+                .post(crate::CLOUDFLARE_SPEEDTEST_UPLOAD_URL)
+                .set("Content-Type", "text/plain;charset=UTF-8"),
+            Some("0"),
+        ),
+    ]
+}
@@ -0,0 +1,20 @@
+use chrono::{DateTime, Utc};
+
+// No clock that's actually been set reads earlier than this (2024-01-01),
+// but one that's counting up from zero on a board without an RTC does
+static EARLIEST_PLAUSIBLE_SECS: i64 = 1_704_067_200;
+
+pub fn is_plausible(now: DateTime<Utc>) -> bool {
+    now.timestamp() >= EARLIEST_PLAUSIBLE_SECS
+}
+
+/* The time of day, if the system clock can be believed. Everything that
+   prints or stores an absolute time goes through here, so that a fresh
+   single-board computer that hasn't synced NTP yet reports no time rather
+   than a start time in 1970. Durations never need the wall clock, use
+   Instant for those.
+*/
+pub fn now() -> Option<DateTime<Utc>> {
+    let now = Utc::now();
+    is_plausible(now).then_some(now)
+}
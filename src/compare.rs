@@ -0,0 +1,60 @@
+use crate::results::RunResult;
+use comfy_table::{presets::UTF8_FULL, Cell, Table};
+
+// How to show one row of the table for a run
+type Figure = fn(&RunResult) -> String;
+
+fn rate(bps: Option<f64>) -> String {
+    match bps {
+        Some(bps) => crate::format_bits_per_sec(bps),
+        None => "-".to_owned(),
+    }
+}
+
+/* Side by side results of the same test run different ways, one column per
+   run. Runs that failed are shown as such rather than left out, so the
+   columns always match what was asked for.
+*/
+pub fn comparison_table(runs: &[(String, Option<RunResult>)]) -> Table {
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL)
+        .set_content_arrangement(comfy_table::ContentArrangement::Dynamic)
+        .set_header(
+            std::iter::once(Cell::new(""))
+                .chain(runs.iter().map(|(label, _)| Cell::new(label)))
+                .collect::<Vec<_>>(),
+        );
+
+    let figures: [(&str, Figure); 5] = [
+        ("Latency", |run| match run.latency_ms {
+            Some(ms) => format!("{ms:.2}ms"),
+            None => "-".to_owned(),
+        }),
+        ("Download (median)", |run| {
+            rate(run.download.as_ref().map(|p| p.median_bps))
+        }),
+        ("Download (90th pctile)", |run| {
+            rate(run.download.as_ref().map(|p| p.p90_bps))
+        }),
+        ("Upload (median)", |run| {
+            rate(run.upload.as_ref().map(|p| p.median_bps))
+        }),
+        ("Upload (90th pctile)", |run| {
+            rate(run.upload.as_ref().map(|p| p.p90_bps))
+        }),
+    ];
+
+    for (name, figure) in figures {
+        table.add_row(
+            std::iter::once(Cell::new(name))
+                .chain(runs.iter().map(|(_, run)| match run {
+                    Some(run) => Cell::new(figure(run)),
+                    None => Cell::new("failed"),
+                }))
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    table
+}
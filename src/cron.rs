@@ -0,0 +1,142 @@
+// `daemon --cron`: when to test as a crontab would put it, five fields of
+// minute, hour, day of the month, month and day of the week (0 or 7 for
+// Sunday), in local time. Each field is *, a number, a range (1-5), a step
+// (*/15, 0-30/10) or a list of those (0,30). As in cron, a run is due when
+// the day of the month *or* the day of the week matches if both are given.
+// @hourly, @daily, @weekly and @monthly are there as shorthands.
+use chrono::{DateTime, Datelike, Duration, Local, TimeZone, Timelike, Utc};
+
+// How far ahead we look for the next run, past it the schedule can't match
+// (e.g. the 31st of February)
+static SEARCH_DAYS: i64 = 366 * 4;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    // bit n set for each value n the field matches
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+    // a field given as * matches any day, which changes how the days combine
+    any_day: bool,
+    any_weekday: bool,
+    pub expression: String,
+}
+
+// One field: which of min..=max it matches, and whether it was *
+fn parse_field(field: &str, min: u32, max: u32) -> Result<(u64, bool), String> {
+    let invalid = || format!("invalid cron field '{field}', expected values {min}-{max}");
+    let mut bits = 0u64;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (
+                    start.parse().map_err(|_| invalid())?,
+                    end.parse().map_err(|_| invalid())?,
+                ),
+                None => {
+                    let value = range.parse().map_err(|_| invalid())?;
+                    // a step from a single value runs to the end, as in 5/15
+                    (value, if part.contains('/') { max } else { value })
+                }
+            },
+        };
+        if step == 0 || start < min || end > max || start > end {
+            return Err(invalid());
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Ok((bits, field == "*"))
+}
+
+impl std::str::FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expanded = match s {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            _ => s,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "invalid cron expression '{s}', expected five fields, e.g. '*/15 * * * *'"
+            ));
+        };
+
+        let (weekdays, any_weekday) = parse_field(weekday, 0, 7)?;
+        let (days, any_day) = parse_field(day, 1, 31)?;
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59)?.0,
+            hours: parse_field(hour, 0, 23)?.0 as u32,
+            days: days as u32,
+            months: parse_field(month, 1, 12)?.0 as u16,
+            // 7 is Sunday too
+            weekdays: (weekdays | weekdays >> 7) as u8 & 0x7f,
+            any_day,
+            any_weekday,
+            expression: s.to_owned(),
+        })
+    }
+}
+
+impl Schedule {
+    pub fn matches(&self, time: &impl Datelike) -> bool {
+        let day = self.days & 1 << time.day() != 0;
+        let weekday = self.weekdays & 1 << time.weekday().num_days_from_sunday() != 0;
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (false, true) => day,
+            (true, false) => weekday,
+            (false, false) => day || weekday,
+        };
+
+        day_matches && self.months & 1 << time.month() != 0
+    }
+
+    // The first minute the schedule matches after `after`, in `zone`. None
+    // if it never does.
+    pub fn next_in<Tz: TimeZone>(&self, after: DateTime<Utc>, zone: &Tz) -> Option<DateTime<Utc>> {
+        let start = after.with_timezone(zone).naive_local();
+        let start = start.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+
+        let mut day = start.date();
+        for _ in 0..SEARCH_DAYS {
+            if self.matches(&day) {
+                for hour in (0..24).filter(|hour| self.hours & 1 << hour != 0) {
+                    for minute in (0..60).filter(|minute| self.minutes & 1 << minute != 0) {
+                        let candidate = day.and_hms_opt(hour, minute, 0)?;
+                        if candidate < start {
+                            continue;
+                        }
+                        // a time skipped by a DST change doesn't happen
+                        if let Some(due) = zone.from_local_datetime(&candidate).earliest() {
+                            return Some(due.with_timezone(&Utc));
+                        }
+                    }
+                }
+            }
+            day = day.succ_opt()?;
+        }
+
+        None
+    }
+
+    pub fn next(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.next_in(after, &Local)
+    }
+}
@@ -0,0 +1,466 @@
+use crate::alerts::{Alert, DropAlerts};
+use crate::args::{DaemonArgs, MeteredPolicy, UserArgs};
+use crate::exporter::Exporter;
+use argh::FromArgs;
+use cf_speedtest::host::{HostState, PowerSource};
+use chrono::{DateTime, Utc};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// How often we check the schedule, signal flags and config file while waiting
+static SCHEDULE_POLL_MILLIS: u64 = 200;
+
+// Flags flipped by signals, checked by the scheduler
+#[derive(Default)]
+struct Controls {
+    paused: AtomicBool,
+    run_now: AtomicBool,
+    reload: AtomicBool,
+}
+
+#[cfg(unix)]
+fn install_signal_handlers(controls: &Arc<Controls>) {
+    use signal_hook::consts::{SIGHUP, SIGUSR1, SIGUSR2};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = match Signals::new([SIGUSR1, SIGUSR2, SIGHUP]) {
+        Ok(signals) => signals,
+        Err(err) => {
+            tracing::warn!(
+                "Couldn't install signal handlers, SIGUSR1/SIGUSR2/SIGHUP won't work: {err}"
+            );
+            return;
+        }
+    };
+
+    let controls = Arc::clone(controls);
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            match signal {
+                SIGUSR1 => {
+                    let was_paused = controls.paused.fetch_xor(true, Ordering::SeqCst);
+                    if was_paused {
+                        tracing::info!("Resuming scheduled tests");
+                    } else {
+                        tracing::info!("Pausing scheduled tests, send SIGUSR1 again to resume");
+                    }
+                }
+                SIGUSR2 => controls.run_now.store(true, Ordering::SeqCst),
+                SIGHUP => controls.reload.store(true, Ordering::SeqCst),
+                _ => {}
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn install_signal_handlers(_controls: &Arc<Controls>) {}
+
+/* The config file holds the same options you'd pass on the command line,
+   spread over as many lines as you like, with # starting a comment:
+
+     --history
+     --output influx-live
+     daemon --interval 30m
+
+   Options that aren't in the file fall back to their defaults, not to the
+   command line the daemon was started with.
+*/
+pub fn load_config(path: &Path, fallback: &DaemonArgs) -> Result<(UserArgs, DaemonArgs)> {
+    let contents = std::fs::read_to_string(path)?;
+    let tokens: Vec<&str> = contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .flat_map(str::split_whitespace)
+        .collect();
+
+    let config = UserArgs::from_args(&["cf_speedtest"], &tokens).map_err(|early_exit| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, early_exit.output)
+    })?;
+    config.validate()?;
+
+    let daemon = match (&config.command, config.daemon_args()) {
+        (_, Some(daemon)) => DaemonArgs {
+            config: fallback.config.clone(),
+            ..daemon
+        },
+        (None, None) => fallback.clone(),
+        (Some(_), None) => {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the only subcommand allowed in a daemon config file is daemon",
+            )))
+        }
+    };
+
+    check_schedule(&daemon)?;
+
+    Ok((config, daemon))
+}
+
+// One way of saying when to test at a time. A jitter as big as half the
+// period could put two runs in one period.
+fn check_schedule(daemon: &DaemonArgs) -> Result<()> {
+    let invalid = |message: &str| {
+        Err(Box::new(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            message.to_owned(),
+        )) as Box<dyn std::error::Error>)
+    };
+
+    if daemon.cron.is_some() && (daemon.align.is_some() || !daemon.busy.is_empty()) {
+        return invalid("--cron is a schedule of its own, leave out --align and --busy");
+    }
+    // aligned runs keep to their boundaries whatever the time of day
+    if daemon.align.is_some() && !daemon.busy.is_empty() {
+        return invalid("--align and --busy are two schedules, pick one");
+    }
+    if daemon
+        .cron
+        .as_ref()
+        .is_some_and(|cron| cron.next(Utc::now()).is_none())
+    {
+        return invalid("--cron never matches a day that exists");
+    }
+    match daemon.align {
+        Some(align) if daemon.align_jitter.0 * 2 >= align.0 => {
+            invalid("--align-jitter must be less than half of --align")
+        }
+        _ => Ok(()),
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// The options currently in effect, and where they came from
+struct Settings {
+    config: UserArgs,
+    daemon: DaemonArgs,
+    config_path: Option<PathBuf>,
+    config_modified: Option<SystemTime>,
+}
+
+impl Settings {
+    fn config_changed(&self) -> bool {
+        match &self.config_path {
+            Some(path) => modified_time(path) != self.config_modified,
+            None => false,
+        }
+    }
+
+    // Keep the old settings if the new ones are broken, a typo shouldn't stop the daemon
+    fn reload(&mut self) {
+        let Some(path) = &self.config_path else {
+            tracing::warn!("No --config file to reload");
+            return;
+        };
+
+        self.config_modified = modified_time(path);
+        match load_config(path, &self.daemon) {
+            Ok((config, daemon)) => {
+                tracing::info!("Reloaded config from {}", path.display());
+                self.config = config;
+                self.daemon = daemon;
+            }
+            Err(err) => tracing::warn!(
+                "Couldn't reload config from {}, keeping the old one: {err}",
+                path.display()
+            ),
+        }
+    }
+}
+
+// A random duration up to `max`. Doesn't need to be good randomness, just
+// different on every machine and every start.
+pub fn random_jitter(max: Duration) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    let max_millis = max.as_millis() as u64;
+    Duration::from_millis(random % max_millis.saturating_add(1))
+}
+
+// The first boundary of `period` after `after`, counting from midnight UTC
+pub fn next_boundary(after: DateTime<Utc>, period: Duration) -> DateTime<Utc> {
+    let period = period.as_secs() as i64;
+    let next = (after.timestamp().div_euclid(period) + 1) * period;
+    DateTime::from_timestamp(next, 0).unwrap_or(after)
+}
+
+// A random time within `jitter` either side of `boundary`
+fn jittered(boundary: DateTime<Utc>, jitter: Duration) -> DateTime<Utc> {
+    let offset = random_jitter(jitter * 2).as_millis() as i64 - jitter.as_millis() as i64;
+    boundary + chrono::Duration::milliseconds(offset)
+}
+
+// The next aligned run: which boundary it's for, and when exactly it's due
+#[derive(Clone, Copy)]
+struct AlignedRun {
+    period: Duration,
+    boundary: DateTime<Utc>,
+    due: DateTime<Utc>,
+}
+
+impl AlignedRun {
+    fn after(after: DateTime<Utc>, period: Duration, jitter: Duration) -> Self {
+        let boundary = next_boundary(after, period);
+        Self {
+            period,
+            boundary,
+            due: jittered(boundary, jitter),
+        }
+    }
+}
+
+// The interval in effect at `now` (local time), if we know what time it is
+pub fn current_interval(daemon: &DaemonArgs, now: Option<chrono::NaiveTime>) -> Duration {
+    now.and_then(|now| daemon.busy.iter().find(|busy| busy.contains(now)))
+        .map_or(daemon.interval.0, |busy| busy.interval.0)
+}
+
+// Why a scheduled run should wait for the next one, per --defer-on-battery
+// and --defer-on-metered or --on-metered. A test asked for with SIGUSR2
+// runs regardless.
+pub fn deferral(config: &UserArgs, daemon: &DaemonArgs) -> Option<&'static str> {
+    if !daemon.defer_on_battery && config.on_metered == MeteredPolicy::Run {
+        return None;
+    }
+
+    let host = cf_speedtest::host::detect(config.via_interface.as_deref());
+    deferral_for(&host, config, daemon)
+}
+
+pub fn deferral_for(
+    host: &HostState,
+    config: &UserArgs,
+    daemon: &DaemonArgs,
+) -> Option<&'static str> {
+    let defer_on_metered = daemon.defer_on_metered || config.on_metered == MeteredPolicy::Skip;
+    if daemon.defer_on_battery && host.power == Some(PowerSource::Battery) {
+        Some("running on battery")
+    } else if defer_on_metered && host.metered == Some(true) {
+        Some("the connection is metered")
+    } else {
+        None
+    }
+}
+
+// --alert-drop's alerts with what went before, from history if it's kept
+fn drop_alerts(config: &UserArgs, daemon: &DaemonArgs) -> Option<DropAlerts> {
+    let mut alerts = DropAlerts::new(daemon.alert_drop?, daemon.alert_window);
+    if config.history {
+        match cf_speedtest::history::History::open(&config.history_path())
+            .and_then(|history| history.recent("cf_speedtest", daemon.alert_window))
+        {
+            Ok(runs) => alerts.seed(&runs),
+            Err(err) => tracing::warn!("Couldn't read history for --alert-drop: {err}"),
+        }
+    }
+
+    Some(alerts)
+}
+
+// Log an alert and hand it to --alert-command, left to finish on its own so
+// a slow notifier can't hold up the schedule
+fn notify(daemon: &DaemonArgs, alert: &Alert) {
+    tracing::warn!("{}", alert.message());
+    let Some(command) = &daemon.alert_command else {
+        return;
+    };
+
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let spawned = Command::new(shell)
+        .args([flag, command])
+        .env("CF_SPEEDTEST_ALERT", alert.message())
+        .env("CF_SPEEDTEST_ALERT_DIRECTION", alert.direction.name())
+        .env(
+            "CF_SPEEDTEST_ALERT_KIND",
+            if alert.recovered { "recovered" } else { "drop" },
+        )
+        .env(
+            "CF_SPEEDTEST_ALERT_DROP_PERCENT",
+            format!("{:.1}", alert.drop_percent),
+        )
+        .stdin(Stdio::null())
+        .spawn();
+    match spawned {
+        Ok(mut child) => {
+            std::thread::spawn(move || child.wait());
+        }
+        Err(err) => tracing::error!("Couldn't run --alert-command: {err}"),
+    }
+}
+
+fn run_once(
+    config: &UserArgs,
+    daemon: &DaemonArgs,
+    exporter: Option<&Exporter>,
+    alerts: Option<&mut DropAlerts>,
+) {
+    match crate::run_speedtest(config, &cf_speedtest::Interrupt::default()) {
+        Ok(run) => {
+            if let Err(err) = crate::record_run(config, &run) {
+                tracing::error!("Couldn't record run in history: {err}");
+            }
+            if let Some(exporter) = exporter {
+                exporter.publish(&run);
+            }
+            for alert in alerts
+                .map(|alerts| alerts.observe(&run))
+                .unwrap_or_default()
+            {
+                notify(daemon, &alert);
+            }
+        }
+        // keep going, the next run might well work
+        Err(err) => {
+            tracing::error!("Speed test failed: {err}");
+            if let Some(exporter) = exporter {
+                exporter.failed();
+            }
+        }
+    }
+}
+
+pub fn run_daemon(config: &UserArgs, daemon: &DaemonArgs) -> Result<()> {
+    let mut settings = Settings {
+        config: config.clone(),
+        daemon: daemon.clone(),
+        config_path: daemon.config.clone(),
+        config_modified: None,
+    };
+    if let Some(path) = &daemon.config {
+        settings.config_modified = modified_time(path);
+        (settings.config, settings.daemon) = load_config(path, daemon)?;
+    }
+    check_schedule(&settings.daemon)?;
+    let exporter = settings.daemon.listen.map(Exporter::listen).transpose()?;
+    if let Some(exporter) = &exporter {
+        tracing::info!(
+            "Serving metrics at http://{}/metrics",
+            exporter.local_addr()
+        );
+    }
+
+    let mut alerts = drop_alerts(&settings.config, &settings.daemon);
+
+    let controls = Arc::new(Controls::default());
+    install_signal_handlers(&controls);
+
+    let mut first_due = Instant::now() + random_jitter(settings.daemon.startup_jitter.0);
+    if !settings.daemon.run_on_start {
+        first_due += settings.daemon.interval.0;
+    }
+
+    // when the last scheduled run was due, so a new interval applies straight away
+    let mut last_scheduled: Option<Instant> = None;
+    let mut aligned: Option<AlignedRun> = None;
+    // the --cron schedule the next run was worked out from, and when it's due
+    let mut next_cron: Option<(crate::cron::Schedule, DateTime<Utc>)> = None;
+    // how much later than the interval says the next run goes, see --run-jitter
+    let mut run_jitter = random_jitter(settings.daemon.run_jitter.0);
+    let mut run_on_start = settings.daemon.run_on_start;
+
+    loop {
+        if controls.reload.swap(false, Ordering::SeqCst) || settings.config_changed() {
+            settings.reload();
+        }
+
+        let interval = current_interval(
+            &settings.daemon,
+            crate::clock::now().map(|now| now.with_timezone(&chrono::Local).time()),
+        );
+        let now = Instant::now();
+        // aligning needs a clock we can believe, until then keep to the interval
+        let due = match (
+            &settings.daemon.cron,
+            settings.daemon.align,
+            crate::clock::now(),
+        ) {
+            (Some(cron), _, Some(wall_now)) => {
+                let jitter = settings.daemon.run_jitter.0;
+                let scheduled = |after| {
+                    cron.next(after).map(|due| {
+                        due + chrono::Duration::milliseconds(
+                            random_jitter(jitter).as_millis() as i64
+                        )
+                    })
+                };
+                let next = match &next_cron {
+                    Some((schedule, due)) if schedule == cron => Some(*due),
+                    _ => scheduled(wall_now),
+                };
+                let reached = next.is_some_and(|next| wall_now >= next);
+
+                // a slow run doesn't try to catch up either
+                next_cron =
+                    if reached { scheduled(wall_now) } else { next }.map(|due| (cron.clone(), due));
+                std::mem::take(&mut run_on_start) || reached
+            }
+            (_, Some(align), Some(wall_now)) => {
+                let jitter = settings.daemon.align_jitter.0;
+                let next = match aligned {
+                    Some(next) if next.period == align.0 => next,
+                    _ => AlignedRun::after(wall_now, align.0, jitter),
+                };
+                let due = std::mem::take(&mut run_on_start) || wall_now >= next.due;
+
+                // a run just before its boundary mustn't count for the one after,
+                // and a slow one doesn't try to catch up
+                aligned = Some(if due && wall_now >= next.due {
+                    AlignedRun::after(wall_now.max(next.boundary), align.0, jitter)
+                } else {
+                    next
+                });
+                due
+            }
+            // first_due has already seen to --run-on-start
+            _ => {
+                run_on_start = false;
+                match last_scheduled {
+                    Some(last) => now >= last + interval + run_jitter,
+                    None => now >= first_due,
+                }
+            }
+        };
+
+        if due {
+            // don't try to catch up on runs we missed while a slow test ran
+            last_scheduled = Some(now);
+            run_jitter = random_jitter(settings.daemon.run_jitter.0);
+            if !controls.paused.load(Ordering::SeqCst) {
+                match deferral(&settings.config, &settings.daemon) {
+                    Some(reason) => tracing::info!("Skipping scheduled test, {reason}"),
+                    None => run_once(
+                        &settings.config,
+                        &settings.daemon,
+                        exporter.as_ref(),
+                        alerts.as_mut(),
+                    ),
+                }
+            }
+        } else if controls.run_now.swap(false, Ordering::SeqCst) {
+            run_once(
+                &settings.config,
+                &settings.daemon,
+                exporter.as_ref(),
+                alerts.as_mut(),
+            );
+        } else {
+            std::thread::sleep(Duration::from_millis(SCHEDULE_POLL_MILLIS));
+        }
+    }
+}
@@ -0,0 +1,244 @@
+/* `cf_speedtest diff before.json after.json`: what changed between two
+   results, e.g. either side of a router firmware update. The figures are
+   compared as numbers, with how much and by what fraction they changed;
+   where and how each run was done (colo, server, interface, how long its
+   phases ran) is compared as is, since a different colo or a shorter test
+   explains a lot of a difference.
+*/
+use crate::results::RunResult;
+use serde::Serialize;
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unit {
+    BitsPerSecond,
+    Millis,
+    Percent,
+    Count,
+}
+
+#[derive(Clone, Copy)]
+enum Getter {
+    Number(Unit, fn(&RunResult) -> Option<f64>),
+    Text(fn(&RunResult) -> Option<String>),
+}
+
+// What's compared, in the order it's shown
+static FIELDS: [(&str, &str, Getter); 15] = [
+    (
+        "download.median_bps",
+        "Download (median)",
+        Getter::Number(Unit::BitsPerSecond, |run| {
+            run.download.as_ref().map(|p| p.median_bps)
+        }),
+    ),
+    (
+        "download.p90_bps",
+        "Download (90th pctile)",
+        Getter::Number(Unit::BitsPerSecond, |run| {
+            run.download.as_ref().map(|p| p.p90_bps)
+        }),
+    ),
+    (
+        "upload.median_bps",
+        "Upload (median)",
+        Getter::Number(Unit::BitsPerSecond, |run| {
+            run.upload.as_ref().map(|p| p.median_bps)
+        }),
+    ),
+    (
+        "upload.p90_bps",
+        "Upload (90th pctile)",
+        Getter::Number(Unit::BitsPerSecond, |run| {
+            run.upload.as_ref().map(|p| p.p90_bps)
+        }),
+    ),
+    (
+        "latency_ms",
+        "Latency",
+        Getter::Number(Unit::Millis, |run| run.latency_ms),
+    ),
+    (
+        "idle_latency.jitter_ms",
+        "Jitter",
+        Getter::Number(Unit::Millis, |run| {
+            run.idle_latency.as_ref().map(|l| l.jitter_ms)
+        }),
+    ),
+    (
+        "download_loaded_latency.median_ms",
+        "Latency (download)",
+        Getter::Number(Unit::Millis, |run| {
+            run.download_loaded_latency.as_ref().map(|l| l.median_ms)
+        }),
+    ),
+    (
+        "upload_loaded_latency.median_ms",
+        "Latency (upload)",
+        Getter::Number(Unit::Millis, |run| {
+            run.upload_loaded_latency.as_ref().map(|l| l.median_ms)
+        }),
+    ),
+    (
+        "loss_percent",
+        "Loss",
+        Getter::Number(Unit::Percent, |run| {
+            run.sockets.as_ref().and_then(|s| s.retransmit_percent())
+        }),
+    ),
+    ("colo", "Colo", Getter::Text(|run| run.colo.clone())),
+    ("server", "Server", Getter::Text(|run| run.server.clone())),
+    (
+        "via_interface",
+        "Interface",
+        Getter::Text(|run| run.via_interface.clone()),
+    ),
+    (
+        "source",
+        "Measured By",
+        Getter::Text(|run| Some(run.source.clone())),
+    ),
+    (
+        "download_seconds",
+        "Download Seconds",
+        Getter::Number(Unit::Count, |run| {
+            run.download
+                .as_ref()
+                .filter(|p| !p.samples.is_empty())
+                .map(|p| p.samples.len() as f64)
+        }),
+    ),
+    (
+        "connections",
+        "Connections",
+        Getter::Number(Unit::Count, |run| {
+            run.sockets.as_ref().map(|s| s.connections as f64)
+        }),
+    ),
+];
+
+// One field of the two runs, as `diff --machine` prints it
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Difference {
+    pub field: &'static str,
+    #[serde(skip)]
+    pub label: &'static str,
+    #[serde(skip)]
+    pub unit: Option<Unit>,
+    pub before: serde_json::Value,
+    pub after: serde_json::Value,
+    pub changed: bool,
+    // after minus before, and that as a percentage of before
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub change: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub change_percent: Option<f64>,
+}
+
+// Every field either run has, measured or not by the other
+pub fn diff(before: &RunResult, after: &RunResult) -> Vec<Difference> {
+    FIELDS
+        .iter()
+        .filter_map(|&(field, label, getter)| {
+            let difference = match getter {
+                Getter::Number(unit, get) => {
+                    let (a, b) = (get(before), get(after));
+                    let change = a.zip(b).map(|(a, b)| b - a);
+                    Difference {
+                        field,
+                        label,
+                        unit: Some(unit),
+                        before: a.into(),
+                        after: b.into(),
+                        changed: a != b,
+                        change,
+                        change_percent: change
+                            .zip(a)
+                            .filter(|&(_, a)| a != 0.0)
+                            .map(|(change, a)| change / a * 100.0),
+                    }
+                }
+                Getter::Text(get) => {
+                    let (a, b) = (get(before), get(after));
+                    Difference {
+                        field,
+                        label,
+                        unit: None,
+                        changed: a != b,
+                        before: a.into(),
+                        after: b.into(),
+                        change: None,
+                        change_percent: None,
+                    }
+                }
+            };
+
+            (!difference.before.is_null() || !difference.after.is_null()).then_some(difference)
+        })
+        .collect()
+}
+
+// A result as --output json, --output-file json: or --results-file wrote
+// it; from a file of one result per line, the last of them
+pub fn read_result(path: &Path) -> Result<RunResult> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("couldn't read {}: {err}", path.display()))?;
+    let json = match serde_json::from_str(&contents) {
+        Ok(run) => return Ok(run),
+        Err(_) => contents.lines().rfind(|line| !line.trim().is_empty()),
+    };
+
+    json.and_then(|line| serde_json::from_str(line).ok())
+        .ok_or_else(|| format!("{} doesn't hold a cf_speedtest result", path.display()).into())
+}
+
+fn format_value(unit: Option<Unit>, value: &serde_json::Value) -> String {
+    match (unit, value) {
+        (_, serde_json::Value::Null) => "-".to_owned(),
+        (Some(unit), value) => format_number(unit, value.as_f64().unwrap_or_default()),
+        (None, serde_json::Value::String(text)) => text.clone(),
+        (None, value) => value.to_string(),
+    }
+}
+
+fn format_number(unit: Unit, value: f64) -> String {
+    match unit {
+        Unit::BitsPerSecond => crate::format_bits_per_sec(value.abs()),
+        Unit::Millis => format!("{:.2}ms", value.abs()),
+        Unit::Percent => format!("{:.2}%", value.abs()),
+        Unit::Count => format!("{}", value.abs()),
+    }
+}
+
+impl Difference {
+    pub fn before_cell(&self) -> String {
+        format_value(self.unit, &self.before)
+    }
+
+    pub fn after_cell(&self) -> String {
+        format_value(self.unit, &self.after)
+    }
+
+    // e.g. "+12.50 Mbit/s (+10.4%)", or "changed" for text
+    pub fn change_cell(&self) -> String {
+        let Some(change) = self.change else {
+            return if self.changed { "changed" } else { "same" }.to_owned();
+        };
+        if change == 0.0 {
+            return "same".to_owned();
+        }
+
+        let sign = if change < 0.0 { "-" } else { "+" };
+        let mut cell = format!(
+            "{sign}{}",
+            format_number(self.unit.unwrap_or(Unit::Count), change)
+        );
+        if let Some(percent) = self.change_percent {
+            cell += &format!(" ({percent:+.1}%)");
+        }
+
+        cell
+    }
+}
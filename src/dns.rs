@@ -0,0 +1,293 @@
+/* --dns and --doh: look names up with a resolver of our choosing rather
+   than the system's, e.g. to see whether the ISP's resolver sends us to a
+   worse Cloudflare datacenter. --dns asks a server over plain UDP, --doh
+   over HTTPS (RFC 8484); both speak the DNS wire format, asking for A and
+   AAAA records as --ipv4 and --ipv6 allow. The DoH server's own name is
+   looked up by the system resolver, as a browser does. An answer is kept
+   for as long as its TTL says, so a run looks each host up once and every
+   connection, and the address in the results, goes where that answer
+   said; one agent asks the DoH server throughout, on one connection.
+*/
+use crate::net::{ConnectOptions, IpFamily};
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+static DNS_PORT: u16 = 53;
+static UDP_TIMEOUT_MILLIS: u64 = 3000;
+static UDP_ATTEMPTS: usize = 2;
+// Big enough for any answer to an A or AAAA query that isn't truncated
+static MAX_MESSAGE_BYTES: usize = 4096;
+static TYPE_A: u16 = 1;
+static TYPE_AAAA: u16 = 28;
+static CLASS_IN: u16 = 1;
+
+// An answer we were given, until its TTL runs out
+struct Cached {
+    server: NameServer,
+    host: String,
+    family: Option<IpFamily>,
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+static CACHE: Mutex<Vec<Cached>> = Mutex::new(Vec::new());
+// The agent asking each DoH server, by the options it connects with
+static DOH_AGENTS: Mutex<Vec<(String, ConnectOptions, ureq::Agent)>> = Mutex::new(Vec::new());
+
+// Who --dns or --doh has us ask
+#[derive(Clone, Debug, PartialEq)]
+pub enum NameServer {
+    Udp(SocketAddr),
+    Doh(String),
+}
+
+impl NameServer {
+    // From --dns: an address, with a port or not
+    pub fn udp(server: &str) -> std::result::Result<Self, String> {
+        server
+            .parse::<SocketAddr>()
+            .or_else(|_| {
+                server
+                    .parse::<IpAddr>()
+                    .map(|ip| SocketAddr::new(ip, DNS_PORT))
+            })
+            .map(Self::Udp)
+            .map_err(|_| format!("--dns '{server}' isn't an IP address, e.g. 1.1.1.1"))
+    }
+
+    // From --doh: an https:// URL
+    pub fn doh(url: &str) -> std::result::Result<Self, String> {
+        match url.parse::<tungstenite::http::Uri>() {
+            Ok(uri) if uri.scheme_str() == Some("https") && uri.host().is_some() => {
+                Ok(Self::Doh(url.to_owned()))
+            }
+            _ => Err(format!(
+                "--doh '{url}' isn't an https:// URL, e.g. https://cloudflare-dns.com/dns-query"
+            )),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Udp(addr) => addr.to_string(),
+            Self::Doh(url) => url.clone(),
+        }
+    }
+
+    // The addresses of `host`, of `family` or else both, from the cache
+    // while the last answer's TTL lasts
+    pub fn lookup(
+        &self,
+        host: &str,
+        family: Option<IpFamily>,
+        connect: &ConnectOptions,
+    ) -> Result<Vec<IpAddr>> {
+        let now = Instant::now();
+        let is_entry = |c: &Cached| {
+            c.server == *self && c.host.eq_ignore_ascii_case(host) && c.family == family
+        };
+        {
+            let mut cache = CACHE.lock().unwrap_or_else(|err| err.into_inner());
+            cache.retain(|c| c.expires > now);
+            if let Some(cached) = cache.iter().find(|c| is_entry(c)) {
+                return Ok(cached.addrs.clone());
+            }
+        }
+
+        let types = match family {
+            Some(IpFamily::V4) => vec![TYPE_A],
+            Some(IpFamily::V6) => vec![TYPE_AAAA],
+            None => vec![TYPE_A, TYPE_AAAA],
+        };
+        let mut records = vec![];
+        for record_type in types {
+            let (id, message) = query(host, record_type)?;
+            let answer = match self {
+                Self::Udp(server) => exchange_udp(*server, &message)?,
+                Self::Doh(url) => exchange_doh(url, &message, connect)?,
+            };
+            records.extend(parse_records(&answer, id, record_type)?);
+        }
+
+        let addrs: Vec<_> = records.iter().map(|&(addr, _)| addr).collect();
+        // an answer is only as fresh as its shortest-lived record
+        if let Some(ttl) = records.iter().map(|&(_, ttl)| ttl).min() {
+            let mut cache = CACHE.lock().unwrap_or_else(|err| err.into_inner());
+            cache.retain(|c| !is_entry(c));
+            cache.push(Cached {
+                server: self.clone(),
+                host: host.to_owned(),
+                family,
+                addrs: addrs.clone(),
+                expires: now + Duration::from_secs(ttl.into()),
+            });
+        }
+
+        Ok(addrs)
+    }
+}
+
+// A recursive query for `name`, with the id it goes out with
+pub fn query(name: &str, record_type: u16) -> Result<(u16, Vec<u8>)> {
+    use std::hash::{BuildHasher, Hasher};
+
+    let id = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish() as u16;
+    let mut message = vec![];
+    message.extend(id.to_be_bytes());
+    // recursion desired, one question
+    message.extend([0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("'{name}' isn't a name DNS can look up").into());
+        }
+        message.push(label.len() as u8);
+        message.extend(label.as_bytes());
+    }
+    message.push(0);
+    message.extend(record_type.to_be_bytes());
+    message.extend(CLASS_IN.to_be_bytes());
+
+    Ok((id, message))
+}
+
+// Past a (possibly compressed) name starting at `at`
+fn skip_name(message: &[u8], mut at: usize) -> Result<usize> {
+    loop {
+        let len = *message.get(at).ok_or("truncated DNS answer")?;
+        match len {
+            0 => return Ok(at + 1),
+            // a pointer ends the name
+            _ if len & 0xc0 == 0xc0 => return Ok(at + 2),
+            _ => at += 1 + len as usize,
+        }
+    }
+}
+
+// The addresses of `record_type` in the answer to query `id`, CNAMEs along
+// the way skipped
+pub fn parse_answer(message: &[u8], id: u16, record_type: u16) -> Result<Vec<IpAddr>> {
+    Ok(parse_records(message, id, record_type)?
+        .into_iter()
+        .map(|(addr, _)| addr)
+        .collect())
+}
+
+// As `parse_answer`, with each address's TTL in seconds
+fn parse_records(message: &[u8], id: u16, record_type: u16) -> Result<Vec<(IpAddr, u32)>> {
+    let u16_at = |at: usize| -> Result<u16> {
+        let bytes = message.get(at..at + 2).ok_or("truncated DNS answer")?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+
+    if u16_at(0)? != id {
+        return Err("the DNS answer is for another query".into());
+    }
+    let flags = u16_at(2)?;
+    if flags & 0x0200 != 0 {
+        return Err("the DNS answer was truncated".into());
+    }
+    match flags & 0x000f {
+        0 => {}
+        3 => return Err("no such name".into()),
+        rcode => return Err(format!("the DNS lookup failed with rcode {rcode}").into()),
+    }
+
+    let (questions, answers) = (u16_at(4)?, u16_at(6)?);
+    let mut at = 12;
+    for _ in 0..questions {
+        at = skip_name(message, at)? + 4;
+    }
+
+    let mut addrs = vec![];
+    for _ in 0..answers {
+        at = skip_name(message, at)?;
+        let (kind, len) = (u16_at(at)?, u16_at(at + 8)? as usize);
+        let ttl = (u32::from(u16_at(at + 4)?) << 16) | u32::from(u16_at(at + 6)?);
+        let data = message
+            .get(at + 10..at + 10 + len)
+            .ok_or("truncated DNS answer")?;
+        at += 10 + len;
+
+        if kind != record_type {
+            continue;
+        }
+        if let Ok(octets) = <[u8; 4]>::try_from(data) {
+            addrs.push((Ipv4Addr::from(octets).into(), ttl));
+        } else if let Ok(octets) = <[u8; 16]>::try_from(data) {
+            addrs.push((Ipv6Addr::from(octets).into(), ttl));
+        } else {
+            return Err("a malformed address in the DNS answer".into());
+        }
+    }
+
+    Ok(addrs)
+}
+
+fn exchange_udp(server: SocketAddr, message: &[u8]) -> Result<Vec<u8>> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(Duration::from_millis(UDP_TIMEOUT_MILLIS)))?;
+
+    let mut buf = vec![0; MAX_MESSAGE_BYTES];
+    let mut last_error = None;
+    // UDP may well lose one, so ask again before giving up
+    for _ in 0..UDP_ATTEMPTS {
+        socket.send(message)?;
+        match socket.recv(&mut buf) {
+            Ok(len) => return Ok(buf[..len].to_vec()),
+            Err(err) => last_error = Some(err),
+        }
+    }
+
+    Err(format!(
+        "{server} didn't answer: {}",
+        last_error.map_or_else(String::new, |err| err.to_string())
+    )
+    .into())
+}
+
+fn exchange_doh(url: &str, message: &[u8], connect: &ConnectOptions) -> Result<Vec<u8>> {
+    // the DoH server is found the usual way, or we'd ask it for itself
+    let bootstrap = ConnectOptions {
+        dns: None,
+        ..connect.clone()
+    };
+    let agent = {
+        let mut agents = DOH_AGENTS.lock().unwrap_or_else(|err| err.into_inner());
+        match agents
+            .iter()
+            .find(|(server, options, _)| server == url && *options == bootstrap)
+        {
+            Some((_, _, agent)) => agent.clone(),
+            None => {
+                let agent = crate::build_agent(&bootstrap);
+                agents.push((url.to_owned(), bootstrap, agent.clone()));
+                agent
+            }
+        }
+    };
+    let response = agent
+        .post(url)
+        .set("Content-Type", "application/dns-message")
+        .set("Accept", "application/dns-message")
+        .set("User-Agent", crate::OUR_USER_AGENT)
+        .send_bytes(message)?;
+
+    let mut answer = vec![];
+    response
+        .into_reader()
+        .take(MAX_MESSAGE_BYTES as u64)
+        .read_to_end(&mut answer)?;
+
+    Ok(answer)
+}
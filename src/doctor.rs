@@ -0,0 +1,373 @@
+/* `cf_speedtest doctor`, for when results look wrong: checks the things
+   around a test that skew or break it without the test itself noticing -
+   DNS, each IP family, proxies we don't go through, something intercepting
+   TLS, a clock that's wrong, and what optional features are allowed here.
+   Every problem comes with what to do about it.
+*/
+use crate::args::UserArgs;
+use crate::net::{ConnectOptions, IpFamily};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::Path;
+
+// A clock further off than this from Cloudflare's puts history and
+// exported timestamps noticeably wrong
+static CLOCK_SKEW_WARN_SECS: f64 = 60.0;
+
+// Variables other tools take a proxy from, which we don't
+static PROXY_VARIABLES: [&str; 6] = [
+    "HTTPS_PROXY",
+    "https_proxy",
+    "HTTP_PROXY",
+    "http_proxy",
+    "ALL_PROXY",
+    "all_proxy",
+];
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    // works, but may explain odd results
+    Warn,
+    // tests will fail or measure the wrong thing
+    Fail,
+}
+
+impl Status {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::Warn => "WARN",
+            Self::Fail => "FAIL",
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Finding {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    // what to do about it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fix: Option<String>,
+}
+
+impl Finding {
+    fn ok(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Ok,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    fn problem(
+        name: &'static str,
+        status: Status,
+        detail: impl Into<String>,
+        fix: impl Into<String>,
+    ) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+pub fn dns_finding(host: &str, resolved: &std::io::Result<Vec<SocketAddr>>) -> Finding {
+    match resolved {
+        Ok(addrs) if !addrs.is_empty() => {
+            let addrs: Vec<_> = addrs.iter().map(|addr| addr.ip().to_string()).collect();
+            Finding::ok("dns", format!("{host} is {}", addrs.join(", ")))
+        }
+        Ok(_) => Finding::problem(
+            "dns",
+            Status::Fail,
+            format!("{host} resolved to nothing"),
+            "Check your DNS server, or try a public one such as 1.1.1.1",
+        ),
+        Err(err) => Finding::problem(
+            "dns",
+            Status::Fail,
+            format!("couldn't resolve {host}: {err}"),
+            "Check your network connection and DNS settings (/etc/resolv.conf on \
+            Linux), or try a public resolver such as 1.1.1.1",
+        ),
+    }
+}
+
+/* Whether each family reached the host: None if it has no address of that
+   family, otherwise how connecting went. IPv6 is optional, but a family
+   that resolves and can't connect makes happy-eyeballs clients slow and
+   our results depend on which one got picked.
+*/
+pub fn family_finding(
+    family: IpFamily,
+    reached: Option<&std::io::Result<()>>,
+    other_works: bool,
+) -> Finding {
+    let name = match family {
+        IpFamily::V4 => "ipv4",
+        IpFamily::V6 => "ipv6",
+    };
+    let (flag, other_flag) = match family {
+        IpFamily::V4 => ("--ipv4", "--ipv6"),
+        IpFamily::V6 => ("--ipv6", "--ipv4"),
+    };
+    let status = if other_works {
+        Status::Warn
+    } else {
+        Status::Fail
+    };
+
+    match reached {
+        Some(Ok(())) => Finding::ok(name, format!("connected over {}", family.name())),
+        None if family == IpFamily::V6 && other_works => Finding::ok(
+            name,
+            "no IPv6 address to test against, tests use IPv4".to_owned(),
+        ),
+        None => Finding::problem(
+            name,
+            status,
+            format!("no {} address to test against", family.name()),
+            format!(
+                "Your resolver may be filtering {} answers, check its settings",
+                family.name()
+            ),
+        ),
+        Some(Err(err)) => Finding::problem(
+            name,
+            status,
+            format!("couldn't connect over {}: {err}", family.name()),
+            if other_works {
+                format!(
+                    "{} routing is broken here, fix it or pass {other_flag} so results don't \
+                    depend on which family gets picked",
+                    family.name()
+                )
+            } else {
+                format!(
+                    "Check your firewall and router; {flag} tests can't run until this connects"
+                )
+            },
+        ),
+    }
+}
+
+// `proxy` is --proxy's server, `lookup` the environment or a stand-in for it
+pub fn proxy_finding(proxy: Option<&str>, lookup: impl Fn(&str) -> Option<String>) -> Finding {
+    if let Some(server) = proxy {
+        return Finding::ok(
+            "proxy",
+            format!("connecting through the SOCKS proxy at {server} (--proxy)"),
+        );
+    }
+
+    let set: Vec<_> = PROXY_VARIABLES
+        .iter()
+        .filter(|var| lookup(var).is_some_and(|value| !value.is_empty()))
+        .copied()
+        .collect();
+
+    if set.is_empty() {
+        return Finding::ok("proxy", "no proxy configured");
+    }
+
+    Finding::problem(
+        "proxy",
+        Status::Warn,
+        format!(
+            "{} set, but cf_speedtest ignores it and connects directly",
+            set.join(", ")
+        ),
+        "Results measure the direct path, not the proxy's. If this network only lets \
+        traffic out through a proxy, pass a SOCKS one with --proxy socks5h://host:port",
+    )
+}
+
+// How the TLS handshake with the test host went
+pub fn tls_finding(host: &str, handshake: &std::io::Result<()>) -> Finding {
+    let err = match handshake {
+        Ok(()) => {
+            return Finding::ok(
+                "tls",
+                format!("{host}'s certificate checks out, nothing intercepting TLS"),
+            )
+        }
+        Err(err) => err,
+    };
+
+    let rejected = err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+        .is_some_and(|inner| matches!(inner, rustls::Error::InvalidCertificate(_)));
+    if rejected {
+        Finding::problem(
+            "tls",
+            Status::Fail,
+            format!("{host} presented a certificate we don't trust: {err}"),
+            "Something between you and the server is intercepting TLS: a corporate proxy, \
+            antivirus \"web shield\" or captive portal. Results would measure it, not your \
+            link; test from a network without it, or turn HTTPS scanning off",
+        )
+    } else {
+        Finding::problem(
+            "tls",
+            Status::Fail,
+            format!("TLS with {host} failed: {err}"),
+            "A firewall may be blocking or resetting HTTPS, check it allows port 443",
+        )
+    }
+}
+
+/* `reference` is the time Cloudflare reported, if it could be asked. A clock
+   from before 2024 hasn't been set at all, see `clock`.
+*/
+pub fn clock_finding(now: DateTime<Utc>, reference: Option<f64>) -> Finding {
+    let ntp = "Sync the clock with NTP (e.g. timedatectl set-ntp true, or enable \
+        \"Set time automatically\")";
+
+    if !crate::clock::is_plausible(now) {
+        return Finding::problem(
+            "clock",
+            Status::Fail,
+            format!("the clock reads {now}, it's never been set"),
+            format!("{ntp}; until then results are recorded without a time"),
+        );
+    }
+
+    let now_secs = now.timestamp_millis() as f64 / 1000.0;
+    match reference.map(|reference| now_secs - reference) {
+        Some(skew) if skew.abs() > CLOCK_SKEW_WARN_SECS => Finding::problem(
+            "clock",
+            Status::Warn,
+            format!(
+                "the clock is {:.0}s {} Cloudflare's",
+                skew.abs(),
+                if skew > 0.0 { "ahead of" } else { "behind" }
+            ),
+            format!("{ntp}, history and exported timestamps are off by as much"),
+        ),
+        Some(skew) => Finding::ok(
+            "clock",
+            format!("within {:.1}s of Cloudflare's", skew.abs()),
+        ),
+        None => Finding::ok("clock", "set, but couldn't compare it with Cloudflare's"),
+    }
+}
+
+// Whether the history database (or the directory it would go in) can be
+// written, without creating either
+pub fn history_finding(path: &Path) -> Finding {
+    let writable = if path.exists() {
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .map(|_| ())
+    } else {
+        let dir = path
+            .ancestors()
+            .skip(1)
+            .find(|dir| dir.exists())
+            .unwrap_or(Path::new("."));
+        let probe = dir.join(".cf_speedtest_doctor");
+        std::fs::File::create(&probe).and_then(|_| std::fs::remove_file(&probe))
+    };
+
+    match writable {
+        Ok(()) => Finding::ok("history", format!("{} is writable", path.display())),
+        Err(err) => Finding::problem(
+            "history",
+            Status::Warn,
+            format!("can't write {}: {err}", path.display()),
+            "--history won't work; fix the permissions or pass --history-db <path>",
+        ),
+    }
+}
+
+fn handshake(host: &str, addr: SocketAddr, connect: &ConnectOptions) -> std::io::Result<()> {
+    let server_name = rustls::ServerName::try_from(host.trim_matches(['[', ']']))
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let mut conn = rustls::ClientConnection::new(crate::tls::client_config(), server_name)
+        .map_err(std::io::Error::other)?;
+    let mut socket = connect.connect(addr)?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut socket)?;
+    }
+    Ok(())
+}
+
+pub fn diagnose(config: &UserArgs) -> Vec<Finding> {
+    let provider = config.provider();
+    let host = provider.host();
+    // every family, whatever --ipv4 or --ipv6 say
+    let connect = ConnectOptions {
+        family: None,
+        ..config.connect_options()
+    };
+
+    let resolved = connect.resolve(&format!("{host}:443"));
+    let mut findings = vec![dns_finding(&host, &resolved)];
+    let addrs = resolved.unwrap_or_default();
+
+    let reach = |family: IpFamily| {
+        addrs
+            .iter()
+            .find(|addr| family.matches(addr))
+            .map(|addr| connect.connect(*addr).map(|_| ()))
+    };
+    let (v4, v6) = (reach(IpFamily::V4), reach(IpFamily::V6));
+    let works = |reached: &Option<std::io::Result<()>>| matches!(reached, Some(Ok(())));
+    // without addresses there's nothing to say about either family
+    if !addrs.is_empty() {
+        findings.push(family_finding(IpFamily::V4, v4.as_ref(), works(&v6)));
+        findings.push(family_finding(IpFamily::V6, v6.as_ref(), works(&v4)));
+    }
+
+    findings.push(proxy_finding(
+        config.proxy.as_ref().map(|proxy| proxy.server.as_str()),
+        |var| std::env::var(var).ok(),
+    ));
+
+    let first_reachable = addrs.iter().find(|addr| {
+        [(IpFamily::V4, &v4), (IpFamily::V6, &v6)]
+            .iter()
+            .any(|(family, reached)| family.matches(addr) && works(reached))
+    });
+    if let Some(addr) = first_reachable {
+        findings.push(tls_finding(&host, &handshake(&host, *addr, &connect)));
+    }
+
+    let reference = crate::get_cdn_cgi_trace(&crate::build_agent(&connect))
+        .ok()
+        .and_then(|trace| trace.get("ts").and_then(|ts| ts.parse().ok()));
+    findings.push(clock_finding(Utc::now(), reference));
+
+    for capability in crate::privileges::ALL {
+        findings.push(match capability.available() {
+            Ok(()) => Finding::ok(
+                capability.name(),
+                format!("allowed, for {}", capability.needed_for()),
+            ),
+            Err(why) => Finding::problem(
+                capability.name(),
+                Status::Warn,
+                why,
+                format!(
+                    "Only needed for {}; allow it with CAP_NET_RAW, or run outside the \
+                    sandbox that blocks it",
+                    capability.needed_for()
+                ),
+            ),
+        });
+    }
+    findings.push(history_finding(&config.history_path()));
+
+    findings
+}
@@ -0,0 +1,91 @@
+use crate::results::{PhaseSummary, RunResult};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+pub static CSV_HEADER: &str = "timestamp,source,colo,country,region,city,latency_ms,\
+download_median_bps,download_average_bps,download_p90_bps,download_min_bps,download_max_bps,\
+upload_median_bps,upload_average_bps,upload_p90_bps,upload_min_bps,upload_max_bps,via_interface";
+
+// Quote a CSV field only when it needs it
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+fn csv_number(value: Option<f64>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn csv_phase(phase: Option<&PhaseSummary>) -> [String; 5] {
+    [
+        csv_number(phase.map(|p| p.median_bps)),
+        csv_number(phase.map(|p| p.average_bps)),
+        csv_number(phase.map(|p| p.p90_bps)),
+        csv_number(phase.map(|p| p.min_bps)),
+        csv_number(phase.map(|p| p.max_bps)),
+    ]
+}
+
+// One CSV line (without newline) matching CSV_HEADER. Without a clock the
+// timestamp is left empty.
+pub fn csv_row(run: &RunResult) -> String {
+    let mut fields = vec![
+        if run.clock_untrusted {
+            String::new()
+        } else {
+            run.timestamp
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        },
+        csv_field(&run.source),
+        csv_field(run.colo.as_deref().unwrap_or_default()),
+        csv_field(run.country.as_deref().unwrap_or_default()),
+        csv_field(run.region.as_deref().unwrap_or_default()),
+        csv_field(run.city.as_deref().unwrap_or_default()),
+        csv_number(run.latency_ms),
+    ];
+    fields.extend(csv_phase(run.download.as_ref()));
+    fields.extend(csv_phase(run.upload.as_ref()));
+    fields.push(csv_field(run.via_interface.as_deref().unwrap_or_default()));
+
+    fields.join(",")
+}
+
+// Add a row for `run` to the CSV file at `path`, creating it (with a header)
+// if it doesn't exist or is empty
+pub fn append_csv(path: &std::path::Path, run: &RunResult) -> Result<()> {
+    let mut text = String::new();
+    if std::fs::metadata(path).map_or(true, |metadata| metadata.len() == 0) {
+        text += CSV_HEADER;
+        text += "\n";
+    }
+    text += &csv_row(run);
+    text += "\n";
+
+    crate::sinks::append(path, &text)
+}
+
+pub fn write_runs(format: &str, runs: &[RunResult], out: &mut impl std::io::Write) -> Result<()> {
+    match format {
+        "csv" => {
+            writeln!(out, "{CSV_HEADER}")?;
+            for run in runs {
+                writeln!(out, "{}", csv_row(run))?;
+            }
+        }
+        "json" => {
+            serde_json::to_writer_pretty(&mut *out, runs)?;
+            writeln!(out)?;
+        }
+        _ => {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Unknown export format '{format}', expected csv or json"),
+            )))
+        }
+    }
+
+    Ok(())
+}
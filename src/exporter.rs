@@ -0,0 +1,119 @@
+/* `daemon --listen`: the latest run's figures served at /metrics in the
+   Prometheus text format, the same gauges a prometheus: result file gets,
+   for Prometheus to scrape between runs. Runs and failed runs are counted
+   too, so an alert can tell a slow line from a test that stopped working.
+*/
+use crate::results::RunResult;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// A scraper that connects and says nothing doesn't hold up the next one
+// for longer than this
+static REQUEST_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Default)]
+struct Scraped {
+    // the latest successful run's gauges
+    latest: String,
+    runs: u64,
+    failed_runs: u64,
+}
+
+#[derive(Clone)]
+pub struct Exporter {
+    scraped: Arc<Mutex<Scraped>>,
+    local_addr: SocketAddr,
+}
+
+impl Exporter {
+    // Serve /metrics on `addr` until the process exits
+    pub fn listen(addr: SocketAddr) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let exporter = Self {
+            scraped: Arc::default(),
+            local_addr: listener.local_addr()?,
+        };
+
+        let serving = exporter.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // a scraper that goes away mid-request is its problem
+                if let Err(err) = serving.answer(stream) {
+                    tracing::debug!("Couldn't answer a scrape: {err}");
+                }
+            }
+        });
+
+        Ok(exporter)
+    }
+
+    // Where it's listening, the port filled in if 0 was asked for
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn publish(&self, run: &RunResult) {
+        let mut scraped = self.scraped.lock().unwrap();
+        scraped.latest = crate::sinks::prometheus_metrics(run);
+        scraped.runs += 1;
+    }
+
+    // A failed run leaves the last good figures in place
+    pub fn failed(&self) {
+        let mut scraped = self.scraped.lock().unwrap();
+        scraped.runs += 1;
+        scraped.failed_runs += 1;
+    }
+
+    pub fn metrics(&self) -> String {
+        let scraped = self.scraped.lock().unwrap();
+        let mut text = scraped.latest.clone();
+        for (name, help, value) in [
+            (
+                "cf_speedtest_runs_total",
+                "Tests run since the daemon started",
+                scraped.runs,
+            ),
+            (
+                "cf_speedtest_failed_runs_total",
+                "Tests that failed since the daemon started",
+                scraped.failed_runs,
+            ),
+        ] {
+            let _ = writeln!(text, "# HELP {name} {help}\n# TYPE {name} counter");
+            let _ = writeln!(text, "{name} {value}");
+        }
+
+        text
+    }
+
+    fn answer(&self, stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+                break;
+            }
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.metrics()),
+            _ => ("404 Not Found", "Metrics are at /metrics\n".to_owned()),
+        };
+        write!(
+            writer,
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+            Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+}
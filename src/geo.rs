@@ -0,0 +1,94 @@
+use crate::args::UserArgs;
+use std::collections::HashMap;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// Sources for "Your Location", besides the country Cloudflare always gives us
+pub static GEOLOCATION_SOURCES: [&str; 3] = ["country", "cloudflare", "maxmind"];
+
+// Where we are, as precisely as the chosen source can tell
+pub struct Geolocation {
+    pub country: String,
+    pub region: Option<String>,
+    pub city: Option<String>,
+}
+
+pub fn locate(
+    config: &UserArgs,
+    trace: &HashMap<String, String>,
+    headers: &HashMap<String, String>,
+) -> Result<Geolocation> {
+    let country = crate::get_country_from_trace(trace)?;
+
+    match config.geolocation.as_str() {
+        // the speed test server already geolocates us for its own UI
+        "cloudflare" => Ok(Geolocation {
+            country,
+            region: headers.get("cf-meta-region").cloned(),
+            city: headers.get("cf-meta-city").cloned(),
+        }),
+        "maxmind" => {
+            let ip = trace
+                .get("ip")
+                .ok_or("Could not find ip= in cdn-cgi response")?;
+            locate_with_maxmind(config, country, ip.parse()?)
+        }
+        _ => Ok(Geolocation {
+            country,
+            region: None,
+            city: None,
+        }),
+    }
+}
+
+#[cfg(feature = "maxmind")]
+fn locate_with_maxmind(
+    config: &UserArgs,
+    country: String,
+    ip: std::net::IpAddr,
+) -> Result<Geolocation> {
+    use maxminddb::geoip2;
+
+    let path = config
+        .maxmind_db
+        .as_ref()
+        .ok_or("--geolocation maxmind needs --maxmind-db")?;
+    let reader = maxminddb::Reader::open_readfile(path)?;
+    let found: geoip2::City = reader.lookup(ip)?;
+
+    // prefer names in the user's language, like the rest of the preamble
+    let name = |names: Option<&std::collections::BTreeMap<&str, &str>>| {
+        names.and_then(|names| {
+            names
+                .get(config.lang.as_str())
+                .or_else(|| names.get("en"))
+                .map(|name| name.to_string())
+        })
+    };
+
+    Ok(Geolocation {
+        country: found
+            .country
+            .as_ref()
+            .and_then(|c| c.iso_code)
+            .map_or(country, str::to_owned),
+        region: found
+            .subdivisions
+            .as_ref()
+            .and_then(|subdivisions| subdivisions.first())
+            .and_then(|subdivision| name(subdivision.names.as_ref())),
+        city: found
+            .city
+            .as_ref()
+            .and_then(|city| name(city.names.as_ref())),
+    })
+}
+
+#[cfg(not(feature = "maxmind"))]
+fn locate_with_maxmind(
+    _config: &UserArgs,
+    _country: String,
+    _ip: std::net::IpAddr,
+) -> Result<Geolocation> {
+    Err("cf_speedtest was built without the maxmind feature".into())
+}
@@ -0,0 +1,475 @@
+use crate::results::{PhaseSummary, RunResult};
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row, TransactionBehavior};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+static SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    timestamp INTEGER NOT NULL,
+    source TEXT NOT NULL,
+    colo TEXT,
+    country TEXT,
+    latency_ms REAL,
+    download_median_bps REAL,
+    download_average_bps REAL,
+    download_p90_bps REAL,
+    download_min_bps REAL,
+    download_max_bps REAL,
+    upload_median_bps REAL,
+    upload_average_bps REAL,
+    upload_p90_bps REAL,
+    upload_min_bps REAL,
+    upload_max_bps REAL
+);
+CREATE INDEX IF NOT EXISTS runs_timestamp ON runs (timestamp);
+";
+
+// Each entry upgrades the schema by one version (tracked in PRAGMA user_version),
+// only ever append to this list
+static MIGRATIONS: [&str; 5] = [
+    "ALTER TABLE runs ADD COLUMN region TEXT;
+    ALTER TABLE runs ADD COLUMN city TEXT;",
+    "ALTER TABLE runs ADD COLUMN via_interface TEXT;",
+    "ALTER TABLE runs ADD COLUMN content_digest TEXT;",
+    // per-second byte counts of each phase, as JSON arrays
+    "ALTER TABLE runs ADD COLUMN download_samples TEXT;
+    ALTER TABLE runs ADD COLUMN upload_samples TEXT;",
+    // how many of those samples --warmup left out of the figures
+    "ALTER TABLE runs ADD COLUMN download_warmup_seconds INTEGER;
+    ALTER TABLE runs ADD COLUMN upload_warmup_seconds INTEGER;",
+];
+
+static RUN_COLUMNS: &str = "timestamp, source, colo, country, latency_ms,
+    download_median_bps, download_average_bps, download_p90_bps,
+    download_min_bps, download_max_bps,
+    upload_median_bps, upload_average_bps, upload_p90_bps,
+    upload_min_bps, upload_max_bps,
+    region, city, via_interface, download_samples, upload_samples,
+    download_warmup_seconds, upload_warmup_seconds";
+
+// How long to wait for another process (the daemon, a run started by hand)
+// to finish writing before giving up
+static BUSY_TIMEOUT_SECS: u64 = 30;
+
+// Figures within this fraction of each other are considered the same
+static DUPLICATE_TOLERANCE: f64 = 0.01;
+
+// Where we keep history when the user doesn't tell us otherwise
+pub fn default_path() -> PathBuf {
+    let data_dir = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .unwrap_or_default();
+
+    data_dir.join("cf_speedtest").join("history.db")
+}
+
+// --since and --until as the times to pass to `History::runs`, the whole
+// of the --until day included
+pub fn day_range(
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+) -> (Option<DateTime<Utc>>, Option<DateTime<Utc>>) {
+    let midnight = |day: NaiveDate| day.and_time(chrono::NaiveTime::MIN).and_utc();
+    (
+        since.map(midnight),
+        until.and_then(|day| day.succ_opt()).map(midnight),
+    )
+}
+
+pub struct History {
+    conn: Connection,
+}
+
+impl History {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        /* Several of us may have the database open at once, e.g. a manual
+           run while the daemon is recording. WAL lets readers carry on while
+           one of us writes, and writers queue for each other rather than
+           failing straight away with "database is locked".
+        */
+        let mut conn = Connection::open(path)?;
+        conn.busy_timeout(std::time::Duration::from_secs(BUSY_TIMEOUT_SECS))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+        migrate(&mut conn)?;
+
+        Ok(Self { conn })
+    }
+
+    // Returns false if the run was a near-duplicate and was skipped
+    pub fn insert(&mut self, run: &RunResult) -> Result<bool> {
+        Ok(self.insert_all(std::slice::from_ref(run))? == 1)
+    }
+
+    // Insert every run in a single transaction, so a bad import leaves no trace.
+    // Near-duplicates of runs we already have (a re-import, or a cron job that
+    // fired twice) are skipped so they don't skew aggregates.
+    // Returns how many runs were actually inserted.
+    pub fn insert_all(&mut self, runs: &[RunResult]) -> Result<usize> {
+        let mut inserted = 0;
+        // taking the write lock up front, so another process can't insert
+        // the same run between our duplicate check and our insert
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        {
+            // ?1 is the start of the minute, see `minute_start`
+            let mut same_minute = tx.prepare(&format!(
+                "SELECT {RUN_COLUMNS} FROM runs WHERE timestamp >= ?1 AND timestamp < ?1 + 60"
+            ))?;
+            let mut stmt = tx.prepare(&format!(
+                "INSERT INTO runs ({RUN_COLUMNS}, content_digest)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23)"
+            ))?;
+
+            for run in runs {
+                let is_duplicate = same_minute
+                    .query_map([minute_start(run)], run_from_row)?
+                    .collect::<std::result::Result<Vec<_>, _>>()?
+                    .iter()
+                    .any(|existing| is_near_duplicate(existing, run));
+
+                if is_duplicate {
+                    continue;
+                }
+
+                let down = run.download.as_ref();
+                let up = run.upload.as_ref();
+
+                stmt.execute(params![
+                    run.timestamp.timestamp(),
+                    run.source,
+                    run.colo,
+                    run.country,
+                    run.latency_ms,
+                    down.map(|d| d.median_bps),
+                    down.map(|d| d.average_bps),
+                    down.map(|d| d.p90_bps),
+                    down.map(|d| d.min_bps),
+                    down.map(|d| d.max_bps),
+                    up.map(|u| u.median_bps),
+                    up.map(|u| u.average_bps),
+                    up.map(|u| u.p90_bps),
+                    up.map(|u| u.min_bps),
+                    up.map(|u| u.max_bps),
+                    run.region,
+                    run.city,
+                    run.via_interface,
+                    down.and_then(samples_to_json),
+                    up.and_then(samples_to_json),
+                    down.and_then(|d| d.warmup_seconds)
+                        .map(|seconds| seconds as i64),
+                    up.and_then(|u| u.warmup_seconds)
+                        .map(|seconds| seconds as i64),
+                    run.content
+                        .as_ref()
+                        .map(|content| &content.digest)
+                        .filter(|digest| !digest.is_empty()),
+                ])?;
+                inserted += 1;
+            }
+        }
+        tx.commit()?;
+
+        Ok(inserted)
+    }
+
+    // The most recent run recorded by `source`, if any
+    pub fn latest(&self, source: &str) -> Result<Option<RunResult>> {
+        let run = self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT {RUN_COLUMNS} FROM runs WHERE source = ?1 ORDER BY timestamp DESC, id DESC LIMIT 1"
+                ),
+                [source],
+                run_from_row,
+            )
+            .optional()?;
+
+        Ok(run)
+    }
+
+    // What the downloads of the most recent run with --verify-content
+    // hashed to, see `integrity`
+    pub fn latest_content_digest(&self) -> Result<Option<String>> {
+        let digest = self
+            .conn
+            .query_row(
+                "SELECT content_digest FROM runs WHERE content_digest IS NOT NULL
+                ORDER BY timestamp DESC, id DESC LIMIT 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(digest)
+    }
+
+    // The last `count` runs recorded by `source`, oldest first
+    pub fn recent(&self, source: &str, count: usize) -> Result<Vec<RunResult>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {RUN_COLUMNS} FROM runs WHERE source = ?1 ORDER BY timestamp DESC, id DESC LIMIT ?2"
+        ))?;
+
+        let mut runs = stmt
+            .query_map(rusqlite::params![source, count as i64], run_from_row)?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        runs.reverse();
+
+        Ok(runs)
+    }
+
+    // Runs on or after `since` and before `until` (either or both of which
+    // may be open), oldest first
+    pub fn runs(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<Vec<RunResult>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {RUN_COLUMNS} FROM runs WHERE timestamp >= ?1 AND timestamp < ?2
+            ORDER BY timestamp, id"
+        ))?;
+
+        let since = since.map_or(i64::MIN, |since| since.timestamp());
+        let until = until.map_or(i64::MAX, |until| until.timestamp());
+        let runs = stmt
+            .query_map([since, until], run_from_row)?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        Ok(runs)
+    }
+}
+
+// The version is read again under the write lock, in case another process
+// opening the database at the same time migrated it first
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version = |conn: &Connection| -> rusqlite::Result<usize> {
+        conn.query_row("PRAGMA user_version", [], |row| row.get::<_, u32>(0))
+            .map(|version| version as usize)
+    };
+    if version(conn)? >= MIGRATIONS.len() {
+        return Ok(());
+    }
+
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version(&tx)?) {
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i as u32 + 1)?;
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+fn is_close(a: Option<f64>, b: Option<f64>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => (a - b).abs() <= a.abs().max(b.abs()) * DUPLICATE_TOLERANCE,
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+// When the minute of the run began, in seconds since the epoch. Rounded
+// down, so a run from before 1970 shares its minute with the same runs as
+// one after.
+fn minute_start(run: &RunResult) -> i64 {
+    run.timestamp.timestamp().div_euclid(60) * 60
+}
+
+// Same minute and (nearly) the same figures
+pub fn is_near_duplicate(a: &RunResult, b: &RunResult) -> bool {
+    minute_start(a) == minute_start(b)
+        && is_close(a.latency_ms, b.latency_ms)
+        && is_close(
+            a.download.as_ref().map(|d| d.average_bps),
+            b.download.as_ref().map(|d| d.average_bps),
+        )
+        && is_close(
+            a.upload.as_ref().map(|u| u.average_bps),
+            b.upload.as_ref().map(|u| u.average_bps),
+        )
+}
+
+fn run_from_row(row: &Row) -> rusqlite::Result<RunResult> {
+    let timestamp: i64 = row.get(0)?;
+
+    Ok(RunResult {
+        timestamp: Utc.timestamp_opt(timestamp, 0).single().unwrap_or_default(),
+        source: row.get(1)?,
+        colo: row.get(2)?,
+        country: row.get(3)?,
+        region: row.get(15)?,
+        city: row.get(16)?,
+        latency_ms: row.get(4)?,
+        idle_latency: None,
+        download: phase_from_row(row, 5, 18, 20)?,
+        upload: phase_from_row(row, 10, 19, 21)?,
+        download_loaded_latency: None,
+        upload_loaded_latency: None,
+        via_interface: row.get(17)?,
+        server: None,
+        sockets: None,
+        content: None,
+        events: vec![],
+        cached: false,
+        interrupted: false,
+        clock_untrusted: false,
+        assertions: vec![],
+        skipped: vec![],
+        host: None,
+        warnings: vec![],
+        resolved: None,
+    })
+}
+
+fn samples_to_json(phase: &PhaseSummary) -> Option<String> {
+    (!phase.samples.is_empty()).then(|| serde_json::to_string(&phase.samples).unwrap_or_default())
+}
+
+/* A phase is either fully recorded or not at all, so the median tells us
+   which. Its samples are only there for runs recorded since we kept them,
+   and not for imported ones.
+*/
+fn phase_from_row(
+    row: &Row,
+    first: usize,
+    samples: usize,
+    warmup_seconds: usize,
+) -> rusqlite::Result<Option<PhaseSummary>> {
+    let median_bps: Option<f64> = row.get(first)?;
+    let samples: Option<String> = row.get(samples)?;
+
+    match median_bps {
+        Some(median_bps) => Ok(Some(PhaseSummary {
+            median_bps,
+            average_bps: row.get(first + 1)?,
+            p90_bps: row.get(first + 2)?,
+            min_bps: row.get(first + 3)?,
+            max_bps: row.get(first + 4)?,
+            saturated_threads: None,
+            saturation_gain_percent: None,
+            warmup_seconds: row
+                .get::<_, Option<i64>>(warmup_seconds)?
+                .map(|seconds| seconds as usize),
+            threads: None,
+            samples: samples
+                .and_then(|samples| serde_json::from_str(&samples).ok())
+                .unwrap_or_default(),
+        })),
+        None => Ok(None),
+    }
+}
+
+// How `cf_speedtest history trends` groups runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrendPeriod {
+    Day,
+    Week,
+    Month,
+}
+
+impl std::str::FromStr for TrendPeriod {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Self::Day),
+            "week" => Ok(Self::Week),
+            "month" => Ok(Self::Month),
+            _ => Err(format!("'{s}' isn't a period, use day, week or month")),
+        }
+    }
+}
+
+impl TrendPeriod {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Day => "day",
+            Self::Week => "week",
+            Self::Month => "month",
+        }
+    }
+
+    // The first day of the period `date` falls in, weeks starting on Monday
+    pub fn start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Self::Day => date,
+            Self::Week => date - chrono::Days::new(date.weekday().num_days_from_monday().into()),
+            Self::Month => date.with_day(1).unwrap_or(date),
+        }
+    }
+}
+
+// The runs of one day, week or month taken together. Figures are the
+// medians of the runs' own, so one bad run doesn't drag a period down.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Trend {
+    // the first day of the period (UTC)
+    pub period: NaiveDate,
+    // the Cloudflare datacenter that served the runs, when grouped by it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub colo: Option<String>,
+    pub runs: usize,
+    pub download_bps: Option<f64>,
+    pub upload_bps: Option<f64>,
+    pub latency_ms: Option<f64>,
+    // how the download changed from the period before (at the same colo),
+    // in percent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_change_percent: Option<f64>,
+}
+
+/* `runs` grouped by `period`, oldest first. With `by_colo`, each period is
+   split by the colo that served its runs, which shows whether a slow spell
+   is the line or being routed to a datacenter further away.
+*/
+pub fn trends(runs: &[RunResult], period: TrendPeriod, by_colo: bool) -> Vec<Trend> {
+    let mut periods: std::collections::BTreeMap<_, Vec<&RunResult>> = Default::default();
+    for run in runs {
+        let colo = run.colo.clone().filter(|_| by_colo);
+        periods
+            .entry((period.start(run.timestamp.date_naive()), colo))
+            .or_default()
+            .push(run);
+    }
+
+    let mut trends: Vec<Trend> = vec![];
+    for ((start, colo), runs) in periods {
+        let figure = |get: fn(&RunResult) -> Option<f64>| {
+            crate::median(runs.iter().filter_map(|run| get(run)).collect())
+        };
+        let download_bps = figure(|run| run.download.as_ref().map(|d| d.median_bps));
+        let download_change_percent = trends
+            .iter()
+            .rev()
+            .find(|previous| previous.colo == colo)
+            .and_then(|previous| previous.download_bps)
+            .zip(download_bps)
+            .filter(|(previous, _)| *previous > 0.0)
+            .map(|(previous, now)| (now / previous - 1.0) * 100.0);
+
+        trends.push(Trend {
+            period: start,
+            colo,
+            runs: runs.len(),
+            download_bps,
+            upload_bps: figure(|run| run.upload.as_ref().map(|u| u.median_bps)),
+            latency_ms: figure(|run| run.latency_ms),
+            download_change_percent,
+        });
+    }
+
+    trends
+}
@@ -0,0 +1,441 @@
+/* What the machine running the test was up to, for laptops mostly: on
+   battery or mains power, on Wi-Fi, Ethernet or a cellular modem, and
+   whether the OS counts the connection as metered. A laptop on battery may
+   throttle its Wi-Fi, and a run over a phone's hotspot says little about
+   the line at home, so results carry this along; the daemon can also put
+   scheduled runs off until it changes (--defer-on-battery,
+   --defer-on-metered).
+
+   Where each comes from:
+     Linux    /sys/class/power_supply, the default route's interface in
+              /sys/class/net, NetworkManager's metered flag (nmcli)
+     macOS    pmset, networksetup's hardware ports, and iPhone USB or
+              Bluetooth tethering counted as metered
+     Windows  Win32_Battery and the internet connection profile, with its
+              cost, through PowerShell
+     Android  Termux:API (pkg install termux-api, and the app), since apps
+              can't read the routing table or most of /sys; a cellular
+              data connection is taken to be metered
+   Anything we can't tell is left out rather than guessed.
+*/
+use serde::{Deserialize, Serialize};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+// Termux:API commands wait for the companion app, forever if it isn't there
+static TERMUX_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerSource {
+    Ac,
+    Battery,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkKind {
+    Ethernet,
+    Wifi,
+    Cellular,
+    Other,
+}
+
+impl LinkKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ethernet => "Ethernet",
+            Self::Wifi => "Wi-Fi",
+            Self::Cellular => "cellular",
+            Self::Other => "other link",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct HostState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub power: Option<PowerSource>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<LinkKind>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metered: Option<bool>,
+}
+
+impl HostState {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // e.g. "on battery, Wi-Fi, metered"
+    pub fn describe(&self) -> String {
+        let mut parts = vec![];
+        match self.power {
+            Some(PowerSource::Ac) => parts.push("on AC power"),
+            Some(PowerSource::Battery) => parts.push("on battery"),
+            None => {}
+        }
+        if let Some(link) = self.link {
+            parts.push(link.name());
+        }
+        match self.metered {
+            Some(true) => parts.push("metered"),
+            Some(false) => parts.push("not metered"),
+            None => {}
+        }
+
+        parts.join(", ")
+    }
+}
+
+// Whatever we can find out, about `interface` if the test is sent through
+// one, otherwise about the one the default route goes through. Windows only
+// tells us about its internet connection, so there `interface` is ignored.
+pub fn detect(interface: Option<&str>) -> HostState {
+    if cfg!(target_os = "android") {
+        // without the Termux:API app each command takes TERMUX_TIMEOUT_SECS
+        // to give up, so they're all asked at once
+        let (power, link) = std::thread::scope(|scope| {
+            let power = scope.spawn(power_source);
+            let link = interface.and_then(link_kind).or_else(termux_link);
+            (power.join().ok().flatten(), link)
+        });
+        return HostState {
+            power,
+            link,
+            metered: (link == Some(LinkKind::Cellular)).then_some(true),
+        };
+    }
+
+    let power = power_source();
+    if cfg!(windows) {
+        let (link, metered) =
+            powershell(WINDOWS_PROFILE).map_or((None, None), |out| parse_windows_profile(&out));
+        return HostState {
+            power,
+            link,
+            metered,
+        };
+    }
+
+    let interface = interface
+        .map(str::to_owned)
+        .or_else(default_route_interface);
+    HostState {
+        power,
+        link: interface.as_deref().and_then(link_kind),
+        metered: interface.as_deref().and_then(metered),
+    }
+}
+
+// Output of a command that ran and succeeded, None if it didn't
+pub fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// command_output for a command that may never finish, killed after `timeout`
+fn command_output_within(program: &str, args: &[&str], timeout: Duration) -> Option<String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    let deadline = Instant::now() + timeout;
+
+    // what these print is far too little to fill the pipe while we wait
+    while child.try_wait().ok()?.is_none() {
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    let output = child.wait_with_output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn termux(command: &str) -> Option<String> {
+    command_output_within(command, &[], Duration::from_secs(TERMUX_TIMEOUT_SECS))
+}
+
+/* On battery if a battery is discharging and no mains supply is online;
+   desktops with neither, and UPSes, say nothing. `dir` is
+   /sys/class/power_supply.
+*/
+pub fn linux_power_source(dir: &std::path::Path) -> Option<PowerSource> {
+    let read = |path: std::path::PathBuf| {
+        std::fs::read_to_string(path)
+            .ok()
+            .map(|s| s.trim().to_owned())
+    };
+    let mut on_mains = false;
+    let mut discharging = false;
+    let mut has_battery = false;
+
+    for supply in std::fs::read_dir(dir).ok()?.flatten() {
+        let path = supply.path();
+        match read(path.join("type")).as_deref() {
+            Some("Mains") | Some("USB") => {
+                on_mains |= read(path.join("online")).as_deref() == Some("1")
+            }
+            // a device's battery, e.g. a wireless mouse's, isn't ours
+            Some("Battery") if read(path.join("scope")).as_deref() != Some("Device") => {
+                has_battery = true;
+                discharging |= read(path.join("status")).as_deref() == Some("Discharging");
+            }
+            _ => {}
+        }
+    }
+
+    match (has_battery, on_mains, discharging) {
+        (false, _, _) => None,
+        (true, false, true) => Some(PowerSource::Battery),
+        _ => Some(PowerSource::Ac),
+    }
+}
+
+// From `pmset -g batt`: "Now drawing from 'Battery Power'"
+pub fn parse_pmset(output: &str) -> Option<PowerSource> {
+    let first = output.lines().next()?;
+    if first.contains("'AC Power'") {
+        Some(PowerSource::Ac)
+    } else if first.contains("'Battery Power'") {
+        Some(PowerSource::Battery)
+    } else {
+        None
+    }
+}
+
+// From `termux-battery-status`, e.g. {"plugged": "PLUGGED_USB", ...}
+pub fn parse_termux_battery(json: &str) -> Option<PowerSource> {
+    let status: serde_json::Value = serde_json::from_str(json).ok()?;
+    match status["plugged"].as_str()? {
+        "UNPLUGGED" => Some(PowerSource::Battery),
+        plugged if plugged.starts_with("PLUGGED") => Some(PowerSource::Ac),
+        _ => None,
+    }
+}
+
+fn power_source() -> Option<PowerSource> {
+    if cfg!(target_os = "android") {
+        parse_termux_battery(&termux("termux-battery-status")?)
+    } else if cfg!(target_os = "linux") {
+        linux_power_source(std::path::Path::new("/sys/class/power_supply"))
+    } else if cfg!(target_os = "macos") {
+        parse_pmset(&command_output("pmset", &["-g", "batt"])?)
+    } else if cfg!(windows) {
+        // BatteryStatus 1 is discharging, the rest are on mains in some way;
+        // no output at all means there's no battery
+        let status = powershell("(Get-CimInstance Win32_Battery).BatteryStatus")?;
+        match status.trim() {
+            "" => None,
+            "1" => Some(PowerSource::Battery),
+            _ => Some(PowerSource::Ac),
+        }
+    } else {
+        None
+    }
+}
+
+fn powershell(script: &str) -> Option<String> {
+    command_output(
+        "powershell",
+        &["-NoProfile", "-NonInteractive", "-Command", script],
+    )
+}
+
+// The interface of the first default route in /proc/net/route
+pub fn parse_proc_net_route(table: &str) -> Option<String> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        (fields.get(1) == Some(&"00000000")).then(|| fields[0].to_owned())
+    })
+}
+
+// From `route -n get default`: "  interface: en0"
+pub fn parse_route_get(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let (key, value) = line.trim().split_once(':')?;
+        (key == "interface").then(|| value.trim().to_owned())
+    })
+}
+
+pub fn default_route_interface() -> Option<String> {
+    if cfg!(target_os = "linux") {
+        parse_proc_net_route(&std::fs::read_to_string("/proc/net/route").ok()?)
+    } else if cfg!(target_os = "macos") {
+        parse_route_get(&command_output("route", &["-n", "get", "default"])?)
+    } else {
+        None
+    }
+}
+
+// From `networksetup -listallhardwareports`, the name of the port that's
+// `interface`, e.g. "Wi-Fi" or "iPhone USB"
+pub fn parse_hardware_port(output: &str, interface: &str) -> Option<String> {
+    let mut port = None;
+    for line in output.lines() {
+        if let Some(name) = line.strip_prefix("Hardware Port: ") {
+            port = Some(name.trim());
+        } else if line.strip_prefix("Device: ").map(str::trim) == Some(interface) {
+            return port.map(str::to_owned);
+        }
+    }
+
+    None
+}
+
+fn hardware_port(interface: &str) -> Option<String> {
+    parse_hardware_port(
+        &command_output("networksetup", &["-listallhardwareports"])?,
+        interface,
+    )
+}
+
+fn link_kind(interface: &str) -> Option<LinkKind> {
+    if cfg!(any(target_os = "linux", target_os = "android")) {
+        let dir = std::path::Path::new("/sys/class/net").join(interface);
+        if !dir.exists() {
+            return None;
+        }
+        let devtype = std::fs::read_to_string(dir.join("uevent"))
+            .ok()
+            .and_then(|uevent| {
+                uevent
+                    .lines()
+                    .find_map(|line| line.strip_prefix("DEVTYPE=").map(str::to_owned))
+            });
+        // type 1 is ARPHRD_ETHER, which Wi-Fi and USB modems say too
+        let ether = std::fs::read_to_string(dir.join("type")).is_ok_and(|t| t.trim() == "1");
+
+        Some(
+            if dir.join("wireless").exists() || devtype.as_deref() == Some("wlan") {
+                LinkKind::Wifi
+            } else if devtype.as_deref() == Some("wwan")
+                || ["wwan", "rmnet", "ccmni"]
+                    .iter()
+                    .any(|prefix| interface.starts_with(prefix))
+            {
+                LinkKind::Cellular
+            } else if ether && dir.join("device").exists() {
+                LinkKind::Ethernet
+            } else {
+                LinkKind::Other
+            },
+        )
+    } else if cfg!(target_os = "macos") {
+        let port = hardware_port(interface)?;
+        Some(if port == "Wi-Fi" || port == "AirPort" {
+            LinkKind::Wifi
+        } else if port.contains("Ethernet") || port.contains("Thunderbolt") || port.contains("LAN")
+        {
+            LinkKind::Ethernet
+        } else if port.contains("iPhone") || port.contains("Modem") {
+            LinkKind::Cellular
+        } else {
+            LinkKind::Other
+        })
+    } else {
+        None
+    }
+}
+
+// From `nmcli -t -f GENERAL.METERED device show <interface>`, where "yes
+// (guessed)" means NetworkManager spotted a phone's hotspot
+pub fn parse_nmcli_metered(output: &str) -> Option<bool> {
+    let value = output
+        .lines()
+        .find_map(|line| line.strip_prefix("GENERAL.METERED:"))?;
+    match value.split_whitespace().next()? {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+// The connection Windows uses for the internet, through WinRT: whether it's
+// Wi-Fi, whether it's cellular, and what it costs, a line each
+static WINDOWS_PROFILE: &str = "[void][Windows.Networking.Connectivity.NetworkInformation, \
+    Windows.Networking.Connectivity, ContentType=WindowsRuntime]; \
+    $p = [Windows.Networking.Connectivity.NetworkInformation]::GetInternetConnectionProfile(); \
+    $p.IsWlanConnectionProfile; $p.IsWwanConnectionProfile; \
+    $p.GetConnectionCost().NetworkCostType";
+
+// The link and whether it's metered from WINDOWS_PROFILE's output. Any cost
+// but Unrestricted is charged for by the byte or up to a cap.
+pub fn parse_windows_profile(output: &str) -> (Option<LinkKind>, Option<bool>) {
+    let lines: Vec<&str> = output.lines().map(str::trim).collect();
+    let [wlan, wwan, cost] = lines[..] else {
+        return (None, None);
+    };
+
+    let link = match (wlan, wwan) {
+        ("True", _) => Some(LinkKind::Wifi),
+        (_, "True") => Some(LinkKind::Cellular),
+        // neither, so wired as far as Windows is concerned
+        ("False", "False") => Some(LinkKind::Ethernet),
+        _ => None,
+    };
+    let metered = match cost {
+        "Unrestricted" => Some(false),
+        "Fixed" | "Variable" => Some(true),
+        _ => None,
+    };
+
+    (link, metered)
+}
+
+/* What the phone is on, from `termux-wifi-connectioninfo` and
+   `termux-telephony-deviceinfo`, either of which may be missing (""). Wi-Fi
+   wins when both are up, as Android sends traffic that way.
+*/
+pub fn parse_termux_link(wifi: &str, telephony: &str) -> Option<LinkKind> {
+    let parse = |json: &str| serde_json::from_str::<serde_json::Value>(json).ok();
+
+    let on_wifi = parse(wifi).is_some_and(|wifi| {
+        wifi["supplicant_state"] == "COMPLETED" && wifi["ip"].as_str() != Some("0.0.0.0")
+    });
+    if on_wifi {
+        return Some(LinkKind::Wifi);
+    }
+    match parse(telephony)?["data_state"].as_str()? {
+        "connected" => Some(LinkKind::Cellular),
+        _ => None,
+    }
+}
+
+fn termux_link() -> Option<LinkKind> {
+    let (wifi, telephony) = std::thread::scope(|scope| {
+        let wifi = scope.spawn(|| termux("termux-wifi-connectioninfo"));
+        let telephony = termux("termux-telephony-deviceinfo");
+        (wifi.join().ok().flatten(), telephony)
+    });
+
+    parse_termux_link(&wifi.unwrap_or_default(), &telephony.unwrap_or_default())
+}
+
+fn metered(interface: &str) -> Option<bool> {
+    if cfg!(target_os = "linux") {
+        parse_nmcli_metered(&command_output(
+            "nmcli",
+            &["-t", "-f", "GENERAL.METERED", "device", "show", interface],
+        )?)
+    } else if cfg!(target_os = "macos") {
+        // macOS keeps its own idea to itself, but tethering is always metered
+        let port = hardware_port(interface)?;
+        Some(port.contains("iPhone") || port.contains("Bluetooth PAN"))
+    } else {
+        None
+    }
+}
@@ -0,0 +1,307 @@
+/* Experimental HTTP/3 transfers (--http-version 3), for comparing QUIC with
+   TCP on the same link: lossy Wi-Fi is where their loss recovery and
+   congestion control part ways. Each worker holds one QUIC connection to
+   Cloudflare and makes requests on it until the phase is over, the way ureq
+   reuses a keep-alive TCP connection. Its packet counts go into the socket
+   stats when it closes, lost packets standing in for TCP's retransmitted
+   segments, so loss reads the same either way.
+*/
+use crate::latency::{LatencyMethod, LatencyProbe};
+use crate::net::ConnectOptions;
+use crate::sockets::{SocketRecord, SocketRegistry};
+use crate::throughput::{Counters, Direction, StreamTarget};
+use bytes::{Buf, Bytes};
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::rustls;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+type SendRequest = h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>;
+
+static ALPN: &[u8] = b"h3";
+
+// Upload bodies go out in pieces this big, counted as each is sent
+static UPLOAD_CHUNK_BYTES: usize = 64 * 1024;
+
+// How long to give the server to acknowledge the connection closing
+static CLOSE_WAIT_MILLIS: u64 = 1000;
+
+// The same roots as the TCP tests use, for the rustls that quinn brings
+fn client_config() -> Result<quinn::ClientConfig> {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS
+            .iter()
+            .map(|ta| rustls::pki_types::TrustAnchor {
+                subject: ta.subject.into(),
+                subject_public_key_info: ta.spki.into(),
+                name_constraints: ta.name_constraints.map(Into::into),
+            })
+            .collect(),
+    };
+
+    let mut tls = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    Ok(quinn::ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(tls)?,
+    )))
+}
+
+// One direction of the test against Cloudflare over HTTP/3
+pub struct Http3Target {
+    pub direction: Direction,
+}
+
+impl StreamTarget for Http3Target {
+    fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    fn stream(&self, bytes: usize, counters: &Counters) -> Result<()> {
+        transfer(
+            crate::CLOUDFLARE_SPEEDTEST_HOST,
+            self.direction,
+            bytes,
+            counters,
+        )
+    }
+}
+
+// Requests of `bytes` to or from `host` over one QUIC connection, until the
+// phase is over
+pub fn transfer(host: &str, direction: Direction, bytes: usize, counters: &Counters) -> Result<()> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(connection(host, direction, bytes, counters))
+}
+
+fn first_addr(host: &str, connect: &ConnectOptions) -> Result<SocketAddr> {
+    Ok(connect
+        .resolve(&format!("{host}:443"))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("{host} has no addresses"))?)
+}
+
+// A client endpoint on a socket of its own, inside a tokio runtime
+fn endpoint(addr: SocketAddr, connect: &ConnectOptions) -> Result<quinn::Endpoint> {
+    let local: SocketAddr = match (connect.source, addr) {
+        (Some(source), _) => (source, 0).into(),
+        (None, SocketAddr::V4(_)) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        (None, SocketAddr::V6(_)) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+
+    let mut endpoint = quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        None,
+        std::net::UdpSocket::bind(local)?,
+        Arc::new(quinn::TokioRuntime),
+    )?;
+    endpoint.set_default_client_config(client_config()?);
+
+    Ok(endpoint)
+}
+
+async fn handshake(
+    endpoint: &quinn::Endpoint,
+    addr: SocketAddr,
+    host: &str,
+) -> Result<quinn::Connection> {
+    let connecting = endpoint.connect(addr, host)?;
+    Ok(tokio::time::timeout(
+        Duration::from_millis(crate::net::CONNECT_TIMEOUT_MILLIS),
+        connecting,
+    )
+    .await
+    .map_err(|_| format!("timed out connecting to {host} over QUIC"))??)
+}
+
+async fn connection(
+    host: &str,
+    direction: Direction,
+    bytes: usize,
+    counters: &Counters,
+) -> Result<()> {
+    let addr = first_addr(host, &counters.connect)?;
+    let endpoint = endpoint(addr, &counters.connect)?;
+
+    let opened = Instant::now();
+    let conn = handshake(&endpoint, addr, host).await?;
+
+    let result = requests(&conn, host, direction, bytes, counters).await;
+
+    record(&counters.sockets, &conn.stats(), opened.elapsed());
+    conn.close(0u32.into(), b"");
+    let _ = tokio::time::timeout(
+        Duration::from_millis(CLOSE_WAIT_MILLIS),
+        endpoint.wait_idle(),
+    )
+    .await;
+
+    result
+}
+
+// --latency-method quic: setting up a QUIC connection to the test server,
+// a new one each time, looked up once
+pub struct QuicProbe {
+    runtime: tokio::runtime::Runtime,
+    connect: ConnectOptions,
+    addr: Option<SocketAddr>,
+}
+
+impl QuicProbe {
+    pub fn new(connect: &ConnectOptions) -> Result<Self> {
+        Ok(Self {
+            runtime: tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?,
+            connect: connect.clone(),
+            addr: None,
+        })
+    }
+
+    fn handshake(&mut self) -> Result<Duration> {
+        let host = crate::CLOUDFLARE_SPEEDTEST_HOST;
+        let addr = match self.addr {
+            Some(addr) => addr,
+            None => *self.addr.insert(first_addr(host, &self.connect)?),
+        };
+
+        self.runtime.block_on(async {
+            let endpoint = endpoint(addr, &self.connect)?;
+            let start = Instant::now();
+            let conn = handshake(&endpoint, addr, host).await?;
+            let took = start.elapsed();
+            conn.close(0u32.into(), b"");
+
+            Ok(took)
+        })
+    }
+}
+
+impl LatencyProbe for QuicProbe {
+    fn method(&self) -> LatencyMethod {
+        LatencyMethod::Quic
+    }
+
+    fn round_trip(&mut self) -> std::io::Result<Duration> {
+        self.handshake()
+            .map_err(|err| std::io::Error::other(err.to_string()))
+    }
+}
+
+async fn requests(
+    conn: &quinn::Connection,
+    host: &str,
+    direction: Direction,
+    bytes: usize,
+    counters: &Counters,
+) -> Result<()> {
+    let (mut driver, mut send_request) =
+        h3::client::new(h3_quinn::Connection::new(conn.clone())).await?;
+    // the connection only makes progress while something polls it
+    let driving =
+        tokio::spawn(async move { std::future::poll_fn(|cx| driver.poll_close(cx)).await });
+
+    let mut result = Ok(());
+    while result.is_ok() && !counters.exit_signal.load(Ordering::Relaxed) {
+        result = match direction {
+            Direction::Download => download(&mut send_request, host, bytes, counters).await,
+            Direction::Upload => upload(&mut send_request, host, bytes, counters).await,
+        };
+    }
+
+    driving.abort();
+    result
+}
+
+fn check_status(host: &str, response: &http::Response<()>) -> Result<()> {
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        status => Err(format!("{host} answered {status} over HTTP/3").into()),
+    }
+}
+
+async fn download(
+    send_request: &mut SendRequest,
+    host: &str,
+    bytes: usize,
+    counters: &Counters,
+) -> Result<()> {
+    let request = http::Request::get(format!("https://{host}/__down?measId=0&bytes={bytes}"))
+        .header("user-agent", crate::OUR_USER_AGENT)
+        .body(())?;
+    let mut stream = send_request.send_request(request).await?;
+    stream.finish().await?;
+    check_status(host, &stream.recv_response().await?)?;
+
+    while let Some(mut chunk) = stream.recv_data().await? {
+        let len = chunk.remaining();
+        if let Some(pacer) = &counters.pacer {
+            pacer.take(len);
+        }
+        counters.add_bytes(len);
+        chunk.advance(len);
+
+        if counters.exit_signal.load(Ordering::Relaxed) {
+            stream.stop_sending(h3::error::Code::H3_REQUEST_CANCELLED);
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+async fn upload(
+    send_request: &mut SendRequest,
+    host: &str,
+    bytes: usize,
+    counters: &Counters,
+) -> Result<()> {
+    let request = http::Request::post(format!("https://{host}/__up?measId=0"))
+        .header("content-type", "text/plain;charset=UTF-8")
+        .header("user-agent", crate::OUR_USER_AGENT)
+        .body(())?;
+    let mut stream = send_request.send_request(request).await?;
+
+    let chunk = Bytes::from(vec![0; UPLOAD_CHUNK_BYTES]);
+    let mut sent = 0;
+    while sent < bytes {
+        if counters.exit_signal.load(Ordering::Relaxed) {
+            stream.stop_stream(h3::error::Code::H3_REQUEST_CANCELLED);
+            return Ok(());
+        }
+
+        let len = chunk.len().min(bytes - sent);
+        if let Some(pacer) = &counters.pacer {
+            pacer.take(len);
+        }
+        stream.send_data(chunk.slice(..len)).await?;
+        sent += len;
+        counters.add_bytes(len);
+    }
+
+    stream.finish().await?;
+    check_status(host, &stream.recv_response().await?)
+}
+
+// What the connection did, as the socket stats TCP connections report
+fn record(sockets: &SocketRegistry, stats: &quinn::ConnectionStats, duration: Duration) {
+    sockets.record(SocketRecord {
+        bytes_sent: stats.udp_tx.bytes,
+        bytes_received: stats.udp_rx.bytes,
+        duration,
+        rtt: Some(stats.path.rtt),
+        retransmits: Some(stats.path.lost_packets as u32),
+        segments_sent: Some(stats.path.sent_packets as u32),
+    });
+}
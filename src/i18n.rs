@@ -0,0 +1,896 @@
+use std::collections::HashMap;
+
+// Languages we can show country and city names in
+pub static SUPPORTED_LANGUAGES: [&str; 4] = ["en", "es", "de", "fr"];
+
+// cca2 to country name in the given language, falling back to English
+pub fn generate_cca2_to_country_name_map(lang: &str) -> HashMap<&'static str, &'static str> {
+    let mut map = crate::locations::generate_cca2_to_full_country_name_map();
+    let localized = match lang {
+        "es" => generate_spanish_country_names(),
+        "de" => generate_german_country_names(),
+        "fr" => generate_french_country_names(),
+        _ => return map,
+    };
+
+    map.extend(localized);
+    map
+}
+
+// English city names (as in the IATA map) to the given language, where they differ
+pub fn generate_city_name_map(lang: &str) -> HashMap<&'static str, &'static str> {
+    match lang {
+        "es" => generate_spanish_city_names(),
+        "de" => generate_german_city_names(),
+        "fr" => generate_french_city_names(),
+        _ => HashMap::new(),
+    }
+}
+
+fn generate_spanish_country_names() -> HashMap<&'static str, &'static str> {
+    let mut map = HashMap::with_capacity(512);
+    map.insert("AF", "Afganistán");
+    map.insert("AX", "Islas Aland");
+    map.insert("AL", "Albania");
+    map.insert("DZ", "Argelia");
+    map.insert("AS", "Samoa Americana");
+    map.insert("AD", "Andorra");
+    map.insert("AO", "Angola");
+    map.insert("AI", "Anguila");
+    map.insert("AQ", "Antártida");
+    map.insert("AG", "Antigua y Barbuda");
+    map.insert("AR", "Argentina");
+    map.insert("AM", "Armenia");
+    map.insert("AW", "Aruba");
+    map.insert("AU", "Australia");
+    map.insert("AT", "Austria");
+    map.insert("AZ", "Azerbaiyán");
+    map.insert("BS", "Bahamas");
+    map.insert("BH", "Baréin");
+    map.insert("BD", "Bangladés");
+    map.insert("BB", "Barbados");
+    map.insert("BY", "Bielorrusia");
+    map.insert("BE", "Bélgica");
+    map.insert("BZ", "Belice");
+    map.insert("BJ", "Benín");
+    map.insert("BM", "Bermudas");
+    map.insert("BT", "Bután");
+    map.insert("BO", "Bolivia");
+    map.insert("BQ", "Caribe neerlandés");
+    map.insert("BA", "Bosnia y Herzegovina");
+    map.insert("BW", "Botsuana");
+    map.insert("BV", "Isla Bouvet");
+    map.insert("BR", "Brasil");
+    map.insert("IO", "Territorio Británico del Océano Índico");
+    map.insert("BN", "Brunéi");
+    map.insert("BG", "Bulgaria");
+    map.insert("BF", "Burkina Faso");
+    map.insert("BI", "Burundi");
+    map.insert("KH", "Camboya");
+    map.insert("CM", "Camerún");
+    map.insert("CA", "Canadá");
+    map.insert("CV", "Cabo Verde");
+    map.insert("KY", "Islas Caimán");
+    map.insert("CF", "República Centroafricana");
+    map.insert("TD", "Chad");
+    map.insert("CL", "Chile");
+    map.insert("CN", "China");
+    map.insert("CX", "Isla de Navidad");
+    map.insert("CC", "Islas Cocos");
+    map.insert("CO", "Colombia");
+    map.insert("KM", "Comoras");
+    map.insert("CG", "Congo");
+    map.insert("CD", "República Democrática del Congo");
+    map.insert("CK", "Islas Cook");
+    map.insert("CR", "Costa Rica");
+    map.insert("CI", "Côte d’Ivoire");
+    map.insert("HR", "Croacia");
+    map.insert("CU", "Cuba");
+    map.insert("CW", "Curazao");
+    map.insert("CY", "Chipre");
+    map.insert("CZ", "Chequia");
+    map.insert("DK", "Dinamarca");
+    map.insert("DJ", "Yibuti");
+    map.insert("DM", "Dominica");
+    map.insert("DO", "República Dominicana");
+    map.insert("EC", "Ecuador");
+    map.insert("EG", "Egipto");
+    map.insert("SV", "El Salvador");
+    map.insert("GQ", "Guinea Ecuatorial");
+    map.insert("ER", "Eritrea");
+    map.insert("EE", "Estonia");
+    map.insert("ET", "Etiopía");
+    map.insert("FK", "Islas Malvinas");
+    map.insert("FO", "Islas Feroe");
+    map.insert("FJ", "Fiyi");
+    map.insert("FI", "Finlandia");
+    map.insert("FR", "Francia");
+    map.insert("GF", "Guayana Francesa");
+    map.insert("PF", "Polinesia Francesa");
+    map.insert("TF", "Territorios Australes Franceses");
+    map.insert("GA", "Gabón");
+    map.insert("GM", "Gambia");
+    map.insert("GE", "Georgia");
+    map.insert("DE", "Alemania");
+    map.insert("GH", "Ghana");
+    map.insert("GI", "Gibraltar");
+    map.insert("GR", "Grecia");
+    map.insert("GL", "Groenlandia");
+    map.insert("GD", "Granada");
+    map.insert("GP", "Guadalupe");
+    map.insert("GU", "Guam");
+    map.insert("GT", "Guatemala");
+    map.insert("GG", "Guernesey");
+    map.insert("GN", "Guinea");
+    map.insert("GW", "Guinea-Bisáu");
+    map.insert("GY", "Guyana");
+    map.insert("HT", "Haití");
+    map.insert("HM", "Islas Heard y McDonald");
+    map.insert("VA", "Ciudad del Vaticano");
+    map.insert("HN", "Honduras");
+    map.insert("HK", "Hong Kong");
+    map.insert("HU", "Hungría");
+    map.insert("IS", "Islandia");
+    map.insert("IN", "India");
+    map.insert("ID", "Indonesia");
+    map.insert("IR", "Irán");
+    map.insert("IQ", "Irak");
+    map.insert("IE", "Irlanda");
+    map.insert("IM", "Isla de Man");
+    map.insert("IL", "Israel");
+    map.insert("IT", "Italia");
+    map.insert("JM", "Jamaica");
+    map.insert("JP", "Japón");
+    map.insert("JE", "Jersey");
+    map.insert("JO", "Jordania");
+    map.insert("KZ", "Kazajistán");
+    map.insert("KE", "Kenia");
+    map.insert("KI", "Kiribati");
+    map.insert("KP", "Corea del Norte");
+    map.insert("KR", "Corea del Sur");
+    map.insert("XK", "Kosovo");
+    map.insert("KW", "Kuwait");
+    map.insert("KG", "Kirguistán");
+    map.insert("LA", "Laos");
+    map.insert("LV", "Letonia");
+    map.insert("LB", "Líbano");
+    map.insert("LS", "Lesoto");
+    map.insert("LR", "Liberia");
+    map.insert("LY", "Libia");
+    map.insert("LI", "Liechtenstein");
+    map.insert("LT", "Lituania");
+    map.insert("LU", "Luxemburgo");
+    map.insert("MO", "Macao");
+    map.insert("MK", "Macedonia del Norte");
+    map.insert("MG", "Madagascar");
+    map.insert("MW", "Malaui");
+    map.insert("MY", "Malasia");
+    map.insert("MV", "Maldivas");
+    map.insert("ML", "Mali");
+    map.insert("MT", "Malta");
+    map.insert("MH", "Islas Marshall");
+    map.insert("MQ", "Martinica");
+    map.insert("MR", "Mauritania");
+    map.insert("MU", "Mauricio");
+    map.insert("YT", "Mayotte");
+    map.insert("MX", "México");
+    map.insert("FM", "Micronesia");
+    map.insert("MD", "Moldavia");
+    map.insert("MC", "Mónaco");
+    map.insert("MN", "Mongolia");
+    map.insert("ME", "Montenegro");
+    map.insert("MS", "Montserrat");
+    map.insert("MA", "Marruecos");
+    map.insert("MZ", "Mozambique");
+    map.insert("MM", "Myanmar (Birmania)");
+    map.insert("NA", "Namibia");
+    map.insert("NR", "Nauru");
+    map.insert("NP", "Nepal");
+    map.insert("NL", "Países Bajos");
+    map.insert("NC", "Nueva Caledonia");
+    map.insert("NZ", "Nueva Zelanda");
+    map.insert("NI", "Nicaragua");
+    map.insert("NE", "Níger");
+    map.insert("NG", "Nigeria");
+    map.insert("NU", "Niue");
+    map.insert("NF", "Isla Norfolk");
+    map.insert("MP", "Islas Marianas del Norte");
+    map.insert("NO", "Noruega");
+    map.insert("OM", "Omán");
+    map.insert("PK", "Pakistán");
+    map.insert("PW", "Palaos");
+    map.insert("PS", "Territorios Palestinos");
+    map.insert("PA", "Panamá");
+    map.insert("PG", "Papúa Nueva Guinea");
+    map.insert("PY", "Paraguay");
+    map.insert("PE", "Perú");
+    map.insert("PH", "Filipinas");
+    map.insert("PN", "Islas Pitcairn");
+    map.insert("PL", "Polonia");
+    map.insert("PT", "Portugal");
+    map.insert("PR", "Puerto Rico");
+    map.insert("QA", "Catar");
+    map.insert("RE", "Reunión");
+    map.insert("RO", "Rumanía");
+    map.insert("RU", "Rusia");
+    map.insert("RW", "Ruanda");
+    map.insert("BL", "San Bartolomé");
+    map.insert("SH", "Santa Elena");
+    map.insert("KN", "San Cristóbal y Nieves");
+    map.insert("LC", "Santa Lucía");
+    map.insert("MF", "San Martín");
+    map.insert("PM", "San Pedro y Miquelón");
+    map.insert("VC", "San Vicente y las Granadinas");
+    map.insert("WS", "Samoa");
+    map.insert("SM", "San Marino");
+    map.insert("ST", "Santo Tomé y Príncipe");
+    map.insert("SA", "Arabia Saudí");
+    map.insert("SN", "Senegal");
+    map.insert("RS", "Serbia");
+    map.insert("SC", "Seychelles");
+    map.insert("SL", "Sierra Leona");
+    map.insert("SG", "Singapur");
+    map.insert("SX", "Sint Maarten");
+    map.insert("SK", "Eslovaquia");
+    map.insert("SI", "Eslovenia");
+    map.insert("SB", "Islas Salomón");
+    map.insert("SO", "Somalia");
+    map.insert("ZA", "Sudáfrica");
+    map.insert("GS", "Islas Georgia del Sur y Sandwich del Sur");
+    map.insert("SS", "Sudán del Sur");
+    map.insert("ES", "España");
+    map.insert("LK", "Sri Lanka");
+    map.insert("SD", "Sudán");
+    map.insert("SR", "Surinam");
+    map.insert("SJ", "Svalbard y Jan Mayen");
+    map.insert("SZ", "Esuatini");
+    map.insert("SE", "Suecia");
+    map.insert("CH", "Suiza");
+    map.insert("SY", "Siria");
+    map.insert("TW", "Taiwán");
+    map.insert("TJ", "Tayikistán");
+    map.insert("TZ", "Tanzania");
+    map.insert("TH", "Tailandia");
+    map.insert("TL", "Timor-Leste");
+    map.insert("TG", "Togo");
+    map.insert("TK", "Tokelau");
+    map.insert("TO", "Tonga");
+    map.insert("TT", "Trinidad y Tobago");
+    map.insert("TN", "Túnez");
+    map.insert("TR", "Turquía");
+    map.insert("TM", "Turkmenistán");
+    map.insert("TC", "Islas Turcas y Caicos");
+    map.insert("TV", "Tuvalu");
+    map.insert("UG", "Uganda");
+    map.insert("UA", "Ucrania");
+    map.insert("AE", "Emiratos Árabes Unidos");
+    map.insert("GB", "Reino Unido");
+    map.insert("US", "Estados Unidos");
+    map.insert("UM", "Islas menores alejadas de EE. UU.");
+    map.insert("UY", "Uruguay");
+    map.insert("UZ", "Uzbekistán");
+    map.insert("VU", "Vanuatu");
+    map.insert("VE", "Venezuela");
+    map.insert("VN", "Vietnam");
+    map.insert("VG", "Islas Vírgenes Británicas");
+    map.insert("VI", "Islas Vírgenes de EE. UU.");
+    map.insert("WF", "Wallis y Futuna");
+    map.insert("EH", "Sáhara Occidental");
+    map.insert("YE", "Yemen");
+    map.insert("ZM", "Zambia");
+    map.insert("ZW", "Zimbabue");
+    map.insert("XX", "Ningún país");
+    map
+}
+
+fn generate_german_country_names() -> HashMap<&'static str, &'static str> {
+    let mut map = HashMap::with_capacity(512);
+    map.insert("AF", "Afghanistan");
+    map.insert("AX", "Ålandinseln");
+    map.insert("AL", "Albanien");
+    map.insert("DZ", "Algerien");
+    map.insert("AS", "Amerikanisch-Samoa");
+    map.insert("AD", "Andorra");
+    map.insert("AO", "Angola");
+    map.insert("AI", "Anguilla");
+    map.insert("AQ", "Antarktis");
+    map.insert("AG", "Antigua und Barbuda");
+    map.insert("AR", "Argentinien");
+    map.insert("AM", "Armenien");
+    map.insert("AW", "Aruba");
+    map.insert("AU", "Australien");
+    map.insert("AT", "Österreich");
+    map.insert("AZ", "Aserbaidschan");
+    map.insert("BS", "Bahamas");
+    map.insert("BH", "Bahrain");
+    map.insert("BD", "Bangladesch");
+    map.insert("BB", "Barbados");
+    map.insert("BY", "Belarus");
+    map.insert("BE", "Belgien");
+    map.insert("BZ", "Belize");
+    map.insert("BJ", "Benin");
+    map.insert("BM", "Bermuda");
+    map.insert("BT", "Bhutan");
+    map.insert("BO", "Bolivien");
+    map.insert("BQ", "Karibische Niederlande");
+    map.insert("BA", "Bosnien und Herzegowina");
+    map.insert("BW", "Botsuana");
+    map.insert("BV", "Bouvetinsel");
+    map.insert("BR", "Brasilien");
+    map.insert("IO", "Britisches Territorium im Indischen Ozean");
+    map.insert("BN", "Brunei Darussalam");
+    map.insert("BG", "Bulgarien");
+    map.insert("BF", "Burkina Faso");
+    map.insert("BI", "Burundi");
+    map.insert("KH", "Kambodscha");
+    map.insert("CM", "Kamerun");
+    map.insert("CA", "Kanada");
+    map.insert("CV", "Cabo Verde");
+    map.insert("KY", "Kaimaninseln");
+    map.insert("CF", "Zentralafrikanische Republik");
+    map.insert("TD", "Tschad");
+    map.insert("CL", "Chile");
+    map.insert("CN", "China");
+    map.insert("CX", "Weihnachtsinsel");
+    map.insert("CC", "Kokosinseln");
+    map.insert("CO", "Kolumbien");
+    map.insert("KM", "Komoren");
+    map.insert("CG", "Kongo-Brazzaville");
+    map.insert("CD", "Kongo-Kinshasa");
+    map.insert("CK", "Cookinseln");
+    map.insert("CR", "Costa Rica");
+    map.insert("CI", "Côte d’Ivoire");
+    map.insert("HR", "Kroatien");
+    map.insert("CU", "Kuba");
+    map.insert("CW", "Curaçao");
+    map.insert("CY", "Zypern");
+    map.insert("CZ", "Tschechien");
+    map.insert("DK", "Dänemark");
+    map.insert("DJ", "Dschibuti");
+    map.insert("DM", "Dominica");
+    map.insert("DO", "Dominikanische Republik");
+    map.insert("EC", "Ecuador");
+    map.insert("EG", "Ägypten");
+    map.insert("SV", "El Salvador");
+    map.insert("GQ", "Äquatorialguinea");
+    map.insert("ER", "Eritrea");
+    map.insert("EE", "Estland");
+    map.insert("ET", "Äthiopien");
+    map.insert("FK", "Falklandinseln");
+    map.insert("FO", "Färöer");
+    map.insert("FJ", "Fidschi");
+    map.insert("FI", "Finnland");
+    map.insert("FR", "Frankreich");
+    map.insert("GF", "Französisch-Guayana");
+    map.insert("PF", "Französisch-Polynesien");
+    map.insert("TF", "Französische Süd- und Antarktisgebiete");
+    map.insert("GA", "Gabun");
+    map.insert("GM", "Gambia");
+    map.insert("GE", "Georgien");
+    map.insert("DE", "Deutschland");
+    map.insert("GH", "Ghana");
+    map.insert("GI", "Gibraltar");
+    map.insert("GR", "Griechenland");
+    map.insert("GL", "Grönland");
+    map.insert("GD", "Grenada");
+    map.insert("GP", "Guadeloupe");
+    map.insert("GU", "Guam");
+    map.insert("GT", "Guatemala");
+    map.insert("GG", "Guernsey");
+    map.insert("GN", "Guinea");
+    map.insert("GW", "Guinea-Bissau");
+    map.insert("GY", "Guyana");
+    map.insert("HT", "Haiti");
+    map.insert("HM", "Heard und McDonaldinseln");
+    map.insert("VA", "Vatikanstadt");
+    map.insert("HN", "Honduras");
+    map.insert("HK", "Hongkong");
+    map.insert("HU", "Ungarn");
+    map.insert("IS", "Island");
+    map.insert("IN", "Indien");
+    map.insert("ID", "Indonesien");
+    map.insert("IR", "Iran");
+    map.insert("IQ", "Irak");
+    map.insert("IE", "Irland");
+    map.insert("IM", "Isle of Man");
+    map.insert("IL", "Israel");
+    map.insert("IT", "Italien");
+    map.insert("JM", "Jamaika");
+    map.insert("JP", "Japan");
+    map.insert("JE", "Jersey");
+    map.insert("JO", "Jordanien");
+    map.insert("KZ", "Kasachstan");
+    map.insert("KE", "Kenia");
+    map.insert("KI", "Kiribati");
+    map.insert("KP", "Nordkorea");
+    map.insert("KR", "Südkorea");
+    map.insert("XK", "Kosovo");
+    map.insert("KW", "Kuwait");
+    map.insert("KG", "Kirgisistan");
+    map.insert("LA", "Laos");
+    map.insert("LV", "Lettland");
+    map.insert("LB", "Libanon");
+    map.insert("LS", "Lesotho");
+    map.insert("LR", "Liberia");
+    map.insert("LY", "Libyen");
+    map.insert("LI", "Liechtenstein");
+    map.insert("LT", "Litauen");
+    map.insert("LU", "Luxemburg");
+    map.insert("MO", "Macau");
+    map.insert("MK", "Nordmazedonien");
+    map.insert("MG", "Madagaskar");
+    map.insert("MW", "Malawi");
+    map.insert("MY", "Malaysia");
+    map.insert("MV", "Malediven");
+    map.insert("ML", "Mali");
+    map.insert("MT", "Malta");
+    map.insert("MH", "Marshallinseln");
+    map.insert("MQ", "Martinique");
+    map.insert("MR", "Mauretanien");
+    map.insert("MU", "Mauritius");
+    map.insert("YT", "Mayotte");
+    map.insert("MX", "Mexiko");
+    map.insert("FM", "Mikronesien");
+    map.insert("MD", "Republik Moldau");
+    map.insert("MC", "Monaco");
+    map.insert("MN", "Mongolei");
+    map.insert("ME", "Montenegro");
+    map.insert("MS", "Montserrat");
+    map.insert("MA", "Marokko");
+    map.insert("MZ", "Mosambik");
+    map.insert("MM", "Myanmar");
+    map.insert("NA", "Namibia");
+    map.insert("NR", "Nauru");
+    map.insert("NP", "Nepal");
+    map.insert("NL", "Niederlande");
+    map.insert("NC", "Neukaledonien");
+    map.insert("NZ", "Neuseeland");
+    map.insert("NI", "Nicaragua");
+    map.insert("NE", "Niger");
+    map.insert("NG", "Nigeria");
+    map.insert("NU", "Niue");
+    map.insert("NF", "Norfolkinsel");
+    map.insert("MP", "Nördliche Marianen");
+    map.insert("NO", "Norwegen");
+    map.insert("OM", "Oman");
+    map.insert("PK", "Pakistan");
+    map.insert("PW", "Palau");
+    map.insert("PS", "Palästinensische Autonomiegebiete");
+    map.insert("PA", "Panama");
+    map.insert("PG", "Papua-Neuguinea");
+    map.insert("PY", "Paraguay");
+    map.insert("PE", "Peru");
+    map.insert("PH", "Philippinen");
+    map.insert("PN", "Pitcairninseln");
+    map.insert("PL", "Polen");
+    map.insert("PT", "Portugal");
+    map.insert("PR", "Puerto Rico");
+    map.insert("QA", "Katar");
+    map.insert("RE", "Réunion");
+    map.insert("RO", "Rumänien");
+    map.insert("RU", "Russland");
+    map.insert("RW", "Ruanda");
+    map.insert("BL", "St. Barthélemy");
+    map.insert("SH", "St. Helena");
+    map.insert("KN", "St. Kitts und Nevis");
+    map.insert("LC", "St. Lucia");
+    map.insert("MF", "St. Martin");
+    map.insert("PM", "St. Pierre und Miquelon");
+    map.insert("VC", "St. Vincent und die Grenadinen");
+    map.insert("WS", "Samoa");
+    map.insert("SM", "San Marino");
+    map.insert("ST", "São Tomé und Príncipe");
+    map.insert("SA", "Saudi-Arabien");
+    map.insert("SN", "Senegal");
+    map.insert("RS", "Serbien");
+    map.insert("SC", "Seychellen");
+    map.insert("SL", "Sierra Leone");
+    map.insert("SG", "Singapur");
+    map.insert("SX", "Sint Maarten");
+    map.insert("SK", "Slowakei");
+    map.insert("SI", "Slowenien");
+    map.insert("SB", "Salomonen");
+    map.insert("SO", "Somalia");
+    map.insert("ZA", "Südafrika");
+    map.insert("GS", "Südgeorgien und die Südlichen Sandwichinseln");
+    map.insert("SS", "Südsudan");
+    map.insert("ES", "Spanien");
+    map.insert("LK", "Sri Lanka");
+    map.insert("SD", "Sudan");
+    map.insert("SR", "Suriname");
+    map.insert("SJ", "Spitzbergen und Jan Mayen");
+    map.insert("SZ", "Eswatini");
+    map.insert("SE", "Schweden");
+    map.insert("CH", "Schweiz");
+    map.insert("SY", "Syrien");
+    map.insert("TW", "Taiwan");
+    map.insert("TJ", "Tadschikistan");
+    map.insert("TZ", "Tansania");
+    map.insert("TH", "Thailand");
+    map.insert("TL", "Timor-Leste");
+    map.insert("TG", "Togo");
+    map.insert("TK", "Tokelau");
+    map.insert("TO", "Tonga");
+    map.insert("TT", "Trinidad und Tobago");
+    map.insert("TN", "Tunesien");
+    map.insert("TR", "Türkei");
+    map.insert("TM", "Turkmenistan");
+    map.insert("TC", "Turks- und Caicosinseln");
+    map.insert("TV", "Tuvalu");
+    map.insert("UG", "Uganda");
+    map.insert("UA", "Ukraine");
+    map.insert("AE", "Vereinigte Arabische Emirate");
+    map.insert("GB", "Vereinigtes Königreich");
+    map.insert("US", "Vereinigte Staaten");
+    map.insert("UM", "Amerikanische Überseeinseln");
+    map.insert("UY", "Uruguay");
+    map.insert("UZ", "Usbekistan");
+    map.insert("VU", "Vanuatu");
+    map.insert("VE", "Venezuela");
+    map.insert("VN", "Vietnam");
+    map.insert("VG", "Britische Jungferninseln");
+    map.insert("VI", "Amerikanische Jungferninseln");
+    map.insert("WF", "Wallis und Futuna");
+    map.insert("EH", "Westsahara");
+    map.insert("YE", "Jemen");
+    map.insert("ZM", "Sambia");
+    map.insert("ZW", "Simbabwe");
+    map.insert("XX", "Kein Land");
+    map
+}
+
+fn generate_french_country_names() -> HashMap<&'static str, &'static str> {
+    let mut map = HashMap::with_capacity(512);
+    map.insert("AF", "Afghanistan");
+    map.insert("AX", "Îles Åland");
+    map.insert("AL", "Albanie");
+    map.insert("DZ", "Algérie");
+    map.insert("AS", "Samoa américaines");
+    map.insert("AD", "Andorre");
+    map.insert("AO", "Angola");
+    map.insert("AI", "Anguilla");
+    map.insert("AQ", "Antarctique");
+    map.insert("AG", "Antigua-et-Barbuda");
+    map.insert("AR", "Argentine");
+    map.insert("AM", "Arménie");
+    map.insert("AW", "Aruba");
+    map.insert("AU", "Australie");
+    map.insert("AT", "Autriche");
+    map.insert("AZ", "Azerbaïdjan");
+    map.insert("BS", "Bahamas");
+    map.insert("BH", "Bahreïn");
+    map.insert("BD", "Bangladesh");
+    map.insert("BB", "Barbade");
+    map.insert("BY", "Biélorussie");
+    map.insert("BE", "Belgique");
+    map.insert("BZ", "Belize");
+    map.insert("BJ", "Bénin");
+    map.insert("BM", "Bermudes");
+    map.insert("BT", "Bhoutan");
+    map.insert("BO", "Bolivie");
+    map.insert("BQ", "Pays-Bas caribéens");
+    map.insert("BA", "Bosnie-Herzégovine");
+    map.insert("BW", "Botswana");
+    map.insert("BV", "Île Bouvet");
+    map.insert("BR", "Brésil");
+    map.insert("IO", "Territoire britannique de l’océan Indien");
+    map.insert("BN", "Brunei");
+    map.insert("BG", "Bulgarie");
+    map.insert("BF", "Burkina Faso");
+    map.insert("BI", "Burundi");
+    map.insert("KH", "Cambodge");
+    map.insert("CM", "Cameroun");
+    map.insert("CA", "Canada");
+    map.insert("CV", "Cap-Vert");
+    map.insert("KY", "Îles Caïmans");
+    map.insert("CF", "République centrafricaine");
+    map.insert("TD", "Tchad");
+    map.insert("CL", "Chili");
+    map.insert("CN", "Chine");
+    map.insert("CX", "Île Christmas");
+    map.insert("CC", "Îles Cocos");
+    map.insert("CO", "Colombie");
+    map.insert("KM", "Comores");
+    map.insert("CG", "Congo-Brazzaville");
+    map.insert("CD", "Congo-Kinshasa");
+    map.insert("CK", "Îles Cook");
+    map.insert("CR", "Costa Rica");
+    map.insert("CI", "Côte d’Ivoire");
+    map.insert("HR", "Croatie");
+    map.insert("CU", "Cuba");
+    map.insert("CW", "Curaçao");
+    map.insert("CY", "Chypre");
+    map.insert("CZ", "Tchéquie");
+    map.insert("DK", "Danemark");
+    map.insert("DJ", "Djibouti");
+    map.insert("DM", "Dominique");
+    map.insert("DO", "République dominicaine");
+    map.insert("EC", "Équateur");
+    map.insert("EG", "Égypte");
+    map.insert("SV", "Salvador");
+    map.insert("GQ", "Guinée équatoriale");
+    map.insert("ER", "Érythrée");
+    map.insert("EE", "Estonie");
+    map.insert("ET", "Éthiopie");
+    map.insert("FK", "Îles Malouines");
+    map.insert("FO", "Îles Féroé");
+    map.insert("FJ", "Fidji");
+    map.insert("FI", "Finlande");
+    map.insert("FR", "France");
+    map.insert("GF", "Guyane française");
+    map.insert("PF", "Polynésie française");
+    map.insert("TF", "Terres australes françaises");
+    map.insert("GA", "Gabon");
+    map.insert("GM", "Gambie");
+    map.insert("GE", "Géorgie");
+    map.insert("DE", "Allemagne");
+    map.insert("GH", "Ghana");
+    map.insert("GI", "Gibraltar");
+    map.insert("GR", "Grèce");
+    map.insert("GL", "Groenland");
+    map.insert("GD", "Grenade");
+    map.insert("GP", "Guadeloupe");
+    map.insert("GU", "Guam");
+    map.insert("GT", "Guatemala");
+    map.insert("GG", "Guernesey");
+    map.insert("GN", "Guinée");
+    map.insert("GW", "Guinée-Bissau");
+    map.insert("GY", "Guyana");
+    map.insert("HT", "Haïti");
+    map.insert("HM", "Îles Heard-et-MacDonald");
+    map.insert("VA", "État de la Cité du Vatican");
+    map.insert("HN", "Honduras");
+    map.insert("HK", "Hong Kong");
+    map.insert("HU", "Hongrie");
+    map.insert("IS", "Islande");
+    map.insert("IN", "Inde");
+    map.insert("ID", "Indonésie");
+    map.insert("IR", "Iran");
+    map.insert("IQ", "Irak");
+    map.insert("IE", "Irlande");
+    map.insert("IM", "Île de Man");
+    map.insert("IL", "Israël");
+    map.insert("IT", "Italie");
+    map.insert("JM", "Jamaïque");
+    map.insert("JP", "Japon");
+    map.insert("JE", "Jersey");
+    map.insert("JO", "Jordanie");
+    map.insert("KZ", "Kazakhstan");
+    map.insert("KE", "Kenya");
+    map.insert("KI", "Kiribati");
+    map.insert("KP", "Corée du Nord");
+    map.insert("KR", "Corée du Sud");
+    map.insert("XK", "Kosovo");
+    map.insert("KW", "Koweït");
+    map.insert("KG", "Kirghizistan");
+    map.insert("LA", "Laos");
+    map.insert("LV", "Lettonie");
+    map.insert("LB", "Liban");
+    map.insert("LS", "Lesotho");
+    map.insert("LR", "Libéria");
+    map.insert("LY", "Libye");
+    map.insert("LI", "Liechtenstein");
+    map.insert("LT", "Lituanie");
+    map.insert("LU", "Luxembourg");
+    map.insert("MO", "Macao");
+    map.insert("MK", "Macédoine du Nord");
+    map.insert("MG", "Madagascar");
+    map.insert("MW", "Malawi");
+    map.insert("MY", "Malaisie");
+    map.insert("MV", "Maldives");
+    map.insert("ML", "Mali");
+    map.insert("MT", "Malte");
+    map.insert("MH", "Îles Marshall");
+    map.insert("MQ", "Martinique");
+    map.insert("MR", "Mauritanie");
+    map.insert("MU", "Maurice");
+    map.insert("YT", "Mayotte");
+    map.insert("MX", "Mexique");
+    map.insert("FM", "Micronésie");
+    map.insert("MD", "Moldavie");
+    map.insert("MC", "Monaco");
+    map.insert("MN", "Mongolie");
+    map.insert("ME", "Monténégro");
+    map.insert("MS", "Montserrat");
+    map.insert("MA", "Maroc");
+    map.insert("MZ", "Mozambique");
+    map.insert("MM", "Myanmar (Birmanie)");
+    map.insert("NA", "Namibie");
+    map.insert("NR", "Nauru");
+    map.insert("NP", "Népal");
+    map.insert("NL", "Pays-Bas");
+    map.insert("NC", "Nouvelle-Calédonie");
+    map.insert("NZ", "Nouvelle-Zélande");
+    map.insert("NI", "Nicaragua");
+    map.insert("NE", "Niger");
+    map.insert("NG", "Nigeria");
+    map.insert("NU", "Niue");
+    map.insert("NF", "Île Norfolk");
+    map.insert("MP", "Îles Mariannes du Nord");
+    map.insert("NO", "Norvège");
+    map.insert("OM", "Oman");
+    map.insert("PK", "Pakistan");
+    map.insert("PW", "Palaos");
+    map.insert("PS", "Territoires palestiniens");
+    map.insert("PA", "Panama");
+    map.insert("PG", "Papouasie-Nouvelle-Guinée");
+    map.insert("PY", "Paraguay");
+    map.insert("PE", "Pérou");
+    map.insert("PH", "Philippines");
+    map.insert("PN", "Îles Pitcairn");
+    map.insert("PL", "Pologne");
+    map.insert("PT", "Portugal");
+    map.insert("PR", "Porto Rico");
+    map.insert("QA", "Qatar");
+    map.insert("RE", "La Réunion");
+    map.insert("RO", "Roumanie");
+    map.insert("RU", "Russie");
+    map.insert("RW", "Rwanda");
+    map.insert("BL", "Saint-Barthélemy");
+    map.insert("SH", "Sainte-Hélène");
+    map.insert("KN", "Saint-Christophe-et-Niévès");
+    map.insert("LC", "Sainte-Lucie");
+    map.insert("MF", "Saint-Martin");
+    map.insert("PM", "Saint-Pierre-et-Miquelon");
+    map.insert("VC", "Saint-Vincent-et-les-Grenadines");
+    map.insert("WS", "Samoa");
+    map.insert("SM", "Saint-Marin");
+    map.insert("ST", "Sao Tomé-et-Principe");
+    map.insert("SA", "Arabie saoudite");
+    map.insert("SN", "Sénégal");
+    map.insert("RS", "Serbie");
+    map.insert("SC", "Seychelles");
+    map.insert("SL", "Sierra Leone");
+    map.insert("SG", "Singapour");
+    map.insert("SX", "Saint-Martin (partie néerlandaise)");
+    map.insert("SK", "Slovaquie");
+    map.insert("SI", "Slovénie");
+    map.insert("SB", "Îles Salomon");
+    map.insert("SO", "Somalie");
+    map.insert("ZA", "Afrique du Sud");
+    map.insert("GS", "Géorgie du Sud-et-les Îles Sandwich du Sud");
+    map.insert("SS", "Soudan du Sud");
+    map.insert("ES", "Espagne");
+    map.insert("LK", "Sri Lanka");
+    map.insert("SD", "Soudan");
+    map.insert("SR", "Suriname");
+    map.insert("SJ", "Svalbard et Jan Mayen");
+    map.insert("SZ", "Eswatini");
+    map.insert("SE", "Suède");
+    map.insert("CH", "Suisse");
+    map.insert("SY", "Syrie");
+    map.insert("TW", "Taïwan");
+    map.insert("TJ", "Tadjikistan");
+    map.insert("TZ", "Tanzanie");
+    map.insert("TH", "Thaïlande");
+    map.insert("TL", "Timor oriental");
+    map.insert("TG", "Togo");
+    map.insert("TK", "Tokelau");
+    map.insert("TO", "Tonga");
+    map.insert("TT", "Trinité-et-Tobago");
+    map.insert("TN", "Tunisie");
+    map.insert("TR", "Turquie");
+    map.insert("TM", "Turkménistan");
+    map.insert("TC", "Îles Turques-et-Caïques");
+    map.insert("TV", "Tuvalu");
+    map.insert("UG", "Ouganda");
+    map.insert("UA", "Ukraine");
+    map.insert("AE", "Émirats arabes unis");
+    map.insert("GB", "Royaume-Uni");
+    map.insert("US", "États-Unis");
+    map.insert("UM", "Îles mineures éloignées des États-Unis");
+    map.insert("UY", "Uruguay");
+    map.insert("UZ", "Ouzbékistan");
+    map.insert("VU", "Vanuatu");
+    map.insert("VE", "Venezuela");
+    map.insert("VN", "Viêt Nam");
+    map.insert("VG", "Îles Vierges britanniques");
+    map.insert("VI", "Îles Vierges des États-Unis");
+    map.insert("WF", "Wallis-et-Futuna");
+    map.insert("EH", "Sahara occidental");
+    map.insert("YE", "Yémen");
+    map.insert("ZM", "Zambie");
+    map.insert("ZW", "Zimbabwe");
+    map.insert("XX", "Aucun pays");
+    map
+}
+
+fn generate_spanish_city_names() -> HashMap<&'static str, &'static str> {
+    let mut map = HashMap::with_capacity(64);
+    map.insert("Algiers", "Argel");
+    map.insert("Athens", "Atenas");
+    map.insert("Baghdad", "Bagdad");
+    map.insert("Belgrade", "Belgrado");
+    map.insert("Brussels", "Bruselas");
+    map.insert("Bucharest", "Bucarest");
+    map.insert("Cairo", "El Cairo");
+    map.insert("Cape Town", "Ciudad del Cabo");
+    map.insert("Copenhagen", "Copenhague");
+    map.insert("Edinburgh", "Edimburgo");
+    map.insert("Frankfurt", "Fráncfort");
+    map.insert("Geneva", "Ginebra");
+    map.insert("Gothenburg", "Gotemburgo");
+    map.insert("Hamburg", "Hamburgo");
+    map.insert("Istanbul", "Estambul");
+    map.insert("Kyiv", "Kiev");
+    map.insert("Lisbon", "Lisboa");
+    map.insert("London", "Londres");
+    map.insert("Luxembourg City", "Luxemburgo");
+    map.insert("Mexico City", "Ciudad de México");
+    map.insert("Milan", "Milán");
+    map.insert("Moscow", "Moscú");
+    map.insert("Munich", "Múnich");
+    map.insert("New Delhi", "Nueva Delhi");
+    map.insert("Prague", "Praga");
+    map.insert("Rome", "Roma");
+    map.insert("Saint Petersburg", "San Petersburgo");
+    map.insert("Seoul", "Seúl");
+    map.insert("Singapore", "Singapur");
+    map.insert("Stockholm", "Estocolmo");
+    map.insert("Tokyo", "Tokio");
+    map.insert("Vienna", "Viena");
+    map.insert("Warsaw", "Varsovia");
+    map.insert("Zurich", "Zúrich");
+    map
+}
+
+fn generate_german_city_names() -> HashMap<&'static str, &'static str> {
+    let mut map = HashMap::with_capacity(64);
+    map.insert("Algiers", "Algier");
+    map.insert("Athens", "Athen");
+    map.insert("Baghdad", "Bagdad");
+    map.insert("Belgrade", "Belgrad");
+    map.insert("Brussels", "Brüssel");
+    map.insert("Bucharest", "Bukarest");
+    map.insert("Cairo", "Kairo");
+    map.insert("Cape Town", "Kapstadt");
+    map.insert("Copenhagen", "Kopenhagen");
+    map.insert("Geneva", "Genf");
+    map.insert("Gothenburg", "Göteborg");
+    map.insert("Kyiv", "Kyjiw");
+    map.insert("Lisbon", "Lissabon");
+    map.insert("Luxembourg City", "Luxemburg");
+    map.insert("Mexico City", "Mexiko-Stadt");
+    map.insert("Milan", "Mailand");
+    map.insert("Moscow", "Moskau");
+    map.insert("Munich", "München");
+    map.insert("New Delhi", "Neu-Delhi");
+    map.insert("Prague", "Prag");
+    map.insert("Rome", "Rom");
+    map.insert("Saint Petersburg", "Sankt Petersburg");
+    map.insert("Singapore", "Singapur");
+    map.insert("Tokyo", "Tokio");
+    map.insert("Vienna", "Wien");
+    map.insert("Warsaw", "Warschau");
+    map.insert("Zurich", "Zürich");
+    map
+}
+
+fn generate_french_city_names() -> HashMap<&'static str, &'static str> {
+    let mut map = HashMap::with_capacity(64);
+    map.insert("Algiers", "Alger");
+    map.insert("Athens", "Athènes");
+    map.insert("Baghdad", "Bagdad");
+    map.insert("Beirut", "Beyrouth");
+    map.insert("Brussels", "Bruxelles");
+    map.insert("Bucharest", "Bucarest");
+    map.insert("Cairo", "Le Caire");
+    map.insert("Cape Town", "Le Cap");
+    map.insert("Copenhagen", "Copenhague");
+    map.insert("Edinburgh", "Édimbourg");
+    map.insert("Frankfurt", "Francfort");
+    map.insert("Geneva", "Genève");
+    map.insert("Gothenburg", "Göteborg");
+    map.insert("Hamburg", "Hambourg");
+    map.insert("Lisbon", "Lisbonne");
+    map.insert("London", "Londres");
+    map.insert("Luxembourg City", "Luxembourg");
+    map.insert("Mexico City", "Mexico");
+    map.insert("Moscow", "Moscou");
+    map.insert("Saint Petersburg", "Saint-Pétersbourg");
+    map.insert("Seoul", "Séoul");
+    map.insert("Singapore", "Singapour");
+    map.insert("Vienna", "Vienne");
+    map.insert("Warsaw", "Varsovie");
+    map
+}
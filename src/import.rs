@@ -0,0 +1,180 @@
+use crate::results::{PhaseSummary, RunResult};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+fn invalid_data(msg: String) -> Box<dyn std::error::Error> {
+    Box::new(std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+}
+
+// Parse runs exported by another tool, in the given format
+pub fn parse_runs(format: &str, contents: &str) -> Result<Vec<RunResult>> {
+    match format {
+        "ooklacsv" => parse_ookla_csv(contents),
+        "ooklajson" => parse_ookla_json(contents),
+        _ => Err(invalid_data(format!(
+            "Unknown import format '{format}', expected ooklacsv or ooklajson"
+        ))),
+    }
+}
+
+// Split a single CSV line into fields, honouring double quotes
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+
+    fields
+}
+
+fn parse_timestamp(value: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(value.trim())?.with_timezone(&Utc))
+}
+
+/* CSV as written by speedtest-cli --csv --csv-header:
+   Server ID,Sponsor,Server Name,Timestamp,Distance,Ping,Download,Upload,Share,IP Address
+   Ping is in milliseconds, Download and Upload in bits per second
+*/
+fn parse_ookla_csv(contents: &str) -> Result<Vec<RunResult>> {
+    let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+    let header = lines
+        .next()
+        .ok_or_else(|| invalid_data("CSV file is empty".to_owned()))?;
+
+    let columns: HashMap<String, usize> = split_csv_line(header)
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| (name.trim().to_ascii_lowercase(), i))
+        .collect();
+
+    let column = |name: &str| {
+        columns.get(name).copied().ok_or_else(|| {
+            invalid_data(format!(
+                "CSV header has no '{name}' column, was it written with --csv-header?"
+            ))
+        })
+    };
+    let timestamp_col = column("timestamp")?;
+    let ping_col = column("ping")?;
+    let download_col = column("download")?;
+    let upload_col = column("upload")?;
+
+    let mut runs = vec![];
+    for (i, line) in lines.enumerate() {
+        let fields = split_csv_line(line);
+        let field = |col: usize| {
+            fields
+                .get(col)
+                .map(|f| f.trim())
+                .ok_or_else(|| invalid_data(format!("CSV row {} has too few columns", i + 2)))
+        };
+
+        runs.push(RunResult {
+            timestamp: parse_timestamp(field(timestamp_col)?)?,
+            source: "speedtest-cli".to_owned(),
+            colo: None,
+            country: None,
+            region: None,
+            city: None,
+            latency_ms: Some(field(ping_col)?.parse()?),
+            idle_latency: None,
+            download: Some(PhaseSummary::single(field(download_col)?.parse()?)),
+            upload: Some(PhaseSummary::single(field(upload_col)?.parse()?)),
+            download_loaded_latency: None,
+            upload_loaded_latency: None,
+            via_interface: None,
+            server: None,
+            sockets: None,
+            content: None,
+            events: vec![],
+            cached: false,
+            interrupted: false,
+            clock_untrusted: false,
+            assertions: vec![],
+            skipped: vec![],
+            host: None,
+            warnings: vec![],
+            resolved: None,
+        });
+    }
+
+    Ok(runs)
+}
+
+/* JSON as written by the Ookla speedtest CLI with --format=json, either one
+   result object per line (as you'd get from cron) or an array of them.
+   Bandwidth is in bytes per second.
+*/
+fn parse_ookla_json(contents: &str) -> Result<Vec<RunResult>> {
+    let values: Vec<serde_json::Value> = match serde_json::from_str(contents) {
+        Ok(serde_json::Value::Array(values)) => values,
+        Ok(value) => vec![value],
+        Err(_) => contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<std::result::Result<_, _>>()?,
+    };
+
+    let mut runs = vec![];
+    for value in values {
+        // the CLI also logs progress and errors as JSON, skip those
+        if value["type"] != "result" {
+            continue;
+        }
+
+        let timestamp = value["timestamp"]
+            .as_str()
+            .ok_or_else(|| invalid_data("Ookla result has no timestamp".to_owned()))?;
+        let bandwidth_bps = |phase: &str| {
+            value[phase]["bandwidth"]
+                .as_f64()
+                .map(|bytes| PhaseSummary::single(bytes * 8.0))
+        };
+
+        runs.push(RunResult {
+            timestamp: parse_timestamp(timestamp)?,
+            source: "ookla".to_owned(),
+            colo: None,
+            country: None,
+            region: None,
+            city: None,
+            latency_ms: value["ping"]["latency"].as_f64(),
+            idle_latency: None,
+            download: bandwidth_bps("download"),
+            upload: bandwidth_bps("upload"),
+            download_loaded_latency: None,
+            upload_loaded_latency: None,
+            via_interface: None,
+            server: None,
+            sockets: None,
+            content: None,
+            events: vec![],
+            cached: false,
+            interrupted: false,
+            clock_untrusted: false,
+            assertions: vec![],
+            skipped: vec![],
+            host: None,
+            warnings: vec![],
+            resolved: None,
+        });
+    }
+
+    Ok(runs)
+}
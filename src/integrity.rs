@@ -0,0 +1,173 @@
+/* Some ISPs, "free" ones especially, route you through a middlebox that
+   rewrites what you download: injecting adverts, substituting content or
+   cutting responses short. Asking Cloudflare for the same number of bytes
+   gets the same bytes every time, so with --verify-content the start of
+   every download is hashed, and a response that differs from the others,
+   or from what earlier runs saw, was changed on the way.
+*/
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// How much of each response is hashed, enough to catch an injected page
+// without slowing fast links down
+pub static SAMPLE_BYTES: usize = 64 * 1024;
+
+// FNV-1a, which unlike std's hashers gives the same digest on every
+// platform and Rust version, so runs can be compared
+#[derive(Clone, Copy)]
+struct Fnv(u64);
+
+impl Fnv {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(Self::PRIME);
+        }
+    }
+}
+
+// What downloaded bytes are copied into: the first `limit` of them are
+// hashed and the rest thrown away
+pub struct Sampler {
+    hash: Fnv,
+    sampled: usize,
+    limit: usize,
+}
+
+impl Sampler {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            hash: Fnv(Fnv::OFFSET),
+            sampled: 0,
+            limit,
+        }
+    }
+
+    pub fn sampled(&self) -> usize {
+        self.sampled
+    }
+
+    pub fn finish(&self, truncated: bool) -> ContentSample {
+        ContentSample {
+            digest: format!("{}:{:016x}", self.sampled, self.hash.0),
+            truncated,
+        }
+    }
+}
+
+impl std::io::Write for Sampler {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let wanted = self.limit.saturating_sub(self.sampled).min(buf.len());
+        self.hash.update(&buf[..wanted]);
+        self.sampled += wanted;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// One response: the digest of its first bytes (prefixed with how many were
+// hashed), and whether it ended before all the bytes asked for arrived
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContentSample {
+    pub digest: String,
+    pub truncated: bool,
+}
+
+// Where a phase's workers put their samples
+#[derive(Clone, Default)]
+pub struct ContentSamples(Arc<Mutex<Vec<ContentSample>>>);
+
+impl ContentSamples {
+    pub fn record(&self, sample: ContentSample) {
+        if let Ok(mut samples) = self.0.lock() {
+            samples.push(sample);
+        }
+    }
+
+    pub fn take(&self) -> Vec<ContentSample> {
+        self.0
+            .lock()
+            .map(|mut samples| std::mem::take(&mut *samples))
+            .unwrap_or_default()
+    }
+}
+
+// What --verify-content found in a run's downloads
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ContentReport {
+    pub samples: usize,
+    // what most responses hashed to
+    pub digest: String,
+    // responses that hashed to something else
+    pub mismatched: usize,
+    // responses cut short
+    pub truncated: usize,
+    // the last run that verified content saw a different digest
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub changed_since_last: bool,
+}
+
+impl ContentReport {
+    pub fn from_samples(samples: &[ContentSample]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for sample in samples.iter().filter(|sample| !sample.truncated) {
+            *counts.entry(&sample.digest).or_default() += 1;
+        }
+        // ties go to the smaller digest, so the report doesn't change from
+        // one call to the next
+        let (digest, count) = counts
+            .into_iter()
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(a.0)))
+            .unwrap_or_default();
+        let truncated = samples.iter().filter(|sample| sample.truncated).count();
+
+        Some(Self {
+            samples: samples.len(),
+            digest: digest.to_owned(),
+            mismatched: samples.len() - truncated - count,
+            truncated,
+            changed_since_last: false,
+        })
+    }
+
+    pub fn compare_with(&mut self, previous: Option<&str>) {
+        self.changed_since_last =
+            !self.digest.is_empty() && previous.is_some_and(|previous| previous != self.digest);
+    }
+
+    pub fn is_anomalous(&self) -> bool {
+        self.mismatched > 0 || self.truncated > 0 || self.changed_since_last
+    }
+
+    pub fn describe(&self) -> String {
+        if !self.is_anomalous() {
+            return format!("{} responses, all as expected", self.samples);
+        }
+
+        let mut problems = vec![];
+        if self.mismatched > 0 {
+            problems.push(format!("{} differed from the rest", self.mismatched));
+        }
+        if self.truncated > 0 {
+            problems.push(format!("{} cut short", self.truncated));
+        }
+        if self.changed_since_last {
+            problems.push("content changed since the last run".to_owned());
+        }
+        format!(
+            "{} responses, {} - something may be tampering with downloads",
+            self.samples,
+            problems.join(", ")
+        )
+    }
+}
@@ -0,0 +1,187 @@
+/* --jumbo: downloads bigger than one request to Cloudflare asks for, for
+   5-10 Gbit/s links. Between one request's last byte and the next one's
+   first, a thread's connection sits idle for a round trip, and at those
+   speeds that gap caps what the test can measure. A jumbo download is
+   asked for in pieces of at most PIECE_BYTES on one connection, with
+   HTTP/1.1 pipelining: the next piece's request goes out as soon as the
+   current one's response starts arriving, so a response is always queued
+   behind the one being read and the connection never goes quiet. Only
+   plain HTTP/1.1 with a Content-Length is spoken, which both Cloudflare
+   and a server behind --download-url answer with.
+*/
+use crate::net::Stream;
+use crate::sockets::SocketTracker;
+use crate::throughput::{Counters, Direction, StreamTarget};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::atomic::Ordering;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// The most one request asks for, as much as Cloudflare sends in one go
+pub static PIECE_BYTES: usize = 100 * 1024 * 1024;
+// How many requests are sent ahead of the response being read
+static PIPELINE_DEPTH: usize = 2;
+static READ_BUFFER_BYTES: usize = 256 * 1024;
+
+// The sizes a download of `bytes` is asked for in, the last one what's left
+pub fn pieces(bytes: usize) -> Vec<usize> {
+    let mut pieces = vec![PIECE_BYTES; bytes / PIECE_BYTES];
+    if !bytes.is_multiple_of(PIECE_BYTES) {
+        pieces.push(bytes % PIECE_BYTES);
+    }
+
+    pieces
+}
+
+// Where a download goes, from a URL template with {bytes} in it
+pub struct JumboDownload {
+    pub template: String,
+}
+
+impl JumboDownload {
+    fn url(&self, bytes: usize) -> String {
+        self.template.replace("{bytes}", &bytes.to_string())
+    }
+
+    fn transfer(&self, bytes: usize, counters: &Counters) -> Result<()> {
+        let uri: tungstenite::http::Uri = self.url(PIECE_BYTES).parse()?;
+        let host = uri.host().ok_or("the download URL has no host")?;
+        let tls = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => return Err("--jumbo needs an http:// or https:// download URL".into()),
+        };
+        let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+
+        let stream = Stream::open(host, port, tls, &counters.connect)?;
+        let mut tracker = SocketTracker::new(counters.sockets.clone(), stream.socket());
+        let mut stream = BufReader::with_capacity(READ_BUFFER_BYTES, stream);
+        let result = self.pipeline(&mut stream, &mut tracker, bytes, counters);
+        tracker.finish(stream.get_ref().socket());
+
+        result
+    }
+
+    fn pipeline(
+        &self,
+        stream: &mut BufReader<Stream>,
+        tracker: &mut SocketTracker,
+        bytes: usize,
+        counters: &Counters,
+    ) -> Result<()> {
+        let pieces = pieces(bytes);
+        let mut sent = 0;
+
+        for &piece in pieces.iter().take(PIPELINE_DEPTH) {
+            self.send(stream, tracker, piece)?;
+            sent += 1;
+        }
+        for _ in 0..pieces.len() {
+            let mut remaining = read_head(stream)?;
+            // the response is coming, queue the next request behind it
+            if sent < pieces.len() {
+                self.send(stream, tracker, pieces[sent])?;
+                sent += 1;
+            }
+
+            while remaining > 0 {
+                if counters.exit_signal.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+
+                let mut chunk = stream.fill_buf()?.len().min(remaining);
+                if chunk == 0 {
+                    return Err("the server closed the connection mid-response".into());
+                }
+                if let Some(pacer) = &counters.pacer {
+                    chunk = chunk.min(pacer.max_chunk());
+                    pacer.take(chunk);
+                }
+                stream.consume(chunk);
+                remaining -= chunk;
+                tracker.bytes_received += chunk as u64;
+                counters.add_bytes(chunk);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send(
+        &self,
+        stream: &mut BufReader<Stream>,
+        tracker: &mut SocketTracker,
+        piece: usize,
+    ) -> Result<()> {
+        let request = request(&self.url(piece).parse()?)?;
+        stream.get_mut().write_all(request.as_bytes())?;
+        stream.get_mut().flush()?;
+        tracker.bytes_sent += request.len() as u64;
+
+        Ok(())
+    }
+}
+
+impl StreamTarget for JumboDownload {
+    fn direction(&self) -> Direction {
+        Direction::Download
+    }
+
+    // a failed request is noted and tried again, as a plain download's is
+    fn stream(&self, bytes: usize, counters: &Counters) -> Result<()> {
+        if let Err(err) = self.transfer(bytes, counters) {
+            counters
+                .events
+                .record("request_failed", None, Some(err.to_string()));
+        }
+
+        Ok(())
+    }
+}
+
+fn request(uri: &tungstenite::http::Uri) -> Result<String> {
+    let host = uri.host().ok_or("the download URL has no host")?;
+    let host = match uri.port_u16() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_owned(),
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+
+    Ok(format!(
+        "GET {path} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: {}\r\nAccept-Encoding: identity\r\n\r\n",
+        crate::OUR_USER_AGENT
+    ))
+}
+
+// Read a response's status line and headers, returning its Content-Length
+pub fn read_head(stream: &mut impl BufRead) -> Result<usize> {
+    let mut status = String::new();
+    if stream.read_line(&mut status)? == 0 {
+        return Err("the server closed the connection".into());
+    }
+    let code = status.split_whitespace().nth(1).unwrap_or_default();
+    if code != "200" {
+        return Err(format!("the server answered {}", status.trim()).into());
+    }
+
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line)? == 0 {
+            return Err("the server closed the connection in the headers".into());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = Some(value.trim().parse()?);
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                return Err("--jumbo needs a server that sends a Content-Length".into());
+            }
+        }
+    }
+
+    length.ok_or_else(|| "--jumbo needs a server that sends a Content-Length".into())
+}
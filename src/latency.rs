@@ -0,0 +1,576 @@
+use crate::net::ConnectOptions;
+use crate::throughput::EventLog;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// Time between the end of one probe and the start of the next
+static PROBE_INTERVAL_MILLIS: u64 = 200;
+
+// One probe on a brand new connection, timing each step of setting it up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ForeignProbe {
+    pub tcp: Duration,
+    pub tls: Duration,
+    // request sent to first byte of the response
+    pub http: Duration,
+}
+
+// Connect to the test server from scratch, timing the TCP connect, the TLS
+// handshake and an HTTP request separately (which ureq can't do for us)
+fn foreign_probe(connect: &ConnectOptions) -> std::io::Result<ForeignProbe> {
+    probe_addr(connect, test_server_addr(connect)?)
+}
+
+// The first address of the test server
+fn test_server_addr(connect: &ConnectOptions) -> std::io::Result<std::net::SocketAddr> {
+    let host = crate::CLOUDFLARE_SPEEDTEST_HOST;
+    connect
+        .resolve(&format!("{host}:443"))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{host} has no addresses"),
+            )
+        })
+}
+
+// A new TLS connection to the test server, with how long the TCP connect
+// and then the handshake took
+fn handshake(
+    connect: &ConnectOptions,
+    addr: std::net::SocketAddr,
+) -> std::io::Result<(
+    std::net::TcpStream,
+    rustls::ClientConnection,
+    Duration,
+    Duration,
+)> {
+    let host = crate::CLOUDFLARE_SPEEDTEST_HOST;
+    let start = Instant::now();
+    let mut socket = connect.connect(addr)?;
+    let tcp = start.elapsed();
+
+    let server_name = rustls::ServerName::try_from(host)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let mut conn = rustls::ClientConnection::new(crate::tls::client_config(), server_name)
+        .map_err(std::io::Error::other)?;
+
+    let start = Instant::now();
+    while conn.is_handshaking() {
+        conn.complete_io(&mut socket)?;
+    }
+
+    Ok((socket, conn, tcp, start.elapsed()))
+}
+
+// The same, to an address of the test server that's already been resolved
+pub fn probe_addr(
+    connect: &ConnectOptions,
+    addr: std::net::SocketAddr,
+) -> std::io::Result<ForeignProbe> {
+    let host = crate::CLOUDFLARE_SPEEDTEST_HOST;
+    let (mut socket, mut conn, tcp, tls) = handshake(connect, addr)?;
+
+    let mut stream = rustls::Stream::new(&mut conn, &mut socket);
+    let start = Instant::now();
+    write!(
+        stream,
+        "GET /cdn-cgi/trace HTTP/1.1\r\nHost: {host}\r\nUser-Agent: {}\r\nConnection: close\r\n\r\n",
+        crate::OUR_USER_AGENT
+    )?;
+    stream.flush()?;
+    stream.read_exact(&mut [0; 1])?;
+    let http = start.elapsed();
+
+    Ok(ForeignProbe { tcp, tls, http })
+}
+
+// Everything a probe measured over one phase
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProbeSamples {
+    // round trips on the probe's keep-alive connection
+    pub http: Vec<Duration>,
+    // fresh connections, only made for --rpm
+    pub foreign: Vec<ForeignProbe>,
+}
+
+/* Measures HTTP round trips to the same endpoint as the idle latency test,
+   on its own keep-alive connection, for as long as a phase is loading the
+   link. With `foreign` set it also sets up a new connection after each
+   round trip, for RPM. Probes that fail are left out rather than counted as slow.
+   With `spikes`, a rolling capture runs alongside and each round trip is
+   checked for a spike worth keeping it for.
+*/
+pub struct LoadedLatencyProbe {
+    stop: Arc<AtomicBool>,
+    samples: Arc<Mutex<ProbeSamples>>,
+    handle: JoinHandle<()>,
+}
+
+impl LoadedLatencyProbe {
+    pub fn start(
+        agent: ureq::Agent,
+        foreign: Option<ConnectOptions>,
+        spikes: Option<(crate::capture::SpikeCapture, EventLog)>,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_clone = Arc::clone(&stop);
+        let samples = Arc::new(Mutex::new(ProbeSamples::default()));
+        let samples_clone = Arc::clone(&samples);
+
+        let handle = std::thread::spawn(move || {
+            let mut ring = spikes.and_then(|(capture, events)| capture.start(&events));
+            while !stop_clone.load(Ordering::Relaxed) {
+                let start = Instant::now();
+                let response = agent
+                    .get(crate::CLOUDFLARE_SPEEDTEST_CGI_URL)
+                    .set("User-Agent", crate::OUR_USER_AGENT)
+                    .call();
+
+                match response.map(|response| response.into_string()) {
+                    Ok(Ok(_)) => {
+                        let round_trip = start.elapsed();
+                        if let Some(ring) = &mut ring {
+                            ring.check(round_trip);
+                        }
+                        samples_clone.lock().unwrap().http.push(round_trip);
+                    }
+                    Ok(Err(err)) => tracing::debug!("Latency probe failed: {err}"),
+                    Err(err) => tracing::debug!("Latency probe failed: {err}"),
+                }
+
+                if let Some(connect) = &foreign {
+                    if let Ok(probe) = foreign_probe(connect) {
+                        samples_clone.lock().unwrap().foreign.push(probe);
+                    }
+                }
+
+                std::thread::sleep(Duration::from_millis(PROBE_INTERVAL_MILLIS));
+            }
+            if let Some(ring) = ring {
+                ring.finish();
+            }
+        });
+
+        Self {
+            stop,
+            samples,
+            handle,
+        }
+    }
+
+    // The round trips measured so far, after the first `seen` of them
+    pub fn round_trips_after(&self, seen: usize) -> Vec<Duration> {
+        self.samples.lock().unwrap().http[seen..].to_vec()
+    }
+
+    // Stop probing and return every round trip measured
+    pub fn stop(self) -> ProbeSamples {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.handle.join();
+        std::mem::take(&mut *self.samples.lock().unwrap())
+    }
+}
+
+// Mean of the fastest 95%, so a few stragglers don't dominate
+fn trimmed_mean_ms(samples: impl Iterator<Item = Duration>) -> Option<f64> {
+    let mut millis: Vec<f64> = samples.map(|d| d.as_secs_f64() * 1000.0).collect();
+    if millis.is_empty() {
+        return None;
+    }
+
+    millis.sort_by(f64::total_cmp);
+    millis.truncate(((millis.len() as f64 * 0.95).ceil() as usize).max(1));
+    Some(millis.iter().sum::<f64>() / millis.len() as f64)
+}
+
+/* Responsiveness in round trips per minute, after the IETF draft
+   "Responsiveness under Working Conditions" (Apple's RPM):
+
+     RPM = 60000 / (1/2 * mean(foreign TCP, TLS, HTTP) + 1/2 * self HTTP)
+
+   using trimmed means in milliseconds. The draft's self probes ride on the
+   load-generating connections; ureq won't share those, so ours use the
+   probe's own keep-alive connection, which queues behind the same load.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Responsiveness {
+    pub rpm: f64,
+    pub foreign_probes: usize,
+    pub foreign_tcp_ms: f64,
+    pub foreign_tls_ms: f64,
+    pub foreign_http_ms: f64,
+    pub self_http_ms: f64,
+}
+
+impl Responsiveness {
+    pub fn from_probes(samples: &ProbeSamples) -> Option<Self> {
+        let foreign_tcp_ms = trimmed_mean_ms(samples.foreign.iter().map(|p| p.tcp))?;
+        let foreign_tls_ms = trimmed_mean_ms(samples.foreign.iter().map(|p| p.tls))?;
+        let foreign_http_ms = trimmed_mean_ms(samples.foreign.iter().map(|p| p.http))?;
+        let self_http_ms = trimmed_mean_ms(samples.http.iter().copied())?;
+
+        let foreign_ms = (foreign_tcp_ms + foreign_tls_ms + foreign_http_ms) / 3.0;
+        let working_latency_ms = foreign_ms / 2.0 + self_http_ms / 2.0;
+
+        Some(Self {
+            rpm: 60_000.0 / working_latency_ms.max(f64::EPSILON),
+            foreign_probes: samples.foreign.len(),
+            foreign_tcp_ms,
+            foreign_tls_ms,
+            foreign_http_ms,
+            self_http_ms,
+        })
+    }
+}
+
+// Nearest-rank percentile of already sorted values
+fn percentile(sorted: &[f64], percentile: u8) -> f64 {
+    let rank = (f64::from(percentile) / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+// The idle latency test's round trips, for judging call quality as well as
+// distance: latency_ms is the minimum, jitter what a VoIP call would notice
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IdleLatency {
+    pub samples: usize,
+    pub min_ms: f64,
+    pub median_ms: f64,
+    pub max_ms: f64,
+    // mean absolute difference between consecutive round trips
+    pub jitter_ms: f64,
+    // what was timed, see --latency-method
+    #[serde(default)]
+    pub method: LatencyMethod,
+    // the same with the other methods asked for, to compare
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub others: Vec<IdleLatency>,
+    // why one of the others couldn't be measured, its figures then all zero
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl IdleLatency {
+    // `samples` in the order they were taken
+    pub fn summarize(samples: &[Duration]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let millis: Vec<f64> = samples.iter().map(|d| d.as_secs_f64() * 1000.0).collect();
+        let jitter_ms = if millis.len() < 2 {
+            0.0
+        } else {
+            millis.windows(2).map(|w| (w[1] - w[0]).abs()).sum::<f64>() / (millis.len() - 1) as f64
+        };
+        let mut sorted = millis;
+        sorted.sort_by(f64::total_cmp);
+
+        Some(Self {
+            samples: sorted.len(),
+            min_ms: sorted[0],
+            median_ms: percentile(&sorted, 50),
+            max_ms: sorted[sorted.len() - 1],
+            jitter_ms,
+            method: LatencyMethod::Http,
+            others: vec![],
+            error: None,
+        })
+    }
+
+    // A method compared that couldn't be measured, and why
+    pub fn unavailable(method: LatencyMethod, error: String) -> Self {
+        Self {
+            samples: 0,
+            min_ms: 0.0,
+            median_ms: 0.0,
+            max_ms: 0.0,
+            jitter_ms: 0.0,
+            method,
+            others: vec![],
+            error: Some(error),
+        }
+    }
+
+    pub fn min(&self) -> Duration {
+        Duration::from_secs_f64(self.min_ms / 1000.0)
+    }
+}
+
+/* --latency-method: what a round trip of the idle latency test is. They
+   differ by tens of milliseconds on the same link: an HTTP request waits
+   on the server as well as the network, a TCP connect is answered by the
+   kernel at the edge, and a TLS or QUIC handshake adds the server's
+   cryptography. http is the default and works with every provider, the
+   others time connections to Cloudflare.
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LatencyMethod {
+    // requests on one keep-alive connection
+    #[default]
+    Http,
+    // setting up a TCP connection, the SYN and SYN-ACK
+    Tcp,
+    // a TLS handshake on a connection that's already up
+    Tls,
+    // setting up a QUIC connection, handshake and all
+    Quic,
+}
+
+impl LatencyMethod {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Http => "http",
+            Self::Tcp => "tcp",
+            Self::Tls => "tls",
+            Self::Quic => "quic",
+        }
+    }
+}
+
+impl std::str::FromStr for LatencyMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(Self::Http),
+            "tcp" => Ok(Self::Tcp),
+            "tls" => Ok(Self::Tls),
+            "quic" => Ok(Self::Quic),
+            _ => Err(format!(
+                "unknown --latency-method '{s}', expected http, tcp, tls or quic"
+            )),
+        }
+    }
+}
+
+// One way of timing a round trip to the test server
+pub trait LatencyProbe {
+    fn method(&self) -> LatencyMethod;
+
+    fn round_trip(&mut self) -> std::io::Result<Duration>;
+}
+
+// GETs of `url` on one keep-alive connection, the first setting it up
+pub struct HttpProbe<'a> {
+    pub agent: &'a ureq::Agent,
+    pub url: String,
+}
+
+impl LatencyProbe for HttpProbe<'_> {
+    fn method(&self) -> LatencyMethod {
+        LatencyMethod::Http
+    }
+
+    fn round_trip(&mut self) -> std::io::Result<Duration> {
+        let start = Instant::now();
+        self.agent
+            .get(&self.url)
+            .call()
+            .map_err(std::io::Error::other)?
+            .into_string()?;
+
+        Ok(start.elapsed())
+    }
+}
+
+// A new connection to the test server each time, looked up once
+pub struct TcpProbe {
+    pub connect: ConnectOptions,
+    addr: Option<std::net::SocketAddr>,
+}
+
+impl TcpProbe {
+    pub fn new(connect: &ConnectOptions) -> Self {
+        Self {
+            connect: connect.clone(),
+            addr: None,
+        }
+    }
+
+    fn addr(&mut self) -> std::io::Result<std::net::SocketAddr> {
+        match self.addr {
+            Some(addr) => Ok(addr),
+            None => Ok(*self.addr.insert(test_server_addr(&self.connect)?)),
+        }
+    }
+}
+
+impl LatencyProbe for TcpProbe {
+    fn method(&self) -> LatencyMethod {
+        LatencyMethod::Tcp
+    }
+
+    fn round_trip(&mut self) -> std::io::Result<Duration> {
+        let addr = self.addr()?;
+        let start = Instant::now();
+        self.connect.connect(addr)?;
+
+        Ok(start.elapsed())
+    }
+}
+
+// A TLS handshake on each new connection, the TCP connect before it not
+// counted
+pub struct TlsProbe(pub TcpProbe);
+
+impl LatencyProbe for TlsProbe {
+    fn method(&self) -> LatencyMethod {
+        LatencyMethod::Tls
+    }
+
+    fn round_trip(&mut self) -> std::io::Result<Duration> {
+        let addr = self.0.addr()?;
+        let (_, _, _, tls) = handshake(&self.0.connect, addr)?;
+
+        Ok(tls)
+    }
+}
+
+// Up to LATENCY_TEST_COUNT round trips with `probe`, the fastest being the
+// latency and the rest telling us about jitter
+pub fn measure(probe: &mut dyn LatencyProbe) -> Result<IdleLatency> {
+    let start = Instant::now();
+    let mut samples = Vec::new();
+
+    for _ in 0..crate::LATENCY_TEST_COUNT {
+        // if we have two or more and we've spent a lot of time calculating
+        // latency, exit early (we could be on satellite or sumthin)
+        if samples.len() >= 2 && start.elapsed() > Duration::from_secs(1) {
+            break;
+        }
+        samples.push(probe.round_trip()?);
+    }
+
+    let latency = IdleLatency::summarize(&samples).ok_or("no latency samples")?;
+    Ok(IdleLatency {
+        method: probe.method(),
+        ..latency
+    })
+}
+
+// The probe for `method`, which isn't http (that's up to the provider)
+fn connection_probe(
+    method: LatencyMethod,
+    connect: &ConnectOptions,
+) -> Result<Box<dyn LatencyProbe>> {
+    match method {
+        LatencyMethod::Http => Err("HTTP latency is the provider's to measure".into()),
+        LatencyMethod::Tcp => Ok(Box::new(TcpProbe::new(connect))),
+        LatencyMethod::Tls => Ok(Box::new(TlsProbe(TcpProbe::new(connect)))),
+        #[cfg(feature = "http3")]
+        LatencyMethod::Quic => Ok(Box::new(crate::http3::QuicProbe::new(connect)?)),
+        #[cfg(not(feature = "http3"))]
+        LatencyMethod::Quic => {
+            Err("--latency-method quic needs cf_speedtest built with the http3 feature".into())
+        }
+    }
+}
+
+// The idle latency with each of `methods`, the first the headline and the
+// rest for comparison. HTTP goes to the provider, see `Provider::idle_latency`.
+// Only the headline has to be measured, a method compared that can't be
+// (QUIC blocked by a firewall, say) is recorded as unavailable.
+pub fn idle(
+    methods: &[LatencyMethod],
+    provider: &dyn crate::provider::Provider,
+    agent: &ureq::Agent,
+    connect: &ConnectOptions,
+) -> Result<IdleLatency> {
+    let idle_with = |method: LatencyMethod| match method {
+        LatencyMethod::Http => provider.idle_latency(agent),
+        _ => measure(connection_probe(method, connect)?.as_mut()),
+    };
+
+    let headline = match methods.first() {
+        Some(&method) => idle_with(method)?,
+        None => provider.idle_latency(agent)?,
+    };
+    let others = methods
+        .iter()
+        .skip(1)
+        .map(|&method| {
+            idle_with(method).unwrap_or_else(|err| {
+                tracing::debug!("{} latency: {err}", method.name());
+                IdleLatency::unavailable(method, err.to_string())
+            })
+        })
+        .collect();
+
+    Ok(IdleLatency { others, ..headline })
+}
+
+// Latency while a phase was running, compared to latency on an idle link
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LoadedLatency {
+    pub samples: usize,
+    pub median_ms: f64,
+    // which percentile `percentile_ms` is, set by --latency-percentile
+    pub percentile: u8,
+    pub percentile_ms: f64,
+    pub idle_ms: f64,
+    // median under load minus idle, and median under load over idle
+    pub delta_ms: f64,
+    pub multiplier: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub responsiveness: Option<Responsiveness>,
+}
+
+impl LoadedLatency {
+    pub fn summarize(samples: &[Duration], idle: Duration, percentile_wanted: u8) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let mut millis: Vec<f64> = samples.iter().map(|d| d.as_secs_f64() * 1000.0).collect();
+        millis.sort_by(f64::total_cmp);
+
+        let median_ms = percentile(&millis, 50);
+        let idle_ms = idle.as_secs_f64() * 1000.0;
+
+        Some(Self {
+            samples: millis.len(),
+            median_ms,
+            percentile: percentile_wanted,
+            percentile_ms: percentile(&millis, percentile_wanted),
+            idle_ms,
+            delta_ms: median_ms - idle_ms,
+            multiplier: if idle_ms > 0.0 {
+                median_ms / idle_ms
+            } else {
+                1.0
+            },
+            responsiveness: None,
+        })
+    }
+
+    pub fn with_responsiveness(self, responsiveness: Option<Responsiveness>) -> Self {
+        Self {
+            responsiveness,
+            ..self
+        }
+    }
+
+    // e.g. "45.00ms median, 80.00ms p95 (+30.00ms under load, 3.0x idle), 950 RPM"
+    pub fn describe(&self) -> String {
+        let mut description = format!(
+            "{:.2}ms median, {:.2}ms p{} ({:+.2}ms under load, {:.1}x idle)",
+            self.median_ms, self.percentile_ms, self.percentile, self.delta_ms, self.multiplier
+        );
+        if let Some(responsiveness) = &self.responsiveness {
+            description += &format!(", {:.0} RPM", responsiveness.rpm);
+        }
+
+        description
+    }
+}
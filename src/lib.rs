@@ -0,0 +1,873 @@
+/* The measurement engine behind the cf_speedtest command, for embedding a
+   speed test in your own program without shelling out to it:
+
+     let run = cf_speedtest::Speedtest::default().run(&mut ())?;
+     println!("{:?}", run.download.map(|d| d.median_bps));
+
+   Implement `Observer` to follow along as the test runs.
+*/
+use latency::{LoadedLatency, ProbeSamples, Responsiveness};
+use net::ConnectOptions;
+use phases::{PhaseConfig, PhaseOrder};
+use results::{Event, PhaseSummary, RunResult};
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use throughput::{Direction, Target};
+
+pub mod assertions;
+pub mod bdp;
+pub mod bursts;
+pub mod calibrate;
+pub mod capture;
+pub mod clock;
+pub mod crash;
+pub mod cron;
+pub mod dns;
+pub mod export;
+pub mod history;
+pub mod host;
+#[cfg(feature = "http3")]
+pub mod http3;
+pub mod i18n;
+pub mod integrity;
+pub mod jumbo;
+pub mod latency;
+pub mod locations;
+pub mod mqtt;
+pub mod ndt7;
+pub mod net;
+pub mod pacing;
+pub mod phases;
+pub mod privileges;
+pub mod provider;
+pub mod resolvers;
+pub mod results;
+pub mod sinks;
+pub mod sockets;
+pub mod template;
+pub mod throughput;
+pub mod tls;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+pub static CLOUDFLARE_SPEEDTEST_HOST: &str = "speed.cloudflare.com";
+pub static CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL: &str = "https://speed.cloudflare.com/__down?measId=0";
+pub static CLOUDFLARE_SPEEDTEST_UPLOAD_URL: &str = "https://speed.cloudflare.com/__up?measId=0";
+pub static CLOUDFLARE_SPEEDTEST_SERVER_URL: &str =
+    "https://speed.cloudflare.com/__down?measId=0&bytes=0";
+pub static CLOUDFLARE_SPEEDTEST_CGI_URL: &str = "https://speed.cloudflare.com/cdn-cgi/trace";
+pub static OUR_USER_AGENT: &str = "cf_speedtest (0.4.6) https://github.com/12932/cf_speedtest";
+
+pub static LATENCY_TEST_COUNT: u8 = 8;
+// How long each burst of --order interleaved moves data one way
+static INTERLEAVED_BURST_SECONDS: u64 = 3;
+
+// Default test duration + a little bit more if we have extra threads, the
+// extra capped at `max_extension` seconds if given
+pub fn get_test_time(
+    test_duration_seconds: u64,
+    thread_count: u32,
+    max_extension: Option<u64>,
+) -> u64 {
+    if thread_count > 4 {
+        let extension = (thread_count as u64 - 4) / 4;
+        return test_duration_seconds + extension.min(max_extension.unwrap_or(u64::MAX));
+    }
+
+    test_duration_seconds
+}
+
+/* Given n bytes, return
+     a: unit of measurement in sensible form of bytes
+     b: unit of measurement in sensible form of bits
+ i.e 12939428 	-> (12.34 MB, 98.76 Mb)
+     814811 	-> (795.8 KB, 6.36 Mb)
+*/
+pub fn get_appropriate_byte_unit(bytes: u64) -> (String, String) {
+    const UNITS: [&str; 5] = [" ", "K", "M", "G", "T"];
+    const KILOBYTE: f64 = 1024.0;
+
+    let mut bytes = bytes as f64;
+    let mut level = 0;
+
+    while bytes >= KILOBYTE && level < UNITS.len() - 1 {
+        bytes /= KILOBYTE;
+        level += 1;
+    }
+
+    let byte_unit = UNITS[level];
+    let mut bits = bytes * 8.0;
+    let mut bit_unit = byte_unit.to_ascii_lowercase();
+
+    if bits >= 1000.0 {
+        bits /= 1000.0;
+        bit_unit = match byte_unit {
+            " " => "k",
+            "K" => "m",
+            "M" => "g",
+            "G" => "t",
+            "T" => "p",
+            _ => "?",
+        }
+        .to_string();
+    }
+
+    (
+        format!("{:.2} {}B", bytes, byte_unit),
+        format!("{:.2} {}b", bits, bit_unit),
+    )
+}
+
+// n bytes a second, in bytes as above and in bits as `format_bits_per_sec`
+pub fn get_appropriate_byte_unit_rate(bytes: u64) -> (String, String) {
+    let (a, _) = get_appropriate_byte_unit(bytes);
+    (format!("{}/s", a), format_bits_per_sec(bytes as f64 * 8.0))
+}
+
+// The unit a speed in bits per second reads best in, and what it's divided
+// by for it, in powers of 1000 as links are sold
+pub fn bit_rate_unit(bps: f64) -> (&'static str, f64) {
+    if bps < 1e3 {
+        ("bit/s", 1.0)
+    } else if bps < 1e6 {
+        ("kbit/s", 1e3)
+    } else if bps < 1e9 {
+        ("Mbit/s", 1e6)
+    } else {
+        ("Gbit/s", 1e9)
+    }
+}
+
+/* A speed in the unit that suits it, so a 56k modem reads as what it is
+   rather than as 0.05 Mbit/s. Every speed we show goes through here.
+     48000     -> 48.0 kbit/s
+     94210000  -> 94.21 Mbit/s
+*/
+pub fn format_bits_per_sec(bps: f64) -> String {
+    let (unit, scale) = bit_rate_unit(bps);
+    let precision = match unit {
+        "bit/s" => 0,
+        "kbit/s" => 1,
+        _ => 2,
+    };
+    format!("{:.precision$} {unit}", bps / scale)
+}
+
+// Everything cloudflare's cdn-cgi endpoint knows about us (ip=, loc=, colo=, ...)
+pub fn get_cdn_cgi_trace(agent: &ureq::Agent) -> Result<std::collections::HashMap<String, String>> {
+    let resp = agent.get(CLOUDFLARE_SPEEDTEST_CGI_URL).call()?;
+    let mut body = String::new();
+    resp.into_reader().read_to_string(&mut body)?;
+
+    Ok(body
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect())
+}
+
+pub fn get_country_from_trace(trace: &std::collections::HashMap<String, String>) -> Result<String> {
+    trace.get("loc").cloned().ok_or_else(|| {
+        Box::new(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Could not find loc= in cdn-cgi response. \
+            Please update to the latest version and make a Github issue if the issue persists",
+        )) as Box<dyn std::error::Error>
+    })
+}
+
+// Get http latency by requesting the cgi endpoint 8 times, the fastest
+// being the latency and the rest telling us about jitter
+pub fn get_download_server_http_latency(my_agent: &ureq::Agent) -> Result<latency::IdleLatency> {
+    get_http_latency(my_agent, CLOUDFLARE_SPEEDTEST_CGI_URL)
+}
+
+// The same against any URL that answers quickly with next to nothing
+pub fn get_http_latency(my_agent: &ureq::Agent, url: &str) -> Result<latency::IdleLatency> {
+    latency::measure(&mut latency::HttpProbe {
+        agent: my_agent,
+        url: url.to_owned(),
+    })
+}
+
+// return all cloufdlare headers from a request
+pub fn get_download_server_info(
+    agent: &ureq::Agent,
+) -> Result<std::collections::HashMap<String, String>> {
+    let mut server_headers = std::collections::HashMap::new();
+    let resp = agent.get(CLOUDFLARE_SPEEDTEST_SERVER_URL).call()?;
+
+    for key in resp.headers_names() {
+        if key.starts_with("cf-") {
+            server_headers.insert(key.clone(), resp.header(&key).unwrap().to_string());
+        }
+    }
+
+    Ok(server_headers)
+}
+
+// An agent for everything but the transfers, connecting the way they do
+pub fn build_agent(connect: &ConnectOptions) -> ureq::Agent {
+    let connector = tls::InterceptingTlsConnector::new().via(connect.clone());
+
+    let mut builder = ureq::AgentBuilder::new()
+        .resolver(connect.resolver())
+        .tls_connector(Arc::new(connector));
+    if let Some(proxy) = &connect.proxy {
+        builder = builder.proxy(proxy.ureq());
+    }
+
+    builder.build()
+}
+
+// The middle of `values`, or the mean of the middle two, None if there
+// aren't any
+pub fn median(mut values: Vec<f64>) -> Option<f64> {
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    match values.len() {
+        0 => None,
+        len if len.is_multiple_of(2) => Some((values[mid - 1] + values[mid]) / 2.0),
+        _ => Some(values[mid]),
+    }
+}
+
+pub fn compute_statistics(data: &mut [usize]) -> (f64, f64, usize, usize, usize, usize) {
+    if data.is_empty() {
+        return (0f64, 0f64, 0, 0, 0, 0);
+    }
+
+    data.sort();
+
+    // summed wider than the samples, a long phase of big ones would
+    // overflow a usize
+    let len = data.len();
+    let sum: u128 = data.iter().map(|&bytes| bytes as u128).sum();
+    let average = sum as f64 / len as f64;
+
+    let median = median(data.iter().map(|&bytes| bytes as f64).collect()).unwrap_or_default();
+
+    let p90_index = (0.90 * len as f64).ceil() as usize - 1;
+    let p99_index = (0.99 * len as f64).ceil() as usize - 1;
+
+    let min = data[0];
+    let max = *data.last().unwrap();
+
+    (median, average, data[p90_index], data[p99_index], min, max)
+}
+
+// Summarise per-second byte counts as bit rates, None if the phase didn't run
+pub fn summarize_phase(data: &mut [usize]) -> Option<PhaseSummary> {
+    if data.is_empty() {
+        return None;
+    }
+
+    // before computing the statistics sorts them
+    let samples = data.to_vec();
+    let (median, average, p90, _, min, max) = compute_statistics(data);
+
+    Some(PhaseSummary {
+        median_bps: median * 8.0,
+        average_bps: average * 8.0,
+        p90_bps: p90 as f64 * 8.0,
+        min_bps: min as f64 * 8.0,
+        max_bps: max as f64 * 8.0,
+        saturated_threads: None,
+        saturation_gain_percent: None,
+        warmup_seconds: None,
+        threads: None,
+        samples,
+    })
+}
+
+// The same leaving out the first `warmup` seconds, while TCP slow start
+// and the staggered threads were getting up to speed, which stay in its
+// samples. A phase no longer than that is summarized whole.
+pub fn summarize_after_warmup(measurements: &[usize], warmup: usize) -> Option<PhaseSummary> {
+    if warmup == 0 || warmup >= measurements.len() {
+        return summarize_phase(&mut measurements.to_vec());
+    }
+
+    summarize_phase(&mut measurements[warmup..].to_vec()).map(|summary| PhaseSummary {
+        samples: measurements.to_vec(),
+        warmup_seconds: Some(warmup),
+        ..summary
+    })
+}
+
+// What a running test reports as it goes, e.g. to show progress. Every
+// method does nothing unless implemented, and () ignores everything.
+pub trait Observer {
+    fn phase_started(&mut self, _direction: Direction) {}
+
+    // Once per second of a phase with how many bytes moved in it
+    fn sample(&mut self, _direction: Direction, _second: usize, _bytes: usize) {}
+
+    fn event(&mut self, _event: &Event) {}
+
+    // Each round trip the loaded latency probe measures, as they come in
+    // with the samples
+    fn latency(&mut self, _direction: Direction, _round_trip: Duration) {}
+
+    // The events that are about one worker, again and typed
+    fn worker(&mut self, _direction: Direction, _event: &throughput::WorkerEvent) {}
+
+    // The phase found the link too slow for the time it had, and runs for
+    // `seconds` in all instead, see `throughput::slow_link_seconds`
+    fn phase_extended(&mut self, _direction: Direction, _seconds: u64) {}
+
+    // The deadline has passed, and we're waiting for requests in flight
+    fn waiting_for_threads(&mut self, _direction: Direction) {}
+
+    fn phase_finished(&mut self, _direction: Direction, _samples: &[usize]) {}
+}
+
+impl Observer for () {}
+
+// One burst of an interleaved phase, its seconds numbered on from where
+// the direction's last burst left off. `run_interleaved` starts and
+// finishes each direction's phase once, not once a burst.
+struct Burst<'a> {
+    observer: &'a mut dyn Observer,
+    first_second: usize,
+}
+
+impl Observer for Burst<'_> {
+    fn phase_started(&mut self, _direction: Direction) {}
+
+    fn sample(&mut self, direction: Direction, second: usize, bytes: usize) {
+        self.observer
+            .sample(direction, self.first_second + second, bytes);
+    }
+
+    fn event(&mut self, event: &Event) {
+        self.observer.event(event);
+    }
+
+    fn latency(&mut self, direction: Direction, round_trip: Duration) {
+        self.observer.latency(direction, round_trip);
+    }
+
+    fn worker(&mut self, direction: Direction, event: &throughput::WorkerEvent) {
+        self.observer.worker(direction, event);
+    }
+
+    fn phase_extended(&mut self, direction: Direction, seconds: u64) {
+        self.observer.phase_extended(direction, seconds);
+    }
+
+    fn waiting_for_threads(&mut self, direction: Direction) {
+        self.observer.waiting_for_threads(direction);
+    }
+
+    fn phase_finished(&mut self, _direction: Direction, _samples: &[usize]) {}
+}
+
+/* Where a running test has got to, to poll from another thread while
+   `Speedtest::run` blocks this one:
+
+     let metrics = speedtest.metrics.clone();
+     std::thread::spawn(move || speedtest.run(&mut ()));
+     if let Some(now) = metrics.snapshot() { ... }
+
+   Clones share what they see, like the test they came from.
+*/
+#[derive(Clone, Default)]
+pub struct Metrics {
+    phase: Arc<Mutex<Option<throughput::PhaseMonitor>>>,
+}
+
+impl Metrics {
+    // None between phases
+    pub fn snapshot(&self) -> Option<throughput::MetricsSnapshot> {
+        self.phase
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(throughput::PhaseMonitor::snapshot)
+    }
+
+    fn watch(&self, phase: Option<throughput::PhaseMonitor>) {
+        *self.phase.lock().unwrap() = phase;
+    }
+}
+
+impl std::fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Metrics")
+            .field("snapshot", &self.snapshot())
+            .finish()
+    }
+}
+
+/* Stops a running test early, e.g. on Ctrl+C: the phase in progress ends at
+   its next sample as if its time were up, and the phases after it don't
+   run, so what was measured can still be reported. Clones trigger the same
+   test, like `Metrics`.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+    pub fn trigger(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+fn report_event(observer: &mut dyn Observer, direction: Direction, event: &Event) {
+    observer.event(event);
+    if let Some(worker) = throughput::WorkerEvent::from_event(event) {
+        observer.worker(direction, &worker);
+    }
+}
+
+// Everything one phase of the test measured
+pub struct PhaseOutcome {
+    pub measurements: Vec<usize>,
+    pub records: throughput::PhaseRecords,
+    pub loaded_latency: ProbeSamples,
+}
+
+impl PhaseOutcome {
+    // Add a later burst of the same phase, see `PhaseOrder::Interleaved`.
+    // Each burst ramps up afresh, so none says when the link saturated.
+    fn extend(&mut self, burst: PhaseOutcome) {
+        self.measurements.extend(burst.measurements);
+        self.records.sockets.extend(burst.records.sockets);
+        self.records.events.extend(burst.records.events);
+        self.records.content.extend(burst.records.content);
+        self.records.threads = self.records.threads.max(burst.records.threads);
+        self.records.saturation = None;
+        self.loaded_latency.http.extend(burst.loaded_latency.http);
+        self.loaded_latency
+            .foreign
+            .extend(burst.loaded_latency.foreign);
+    }
+
+    // See `summarize_after_warmup`
+    pub fn summarize(&self, warmup: usize) -> Option<PhaseSummary> {
+        summarize_after_warmup(&self.measurements, warmup).map(|summary| PhaseSummary {
+            saturated_threads: self.records.saturation.map(|s| s.threads),
+            saturation_gain_percent: self.records.saturation.map(|s| s.gain_percent),
+            threads: self.records.threads,
+            ..summary
+        })
+    }
+}
+
+// What the phases of a run measured, a RunResult without the where and when
+pub struct Measured {
+    pub download: Option<PhaseSummary>,
+    pub upload: Option<PhaseSummary>,
+    pub download_loaded_latency: Option<LoadedLatency>,
+    pub upload_loaded_latency: Option<LoadedLatency>,
+    pub sockets: Option<sockets::SocketSummary>,
+    pub content: Option<integrity::ContentReport>,
+    pub events: Vec<Event>,
+    // stopped by `Speedtest::interrupt` before every phase had run its course
+    pub interrupted: bool,
+}
+
+// How to run a test: which phases, how, and over what connection
+#[derive(Clone, Debug)]
+pub struct Speedtest {
+    // None to skip the phase
+    pub download: Option<PhaseConfig>,
+    pub upload: Option<PhaseConfig>,
+    pub connect: ConnectOptions,
+    // who the test runs against, Cloudflare unless told otherwise
+    pub provider: Arc<dyn provider::Provider>,
+    // tried in turn when `provider` can't be reached or turns us away
+    // before the phases; see `run`
+    pub fallbacks: Vec<Arc<dyn provider::Provider>>,
+    // test against these URL templates instead of the provider, see
+    // `throughput::UrlTarget`
+    pub download_url: Option<String>,
+    pub upload_url: Option<String>,
+    // HTTP/3 transfers go to Cloudflare whatever the provider, see `http3`
+    pub http_version: throughput::HttpVersion,
+    pub limit: Option<pacing::Rate>,
+    pub limit_burst: Option<usize>,
+    // also measure responsiveness (RPM) under load
+    pub rpm: bool,
+    // which percentile of loaded latency to report besides the median
+    pub latency_percentile: u8,
+    // seconds at the start of each phase left out of its figures, see
+    // `summarize_after_warmup`
+    pub warmup_seconds: usize,
+    // how idle latency is timed, the first the headline, see
+    // `latency::LatencyMethod`
+    pub latency_methods: Vec<latency::LatencyMethod>,
+    pub order: PhaseOrder,
+    // download in pipelined pieces, see `jumbo`
+    pub jumbo: bool,
+    // capture packets when latency under load spikes, see `capture`
+    pub capture_spikes: Option<capture::SpikeCapture>,
+    // how long to wait for requests in flight after each phase's deadline
+    pub join_timeout: Duration,
+    pub metrics: Metrics,
+    pub interrupt: Interrupt,
+}
+
+impl Default for Speedtest {
+    fn default() -> Self {
+        Self {
+            download: Some(PhaseConfig::default_for(Direction::Download)),
+            upload: Some(PhaseConfig::default_for(Direction::Upload)),
+            connect: ConnectOptions::default(),
+            provider: Arc::new(provider::Cloudflare),
+            fallbacks: vec![],
+            download_url: None,
+            upload_url: None,
+            http_version: throughput::HttpVersion::Http1,
+            limit: None,
+            limit_burst: None,
+            rpm: false,
+            latency_percentile: 95,
+            warmup_seconds: 0,
+            latency_methods: vec![latency::LatencyMethod::Http],
+            order: PhaseOrder::DownloadFirst,
+            jumbo: false,
+            capture_spikes: None,
+            join_timeout: Duration::from_secs(5),
+            metrics: Metrics::default(),
+            interrupt: Interrupt::default(),
+        }
+    }
+}
+
+impl Speedtest {
+    // What keeps a phase under `limit`, if there is one
+    pub fn pacer(&self) -> Option<Arc<pacing::TokenBucket>> {
+        self.limit
+            .map(|limit| Arc::new(pacing::TokenBucket::new(limit, self.limit_burst)))
+    }
+
+    // What a phase in `direction` transfers to or from
+    pub fn target(&self, direction: Direction) -> Target {
+        #[cfg(feature = "http3")]
+        if self.http_version == throughput::HttpVersion::Http3 {
+            return Target::Stream(Arc::new(http3::Http3Target { direction }));
+        }
+
+        let template = match direction {
+            Direction::Download => &self.download_url,
+            Direction::Upload => &self.upload_url,
+        };
+        if self.jumbo && direction == Direction::Download {
+            return Target::Stream(Arc::new(jumbo::JumboDownload {
+                template: template.clone().unwrap_or_else(|| {
+                    format!("{}&bytes={{bytes}}", CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL)
+                }),
+            }));
+        }
+
+        match template {
+            Some(template) => Target::Requests(Arc::new(throughput::UrlTarget {
+                direction,
+                template: template.clone(),
+            })),
+            None => self.provider.target(direction),
+        }
+    }
+
+    // A quick download to size the link up, for choosing phases that suit it
+    pub fn probe_link(&self) -> (phases::LinkClass, f64) {
+        phases::probe_link(
+            self.target(Direction::Download),
+            &self.connect,
+            self.pacer(),
+        )
+    }
+
+    // Run one phase of the test against `target`, reporting samples and
+    // events to `observer` as they come in
+    pub fn run_phase(
+        &self,
+        target: Target,
+        phase_config: &PhaseConfig,
+        observer: &mut dyn Observer,
+    ) -> PhaseOutcome {
+        let direction = target.direction();
+        let phase_config = &PhaseConfig {
+            threads: self.provider.streams().unwrap_or(phase_config.threads),
+            ..phase_config.clone()
+        };
+
+        observer.phase_started(direction);
+        let phase = throughput::Phase::start(target, phase_config, &self.connect, self.pacer())
+            .interrupted_by(&self.interrupt);
+        let probe = latency::LoadedLatencyProbe::start(
+            build_agent(&self.connect),
+            self.rpm.then(|| self.connect.clone()),
+            self.capture_spikes
+                .clone()
+                .map(|capture| (capture, phase.event_log())),
+        );
+        self.metrics.watch(Some(phase.monitor()));
+        let mut second = 0;
+        let mut seconds = phase_config.seconds;
+        let mut events_seen = 0;
+        let mut round_trips_seen = 0;
+        let measurements = phase.sample_for(phase_config.seconds, |bytes| {
+            if phase.seconds() != seconds {
+                seconds = phase.seconds();
+                observer.phase_extended(direction, seconds);
+            }
+            observer.sample(direction, second, bytes);
+            second += 1;
+
+            let round_trips = probe.round_trips_after(round_trips_seen);
+            round_trips_seen += round_trips.len();
+            for round_trip in round_trips {
+                observer.latency(direction, round_trip);
+            }
+
+            let events = phase.events();
+            for event in &events[events_seen..] {
+                report_event(observer, direction, event);
+            }
+            events_seen = events.len();
+        });
+
+        let loaded_latency = probe.stop();
+
+        observer.waiting_for_threads(direction);
+        let records = phase.join(self.join_timeout);
+        self.metrics.watch(None);
+        for event in &records.events[events_seen..] {
+            report_event(observer, direction, event);
+        }
+        observer.phase_finished(direction, &measurements);
+
+        PhaseOutcome {
+            measurements,
+            records,
+            loaded_latency,
+        }
+    }
+
+    fn phase_config(&self, direction: Direction) -> Option<&PhaseConfig> {
+        match direction {
+            Direction::Download => self.download.as_ref(),
+            Direction::Upload => self.upload.as_ref(),
+        }
+    }
+
+    // Each phase in turn, in `order`
+    fn run_sequential(&self, observer: &mut dyn Observer) -> Vec<(Direction, PhaseOutcome)> {
+        let directions = match self.order {
+            PhaseOrder::UploadFirst => [Direction::Upload, Direction::Download],
+            _ => [Direction::Download, Direction::Upload],
+        };
+
+        directions
+            .into_iter()
+            .filter_map(|direction| {
+                let phase_config = self
+                    .phase_config(direction)
+                    .filter(|_| !self.interrupt.is_triggered())?;
+                Some((
+                    direction,
+                    self.run_phase(self.target(direction), phase_config, observer),
+                ))
+            })
+            .collect()
+    }
+
+    // The phases in bursts of INTERLEAVED_BURST_SECONDS taking turns, each
+    // starting its threads at once since a burst is too short to ramp up
+    // in, until each direction has had as long as its phase
+    fn run_interleaved(&self, observer: &mut dyn Observer) -> Vec<(Direction, PhaseOutcome)> {
+        static DIRECTIONS: [Direction; 2] = [Direction::Download, Direction::Upload];
+        let mut outcomes: Vec<(Direction, PhaseOutcome)> = vec![];
+        let mut seconds_done = [0, 0];
+        let mut started = [false, false];
+        let mut finished = [false, false];
+
+        loop {
+            let mut ran = false;
+            for (i, direction) in DIRECTIONS.into_iter().enumerate() {
+                let Some(phase_config) = self.phase_config(direction) else {
+                    continue;
+                };
+                let seconds = phase_config
+                    .seconds
+                    .saturating_sub(seconds_done[i])
+                    .min(INTERLEAVED_BURST_SECONDS);
+                if seconds == 0 || self.interrupt.is_triggered() {
+                    continue;
+                }
+
+                // a burst that ran on would throw the turns out
+                let burst_config = PhaseConfig {
+                    seconds,
+                    ramp: phases::Ramp::Immediate,
+                    extend_slow_link: false,
+                    ..phase_config.clone()
+                };
+                if !started[i] {
+                    observer.phase_started(direction);
+                    started[i] = true;
+                }
+                let mut burst = Burst {
+                    observer: &mut *observer,
+                    first_second: seconds_done[i] as usize,
+                };
+                let outcome = self.run_phase(self.target(direction), &burst_config, &mut burst);
+                seconds_done[i] += seconds;
+                ran = true;
+
+                let merged = match outcomes.iter_mut().position(|(d, _)| *d == direction) {
+                    Some(at) => {
+                        outcomes[at].1.extend(outcome);
+                        &outcomes[at].1
+                    }
+                    None => {
+                        outcomes.push((direction, outcome));
+                        &outcomes[outcomes.len() - 1].1
+                    }
+                };
+                if seconds_done[i] >= phase_config.seconds {
+                    observer.phase_finished(direction, &merged.measurements);
+                    finished[i] = true;
+                }
+            }
+            if !ran {
+                // an interrupted direction still gets its end
+                for (i, direction) in DIRECTIONS.into_iter().enumerate() {
+                    let outcome = outcomes.iter().find(|(d, _)| *d == direction);
+                    if let Some((_, outcome)) = outcome.filter(|_| started[i] && !finished[i]) {
+                        observer.phase_finished(direction, &outcome.measurements);
+                    }
+                }
+                return outcomes;
+            }
+        }
+    }
+
+    // Run every phase against the provider. `idle` is the latency measured
+    // before, what loaded latency is compared to.
+    pub fn run_phases(&self, idle: Duration, observer: &mut dyn Observer) -> Measured {
+        let mut measured = Measured {
+            download: None,
+            upload: None,
+            download_loaded_latency: None,
+            upload_loaded_latency: None,
+            sockets: None,
+            content: None,
+            events: vec![],
+            interrupted: false,
+        };
+        let mut socket_records = vec![];
+
+        let outcomes = match self.order {
+            PhaseOrder::Interleaved => self.run_interleaved(observer),
+            _ => self.run_sequential(observer),
+        };
+        for (direction, outcome) in outcomes {
+            match direction {
+                Direction::Download => {
+                    measured.download = outcome.summarize(self.warmup_seconds);
+                    measured.download_loaded_latency =
+                        self.summarize_loaded_latency(&outcome, idle);
+                    measured.content =
+                        integrity::ContentReport::from_samples(&outcome.records.content);
+                }
+                Direction::Upload => {
+                    measured.upload = outcome.summarize(self.warmup_seconds);
+                    measured.upload_loaded_latency = self.summarize_loaded_latency(&outcome, idle);
+                }
+            }
+            socket_records.extend(outcome.records.sockets);
+            measured.events.extend(outcome.records.events);
+        }
+
+        measured.sockets = sockets::SocketSummary::from_records(&socket_records);
+        measured.interrupted = self.interrupt.is_triggered();
+        measured
+    }
+
+    fn summarize_loaded_latency(
+        &self,
+        outcome: &PhaseOutcome,
+        idle: Duration,
+    ) -> Option<LoadedLatency> {
+        let samples = &outcome.loaded_latency;
+        LoadedLatency::summarize(&samples.http, idle, self.latency_percentile)
+            .map(|loaded| loaded.with_responsiveness(Responsiveness::from_probes(samples)))
+    }
+
+    // The provider, then its fallbacks
+    pub fn providers(&self) -> Vec<Arc<dyn provider::Provider>> {
+        std::iter::once(&self.provider)
+            .chain(&self.fallbacks)
+            .cloned()
+            .collect()
+    }
+
+    // A whole run: where we are and how far from the first provider that
+    // answers, then every phase against it. The choice is made once, up
+    // front: a provider that fails partway through a phase isn't swapped
+    // for a fallback, as a median over two servers measures neither, and
+    // its failed requests are retried and counted as usual.
+    pub fn run(&self, observer: &mut dyn Observer) -> Result<RunResult> {
+        let timestamp = clock::now();
+        let agent = build_agent(&self.connect);
+        let mut answered = Err("no provider to test against".into());
+        for provider in self.providers() {
+            answered = provider.locate(&agent).and_then(|located| {
+                let latency = latency::idle(
+                    &self.latency_methods,
+                    provider.as_ref(),
+                    &agent,
+                    &self.connect,
+                )?;
+                Ok((provider.clone(), located, latency))
+            });
+            if answered.is_ok() {
+                break;
+            }
+        }
+        let (provider, located, latency) = answered?;
+        let speedtest = Self {
+            provider,
+            ..self.clone()
+        };
+        let measured = speedtest.run_phases(latency.min(), observer);
+
+        Ok(RunResult {
+            timestamp: timestamp.unwrap_or_else(chrono::Utc::now),
+            source: "cf_speedtest".to_owned(),
+            colo: located.colo,
+            country: located.country,
+            region: None,
+            city: None,
+            latency_ms: Some(latency.min_ms),
+            idle_latency: Some(latency),
+            download: measured.download,
+            upload: measured.upload,
+            download_loaded_latency: measured.download_loaded_latency,
+            upload_loaded_latency: measured.upload_loaded_latency,
+            via_interface: self.connect.interface.clone(),
+            server: speedtest.provider.server(),
+            sockets: measured.sockets,
+            content: measured.content,
+            events: measured.events,
+            cached: false,
+            interrupted: measured.interrupted,
+            clock_untrusted: timestamp.is_none(),
+            assertions: vec![],
+            skipped: privileges::skipped_measurements(),
+            host: None,
+            warnings: vec![],
+            resolved: None,
+        })
+    }
+}
@@ -0,0 +1,54 @@
+/* Diagnostics, as tracing events on stderr so stdout stays the results
+   alone whatever --output is. How much is shown is up to --verbose:
+
+     (none)  problems outside a test that aren't in its results, e.g. a
+             daemon that couldn't save a run
+     -v      what each phase did: saturation, deadlines, failed threads,
+             slow joins and the like, and what a daemon is doing: pausing,
+             reloading, skipping a run
+     -vv     every thread started and stopped and every request that failed
+             and was retried as well
+     -vvv    everything, with the module it came from
+*/
+use crate::results::Event;
+use tracing::level_filters::LevelFilter;
+use tracing::Level;
+
+// How much -v, -vv and so on show
+pub fn filter(verbosity: u8) -> LevelFilter {
+    match verbosity {
+        0 => LevelFilter::WARN,
+        1 => LevelFilter::INFO,
+        2 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+// Send diagnostics to stderr, once at startup
+pub fn init(verbosity: u8) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(filter(verbosity))
+        .with_writer(std::io::stderr)
+        .with_target(verbosity > 2)
+        .without_time()
+        .finish();
+    // a second call (in tests, say) keeps the first subscriber
+    let _ = tracing::subscriber::set_global_default(subscriber);
+}
+
+// What an event from a test thread is logged as, the ones every thread
+// makes over and over only with -vv
+pub fn level(kind: &str) -> Level {
+    match kind {
+        "thread_started" | "thread_stopped" | "request_failed" => Level::DEBUG,
+        _ => Level::INFO,
+    }
+}
+
+pub fn event(event: &Event) {
+    let line = crate::output::describe_event(event);
+    match level(&event.kind) {
+        Level::DEBUG => tracing::debug!("{line}"),
+        _ => tracing::info!("{line}"),
+    }
+}
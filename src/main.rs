@@ -1,599 +1,1976 @@
 use comfy_table::{presets::UTF8_FULL, Cell, Table};
-use std::io::Read;
-use std::io::{self, Write};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::thread::JoinHandle;
-use std::time::Instant;
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::vec;
-use ureq::Agent;
-use ureq::AgentBuilder;
 
+use cf_speedtest::*;
+mod acceptance;
+mod alerts;
 mod args;
-use args::UserArgs;
-
-mod locations;
+mod check;
+mod compare;
+mod daemon;
+mod diff;
+mod doctor;
+mod exporter;
+mod geo;
+mod import;
+mod logging;
+mod manifest;
+mod output;
+mod plan;
+mod progress;
 #[cfg(test)]
 mod tests;
-mod tls;
+#[cfg(feature = "tui")]
+mod tui;
+mod wan;
+mod warnings;
+
+use args::{Command, HistoryCommand, UserArgs};
+use output::OutputFormat;
+use results::{PhaseSummary, RunResult};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
-static CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL: &str = "https://speed.cloudflare.com/__down?measId=0";
-static CLOUDFLARE_SPEEDTEST_UPLOAD_URL: &str = "https://speed.cloudflare.com/__up?measId=0";
-static CLOUDFLARE_SPEEDTEST_SERVER_URL: &str =
-    "https://speed.cloudflare.com/__down?measId=0&bytes=0";
-static CLOUDFLARE_SPEEDTEST_CGI_URL: &str = "https://speed.cloudflare.com/cdn-cgi/trace";
-static OUR_USER_AGENT: &str = "cf_speedtest (0.4.6) https://github.com/12932/cf_speedtest";
+// Exit codes are part of the --machine contract, never renumber them
+// (1 is also what argh exits with on a bad command line)
+const EXIT_INVALID_ARGUMENTS: i32 = 1;
+const EXIT_TEST_FAILED: i32 = 2;
+const EXIT_HISTORY_FAILED: i32 = 3;
+const EXIT_ASSERTIONS_FAILED: i32 = 4;
+const EXIT_INTERRUPTED: i32 = 5;
+const EXIT_TIMED_OUT: i32 = 6;
+const EXIT_SKIPPED_METERED: i32 = 7;
+const EXIT_BELOW_MIN_DOWNLOAD: i32 = 8;
+const EXIT_BELOW_MIN_UPLOAD: i32 = 9;
+const EXIT_ABOVE_MAX_LATENCY: i32 = 10;
+
+// With --overall-timeout, time left after stopping the test for its
+// requests to be cut off (on top of --join-timeout-seconds) and the results
+// printed
+static OVERALL_TIMEOUT_MARGIN_SECS: u64 = 3;
+
+fn get_current_timestamp() -> String {
+    let Some(now) = clock::now() else {
+        return "unknown (system clock not set)".to_owned();
+    };
+    let now = now.with_timezone(&chrono::Local);
+
+    format!("{} {}", now.format("%Y-%m-%d %H:%M:%S"), now.format("%Z"))
+}
+
+// Where and how far away we are, as discovered by the preamble. Only
+// Cloudflare tells us where we and its server are.
+struct PreambleInfo {
+    location: Option<geo::Geolocation>,
+    colo: Option<String>,
+    latency: latency::IdleLatency,
+    // for the run's warnings, e.g. that we fell back to another provider
+    warnings: Vec<results::Warning>,
+    resolved: Option<results::Resolved>,
+}
+
+fn print_latency(config: &UserArgs, latency: &latency::IdleLatency) {
+    if !config.measures(args::Measurement::Latency) {
+        println!();
+        return;
+    }
+    let label = |latency: &latency::IdleLatency| {
+        let method = latency.method.name().to_uppercase();
+        output::label(config.output_format(), format!("Latency ({method}):"))
+    };
+    println!("{} {:.2}ms", label(latency), latency.min().as_millis());
+    // the others only for comparison, so on one line each
+    for other in &latency.others {
+        if let Some(error) = &other.error {
+            println!("{} unavailable ({error})", label(other));
+            continue;
+        }
+        println!(
+            "{} {:.2}ms (median {:.2}ms, jitter {:.2}ms)",
+            label(other),
+            other.min().as_millis(),
+            other.median_ms,
+            other.jitter_ms
+        );
+    }
+    println!(
+        "{} {:.2}ms (median {:.2}ms, max {:.2}ms)\n",
+        output::label(config.output_format(), "Jitter:"),
+        latency.jitter_ms,
+        latency.median_ms,
+        latency.max_ms
+    );
+}
+
+// Where the test server's name took us, when it wasn't up to the system
+fn print_resolved(config: &UserArgs, resolved: &Option<results::Resolved>) {
+    if let Some(resolved) = resolved.as_ref().filter(|r| r.resolver != "system") {
+        println!(
+            "{} {} is {} (from {})",
+            output::label(config.output_format(), "Resolved:"),
+            resolved.host,
+            resolved.address,
+            resolved.resolver
+        );
+    }
+}
 
-static CONNECT_TIMEOUT_MILLIS: u64 = 9600;
-static LATENCY_TEST_COUNT: u8 = 8;
-static NEW_METAL_SLEEP_MILLIS: u32 = 250;
+fn print_test_preamble(
+    config: &UserArgs,
+    provider: &dyn provider::Provider,
+) -> Result<PreambleInfo> {
+    let connect = config.connect_options();
+    if provider.kind() != provider::ProviderKind::Cloudflare {
+        let agent = build_agent(&connect);
+        let located = provider.locate(&agent)?;
+        let info = PreambleInfo {
+            location: None,
+            colo: located.colo,
+            latency: latency::idle(&config.latency_methods(), provider, &agent, &connect)?,
+            warnings: vec![],
+            resolved: connect.resolved(&provider.host()),
+        };
 
-impl std::io::Read for UploadHelper {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        // upload is finished, or we are exiting
-        if self.byte_ctr.load(Ordering::SeqCst) >= self.bytes_to_send
-            || self.exit_signal.load(Ordering::SeqCst)
-        {
-            return Ok(0);
+        if config.output_format().is_human() {
+            println!(
+                "{} {}",
+                output::label(config.output_format(), "Start:"),
+                get_current_timestamp()
+            );
+            println!(
+                "{} {}",
+                output::label(config.output_format(), "Server:"),
+                provider.describe()
+            );
+            print_resolved(config, &info.resolved);
+            print_latency(config, &info.latency);
         }
+        return Ok(info);
+    }
+
+    let iata_mapping = locations::generate_iata_to_city_map();
+    let country_mapping = i18n::generate_cca2_to_country_name_map(&config.lang);
+    let city_mapping = i18n::generate_city_name_map(&config.lang);
+
+    let agent = build_agent(&connect);
+    let trace = get_cdn_cgi_trace(&agent)?;
+    let latency = latency::idle(&config.latency_methods(), provider, &agent, &connect)?;
+    let headers = get_download_server_info(&agent)?;
+    let location = geo::locate(config, &trace, &headers)?;
+    let our_country_full = country_mapping.get(&location.country as &str);
+
+    let unknown_colo = &"???".to_owned();
+    let unknown_colo_info = &("UNKNOWN", "UNKNOWN");
+    let cf_colo = headers.get("cf-meta-colo").unwrap_or(unknown_colo);
+    let colo_info = iata_mapping
+        .get(cf_colo as &str)
+        .unwrap_or(unknown_colo_info);
+
+    let our_location = [location.city.as_deref(), location.region.as_deref()]
+        .into_iter()
+        .flatten()
+        .chain([*our_country_full.unwrap_or(&"UNKNOWN")])
+        .collect::<Vec<_>>()
+        .join(", ");
+    let info = PreambleInfo {
+        location: Some(location),
+        colo: Some(cf_colo.to_owned()),
+        latency,
+        warnings: warnings::vpn(None, trace.get("warp").map(String::as_str))
+            .into_iter()
+            .collect(),
+        resolved: connect.resolved(&provider.host()),
+    };
 
-        buf.fill(1);
+    if !config.output_format().is_human() {
+        return Ok(info);
+    }
 
-        self.byte_ctr.fetch_add(buf.len(), Ordering::SeqCst);
-        self.total_uploaded_counter
-            .fetch_add(buf.len(), Ordering::SeqCst);
-        Ok(buf.len())
+    println!(
+        "{} {}",
+        output::label(config.output_format(), "Start:"),
+        get_current_timestamp()
+    );
+    println!(
+        "{} {}",
+        output::label(config.output_format(), "Your Location:"),
+        our_location
+    );
+    if let Some(interface) = &config.via_interface {
+        println!(
+            "{} {} (tunneled)",
+            output::label(config.output_format(), "Via Interface:"),
+            interface
+        );
+    }
+    if let Some(source) = &config.source_address {
+        println!(
+            "{} {}",
+            output::label(config.output_format(), "Source Address:"),
+            source
+        );
+    }
+    if config.http_version != throughput::HttpVersion::Http1 {
+        println!(
+            "{} {} (experimental)",
+            output::label(config.output_format(), "Protocol:"),
+            config.http_version.name()
+        );
     }
+    println!(
+        "{} {} - {}, {}",
+        output::label(config.output_format(), "Server Location:"),
+        cf_colo,
+        city_mapping.get(colo_info.0).unwrap_or(&colo_info.0),
+        country_mapping.get(colo_info.1).unwrap_or(&"UNKNOWN")
+    );
+    print_resolved(config, &info.resolved);
+
+    print_latency(config, &info.latency);
+
+    Ok(info)
 }
 
-struct UploadHelper {
-    bytes_to_send: usize,
-    byte_ctr: Arc<AtomicUsize>,
-    total_uploaded_counter: Arc<AtomicUsize>,
-    exit_signal: Arc<AtomicBool>,
+// Stream an event if --output wants them, and log it for --verbose
+fn print_event(config: &UserArgs, event: &results::Event) {
+    output::print_event(config.output_format(), event);
+    logging::event(event);
 }
 
-fn get_secs_since_unix_epoch() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
+// Shows a test's progress as asked for by --output
+struct Console<'a> {
+    config: &'a UserArgs,
+    // how long each phase is meant to run, for the progress bars
+    download_seconds: u64,
+    upload_seconds: u64,
+    // an interleaved test's bursts are too short for a bar each
+    bars: bool,
+    // the bar of the phase running now, human output on a terminal only
+    progress: Option<progress::PhaseProgress>,
+    // the speed shown for the phase running now
+    smoothing: progress::Smoothing,
 }
 
-// Default test duration + a little bit more if we have extra threads
-fn get_test_time(test_duration_seconds: u64, thread_count: u32) -> u64 {
-    if thread_count > 4 {
-        return test_duration_seconds + (thread_count as u64 - 4) / 4;
+impl<'a> Console<'a> {
+    fn new(config: &'a UserArgs, speedtest: &Speedtest) -> Self {
+        Self {
+            config,
+            download_seconds: speedtest.download.as_ref().map_or(0, |p| p.seconds),
+            upload_seconds: speedtest.upload.as_ref().map_or(0, |p| p.seconds),
+            bars: progress::enabled() && speedtest.order != phases::PhaseOrder::Interleaved,
+            progress: None,
+            smoothing: progress::Smoothing::new(config.smoothing),
+        }
     }
 
-    test_duration_seconds
+    fn println(&self, line: &str) {
+        match &self.progress {
+            Some(progress) => progress.println(line),
+            None => println!("{line}"),
+        }
+    }
 }
 
-/* Given n bytes, return
-     a: unit of measurement in sensible form of bytes
-     b: unit of measurement in sensible form of bits
- i.e 12939428 	-> (12.34 MB, 98.76 Mb)
-     814811 	-> (795.8 KB, 6.36 Mb)
-*/
-fn get_appropriate_byte_unit(bytes: u64) -> (String, String) {
-    const UNITS: [&str; 5] = [" ", "K", "M", "G", "T"];
-    const KILOBYTE: f64 = 1024.0;
+impl Observer for Console<'_> {
+    fn phase_started(&mut self, direction: throughput::Direction) {
+        if direction == throughput::Direction::Upload && self.config.output_format().is_human() {
+            println!("Starting upload tests...");
+        }
+        output::print_phase_header(self.config.output_format(), direction.name());
+        self.smoothing = progress::Smoothing::new(self.config.smoothing);
+
+        if self.config.output_format().is_human() && self.bars {
+            let seconds = match direction {
+                throughput::Direction::Download => self.download_seconds,
+                throughput::Direction::Upload => self.upload_seconds,
+            };
+            self.progress = Some(progress::PhaseProgress::start(
+                direction,
+                seconds,
+                self.config.output_format() == OutputFormat::Compact,
+            ));
+        }
+    }
 
-    let mut bytes = bytes as f64;
-    let mut level = 0;
+    fn sample(&mut self, direction: throughput::Direction, second: usize, bytes: usize) {
+        // streamed samples are data for other tools, only what people read
+        // is smoothed
+        let format = self.config.output_format();
+        let shown = if format.is_human() {
+            self.smoothing.next(bytes)
+        } else {
+            bytes
+        };
+        match &mut self.progress {
+            Some(progress) => progress.sample(bytes, shown),
+            None => output::print_sample(format, direction.name(), second, shown),
+        }
+    }
 
-    while bytes >= KILOBYTE && level < UNITS.len() - 1 {
-        bytes /= KILOBYTE;
-        level += 1;
+    fn event(&mut self, event: &results::Event) {
+        match &self.progress {
+            Some(progress) => progress.suspend(|| print_event(self.config, event)),
+            None => print_event(self.config, event),
+        }
     }
 
-    let byte_unit = UNITS[level];
-    let mut bits = bytes * 8.0;
-    let mut bit_unit = byte_unit.to_ascii_lowercase();
+    fn phase_extended(&mut self, direction: throughput::Direction, seconds: u64) {
+        if let Some(progress) = &mut self.progress {
+            progress.extend(seconds);
+        }
+        if self.config.output_format().is_human() {
+            self.println(&format!(
+                "Slow link, measuring the {} for {seconds}s with at most {} threads...",
+                direction.name(),
+                throughput::SLOW_LINK_THREADS
+            ));
+        }
+    }
 
-    if bits >= 1000.0 {
-        bits /= 1000.0;
-        bit_unit = match byte_unit {
-            " " => "k",
-            "K" => "m",
-            "M" => "g",
-            "G" => "t",
-            "T" => "p",
-            _ => "?",
+    fn waiting_for_threads(&mut self, direction: throughput::Direction) {
+        if self.config.output_format().is_human() {
+            self.println(&format!(
+                "Waiting for {} threads to finish...",
+                direction.name()
+            ));
         }
-        .to_string();
     }
 
-    (
-        format!("{:.2} {}B", bytes, byte_unit),
-        format!("{:.2} {}b", bits, bit_unit),
-    )
-}
+    fn latency(&mut self, direction: throughput::Direction, round_trip: std::time::Duration) {
+        output::print_latency(self.config.output_format(), direction.name(), round_trip);
+    }
 
-fn get_appropriate_byte_unit_rate(bytes: u64) -> (String, String) {
-    let (a, b) = get_appropriate_byte_unit(bytes);
-    (format!("{}/s", a), format!("{}it/s", b))
+    fn phase_finished(&mut self, direction: throughput::Direction, samples: &[usize]) {
+        if let Some(progress) = self.progress.take() {
+            progress.finish();
+        }
+        output::print_phase_summary(
+            self.config.output_format(),
+            direction.name(),
+            samples,
+            self.config.warmup_seconds(),
+        );
+    }
 }
 
-fn get_appropriate_buff_size(speed: usize) -> u64 {
-    match speed {
-        0..=1000 => 4,
-        1001..=10000 => 32,
-        10001..=100000 => 512,
-        100001..=1000000 => 4096,
-        _ => 16384,
+fn run_import(config: &UserArgs, import: &args::ImportArgs) -> Result<()> {
+    let contents = std::fs::read_to_string(&import.path)?;
+    let runs = import::parse_runs(&import.format, &contents)?;
+
+    let history_path = config.history_path();
+    let inserted = history::History::open(&history_path)?.insert_all(&runs)?;
+
+    println!(
+        "Imported {} runs from {} into {}",
+        inserted,
+        import.path.display(),
+        history_path.display()
+    );
+    if inserted < runs.len() {
+        println!(
+            "Skipped {} runs that duplicate ones already in history",
+            runs.len() - inserted
+        );
     }
+
+    Ok(())
 }
 
-// Use cloudflare's cdn-cgi endpoint to get our ip address country
-fn get_our_ip_address_country() -> Result<String> {
-    let resp = ureq::get(CLOUDFLARE_SPEEDTEST_CGI_URL).call()?;
-    let mut body = String::new();
-    resp.into_reader().read_to_string(&mut body)?;
+fn run_history_export(config: &UserArgs, export: &args::ExportArgs) -> Result<()> {
+    let (since, until) = history::day_range(export.since, export.until);
+    let runs = history::History::open(&config.history_path())?.runs(since, until)?;
+    export::write_runs(&export.format, &runs, &mut io::stdout().lock())
+}
 
-    for line in body.lines() {
-        if let Some(loc) = line.strip_prefix("loc=") {
-            return Ok(loc.to_string());
-        }
+fn run_diff(config: &UserArgs, diff: &args::DiffArgs) -> Result<()> {
+    let before = diff::read_result(&diff.before)?;
+    let after = diff::read_result(&diff.after)?;
+    let differences = diff::diff(&before, &after);
+
+    if config.machine {
+        println!("{}", serde_json::to_string(&differences)?);
+        return Ok(());
     }
 
-    panic!(
-        "Could not find loc= in cdn-cgi response\n
-			Please update to the latest version and make a Github issue if the issue persists"
-    );
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL)
+        .set_content_arrangement(comfy_table::ContentArrangement::Dynamic)
+        .set_header(vec!["", "Before", "After", "Change"]);
+    table.add_row(vec![
+        Cell::new("Measured At"),
+        Cell::new(
+            before
+                .timestamp
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        ),
+        Cell::new(
+            after
+                .timestamp
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        ),
+        Cell::new(""),
+    ]);
+    for difference in &differences {
+        table.add_row(vec![
+            Cell::new(difference.label),
+            Cell::new(difference.before_cell()),
+            Cell::new(difference.after_cell()),
+            Cell::new(difference.change_cell()),
+        ]);
+    }
+
+    println!("{table}");
+    Ok(())
 }
 
-// Get http latency by requesting the cgi endpoint 8 times
-// and taking the fastest
-fn get_download_server_http_latency() -> Result<std::time::Duration> {
-    let start = Instant::now();
-    let my_agent = ureq::AgentBuilder::new().build();
-    let mut latency_vec = Vec::new();
+// A bit rate for a table cell, or a dash if it wasn't measured
+fn rate_cell(bps: Option<f64>) -> Cell {
+    Cell::new(bps.map_or("-".to_owned(), format_bits_per_sec))
+}
 
-    for _ in 0..LATENCY_TEST_COUNT {
-        // if vec length 2 or greater and we've spent a lot of time
-        // calculating latency, exit early (we could be on satellite or sumthin)
-        if latency_vec.len() >= 2 && start.elapsed() > std::time::Duration::from_secs(1) {
-            break;
-        }
+fn run_history_list(config: &UserArgs, list: &args::ListArgs) -> Result<()> {
+    let (since, until) = history::day_range(list.since, list.until);
+    let runs = history::History::open(&config.history_path())?.runs(since, until)?;
 
-        let now = Instant::now();
-        let _response = my_agent
-            .get(CLOUDFLARE_SPEEDTEST_CGI_URL)
-            .call()?
-            .into_string()?;
+    if config.machine {
+        println!("{}", serde_json::to_string(&runs)?);
+        return Ok(());
+    }
 
-        let total_time = now.elapsed();
-        latency_vec.push(total_time);
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL)
+        .set_content_arrangement(comfy_table::ContentArrangement::Dynamic)
+        .set_header(vec![
+            "Time",
+            "Source",
+            "Colo",
+            "Latency",
+            "Download",
+            "Upload",
+            "Download (per second)",
+        ]);
+    for run in &runs {
+        let samples: Vec<_> = run
+            .download
+            .iter()
+            .flat_map(|d| &d.samples)
+            .map(|&bytes| Some(bytes as f64))
+            .collect();
+        table.add_row(vec![
+            Cell::new(
+                run.timestamp
+                    .with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M"),
+            ),
+            Cell::new(&run.source),
+            Cell::new(run.colo.as_deref().unwrap_or("-")),
+            Cell::new(
+                run.latency_ms
+                    .map_or("-".to_owned(), |ms| format!("{ms:.1}ms")),
+            ),
+            rate_cell(run.download.as_ref().map(|d| d.median_bps)),
+            rate_cell(run.upload.as_ref().map(|u| u.median_bps)),
+            Cell::new(output::sparkline(&samples)),
+        ]);
     }
 
-    let best_time = latency_vec.iter().min().unwrap().to_owned();
-    Ok(best_time)
+    println!("{table}");
+    println!("{} runs", runs.len());
+    Ok(())
 }
 
-// return all cloufdlare headers from a request
-fn get_download_server_info() -> Result<std::collections::HashMap<String, String>> {
-    let mut server_headers = std::collections::HashMap::new();
-    let resp = ureq::get(CLOUDFLARE_SPEEDTEST_SERVER_URL)
-        .call()
-        .expect("Failed to get server info");
+fn run_history_trends(config: &UserArgs, trends: &args::TrendsArgs) -> Result<()> {
+    let (since, until) = history::day_range(trends.since, trends.until);
+    let runs = history::History::open(&config.history_path())?.runs(since, until)?;
+    let by_colo = trends.by_colo;
+    let trends = history::trends(&runs, trends.by, by_colo);
 
-    for key in resp.headers_names() {
-        if key.starts_with("cf-") {
-            server_headers.insert(key.clone(), resp.header(&key).unwrap().to_string());
+    if config.machine {
+        println!("{}", serde_json::to_string(&trends)?);
+        return Ok(());
+    }
+
+    let iata_mapping = locations::generate_iata_to_city_map();
+    let mut header = vec!["Starting"];
+    if by_colo {
+        header.push("Colo");
+    }
+    header.extend(["Runs", "Download", "Change", "Upload", "Latency"]);
+
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL)
+        .set_content_arrangement(comfy_table::ContentArrangement::Dynamic)
+        .set_header(header);
+    for trend in &trends {
+        let mut row = vec![Cell::new(trend.period)];
+        if by_colo {
+            row.push(Cell::new(match trend.colo.as_deref() {
+                Some(colo) => match iata_mapping.get(colo) {
+                    Some((city, _)) => format!("{colo} ({city})"),
+                    None => colo.to_owned(),
+                },
+                None => "-".to_owned(),
+            }));
         }
+        row.extend([
+            Cell::new(trend.runs),
+            rate_cell(trend.download_bps),
+            Cell::new(
+                trend
+                    .download_change_percent
+                    .map_or("".to_owned(), |change| format!("{change:+.1}%")),
+            ),
+            rate_cell(trend.upload_bps),
+            Cell::new(
+                trend
+                    .latency_ms
+                    .map_or("-".to_owned(), |ms| format!("{ms:.1}ms")),
+            ),
+        ]);
+        table.add_row(row);
     }
 
-    Ok(server_headers)
+    println!("{table}");
+    Ok(())
 }
 
-fn get_current_timestamp() -> String {
-    let now = chrono::Local::now();
+// Returns whether every endpoint passed
+fn run_doctor(config: &UserArgs) -> bool {
+    let findings = doctor::diagnose(config);
+    let healthy = findings
+        .iter()
+        .all(|finding| finding.status != doctor::Status::Fail);
 
-    format!("{} {}", now.format("%Y-%m-%d %H:%M:%S"), now.format("%Z"))
+    if config.machine {
+        println!(
+            "{}",
+            serde_json::to_string(&findings).expect("Couldn't serialize findings")
+        );
+        return healthy;
+    }
+
+    for finding in &findings {
+        println!(
+            "{:<6} {:<16} {}",
+            finding.status.name(),
+            finding.name,
+            finding.detail
+        );
+        if let Some(fix) = &finding.fix {
+            println!("{:<23} -> {fix}", "");
+        }
+    }
+
+    healthy
 }
 
-//
-fn upload_test(
-    bytes: usize,
-    total_up_bytes_counter: &Arc<AtomicUsize>,
-    _current_speed: &Arc<AtomicUsize>,
-    exit_signal: &Arc<AtomicBool>,
-) -> Result<()> {
-    let custom_connector = tls::InterceptingTlsConnector::new();
-
-    let agent: Agent = AgentBuilder::new()
-        .tls_connector(Arc::new(custom_connector))
-        .timeout_connect(std::time::Duration::from_millis(CONNECT_TIMEOUT_MILLIS))
-        .redirects(0)
-        .build();
-
-    loop {
-        let upload_helper = UploadHelper {
-            bytes_to_send: bytes,
-            byte_ctr: Arc::new(AtomicUsize::new(0)),
-            total_uploaded_counter: total_up_bytes_counter.clone(),
-            exit_signal: exit_signal.clone(),
-        };
+fn run_check(config: &UserArgs) -> bool {
+    let checks = check::check_endpoints();
+    let passed = checks.iter().all(|check| check.passed);
 
-        let resp = match agent
-            .post(CLOUDFLARE_SPEEDTEST_UPLOAD_URL)
-            .set("Content-Type", "text/plain;charset=UTF-8")
-            .set("User-Agent", OUR_USER_AGENT)
-            .send(upload_helper)
-        {
-            Ok(resp) => resp,
-            Err(err) => {
-                eprintln!("Error in upload thread: {err}");
-                return Ok(());
-            }
+    if config.machine {
+        println!(
+            "{}",
+            serde_json::to_string(&checks).expect("Couldn't serialize checks")
+        );
+        return passed;
+    }
+
+    for check in &checks {
+        let status = check
+            .status
+            .map_or("---".to_owned(), |status| status.to_string());
+        let latency = check
+            .latency_ms
+            .map_or("".to_owned(), |latency| format!("{latency:.0}ms"));
+
+        println!(
+            "{:<6} {:<10} {:<5} {:>8}  {}",
+            if check.passed { "PASS" } else { "FAIL" },
+            check.name,
+            status,
+            latency,
+            check.error.as_deref().unwrap_or(check.url)
+        );
+    }
+
+    // not failures, the test runs without them, but worth knowing
+    println!();
+    for capability in privileges::ALL {
+        let (status, detail) = match capability.available() {
+            Ok(()) => ("OK", format!("for {}", capability.needed_for())),
+            Err(why) => ("SKIP", format!("no {}: {why}", capability.needed_for())),
         };
+        println!("{status:<6} {:<16} {detail}", capability.name());
+    }
+
+    passed
+}
 
-        // read the POST response body into the void if response is okay
-        let _ = std::io::copy(&mut resp.into_reader(), &mut std::io::sink());
+// Show what a run would do, without transferring anything
+fn run_dry_run(config: &UserArgs) -> Result<()> {
+    let plan = plan::TestPlan::from_args(config)?;
+    let addresses = plan::resolve_host(&plan.host, &config.connect_options())?;
+    let per_100_mbit = plan.bytes_per_100_mbit();
+
+    if config.machine {
+        let dry_run = serde_json::json!({
+            "plan": plan,
+            "resolved_addresses": addresses,
+            "bytes_per_100_mbit": per_100_mbit,
+            "estimated_bytes": plan.estimated_bytes(),
+        });
+        println!("{dry_run}");
+        return Ok(());
+    }
+
+    println!(
+        "{} {}",
+        output::label(config.output_format(), "Backend:"),
+        plan.backend
+    );
+    println!(
+        "{} {}",
+        output::label(config.output_format(), "Host:"),
+        plan.host
+    );
+    println!(
+        "{} {}",
+        output::label(config.output_format(), "Resolves to:"),
+        addresses.join(", ")
+    );
+    if !plan.fallbacks.is_empty() {
+        println!(
+            "{} {}",
+            output::label(config.output_format(), "Fallbacks:"),
+            plan.fallbacks.join(", ")
+        );
+    }
 
-        if exit_signal.load(Ordering::Relaxed) {
-            return Ok(());
+    for (name, phase) in [("Download", &plan.download), ("Upload", &plan.upload)] {
+        match phase {
+            Some(phase) => println!(
+                "{} {} threads for {}s{}, {} per request{}",
+                output::label(config.output_format(), format!("{name}:")),
+                phase.threads,
+                phase.seconds,
+                phase
+                    .slow_link_seconds
+                    .map(|seconds| format!(" ({seconds}s on a slow link)"))
+                    .unwrap_or_default(),
+                get_appropriate_byte_unit(phase.bytes_per_request as u64).0,
+                phase
+                    .expected_bps
+                    .map(|bps| format!(", expecting {}", format_bits_per_sec(bps)))
+                    .unwrap_or_default()
+            ),
+            None => println!(
+                "{} skipped",
+                output::label(config.output_format(), format!("{name}:"))
+            ),
         }
     }
+    println!(
+        "{} {}",
+        output::label(config.output_format(), "Order:"),
+        plan.order
+    );
+
+    println!(
+        "{} {}",
+        output::label(config.output_format(), "Output:"),
+        plan.output
+    );
+    println!(
+        "{} {}",
+        output::label(config.output_format(), "History:"),
+        plan.history_db.as_deref().unwrap_or("not recorded")
+    );
+    match plan.estimated_bytes() {
+        Some(bytes) => println!(
+            "{} about {} at the expected rate",
+            output::label(config.output_format(), "Data usage:"),
+            get_appropriate_byte_unit(bytes).0
+        ),
+        None => println!(
+            "{} up to {} per 100 Mbit/s of link speed",
+            output::label(config.output_format(), "Data usage:"),
+            get_appropriate_byte_unit(per_100_mbit).0
+        ),
+    }
+
+    Ok(())
 }
 
-// download some bytes from cloudflare
-fn download_test(
-    bytes_to_request: usize,
-    total_bytes_counter: &Arc<AtomicUsize>,
-    current_down_speed: &Arc<AtomicUsize>,
-    exit_signal: &Arc<AtomicBool>,
-) -> Result<()> {
-    let custom_connector = tls::InterceptingTlsConnector::new();
+// The preamble against the first of `speedtest`'s providers that answers,
+// which the rest of the run then uses
+fn print_preamble_with_failover(
+    config: &UserArgs,
+    speedtest: &mut Speedtest,
+) -> Result<PreambleInfo> {
+    let providers = speedtest.providers();
+    let mut fallbacks = vec![];
+    for (i, provider) in providers.iter().enumerate() {
+        match print_test_preamble(config, provider.as_ref()) {
+            Ok(mut preamble) => {
+                speedtest.provider = provider.clone();
+                preamble.warnings.splice(0..0, fallbacks);
+                return Ok(preamble);
+            }
+            Err(err) if i + 1 < providers.len() => fallbacks.push(results::Warning::new(
+                "provider_fallback",
+                format!(
+                    "couldn't reach {} ({err}), fell back to {}",
+                    provider.describe(),
+                    providers[i + 1].describe()
+                ),
+            )),
+            Err(err) => return Err(err),
+        }
+    }
 
-    let agent: Agent = AgentBuilder::new()
-        .tls_connector(Arc::new(custom_connector))
-        .timeout_connect(std::time::Duration::from_millis(CONNECT_TIMEOUT_MILLIS))
-        .redirects(0)
-        .build();
+    Err("no provider to test against".into())
+}
 
-    let resp = match agent
-        .get(format!("{CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL}&bytes={bytes_to_request}").as_str())
-        .set("User-Agent", OUR_USER_AGENT)
-        .call()
-    {
-        Ok(resp) => resp,
+// The first Ctrl+C or SIGTERM stops the test where it is, so what it has
+// measured still gets reported; a second one exits straight away
+#[cfg(unix)]
+fn install_interrupt_handler(interrupt: &Interrupt) {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = match Signals::new([SIGINT, SIGTERM]) {
+        Ok(signals) => signals,
         Err(err) => {
-            eprintln!("Error in download thread: {err}");
-            return Ok(());
+            tracing::warn!(
+                "Couldn't install signal handlers, Ctrl+C won't report partial results: {err}"
+            );
+            return;
         }
     };
 
-    let mut resp_reader = resp.into_reader();
-    let mut total_bytes_sank: usize = 0;
-
-    loop {
-        // exit if we have passed deadline
-        if exit_signal.load(Ordering::Relaxed) {
-            return Ok(());
+    let interrupt = interrupt.clone();
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            if interrupt.is_triggered() {
+                std::process::exit(EXIT_INTERRUPTED);
+            }
+            eprintln!("Interrupted, stopping the test (press Ctrl+C again to quit at once)");
+            interrupt.trigger();
         }
+    });
+}
 
-        // if we are fast, take big chunks
-        // if we are slow, take small chunks
-        let current_recv_buff =
-            get_appropriate_buff_size(current_down_speed.load(Ordering::Relaxed));
+#[cfg(not(unix))]
+fn install_interrupt_handler(_interrupt: &Interrupt) {}
 
-        // copy bytes into the void
-        let bytes_sank = std::io::copy(
-            &mut resp_reader.by_ref().take(current_recv_buff),
-            &mut std::io::sink(),
-        )? as usize;
+/* --overall-timeout: interrupt the test early enough that what it measured
+   is printed by `timeout`, and exit at `timeout` whatever is still running.
+   Returns whether the test was stopped for it.
+*/
+fn start_watchdog(
+    config: &UserArgs,
+    timeout: std::time::Duration,
+    interrupt: &Interrupt,
+) -> Arc<AtomicBool> {
+    let expired = Arc::new(AtomicBool::new(false));
+    let wind_down =
+        std::time::Duration::from_secs(config.join_timeout_seconds + OVERALL_TIMEOUT_MARGIN_SECS);
+
+    let (interrupt, stopped) = (interrupt.clone(), Arc::clone(&expired));
+    let nagios = config.output_format() == OutputFormat::Nagios;
+    std::thread::spawn(move || {
+        std::thread::sleep(timeout.saturating_sub(wind_down));
+        eprintln!("Nearly out of --overall-timeout, stopping the test");
+        stopped.store(true, Ordering::SeqCst);
+        interrupt.trigger();
+
+        std::thread::sleep(timeout.min(wind_down));
+        eprintln!("Still running at --overall-timeout, exiting");
+        if nagios {
+            exit_nagios_unknown("still running at --overall-timeout");
+        }
+        std::process::exit(EXIT_TIMED_OUT);
+    });
 
-        //println!("Thread {:?} sank {} bytes", std::thread::current().id(), bytes_sank);
+    expired
+}
 
-        if bytes_sank == 0 {
-            if total_bytes_sank == 0 {
-                eprintln!("Cloudflare sent an empty response?");
-            }
+// One complete test run, with results printed as asked for by --output
+fn run_speedtest(config: &UserArgs, interrupt: &Interrupt) -> Result<RunResult> {
+    let timestamp = clock::now();
+    let mut speedtest = Speedtest {
+        interrupt: interrupt.clone(),
+        ..config.speedtest(config.phase_configs(None)?)
+    };
+    let preamble = print_preamble_with_failover(config, &mut speedtest)?;
 
-            return Ok(());
+    if config.auto_tune {
+        let probed = speedtest.probe_link();
+        if config.output_format().is_human() {
+            println!(
+                "{} {} ({} in the probe)",
+                output::label(config.output_format(), "Link Class:"),
+                probed.0.name(),
+                format_bits_per_sec(probed.1)
+            );
         }
+        speedtest = Speedtest {
+            provider: speedtest.provider,
+            interrupt: speedtest.interrupt,
+            ..config.speedtest(config.phase_configs(Some(probed))?)
+        };
+    }
 
-        total_bytes_sank += bytes_sank;
-        total_bytes_counter.fetch_add(bytes_sank, Ordering::SeqCst);
+    if let Some(path) = &config.manifest {
+        manifest::Manifest::new(
+            manifest::recorded_args(args::expand_verbosity(std::env::args().skip(1))),
+            &speedtest,
+        )
+        .write(path)?;
     }
-}
 
-fn print_test_preamble() {
-    println!("{:<32} {}", "Start:", get_current_timestamp());
+    let (cpu_before, phases_start) = (warnings::cpu_time(), std::time::Instant::now());
+    let measured = if config.tui {
+        run_phases_in_tui(&speedtest, preamble.latency.min())?
+    } else {
+        output::print_sample_header(config.output_format());
+        speedtest.run_phases(
+            preamble.latency.min(),
+            &mut Console::new(config, &speedtest),
+        )
+    };
 
-    let iata_mapping = locations::generate_iata_to_city_map();
-    let country_mapping = locations::generate_cca2_to_full_country_name_map();
+    let mut run = RunResult {
+        timestamp: timestamp.unwrap_or_else(chrono::Utc::now),
+        source: "cf_speedtest".to_owned(),
+        colo: preamble.colo,
+        country: preamble.location.as_ref().map(|l| l.country.clone()),
+        region: preamble.location.as_ref().and_then(|l| l.region.clone()),
+        city: preamble.location.and_then(|l| l.city),
+        latency_ms: Some(preamble.latency.min_ms),
+        idle_latency: Some(preamble.latency),
+        download: measured.download,
+        upload: measured.upload,
+        download_loaded_latency: measured.download_loaded_latency,
+        upload_loaded_latency: measured.upload_loaded_latency,
+        via_interface: config.via_interface.clone(),
+        server: speedtest.provider.server(),
+        sockets: measured.sockets,
+        content: measured.content,
+        events: measured.events,
+        cached: false,
+        interrupted: measured.interrupted,
+        clock_untrusted: timestamp.is_none(),
+        assertions: vec![],
+        skipped: privileges::skipped_measurements(),
+        host: Some(host::detect(config.via_interface.as_deref())).filter(|host| !host.is_empty()),
+        warnings: preamble.warnings,
+        resolved: preamble.resolved,
+    };
+    run.warnings.extend(run_warnings(
+        config,
+        &run,
+        cpu_before,
+        phases_start.elapsed(),
+    ));
+    if let Some(only) = &config.only {
+        only.apply(&mut run);
+    }
+    // what was measured is still worth having if these fail
+    if let Err(error) = check_run(config, &mut run) {
+        return Err(Box::new(results::PartialRun { run, error }));
+    }
 
-    let our_country = get_our_ip_address_country().expect("Couldn't get our country");
-    let our_country_full = country_mapping.get(&our_country as &str);
-    let latency = get_download_server_http_latency().expect("Couldn't get server latency");
-    let headers = get_download_server_info().expect("Couldn't get download server info");
+    print_run(config, &run, get_current_timestamp())?;
 
-    let unknown_colo = &"???".to_owned();
-    let unknown_colo_info = &("UNKNOWN", "UNKNOWN");
-    let cf_colo = headers.get("cf-meta-colo").unwrap_or(unknown_colo);
-    let colo_info = iata_mapping
-        .get(cf_colo as &str)
-        .unwrap_or(unknown_colo_info);
+    Ok(run)
+}
 
-    println!(
-        "{:<32} {}",
-        "Your Location:",
-        our_country_full.unwrap_or(&"UNKNOWN")
-    );
-    println!(
-        "{:<32} {} - {}, {}",
-        "Server Location:",
-        cf_colo,
-        colo_info.0,
-        country_mapping.get(colo_info.1).unwrap_or(&"UNKNOWN")
+// Compare a measured run with history and evaluate its assertions
+fn check_run(config: &UserArgs, run: &mut RunResult) -> Result<()> {
+    if let (Some(content), true) = (&mut run.content, config.history) {
+        content.compare_with(
+            history::History::open(&config.history_path())?
+                .latest_content_digest()?
+                .as_deref(),
+        );
+    }
+    let mut checks = match &config.assertions {
+        Some(path) => assertions::load(path)?,
+        None => vec![],
+    };
+    checks.extend(
+        config
+            .thresholds()
+            .into_iter()
+            .map(|(_, assertion)| assertion),
     );
+    run.assertions = checks
+        .iter()
+        .map(|assertion| assertion.evaluate(run))
+        .collect();
 
-    println!("{:<32} {:.2}ms\n", "Latency (HTTP):", latency.as_millis());
-}
-
-// Spawn a given amount of threads to run a specific test
-fn spawn_test_threads<F>(
-    threads_to_spawn: u32,
-    target_test: Arc<F>,
-    bytes_to_request: usize,
-    total_bytes_counter: &Arc<AtomicUsize>,
-    current_speed: &Arc<AtomicUsize>,
-    exit_signal: &Arc<AtomicBool>,
-) -> Vec<JoinHandle<()>>
-where
-    F: Fn(
-            usize,
-            &Arc<AtomicUsize>,
-            &Arc<AtomicUsize>,
-            &Arc<AtomicBool>,
-        ) -> std::result::Result<(), Box<dyn std::error::Error>>
-        + Send
-        + Sync
-        + 'static,
-{
-    let mut thread_handles = vec![];
-
-    for i in 0..threads_to_spawn {
-        let target_test_clone = Arc::clone(&target_test);
-        let total_downloaded_bytes_counter = Arc::clone(&total_bytes_counter.clone());
-        let current_down_clone = Arc::clone(&current_speed.clone());
-        let exit_signal_clone = Arc::clone(&exit_signal.clone());
-        let handle = std::thread::spawn(move || {
-            if i > 0 {
-                // sleep a little to hit a new cloudflare metal
-                // (each metal will throttle to 1 gigabit)
-                std::thread::sleep(std::time::Duration::from_millis(
-                    (i * NEW_METAL_SLEEP_MILLIS).try_into().unwrap(),
-                ));
-            }
+    Ok(())
+}
+
+// What the run itself turned up for its warnings, past the preamble's.
+// `cpu_before` is our CPU time before the phases, which took `elapsed`.
+fn run_warnings(
+    config: &UserArgs,
+    run: &RunResult,
+    cpu_before: Option<std::time::Duration>,
+    elapsed: std::time::Duration,
+) -> Vec<results::Warning> {
+    let mut found = vec![];
+    // a tunnel asked for with --via-interface is the point of the test
+    if config.via_interface.is_none() {
+        found.extend(warnings::vpn(
+            host::default_route_interface().as_deref(),
+            None,
+        ));
+    }
+    if config.on_metered == args::MeteredPolicy::Warn
+        && run.host.as_ref().and_then(|host| host.metered) == Some(true)
+    {
+        found.push(warnings::metered());
+    }
+    if let (Some(before), Some(after)) = (cpu_before, warnings::cpu_time()) {
+        let cores = std::thread::available_parallelism().map_or(1, usize::from);
+        found.extend(warnings::cpu_bound(
+            after.saturating_sub(before),
+            elapsed,
+            cores,
+        ));
+    }
+    if config.history {
+        let previous = history::History::open(&config.history_path())
+            .and_then(|history| history.latest("cf_speedtest"))
+            .ok()
+            .flatten();
+        found.extend(warnings::colo_change(
+            run.colo.as_deref(),
+            previous
+                .as_ref()
+                .and_then(|previous| previous.colo.as_deref()),
+        ));
+    }
+    found.extend(warnings::retries(&run.events));
+    if let Some(proxy) = &config.proxy {
+        found.push(warnings::proxy(&proxy.server));
+    }
+
+    found
+}
+
+// --tui: the phases full screen, then the results printed as usual
+#[cfg(feature = "tui")]
+fn run_phases_in_tui(speedtest: &Speedtest, idle: std::time::Duration) -> Result<Measured> {
+    let mut tui = tui::Tui::start(speedtest)?;
+    let measured = speedtest.run_phases(idle, &mut tui);
+    tui.finish();
+
+    Ok(measured)
+}
 
-            loop {
-                match target_test_clone(
-                    bytes_to_request,
-                    &total_downloaded_bytes_counter,
-                    &current_down_clone,
-                    &exit_signal_clone,
-                ) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        println!("Error in download test thread {i}: {e:?}");
-                        return;
+#[cfg(not(feature = "tui"))]
+fn run_phases_in_tui(_speedtest: &Speedtest, _idle: std::time::Duration) -> Result<Measured> {
+    Err("--tui needs cf_speedtest built with the tui feature".into())
+}
+
+// Print a finished run as asked for by --output
+fn print_run(config: &UserArgs, run: &RunResult, timestamp: String) -> Result<()> {
+    let sparkline = download_sparkline(config, run);
+    let sparkline = sparkline.as_deref();
+
+    if let Some(template) = &config.format_template {
+        println!("{}", template.render(run, sparkline));
+    } else if !config.field.is_empty() {
+        for path in &config.field {
+            println!("{}", template::select(run, sparkline, path));
+        }
+    } else {
+        match config.output_format() {
+            OutputFormat::Human | OutputFormat::Compact => {
+                print_results_table(config.output_format(), run, timestamp);
+                if let Some(sparkline) = sparkline {
+                    println!(
+                        "{} {sparkline}",
+                        output::label(config.output_format(), "Download History:")
+                    );
+                }
+                if let Some(content) = &run.content {
+                    println!(
+                        "{} {}",
+                        output::label(config.output_format(), "Content:"),
+                        content.describe()
+                    );
+                }
+                if run.interrupted {
+                    println!(
+                        "{} stopped early, results cover the part of the test that ran",
+                        output::label(config.output_format(), "Interrupted:")
+                    );
+                }
+                if config.only.is_some() && config.measures(args::Measurement::Loss) {
+                    let loss = run.sockets.as_ref().and_then(|s| s.retransmit_percent());
+                    println!(
+                        "{} {}",
+                        output::label(config.output_format(), "Loss:"),
+                        loss.map_or("not measured, needs TCP_INFO".to_owned(), |loss| format!(
+                            "{loss:.2}% of segments retransmitted"
+                        ))
+                    );
+                }
+                // see --capture-spikes-ms
+                for event in run.events.iter().filter(|e| e.kind == "latency_spike") {
+                    println!(
+                        "{} {} at {:.1}s: {}",
+                        output::label(config.output_format(), "Latency Spike:"),
+                        event.phase,
+                        event.at_secs,
+                        event.detail.as_deref().unwrap_or_default()
+                    );
+                }
+                for skipped in &run.skipped {
+                    println!(
+                        "{} {skipped}",
+                        output::label(config.output_format(), "Not Measured:")
+                    );
+                }
+                if let Some(host) = &run.host {
+                    println!(
+                        "{} {}",
+                        output::label(config.output_format(), "Measured On:"),
+                        host.describe()
+                    );
+                }
+                if !run.warnings.is_empty() {
+                    println!("\nWarnings:");
+                    for warning in &run.warnings {
+                        println!("  * {}", warning.message);
                     }
                 }
-
-                // exit if we have passed the deadline
-                if exit_signal_clone.load(Ordering::Relaxed) {
-                    // println!("Thread {} exiting...", i);
-                    return;
+            }
+            OutputFormat::Json => println!("{}", serde_json::to_string(run)?),
+            OutputFormat::Csv => match &config.append {
+                Some(path) => {
+                    if let Some(rotation) = config.rotation() {
+                        rotation.rotate_if_due(path)?;
+                    }
+                    export::append_csv(path, run)?
+                }
+                None => println!("{}\n{}", export::CSV_HEADER, export::csv_row(run)),
+            },
+            OutputFormat::Influx => {
+                if let Some(line) = output::influx_run_line(run, output::hostname().as_deref()) {
+                    println!("{line}");
                 }
             }
-        });
-        thread_handles.push(handle);
+            // the samples were the output
+            OutputFormat::CsvLive | OutputFormat::InfluxLive => {}
+            OutputFormat::Iperf => println!("iperf Done."),
+            OutputFormat::Ndjson => {
+                output::print_ndjson("result", serde_json::json!({ "result": run }))
+            }
+            OutputFormat::Nagios => println!(
+                "{}",
+                output::nagios_line(run, &config.warning, &config.critical_assertions()).1
+            ),
+            OutputFormat::Quiet => println!("{}", output::quiet_line(run)),
+        }
+    }
+
+    for sink in config.file_sinks() {
+        sink.write(run, config.rotation().as_ref())?;
+    }
+    if let Some(broker) = &config.mqtt {
+        let messages = mqtt::run_messages(run, config.mqtt_topic(), config.mqtt_discovery)?;
+        mqtt::publish(broker, &messages)
+            .map_err(|err| format!("couldn't publish to {}: {err}", broker.host))?;
+    }
+
+    // json has them in the object, streams and templates mustn't get stray
+    // lines on stdout
+    for outcome in &run.assertions {
+        match config.output_format() {
+            OutputFormat::Human | OutputFormat::Compact if !config.custom_result() => {
+                println!("{}", outcome.describe())
+            }
+            OutputFormat::Json if !config.custom_result() => {}
+            _ => eprintln!("{}", outcome.describe()),
+        }
+    }
+
+    Ok(())
+}
+
+// Download speeds of the last --sparkline-runs runs ending with `run`, if
+// we keep a history
+fn download_sparkline(config: &UserArgs, run: &RunResult) -> Option<String> {
+    if !config.history || config.sparkline_runs == 0 {
+        return None;
     }
 
-    thread_handles
+    // `run` may or may not have been recorded yet (it has if it's --cached)
+    let earlier = history::History::open(&config.history_path())
+        .and_then(|history| history.recent("cf_speedtest", config.sparkline_runs))
+        .inspect_err(|err| eprintln!("Couldn't read history for the sparkline: {err}"))
+        .ok()?;
+    let mut speeds: Vec<Option<f64>> = earlier
+        .iter()
+        .filter(|earlier| earlier.timestamp != run.timestamp)
+        .map(|earlier| earlier.download.as_ref().map(|p| p.median_bps))
+        .collect();
+    speeds.push(run.download.as_ref().map(|p| p.median_bps));
+
+    let skip = speeds.len().saturating_sub(config.sparkline_runs);
+    Some(output::sparkline(&speeds[skip..]))
 }
 
-fn run_download_test(config: &UserArgs) -> Vec<usize> {
-    let total_downloaded_bytes_counter = Arc::new(AtomicUsize::new(0));
-    let exit_signal = Arc::new(AtomicBool::new(false));
+// Our most recent run from history, if it's no older than `max_age`
+fn get_cached_run(config: &UserArgs, max_age: std::time::Duration) -> Result<Option<RunResult>> {
+    let latest = history::History::open(&config.history_path())?.latest("cf_speedtest")?;
+    let max_age = chrono::Duration::from_std(max_age)?;
+    // without a clock there's no telling how old it is
+    let Some(now) = clock::now() else {
+        return Ok(None);
+    };
 
-    exit_signal.store(false, Ordering::SeqCst);
-    let current_down_speed = Arc::new(AtomicUsize::new(0));
-    let down_deadline = get_secs_since_unix_epoch() + get_test_time(config.test_duration_seconds, config.download_threads);
+    Ok(latest
+        .filter(|run| now - run.timestamp <= max_age)
+        .map(|run| RunResult {
+            cached: true,
+            ..run
+        }))
+}
 
-    let target_test = Arc::new(download_test);
-    let down_handles = spawn_test_threads(
-        config.download_threads,
-        target_test,
-        config.bytes_to_download,
-        &total_downloaded_bytes_counter,
-        &current_down_speed,
-        &exit_signal,
-    );
+// Record the run in history, if the user asked for that
+fn record_run(config: &UserArgs, run: &RunResult) -> Result<()> {
+    if !config.history {
+        return Ok(());
+    }
+    if run.clock_untrusted {
+        eprintln!("Not recording run in history, the system clock isn't set");
+        return Ok(());
+    }
 
-    let mut last_bytes_down = 0;
-    total_downloaded_bytes_counter.store(0, Ordering::SeqCst);
-    let mut down_measurements = vec![];
+    if !history::History::open(&config.history_path())?.insert(run)? {
+        eprintln!("Not recording run in history, an identical run was recorded this minute");
+    }
 
-    // Calculate and print download speed
-    loop {
-        let bytes_down = total_downloaded_bytes_counter.load(Ordering::Relaxed);
-        let bytes_down_diff = bytes_down - last_bytes_down;
+    Ok(())
+}
 
-        // set current_down
-        current_down_speed.store(bytes_down_diff, Ordering::SeqCst);
-        down_measurements.push(bytes_down_diff);
+// Measure a loopback source of known rate, returning how far off the
+// measurements were
+fn run_calibration(config: &UserArgs, rate: pacing::Rate) -> Result<calibrate::CalibrationReport> {
+    let speedtest = config.speedtest(config.phase_configs(None)?);
+    if config.output_format().is_human() {
+        println!(
+            "{} {} on loopback\n",
+            output::label(config.output_format(), "Calibration Source:"),
+            format_bits_per_sec(rate.bits_per_sec)
+        );
+    }
+    output::print_sample_header(config.output_format());
+    let report = calibrate::calibrate(&speedtest, rate, &mut Console::new(config, &speedtest))?;
+
+    if config.machine {
+        println!("{}", serde_json::to_string(&report)?);
+        return Ok(report);
+    }
 
-        let speed_values = get_appropriate_byte_unit(bytes_down_diff as u64);
-        // only print progress if we are before deadline
-        if get_secs_since_unix_epoch() < down_deadline {
+    println!();
+    for (name, phase) in [("Download", &report.download), ("Upload", &report.upload)] {
+        if let Some(phase) = phase {
             println!(
-                "Download: {bit_speed:>12.*}it/s       ({byte_speed:>10.*}/s)",
-                16,
-                16,
-                byte_speed = speed_values.0,
-                bit_speed = speed_values.1
+                "{} {} median, {:+.2}% off",
+                output::label(config.output_format(), format!("{name}:")),
+                format_bits_per_sec(phase.median_bps),
+                phase.error_percent
             );
         }
-        io::stdout().flush().unwrap();
-        std::thread::sleep(std::time::Duration::from_millis(1000));
-        last_bytes_down = bytes_down;
+    }
+    println!(
+        "{} {}",
+        output::label(config.output_format(), "Verdict:"),
+        report.verdict()
+    );
+
+    Ok(report)
+}
+
+// Run the acceptance battery and write its report. Returns whether the
+// circuit passed.
+fn run_acceptance(config: &UserArgs) -> Result<bool> {
+    let config = UserArgs {
+        test_duration_seconds: config.test_duration_seconds.max(acceptance::MIN_PHASE_SECS),
+        duration: config
+            .duration
+            .map(|seconds| seconds.max(acceptance::MIN_PHASE_SECS)),
+        download_duration: config
+            .download_duration
+            .map(|seconds| seconds.max(acceptance::MIN_PHASE_SECS)),
+        upload_duration: config
+            .upload_duration
+            .map(|seconds| seconds.max(acceptance::MIN_PHASE_SECS)),
+        rpm: true,
+        ..config.clone()
+    };
 
-        // exit if we have passed the deadline
-        if get_secs_since_unix_epoch() > down_deadline {
-            exit_signal.store(true, Ordering::SeqCst);
-            break;
+    let mut outcomes = vec![];
+    for i in 1..=config.acceptance_runs {
+        println!("\nAcceptance run {i} of {}...", config.acceptance_runs);
+        match run_speedtest(&config, &Interrupt::default()) {
+            Ok(run) => {
+                if let Err(err) = record_run(&config, &run) {
+                    eprintln!("Couldn't record run in history: {err}");
+                }
+                outcomes.push(Ok(run));
+            }
+            Err(err) => {
+                eprintln!("Acceptance run {i} failed: {err}");
+                outcomes.push(Err(err.to_string()));
+            }
         }
     }
 
-    println!("Waiting for download threads to finish...");
-    for handle in down_handles {
-        handle.join().expect("Couldn't join download thread");
+    let report = acceptance::AcceptanceReport::new(outcomes);
+    let payload_path = acceptance::write(
+        &report,
+        &config.acceptance_report,
+        config.acceptance_key.as_deref(),
+    )?;
+
+    println!();
+    for summary in &report.assertions {
+        println!(
+            "{}  {} ({} of {} runs)",
+            if summary.passed { "PASS" } else { "FAIL" },
+            summary.assertion,
+            summary.runs_passed,
+            summary.runs
+        );
     }
+    println!(
+        "\nCircuit {}, report written to {} (results in {})",
+        if report.passed { "passed" } else { "failed" },
+        config.acceptance_report.display(),
+        payload_path.display()
+    );
 
-    down_measurements
+    Ok(report.passed)
 }
 
-fn run_upload_test(config: &UserArgs) -> Vec<usize> {
-    let exit_signal = Arc::new(AtomicBool::new(false));
-    let total_uploaded_bytes_counter = Arc::new(AtomicUsize::new(0));
-    let current_up_speed = Arc::new(AtomicUsize::new(0));
-    // re-use exit_signal for upload tests
-    exit_signal.store(false, Ordering::SeqCst);
+// Run the test through each interface in turn, then show them side by side.
+// Fails if any of them did.
+fn run_interface_comparison(config: &UserArgs, interfaces: &[String]) -> Result<()> {
+    let variants = interfaces
+        .iter()
+        .map(|interface| {
+            let config = UserArgs {
+                via_interface: Some(interface.clone()),
+                compare_interfaces: None,
+                ..config.clone()
+            };
+            (interface.clone(), config)
+        })
+        .collect::<Vec<_>>();
+
+    run_comparison(config, &variants)
+}
 
-    let up_deadline = get_secs_since_unix_epoch() + get_test_time(config.test_duration_seconds, config.upload_threads);
+// Run the test over IPv4 and then IPv6, and show them side by side.
+// Fails if either did.
+fn run_stack_comparison(config: &UserArgs) -> Result<()> {
+    let variants = [net::IpFamily::V4, net::IpFamily::V6]
+        .into_iter()
+        .map(|family| {
+            let config = UserArgs {
+                ipv4: family == net::IpFamily::V4,
+                ipv6: family == net::IpFamily::V6,
+                compare_stacks: false,
+                ..config.clone()
+            };
+            (family.name().to_owned(), config)
+        })
+        .collect::<Vec<_>>();
+
+    run_comparison(config, &variants)
+}
 
-    let target_test = Arc::new(upload_test);
-    let up_handles = spawn_test_threads(
-        config.upload_threads,
-        target_test,
-        config.bytes_to_upload,
-        &total_uploaded_bytes_counter,
-        &current_up_speed,
-        &exit_signal,
-    );
+// Time connection setup with the system resolver and with DoH, and show the
+// difference. Fails if either resolver never got us connected.
+fn run_resolver_comparison(config: &UserArgs) -> Result<()> {
+    let doh = config.doh.clone().unwrap_or_else(resolvers::cloudflare);
+    if config.output_format().is_human() {
+        println!("Comparing resolvers (DoH: {})...", doh.describe());
+    }
+    let comparison =
+        resolvers::compare(&config.connect_options(), &doh, LATENCY_TEST_COUNT as usize);
 
-    let mut last_bytes_up = 0;
-    let mut up_measurements = vec![];
-    total_uploaded_bytes_counter.store(0, Ordering::SeqCst);
+    if !config.output_format().is_human() {
+        println!("{}", serde_json::to_string(&comparison).unwrap());
+    } else {
+        let ms = |ms: Option<f64>| ms.map_or("-".to_owned(), |ms| format!("{ms:.2}ms"));
+        let mut table = Table::new();
+        table.load_preset(UTF8_FULL).set_header(vec![
+            "",
+            "Lookup (median)",
+            "Connection Setup (median)",
+            "Failed",
+        ]);
+        for summary in [&comparison.system, &comparison.doh] {
+            table.add_row(vec![
+                Cell::new(summary.resolver.name()),
+                Cell::new(ms(summary.resolve_ms)),
+                Cell::new(ms(summary.setup_ms)),
+                Cell::new(format!("{} of {}", summary.failures, summary.probes)),
+            ]);
+        }
+        println!("{table}");
 
-    // Calculate and print upload speed
-    loop {
-        let bytes_up = total_uploaded_bytes_counter.load(Ordering::Relaxed);
+        for summary in [&comparison.system, &comparison.doh] {
+            if let Some(err) = &summary.last_error {
+                eprintln!("{} failed: {err}", summary.resolver.name());
+            }
+        }
+        if let Some(difference) = comparison.difference_ms {
+            println!(
+                "{} {:.2}ms {} per new connection",
+                output::label(config.output_format(), "DoH Difference:"),
+                difference.abs(),
+                if difference < 0.0 { "faster" } else { "slower" }
+            );
+        }
+    }
 
-        let bytes_up_diff = bytes_up - last_bytes_up;
-        up_measurements.push(bytes_up_diff);
+    let failed: Vec<_> = [&comparison.system, &comparison.doh]
+        .into_iter()
+        .filter(|summary| summary.setup_ms.is_none())
+        .map(|summary| summary.resolver.name())
+        .collect();
+    if !failed.is_empty() {
+        return Err(format!("no connection could be set up with {}", failed.join(" or ")).into());
+    }
+    Ok(())
+}
 
-        let speed_values = get_appropriate_byte_unit(bytes_up_diff as u64);
+// --pattern bursty instead of the usual phases
+fn run_bursty(config: &UserArgs, interrupt: &Interrupt) -> Result<bursts::BurstSummary> {
+    let speedtest = Speedtest {
+        interrupt: interrupt.clone(),
+        ..config.speedtest(config.phase_configs(None)?)
+    };
+    if config.output_format().is_human() {
+        println!("Downloading in bursts...");
+    }
+    let summary = bursts::run(&speedtest);
 
+    // validate leaves JSON as the only other format
+    if !config.output_format().is_human() {
+        println!("{}", serde_json::to_string(&summary).unwrap());
+    } else {
+        let ms = |ms: Option<f64>| ms.map_or("-".to_owned(), |ms| format!("{ms:.2}ms"));
+        println!(
+            "{} {} median of {} bursts, each after {}s idle",
+            output::label(config.output_format(), "Burst Speed:"),
+            summary
+                .burst_bps
+                .map_or("-".to_owned(), format_bits_per_sec),
+            summary.bursts - summary.failures,
+            summary.idle_ms / 1000
+        );
+        println!(
+            "{} {}",
+            output::label(config.output_format(), "First Byte After Idle:"),
+            ms(summary.first_byte_ms)
+        );
         println!(
-            "Upload:   {bit_speed:>12.*}it/s       ({byte_speed:>10.*}/s)",
-            16,
-            16,
-            byte_speed = speed_values.0,
-            bit_speed = speed_values.1
+            "{} {}",
+            output::label(config.output_format(), "Up To Speed After Idle:"),
+            ms(summary.ramp_ms)
         );
+        if let Some(err) = &summary.last_error {
+            eprintln!(
+                "{} of {} bursts failed: {err}",
+                summary.failures, summary.bursts
+            );
+        }
+    }
 
-        io::stdout().flush().unwrap();
-        std::thread::sleep(std::time::Duration::from_millis(1000));
-        last_bytes_up = bytes_up;
+    Ok(summary)
+}
 
-        // exit if we have passed the deadline
-        if get_secs_since_unix_epoch() > up_deadline {
-            exit_signal.store(true, Ordering::SeqCst);
-            break;
+// The variants of the test for each WAN link in --wan-profiles, to measure
+// in turn and show side by side
+fn failover_variants(config: &UserArgs) -> Result<Vec<(String, UserArgs)>> {
+    let path = config
+        .wan_profiles
+        .as_ref()
+        .ok_or("failover-test needs --wan-profiles")?;
+    let profiles = wan::load_profiles(path)?;
+    if profiles.iter().any(|profile| profile.interface.is_some()) {
+        privileges::require(
+            privileges::Capability::BindToDevice,
+            "a WAN profile with interface=",
+        )?;
+    }
+
+    Ok(profiles
+        .iter()
+        .map(|profile| {
+            let config = UserArgs {
+                command: None,
+                ..profile.apply(config)
+            };
+            (profile.name.clone(), config)
+        })
+        .collect())
+}
+
+// Run the test once per labelled variant of `config`, then print them side
+// by side. Fails naming those that did.
+fn run_comparison(config: &UserArgs, variants: &[(String, UserArgs)]) -> Result<()> {
+    let mut runs = vec![];
+
+    for (label, variant) in variants {
+        if config.output_format().is_human() {
+            println!("\nTesting via {label}...");
+        }
+
+        let run = match run_speedtest(variant, &Interrupt::default()) {
+            Ok(run) => Some(run),
+            Err(err) => {
+                eprintln!("Test via {label} failed: {err}");
+                None
+            }
+        };
+
+        if let Some(run) = &run {
+            if let Err(err) = record_run(variant, run) {
+                eprintln!("Couldn't record run via {label} in history: {err}");
+            }
         }
+        runs.push((label.clone(), run));
+    }
+
+    if config.output_format().is_human() {
+        println!("\n{}", compare::comparison_table(&runs));
     }
 
-    // wait for upload threads to finish
-    println!("Waiting for upload threads to finish...");
-    for handle in up_handles {
-        handle.join().expect("Couldn't join upload thread");
+    let failed: Vec<_> = runs
+        .iter()
+        .filter(|(_, run)| run.is_none())
+        .map(|(label, _)| label.as_str())
+        .collect();
+    if !failed.is_empty() {
+        return Err(format!("the test via {} failed", failed.join(" and ")).into());
+    }
+    Ok(())
+}
+
+// --output nagios: a check that couldn't be made is UNKNOWN
+fn exit_nagios_unknown(why: &str) -> ! {
+    println!("SPEEDTEST {} - {why}", output::NagiosStatus::Unknown.name());
+    std::process::exit(output::NagiosStatus::Unknown as i32);
+}
+
+// For --output json, what kind of failure an exit code is
+fn error_category(code: i32) -> &'static str {
+    [
+        (EXIT_INVALID_ARGUMENTS, "invalid_arguments"),
+        (EXIT_TEST_FAILED, "test_failed"),
+        (EXIT_HISTORY_FAILED, "history"),
+        (EXIT_INTERRUPTED, "interrupted"),
+        (EXIT_TIMED_OUT, "timed_out"),
+        (EXIT_SKIPPED_METERED, "skipped_metered"),
+    ]
+    .into_iter()
+    .find_map(|(exit_code, category)| (exit_code == code).then_some(category))
+    .unwrap_or("error")
+}
+
+// With --output json (or --machine) a failure still prints a JSON document
+// on stdout, with what the run measured if it got that far
+fn print_error_json(config: &UserArgs, code: i32, message: &str, partial: Option<RunResult>) {
+    if config.output_format() == OutputFormat::Json {
+        let report = results::ErrorReport {
+            partial,
+            ..results::ErrorReport::new(error_category(code), message, code)
+        };
+        println!("{}", serde_json::to_string(&report).unwrap());
+    }
+}
+
+// argh::from_env, with -vv and -vvv understood
+fn args_from_env() -> UserArgs {
+    use argh::FromArgs;
+
+    let args: Vec<String> = args::expand_verbosity(std::env::args().skip(1)).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    UserArgs::from_args(&["cf_speedtest"], &args).unwrap_or_else(|early_exit| {
+        std::process::exit(match early_exit.status {
+            Ok(()) => {
+                println!("{}", early_exit.output);
+                0
+            }
+            Err(()) => {
+                eprintln!(
+                    "{}\nRun cf_speedtest --help for more information.",
+                    early_exit.output
+                );
+                // the command line didn't parse, but may still have asked
+                // for failures as JSON
+                if args.contains(&"--machine") || args.windows(2).any(|w| w == ["--output", "json"])
+                {
+                    let report = results::ErrorReport::new(
+                        "invalid_arguments",
+                        early_exit.output.trim(),
+                        EXIT_INVALID_ARGUMENTS,
+                    );
+                    println!("{}", serde_json::to_string(&report).unwrap());
+                }
+                if args.windows(2).any(|w| w == ["--output", "nagios"]) {
+                    let first_line = early_exit.output.lines().next().unwrap_or_default();
+                    exit_nagios_unknown(&format!("Invalid arguments: {first_line}"));
+                }
+                EXIT_INVALID_ARGUMENTS
+            }
+        })
+    })
+}
+
+// From --replay: the command line to run instead of the one given
+fn replay_manifest(path: &std::path::Path, invoked: &UserArgs) -> Result<UserArgs> {
+    let manifest = manifest::Manifest::load(path)?;
+    let config = manifest.user_args(path, invoked)?;
+
+    eprintln!(
+        "Replaying {}: cf_speedtest {}",
+        path.display(),
+        manifest.args.join(" ")
+    );
+    for difference in manifest.differences(&config) {
+        eprintln!("Note: {difference}");
     }
 
-    up_measurements
+    Ok(config)
 }
 
-fn compute_statistics(data: &mut Vec<usize>) -> (f64, f64, usize, usize, usize, usize) {
-    if data.is_empty() {
-        return (0f64, 0f64, 0, 0, 0, 0);
+// Write a crash report for `error` and tell the user where it is and how
+// to send it to us
+fn report_crash(error: &str) {
+    match crash::write_report(error) {
+        Ok(path) => eprintln!("A crash report was written to {}", path.display()),
+        Err(err) => eprintln!("Couldn't write a crash report: {err}"),
     }
+    eprintln!(
+        "If this looks like a bug, please report it:\n{}",
+        crash::issue_url(error)
+    );
+}
 
-    data.sort();
+// Report panics on top of the usual panic message
+fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        report_crash(&info.to_string());
+    }));
+}
 
-    let len = data.len();
-    let sum: usize = data.iter().sum();
-    let average = sum as f64 / len as f64;
+// Print an error and exit with one of the documented exit codes, or as
+// UNKNOWN for --output nagios
+fn exit_with_error(
+    config: &UserArgs,
+    code: i32,
+    context: &str,
+    err: Box<dyn std::error::Error>,
+) -> ! {
+    let message = format!("{context}: {err}");
+    eprintln!("{message}");
+    let partial = err
+        .downcast::<results::PartialRun>()
+        .ok()
+        .map(|partial| partial.run);
+    print_error_json(config, code, &message, partial);
+    // a bad command line is the user's to fix, anything else may be ours
+    if code != EXIT_INVALID_ARGUMENTS {
+        report_crash(&message);
+    }
+    if config.output_format() == OutputFormat::Nagios {
+        exit_nagios_unknown(&message);
+    }
+    std::process::exit(code);
+}
 
-    let median = if len % 2 == 0 {
-        (data[len / 2 - 1] + data[len / 2]) as f64 / 2.0
+fn print_results_table(format: OutputFormat, run: &RunResult, timestamp: String) {
+    if format == OutputFormat::Compact {
+        // a table is too wide for a phone, so a couple of lines per phase
+        println!("\n{timestamp}");
+        for (name, phase) in [("Download", &run.download), ("Upload", &run.upload)] {
+            if let Some(phase) = phase {
+                for line in output::compact_phase_lines(name, phase) {
+                    println!("{line}");
+                }
+            }
+        }
     } else {
-        data[len / 2] as f64
-    };
+        let mut table = Table::new();
+        table
+            .load_preset(UTF8_FULL)
+            .set_content_arrangement(comfy_table::ContentArrangement::Dynamic)
+            .set_header(vec![
+                Cell::new(""),
+                Cell::new("Min"),
+                Cell::new("Median"),
+                Cell::new("Average"),
+                Cell::new("90th pctile"),
+                Cell::new("Peak"),
+            ]);
+
+        // a row, in bytes per second, for each phase that ran
+        for (name, phase) in [("Download", &run.download), ("Upload", &run.upload)] {
+            if let Some(phase) = phase {
+                let rate = |bps: f64| Cell::new(format_bits_per_sec(bps));
+                table.add_row(vec![
+                    Cell::new(name),
+                    rate(phase.min_bps),
+                    rate(phase.median_bps),
+                    rate(phase.average_bps),
+                    rate(phase.p90_bps),
+                    rate(phase.max_bps),
+                ]);
+            }
+        }
 
-    let p90_index = (0.90 * len as f64).ceil() as usize - 1;
-    let p99_index = (0.99 * len as f64).ceil() as usize - 1;
+        if run.download.is_some() || run.upload.is_some() {
+            print!("\n{}\n{}\n", timestamp, table);
+        } else {
+            println!("\n{timestamp}");
+        }
+    }
 
-    let min = data[0];
-    let max = *data.last().unwrap();
+    for (name, loaded) in [
+        ("Download", &run.download_loaded_latency),
+        ("Upload", &run.upload_loaded_latency),
+    ] {
+        if let Some(loaded) = loaded {
+            println!(
+                "{} {}",
+                output::label(format, format!("Latency ({name}):")),
+                loaded.describe()
+            );
+        }
+    }
 
-    (median, average, data[p90_index], data[p99_index], min, max)
+    for (name, phase) in [("Download", &run.download), ("Upload", &run.upload)] {
+        if let Some(saturation) = phase.as_ref().and_then(PhaseSummary::describe_saturation) {
+            println!(
+                "{} {saturation}",
+                output::label(format, format!("Saturated ({name}):"))
+            );
+        }
+    }
+    for (name, phase) in [("Download", &run.download), ("Upload", &run.upload)] {
+        if let Some(warmup) = phase.as_ref().and_then(|phase| phase.warmup_seconds) {
+            println!(
+                "{} the first {warmup}s are left out of the figures above",
+                output::label(format, format!("Warmup ({name}):"))
+            );
+        }
+    }
+    for advice in bdp::BdpAdvice::from_run(run, bdp::local_limit) {
+        let name = match advice.direction {
+            throughput::Direction::Download => "Download",
+            throughput::Direction::Upload => "Upload",
+        };
+        println!(
+            "{} {}",
+            output::label(format, format!("Tuning ({name}):")),
+            advice.describe()
+        );
+    }
 }
 
 fn main() {
-    let config: UserArgs = argh::from_env();
-    config.validate().expect("Invalid arguments");
+    install_panic_hook();
+
+    let mut config = args_from_env();
+    if let Some(path) = config.replay.clone() {
+        config = replay_manifest(&path, &config).unwrap_or_else(|err| {
+            exit_with_error(
+                &config,
+                EXIT_INVALID_ARGUMENTS,
+                "Couldn't replay the manifest",
+                err,
+            )
+        });
+    }
+    if let Err(err) = config.validate() {
+        exit_with_error(&config, EXIT_INVALID_ARGUMENTS, "Invalid arguments", err);
+    }
+    logging::init(config.verbose);
 
-    print_test_preamble();
+    let interrupt = Interrupt::default();
+    let timed_out = config
+        .overall_timeout
+        .map(|timeout| start_watchdog(&config, timeout.0, &interrupt));
 
-    let mut down_measurements: Vec<usize> = Vec::new();
-    let mut up_measurements: Vec<usize> = Vec::new();
+    match &config.command {
+        Some(Command::Import(import)) => {
+            if let Err(err) = run_import(&config, import) {
+                exit_with_error(&config, EXIT_HISTORY_FAILED, "Couldn't import results", err);
+            }
+            return;
+        }
+        Some(Command::History(history)) => {
+            let result = match &history.command {
+                HistoryCommand::Export(export) => run_history_export(&config, export),
+                HistoryCommand::List(list) => run_history_list(&config, list),
+                HistoryCommand::Trends(trends) => run_history_trends(&config, trends),
+            };
+            if let Err(err) = result {
+                exit_with_error(&config, EXIT_HISTORY_FAILED, "Couldn't read history", err);
+            }
+            return;
+        }
+        Some(Command::Check(_)) => {
+            if !run_check(&config) {
+                std::process::exit(EXIT_TEST_FAILED);
+            }
+            return;
+        }
+        Some(Command::Doctor(_)) => {
+            if !run_doctor(&config) {
+                std::process::exit(EXIT_TEST_FAILED);
+            }
+            return;
+        }
+        Some(Command::FailoverTest(_)) => {
+            let variants = failover_variants(&config).unwrap_or_else(|err| {
+                exit_with_error(&config, EXIT_INVALID_ARGUMENTS, "Invalid WAN profiles", err)
+            });
+            if let Err(err) = run_comparison(&config, &variants) {
+                exit_with_error(&config, EXIT_TEST_FAILED, "Couldn't compare WAN links", err);
+            }
+            return;
+        }
+        Some(Command::Diff(diff)) => {
+            if let Err(err) = run_diff(&config, diff) {
+                exit_with_error(
+                    &config,
+                    EXIT_INVALID_ARGUMENTS,
+                    "Couldn't compare results",
+                    err,
+                );
+            }
+            return;
+        }
+        Some(Command::Daemon(_)) | None => {}
+    }
 
-    if !config.upload_only {
-        down_measurements = run_download_test(&config);
+    if config.dry_run {
+        if let Err(err) = run_dry_run(&config) {
+            exit_with_error(
+                &config,
+                EXIT_TEST_FAILED,
+                "Couldn't resolve test endpoints",
+                err,
+            );
+        }
+        return;
     }
 
-    if !config.download_only {
-        println!("Starting upload tests...");
-        up_measurements = run_upload_test(&config);
+    if let Some(daemon) = config.daemon_args() {
+        if let Err(err) = daemon::run_daemon(&config, &daemon) {
+            exit_with_error(
+                &config,
+                EXIT_INVALID_ARGUMENTS,
+                "Couldn't start daemon",
+                err,
+            );
+        }
+        return;
     }
 
-    let (download_median, download_avg, download_p90, _, _, _) =
-        compute_statistics(&mut down_measurements);
-    let (upload_median, upload_avg, upload_p90, _, _, _) = compute_statistics(&mut up_measurements);
+    if let Some(rate) = config.calibrate {
+        match run_calibration(&config, rate) {
+            Ok(report) if !report.within_tolerance() => exit_with_error(
+                &config,
+                EXIT_TEST_FAILED,
+                "Calibration failed",
+                report.verdict().into(),
+            ),
+            Ok(_) => {}
+            Err(err) => exit_with_error(&config, EXIT_TEST_FAILED, "Couldn't calibrate", err),
+        }
+        return;
+    }
 
-    let mut table = Table::new();
-    table
-        .load_preset(UTF8_FULL)
-        .set_content_arrangement(comfy_table::ContentArrangement::Dynamic)
-        .set_header(vec![
-            Cell::new(""),
-            Cell::new("Median"),
-            Cell::new("Average"),
-            Cell::new("90th pctile"),
-        ]);
+    if config.acceptance {
+        match run_acceptance(&config) {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(EXIT_ASSERTIONS_FAILED),
+            Err(err) => exit_with_error(
+                &config,
+                EXIT_TEST_FAILED,
+                "Couldn't write acceptance report",
+                err,
+            ),
+        }
+        return;
+    }
 
-    // Populate rows based on computed statistics
-    table.add_row(vec![
-        Cell::new("Download"),
-        Cell::new(get_appropriate_byte_unit_rate(download_median as u64).1),
-        Cell::new(get_appropriate_byte_unit_rate(download_avg as u64).1),
-        Cell::new(get_appropriate_byte_unit_rate(download_p90 as u64).1),
-    ]);
+    if let Some(interfaces) = config.interfaces_to_compare() {
+        if let Err(err) = run_interface_comparison(&config, &interfaces) {
+            exit_with_error(
+                &config,
+                EXIT_TEST_FAILED,
+                "Couldn't compare interfaces",
+                err,
+            );
+        }
+        return;
+    }
 
-    table.add_row(vec![
-        Cell::new("Upload"),
-        Cell::new(get_appropriate_byte_unit_rate(upload_median as u64).1),
-        Cell::new(get_appropriate_byte_unit_rate(upload_avg as u64).1),
-        Cell::new(get_appropriate_byte_unit_rate(upload_p90 as u64).1),
-    ]);
+    if config.compare_stacks {
+        if let Err(err) = run_stack_comparison(&config) {
+            exit_with_error(
+                &config,
+                EXIT_TEST_FAILED,
+                "Couldn't compare IPv4 and IPv6",
+                err,
+            );
+        }
+        return;
+    }
+
+    if config.compare_resolvers {
+        if let Err(err) = run_resolver_comparison(&config) {
+            exit_with_error(&config, EXIT_TEST_FAILED, "Couldn't compare resolvers", err);
+        }
+        return;
+    }
 
-    print!("\n{}\n{}\n", get_current_timestamp(), table);
+    if config.pattern == bursts::TrafficPattern::Bursty {
+        install_interrupt_handler(&interrupt);
+        match run_bursty(&config, &interrupt) {
+            Ok(summary) if summary.burst_bps.is_none() => exit_with_error(
+                &config,
+                EXIT_TEST_FAILED,
+                "Every burst failed",
+                summary.last_error.unwrap_or_default().into(),
+            ),
+            Ok(_) => {}
+            Err(err) => {
+                exit_with_error(&config, EXIT_INVALID_ARGUMENTS, "Invalid phase config", err)
+            }
+        }
+        return;
+    }
+
+    if let Some(max_age) = config.cached {
+        match get_cached_run(&config, max_age.0) {
+            Ok(Some(run)) => {
+                let timestamp = run.timestamp.with_timezone(&chrono::Local);
+                let timestamp = format!("{} (cached)", timestamp.format("%Y-%m-%d %H:%M:%S %Z"));
+                if let Err(err) = print_run(&config, &run, timestamp) {
+                    exit_with_error(
+                        &config,
+                        EXIT_HISTORY_FAILED,
+                        "Couldn't print cached run",
+                        err,
+                    );
+                }
+                return;
+            }
+            Ok(None) => {}
+            // a fresh test is still better than nothing
+            Err(err) => eprintln!("Couldn't read cached run from history: {err}"),
+        }
+    }
+
+    // a monitoring system needs its status on stdout and a code it knows
+    let nagios = config.output_format() == OutputFormat::Nagios;
+    // --on-metered warn is left to the run's warnings
+    if config.on_metered == args::MeteredPolicy::Skip
+        && host::detect(config.via_interface.as_deref()).metered == Some(true)
+    {
+        if nagios {
+            exit_nagios_unknown("skipped, the connection is metered");
+        }
+        eprintln!("Skipping the test, the connection is metered");
+        print_error_json(
+            &config,
+            EXIT_SKIPPED_METERED,
+            "Skipping the test, the connection is metered",
+            None,
+        );
+        std::process::exit(EXIT_SKIPPED_METERED);
+    }
+
+    install_interrupt_handler(&interrupt);
+    let run = match run_speedtest(&config, &interrupt) {
+        Ok(run) => run,
+        Err(err) if nagios => exit_nagios_unknown(&format!("couldn't reach Cloudflare: {err}")),
+        Err(err) if err.is::<results::PartialRun>() => {
+            exit_with_error(&config, EXIT_TEST_FAILED, "Couldn't finish the test", err)
+        }
+        Err(err) => exit_with_error(&config, EXIT_TEST_FAILED, "Couldn't reach Cloudflare", err),
+    };
+    if run.interrupted && nagios {
+        exit_nagios_unknown("the test was interrupted");
+    }
+    // a partial run would drag history's trends down
+    if run.interrupted {
+        let timed_out = timed_out.is_some_and(|timed_out| timed_out.load(Ordering::SeqCst));
+        std::process::exit(if timed_out {
+            EXIT_TIMED_OUT
+        } else {
+            EXIT_INTERRUPTED
+        });
+    }
+
+    if let Err(err) = record_run(&config, &run) {
+        exit_with_error(
+            &config,
+            EXIT_HISTORY_FAILED,
+            "Couldn't record run in history",
+            err,
+        );
+    }
+
+    if nagios {
+        let critical = config.critical_assertions();
+        std::process::exit(output::nagios_line(&run, &config.warning, &critical).0 as i32);
+    }
+
+    // the first threshold that failed decides the code, in the order they're
+    // documented
+    let failed = |text: &str| {
+        run.assertions
+            .iter()
+            .any(|outcome| !outcome.passed && outcome.assertion == text)
+    };
+    for (threshold, assertion) in config.thresholds() {
+        if failed(assertion.text()) {
+            std::process::exit(match threshold {
+                args::Threshold::MinDownload => EXIT_BELOW_MIN_DOWNLOAD,
+                args::Threshold::MinUpload => EXIT_BELOW_MIN_UPLOAD,
+                args::Threshold::MaxLatency => EXIT_ABOVE_MAX_LATENCY,
+            });
+        }
+    }
+    if run.assertions.iter().any(|outcome| !outcome.passed) {
+        std::process::exit(EXIT_ASSERTIONS_FAILED);
+    }
 }
@@ -0,0 +1,206 @@
+/* --manifest: every setting a run used, as JSON, so someone helping with a
+   problem can run the same test with --replay. The command line is kept
+   as it was given and parsed again on replay, while the phases are kept
+   as they were worked out from it (after --auto-tune, a --phase-config
+   file and this version's defaults) and replayed as they are, so they
+   hold even if the file isn't at hand or a later version picks other
+   defaults. Where the results went (--history, --output-file, --mqtt and
+   so on) and what they were checked against (--assertions, --min-download
+   and so on) aren't part of how the test ran and aren't replayed.
+*/
+use crate::args::UserArgs;
+use crate::phases::{PhaseConfig, PhaseConfigs};
+use crate::throughput::Direction;
+use crate::Speedtest;
+use argh::FromArgs;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PhaseManifest {
+    pub url: String,
+    #[serde(flatten)]
+    pub config: PhaseConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Manifest {
+    // of cf_speedtest, which decides what the command line means
+    pub version: String,
+    // e.g. "linux x86_64"
+    pub platform: String,
+    // the command line, less --manifest
+    pub args: Vec<String>,
+    pub backend: String,
+    // the provider the run went to, a fallback if the first didn't answer
+    pub host: String,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub fallbacks: Vec<String>,
+    pub download: Option<PhaseManifest>,
+    pub upload: Option<PhaseManifest>,
+}
+
+impl Manifest {
+    pub fn new(args: Vec<String>, speedtest: &Speedtest) -> Self {
+        let provider = &speedtest.provider;
+        let phase = |direction: Direction, config: &Option<PhaseConfig>, url: &Option<String>| {
+            config.as_ref().map(|config| PhaseManifest {
+                url: url.clone().unwrap_or_else(|| provider.url(direction)),
+                config: PhaseConfig {
+                    threads: provider.streams().unwrap_or(config.threads),
+                    ..config.clone()
+                },
+            })
+        };
+        let custom = speedtest.download_url.is_some() || speedtest.upload_url.is_some();
+
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            platform: format!("{} {}", std::env::consts::OS, std::env::consts::ARCH),
+            args,
+            backend: if custom {
+                "custom"
+            } else {
+                provider.kind().name()
+            }
+            .to_owned(),
+            host: provider.host(),
+            fallbacks: speedtest.fallbacks.iter().map(|f| f.describe()).collect(),
+            download: phase(
+                Direction::Download,
+                &speedtest.download,
+                &speedtest.download_url,
+            ),
+            upload: phase(Direction::Upload, &speedtest.upload, &speedtest.upload_url),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)? + "\n")
+            .map_err(|err| format!("couldn't write {}: {err}", path.display()).into())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("couldn't read {}: {err}", path.display()))?;
+        serde_json::from_str(&text).map_err(|err| format!("{}: {err}", path.display()).into())
+    }
+
+    // The phases as the run had them, a phase it skipped isn't run on
+    // replay either so its defaults don't matter
+    pub fn phases(&self) -> PhaseConfigs {
+        let pinned = |phase: &Option<PhaseManifest>, direction| {
+            phase
+                .as_ref()
+                .map(|phase| phase.config.clone())
+                .unwrap_or_else(|| PhaseConfig::default_for(direction))
+        };
+
+        PhaseConfigs {
+            download: pinned(&self.download, Direction::Download),
+            upload: pinned(&self.upload, Direction::Upload),
+        }
+    }
+
+    // The run's command line, to be run again from `path`. How the replay
+    // is shown (--output, --machine, --verbose) comes from `invoked`, the
+    // command line doing the replaying.
+    pub fn user_args(&self, path: &Path, invoked: &UserArgs) -> Result<UserArgs> {
+        let tokens: Vec<&str> = self.args.iter().map(String::as_str).collect();
+        let config = UserArgs::from_args(&["cf_speedtest"], &tokens).map_err(|early_exit| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, early_exit.output)
+        })?;
+        // the phases are pinned as they ran, ramp-up seconds and all
+        if invoked.strict_duration && !config.strict_duration {
+            return Err(
+                "--strict-duration can't change a --replay, its phases run as they \
+                were recorded; record the run with --strict-duration to replay it strictly"
+                    .into(),
+            );
+        }
+
+        Ok(UserArgs {
+            replay: Some(path.to_owned()),
+            manifest: None,
+            // the phases are pinned, no need to probe or read the file
+            auto_tune: false,
+            phase_config: None,
+            output: invoked.output,
+            machine: invoked.machine,
+            verbose: invoked.verbose,
+            history: false,
+            history_db: None,
+            output_file: vec![],
+            results_file: None,
+            append: None,
+            mqtt: None,
+            mqtt_discovery: false,
+            assertions: None,
+            warning: vec![],
+            critical: vec![],
+            min_download: None,
+            min_upload: None,
+            max_latency: None,
+            // one run, even of a manifest a daemon wrote
+            command: None,
+            interval: None,
+            ..config
+        })
+    }
+
+    // What may make a replay differ from the run it's replaying
+    pub fn differences(&self, config: &UserArgs) -> Vec<String> {
+        let mut differences = vec![];
+
+        if self.version != env!("CARGO_PKG_VERSION") {
+            differences.push(format!(
+                "the manifest was written by cf_speedtest {}, this is {}",
+                self.version,
+                env!("CARGO_PKG_VERSION")
+            ));
+        }
+        let host = config.provider().host();
+        if self.host != host {
+            differences.push(format!(
+                "the run went to {} (a fallback), the replay starts with {host}",
+                self.host
+            ));
+        }
+
+        differences
+    }
+}
+
+// Options whose values a manifest leaves out, with the option: where it
+// was written, and commands that could hold anything (tokens, passwords)
+static UNRECORDED_OPTIONS: [&str; 2] = ["--manifest", "--alert-command"];
+
+// The command line of this process as a manifest keeps it. A manifest is
+// meant to be handed to someone else, so credentials in URLs (--proxy,
+// --mqtt, --doh and the like) are left out too.
+pub fn recorded_args(mut args: impl Iterator<Item = String>) -> Vec<String> {
+    let mut recorded = vec![];
+    while let Some(arg) = args.next() {
+        if UNRECORDED_OPTIONS.contains(&arg.as_str()) {
+            args.next();
+            continue;
+        }
+        recorded.push(without_userinfo(&arg));
+    }
+
+    recorded
+}
+
+// A URL less the user:password@ in it, anything else as it is
+pub fn without_userinfo(arg: &str) -> String {
+    let Some((scheme, rest)) = arg.split_once("://") else {
+        return arg.to_owned();
+    };
+    let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    match rest[..authority_end].rsplit_once('@') {
+        Some((_, host)) => format!("{scheme}://{host}{}", &rest[authority_end..]),
+        None => arg.to_owned(),
+    }
+}
@@ -0,0 +1,280 @@
+/* --mqtt: each run's result JSON published to a broker, e.g. for Home
+   Assistant or Node-RED. Just enough MQTT 3.1.1 for that, with no broker
+   connection kept between runs: connect, publish at QoS 0, disconnect.
+   The result is retained so a dashboard that starts later still has the
+   latest run. With --mqtt-discovery, Home Assistant's discovery configs go
+   out first, which add the figures as sensors without any YAML.
+*/
+use crate::results::RunResult;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+pub static DEFAULT_PORT: u16 = 1883;
+pub static DEFAULT_TOPIC: &str = "cf_speedtest";
+// Where Home Assistant looks for discovery configs unless told otherwise
+static DISCOVERY_PREFIX: &str = "homeassistant";
+static TIMEOUT_SECS: u64 = 10;
+
+// Packet types, in the high nibble of the first byte
+static CONNECT: u8 = 0x10;
+static CONNACK: u8 = 0x20;
+static PUBLISH: u8 = 0x30;
+static DISCONNECT: u8 = 0xe0;
+
+// An mqtt://[user[:password]@]host[:port] URL
+#[derive(Clone, Debug, PartialEq)]
+pub struct Broker {
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl std::str::FromStr for Broker {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("invalid broker '{s}', expected e.g. mqtt://broker.lan:1883");
+        let rest = s.strip_prefix("mqtt://").ok_or_else(|| {
+            if s.starts_with("mqtts://") {
+                format!("'{s}' wants TLS, which --mqtt doesn't do yet, use mqtt://")
+            } else {
+                invalid()
+            }
+        })?;
+        let rest = rest.trim_end_matches('/');
+
+        let (credentials, netloc) = match rest.rsplit_once('@') {
+            Some((credentials, netloc)) => (Some(credentials), netloc),
+            None => (None, rest),
+        };
+        let (username, password) = match credentials.map(|c| c.split_once(':')) {
+            Some(Some((username, password))) => {
+                (Some(username.to_owned()), Some(password.to_owned()))
+            }
+            Some(None) => (credentials.map(str::to_owned), None),
+            None => (None, None),
+        };
+
+        // a bracketed IPv6 address has colons of its own
+        let (host, port) = match netloc.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|_| invalid())?)
+            }
+            _ => (netloc, DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_owned(),
+            port,
+            username,
+            password,
+        })
+    }
+}
+
+// One message to publish
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+// MQTT's variable length encoding of the rest of a packet's length
+fn remaining_length(mut len: usize) -> Vec<u8> {
+    let mut encoded = vec![];
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        encoded.push(byte);
+        if len == 0 {
+            return encoded;
+        }
+    }
+}
+
+fn packet(first_byte: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![first_byte];
+    packet.extend(remaining_length(body.len()));
+    packet.extend(body);
+    packet
+}
+
+// A length-prefixed UTF-8 string
+fn string(s: &str) -> Vec<u8> {
+    let mut encoded = (s.len() as u16).to_be_bytes().to_vec();
+    encoded.extend(s.as_bytes());
+    encoded
+}
+
+pub fn connect_packet(broker: &Broker, client_id: &str) -> Vec<u8> {
+    // clean session, with the credentials we have
+    let mut flags = 0x02;
+    if broker.username.is_some() {
+        flags |= 0x80;
+    }
+    if broker.password.is_some() {
+        flags |= 0x40;
+    }
+
+    let mut body = string("MQTT");
+    body.push(4); // protocol level 3.1.1
+    body.push(flags);
+    body.extend(60u16.to_be_bytes()); // keep alive, not that we stay that long
+    body.extend(string(client_id));
+    for credential in [&broker.username, &broker.password].into_iter().flatten() {
+        body.extend(string(credential));
+    }
+
+    packet(CONNECT, &body)
+}
+
+pub fn publish_packet(message: &Message) -> Vec<u8> {
+    let mut body = string(&message.topic);
+    body.extend(message.payload.as_bytes());
+    packet(PUBLISH | u8::from(message.retain), &body)
+}
+
+// Why the broker turned us away, from the CONNACK return code
+fn refusal(code: u8) -> &'static str {
+    match code {
+        1 => "it doesn't speak MQTT 3.1.1",
+        2 => "it rejected the client id",
+        3 => "the service is unavailable",
+        4 => "bad username or password",
+        5 => "not authorized",
+        _ => "unknown reason",
+    }
+}
+
+// Connect, publish `messages` in order and disconnect
+pub fn publish(broker: &Broker, messages: &[Message]) -> Result<()> {
+    let timeout = Duration::from_secs(TIMEOUT_SECS);
+    let addr = (broker.host.as_str(), broker.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("{} has no addresses", broker.host))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let client_id = format!("cf_speedtest-{}", std::process::id());
+    stream.write_all(&connect_packet(broker, &client_id))?;
+    let mut connack = [0; 4];
+    stream.read_exact(&mut connack)?;
+    if connack[0] != CONNACK {
+        return Err(format!("{} didn't answer like an MQTT broker", broker.host).into());
+    }
+    if connack[3] != 0 {
+        return Err(format!(
+            "{} refused the connection: {}",
+            broker.host,
+            refusal(connack[3])
+        )
+        .into());
+    }
+
+    for message in messages {
+        stream.write_all(&publish_packet(message))?;
+    }
+    stream.write_all(&packet(DISCONNECT, &[]))?;
+
+    Ok(())
+}
+
+/* Home Assistant sensors for a run's figures, read off the result JSON
+   published to `topic`. Devices are told apart by topic, so two machines
+   publishing to different topics show up as two devices.
+*/
+pub fn discovery_messages(topic: &str) -> Vec<Message> {
+    let node_id: String = topic
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let sensors = [
+        (
+            "download",
+            "Download",
+            "Mbit/s",
+            "data_rate",
+            "{{ (value_json.download.median_bps / 1000000) | round(1) }}",
+        ),
+        (
+            "upload",
+            "Upload",
+            "Mbit/s",
+            "data_rate",
+            "{{ (value_json.upload.median_bps / 1000000) | round(1) }}",
+        ),
+        (
+            "latency",
+            "Latency",
+            "ms",
+            "duration",
+            "{{ value_json.latency_ms | round(1) }}",
+        ),
+        (
+            "jitter",
+            "Jitter",
+            "ms",
+            "duration",
+            "{{ value_json.idle_latency.jitter_ms | round(1) }}",
+        ),
+    ];
+
+    sensors
+        .into_iter()
+        .map(|(key, name, unit, device_class, value_template)| {
+            let config = serde_json::json!({
+                "name": name,
+                "unique_id": format!("{node_id}_{key}"),
+                "state_topic": topic,
+                "value_template": value_template,
+                "unit_of_measurement": unit,
+                "device_class": device_class,
+                "state_class": "measurement",
+                "device": {
+                    "identifiers": [node_id],
+                    "name": format!("Speed test ({topic})"),
+                    "model": "cf_speedtest",
+                    "sw_version": env!("CARGO_PKG_VERSION"),
+                },
+            });
+
+            Message {
+                topic: format!("{DISCOVERY_PREFIX}/sensor/{node_id}/{key}/config"),
+                payload: config.to_string(),
+                retain: true,
+            }
+        })
+        .collect()
+}
+
+// What --mqtt publishes after a run
+pub fn run_messages(run: &RunResult, topic: &str, discovery: bool) -> Result<Vec<Message>> {
+    let mut messages = if discovery {
+        discovery_messages(topic)
+    } else {
+        vec![]
+    };
+    messages.push(Message {
+        topic: topic.to_owned(),
+        payload: serde_json::to_string(run)?,
+        retain: true,
+    });
+
+    Ok(messages)
+}
@@ -0,0 +1,300 @@
+/* M-Lab's ndt7 protocol, for cross-checking Cloudflare's figures against
+   M-Lab's (https://github.com/m-lab/ndt-server/blob/main/spec/ndt7-protocol.md).
+   M-Lab's locate service picks the nearest server and hands out URLs for
+   it that carry a short-lived access token. Each direction is then one WebSocket: the server fills it
+   with binary messages for a download, we fill it for an upload, for up to
+   ten seconds. The server's own measurements arrive as text messages,
+   which are counted like any other bytes and otherwise ignored.
+*/
+use crate::net::{ConnectOptions, Stream};
+use crate::throughput::{Counters, Direction, StreamTarget};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::{Message, WebSocket};
+use ureq::Agent;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+pub static LOCATE_HOST: &str = "locate.measurementlab.net";
+static LOCATE_URL: &str = "https://locate.measurementlab.net/v2/nearest/ndt/ndt7";
+static SUBPROTOCOL: &str = "net.measurementlab.ndt.v7";
+
+// Upload messages start small and double while they're under 1/16th of
+// what's been sent, up to the biggest the server accepts, as the spec
+// recommends
+static MIN_MESSAGE_BYTES: usize = 1 << 13;
+static MAX_MESSAGE_BYTES: usize = 1 << 24;
+static MESSAGE_SCALING_FRACTION: usize = 16;
+
+// How long a read waits before we check whether the phase is over
+static READ_POLL_MILLIS: u64 = 250;
+
+// How long to wait for the server to finish the closing handshake
+static CLOSE_WAIT_MILLIS: u64 = 2000;
+
+// Probes of the server for its idle latency
+static LATENCY_PROBES: usize = 8;
+
+// The server the locate service picked, and where to connect to it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Server {
+    pub machine: String,
+    // the site the machine is at, e.g. lga05
+    pub site: Option<String>,
+    pub download_url: String,
+    pub upload_url: String,
+}
+
+// Parse the locate service's answer, taking the `machine` asked for if it's
+// among the servers offered, or else the first (nearest)
+pub fn parse_locate(body: &str, machine: Option<&str>) -> Result<Server> {
+    let json: serde_json::Value = serde_json::from_str(body)?;
+    let results = json["results"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    let nearest = results
+        .iter()
+        .find(|result| machine.is_some() && result["machine"].as_str() == machine)
+        .or(results.first())
+        .unwrap_or(&serde_json::Value::Null);
+    let url = |path: &str| {
+        nearest["urls"][path]
+            .as_str()
+            .map(str::to_owned)
+            .ok_or_else(|| format!("M-Lab's locate service gave no {path} URL"))
+    };
+
+    Ok(Server {
+        machine: nearest["machine"]
+            .as_str()
+            .ok_or("M-Lab's locate service found no server")?
+            .to_owned(),
+        site: nearest["location"]["site"]
+            .as_str()
+            .or_else(|| nearest["site"].as_str())
+            .map(str::to_owned),
+        download_url: url("wss:///ndt/v7/download")?,
+        upload_url: url("wss:///ndt/v7/upload")?,
+    })
+}
+
+pub fn locate(agent: &Agent, machine: Option<&str>) -> Result<Server> {
+    let body = agent
+        .get(LOCATE_URL)
+        .set("User-Agent", crate::OUR_USER_AGENT)
+        .call()?
+        .into_string()?;
+    parse_locate(&body, machine)
+}
+
+// Open the WebSocket at `url` (ws:// or wss://), connecting as `connect` says
+fn open(url: &str, connect: &ConnectOptions) -> Result<WebSocket<Stream>> {
+    use tungstenite::client::IntoClientRequest;
+
+    let mut request = url.into_client_request()?;
+    let uri = request.uri().clone();
+    let host = uri.host().ok_or("ndt7 URL has no host")?;
+    let tls = uri.scheme_str() == Some("wss");
+    let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+
+    let stream = Stream::open(host, port, tls, connect)?;
+    stream
+        .socket()
+        .set_read_timeout(Some(Duration::from_millis(READ_POLL_MILLIS)))?;
+
+    let headers = request.headers_mut();
+    headers.insert("Sec-WebSocket-Protocol", SUBPROTOCOL.parse()?);
+    headers.insert("User-Agent", crate::OUR_USER_AGENT.parse()?);
+
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE_BYTES * 2),
+        max_frame_size: Some(MAX_MESSAGE_BYTES * 2),
+        ..WebSocketConfig::default()
+    };
+    // the handshake mustn't give up on the read timeout we poll with
+    let mut handshake = tungstenite::client::client_with_config(request, stream, Some(config));
+    let started = Instant::now();
+    loop {
+        match handshake {
+            Ok((socket, _)) => return Ok(socket),
+            Err(tungstenite::HandshakeError::Interrupted(mid))
+                if started.elapsed()
+                    < Duration::from_millis(crate::net::CONNECT_TIMEOUT_MILLIS) =>
+            {
+                handshake = mid.handshake();
+            }
+            Err(err) => return Err(err.to_string().into()),
+        }
+    }
+}
+
+// Whether a read only timed out, so the phase can be checked on
+fn timed_out(err: &tungstenite::Error) -> bool {
+    matches!(err, tungstenite::Error::Io(err)
+        if matches!(err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut))
+}
+
+/* Close the WebSocket and wait for the server to agree. Until it does, what
+   it sent is left unread, and dropping the socket then would reset the
+   connection and lose our last messages in flight.
+*/
+fn close(socket: &mut WebSocket<Stream>) {
+    let _ = socket.close(None);
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_millis(CLOSE_WAIT_MILLIS) {
+        match socket.read() {
+            Ok(_) => {}
+            Err(err) if timed_out(&err) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+fn download(socket: &mut WebSocket<Stream>, counters: &Counters) -> Result<()> {
+    while !counters.exit_signal.load(Ordering::Relaxed) {
+        let len = match socket.read() {
+            Ok(Message::Binary(data)) => data.len(),
+            Ok(Message::Text(text)) => text.len(),
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(_) => continue,
+            Err(err) if timed_out(&err) => continue,
+            Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                return Ok(())
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if let Some(pacer) = &counters.pacer {
+            pacer.take(len);
+        }
+        counters.add_bytes(len);
+    }
+
+    close(socket);
+    Ok(())
+}
+
+fn upload(socket: &mut WebSocket<Stream>, counters: &Counters) -> Result<()> {
+    let mut message_bytes = MIN_MESSAGE_BYTES;
+    let mut sent = 0;
+
+    while !counters.exit_signal.load(Ordering::Relaxed) {
+        if let Some(pacer) = &counters.pacer {
+            pacer.take(message_bytes);
+        }
+        match socket.send(Message::Binary(vec![0; message_bytes])) {
+            Ok(()) => {}
+            Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                return Ok(())
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        sent += message_bytes;
+        counters.add_bytes(message_bytes);
+        if message_bytes < MAX_MESSAGE_BYTES && message_bytes <= sent / MESSAGE_SCALING_FRACTION {
+            message_bytes *= 2;
+        }
+    }
+
+    close(socket);
+    Ok(())
+}
+
+// Run one stream against the WebSocket at `url` until the server ends it
+// or the phase is over
+pub fn transfer(url: &str, direction: Direction, counters: &Counters) -> Result<()> {
+    exchange(&mut open(url, &counters.connect)?, direction, counters)
+}
+
+fn exchange(
+    socket: &mut WebSocket<Stream>,
+    direction: Direction,
+    counters: &Counters,
+) -> Result<()> {
+    match direction {
+        Direction::Download => download(socket, counters),
+        Direction::Upload => upload(socket, counters),
+    }
+}
+
+/* One direction of an ndt7 test, against the machine the run located and
+   measured idle latency to. Its URLs are asked for once per phase, as their
+   tokens expire, and again only if a stream can't open them.
+*/
+pub struct Ndt7Target {
+    pub direction: Direction,
+    pub machine: Option<String>,
+    pub urls: Located,
+}
+
+impl StreamTarget for Ndt7Target {
+    fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    fn stream(&self, _bytes: usize, counters: &Counters) -> Result<()> {
+        let agent = crate::build_agent(&counters.connect);
+        let server = self.urls.get(&agent, self.machine.as_deref())?;
+        let url = match self.direction {
+            Direction::Download => &server.download_url,
+            Direction::Upload => &server.upload_url,
+        };
+        // a token that's expired or been used is refused at the handshake
+        let mut socket = open(url, &counters.connect).inspect_err(|_| self.urls.forget())?;
+        exchange(&mut socket, self.direction, counters)
+    }
+}
+
+// The round trip of an HTTP request to the machine, any response will do
+fn probe_latency(agent: &Agent, machine: &str) -> Option<Duration> {
+    let start = Instant::now();
+    match agent
+        .get(&format!("https://{machine}/"))
+        .set("User-Agent", crate::OUR_USER_AGENT)
+        .call()
+    {
+        Ok(_) | Err(ureq::Error::Status(..)) => Some(start.elapsed()),
+        Err(_) => None,
+    }
+}
+
+// The nearest server (or `machine`), found once and kept until forgotten
+#[derive(Debug, Default)]
+pub struct Located(Mutex<Option<Server>>);
+
+impl Located {
+    pub fn get(&self, agent: &Agent, machine: Option<&str>) -> Result<Server> {
+        let mut located = self.0.lock().map_err(|_| "ndt7 locate lock poisoned")?;
+        if let Some(server) = &*located {
+            return Ok(server.clone());
+        }
+        let server = locate(agent, machine)?;
+        *located = Some(server.clone());
+        Ok(server)
+    }
+
+    pub fn forget(&self) {
+        if let Ok(mut located) = self.0.lock() {
+            *located = None;
+        }
+    }
+
+    pub fn machine(&self) -> Option<String> {
+        self.0
+            .lock()
+            .ok()
+            .and_then(|located| located.as_ref().map(|server| server.machine.clone()))
+    }
+}
+
+pub fn idle_latency(agent: &Agent, machine: &str) -> Result<crate::latency::IdleLatency> {
+    let samples: Vec<_> = (0..LATENCY_PROBES)
+        .filter_map(|_| probe_latency(agent, machine))
+        .collect();
+    Ok(crate::latency::IdleLatency::summarize(&samples)
+        .ok_or_else(|| format!("{machine} didn't answer any latency probes"))?)
+}
@@ -0,0 +1,514 @@
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub static CONNECT_TIMEOUT_MILLIS: u64 = 9600;
+
+// IPv4 or IPv6, for comparing the two when an ISP routes them differently
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::V4 => "IPv4",
+            Self::V6 => "IPv6",
+        }
+    }
+
+    pub fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            Self::V4 => addr.is_ipv4(),
+            Self::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+// --resolve, as curl has it: connect to `host` on `port` at these addresses
+// rather than the ones DNS gives, e.g. to pin the test to one Cloudflare
+// edge. The TLS handshake still names `host`, so certificates check out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolveOverride {
+    pub host: String,
+    pub port: u16,
+    pub addrs: Vec<IpAddr>,
+}
+
+impl ResolveOverride {
+    fn matches(&self, netloc: &str) -> bool {
+        netloc.rsplit_once(':').is_some_and(|(host, port)| {
+            host.eq_ignore_ascii_case(&self.host) && port.parse() == Ok(self.port)
+        })
+    }
+}
+
+impl std::str::FromStr for ResolveOverride {
+    type Err = String;
+
+    // host:port:addr[,addr...], an IPv6 address in brackets or not
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!("--resolve '{s}' should be host:port:address, e.g. speed.cloudflare.com:443:104.16.0.1")
+        };
+        let mut parts = s.splitn(3, ':');
+        let (Some(host), Some(port), Some(addrs)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let port = port.parse().map_err(|_| invalid())?;
+        let addrs = addrs
+            .split(',')
+            .map(|addr| addr.trim_start_matches('[').trim_end_matches(']').parse())
+            .collect::<Result<Vec<IpAddr>, _>>()
+            .map_err(|_| invalid())?;
+
+        Ok(Self {
+            host: host.to_owned(),
+            port,
+            addrs,
+        })
+    }
+}
+
+static SOCKS_PORT: u16 = 1080;
+
+// --proxy: a SOCKS5 proxy that looks names up itself, socks5h:// as curl
+// has it. Everything goes through it, names included, for networks where
+// only the proxy can resolve or reach the outside (a corporate split DNS,
+// Tor). socks5:// would have names looked up here first, which ureq can't
+// do through a proxy, so it's turned away rather than quietly treated the
+// same.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SocksProxy {
+    // host:port, without any credentials, for the results
+    pub server: String,
+    proxy: ureq::Proxy,
+}
+
+impl SocksProxy {
+    pub fn ureq(&self) -> ureq::Proxy {
+        self.proxy.clone()
+    }
+}
+
+impl std::str::FromStr for SocksProxy {
+    type Err = String;
+
+    // socks5h://[user:password@]host[:port]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = match s.split_once("://") {
+            Some(("socks5h", rest)) => rest.trim_end_matches('/'),
+            Some(("socks5" | "socks", _)) => {
+                return Err(format!(
+                    "--proxy '{s}' would look names up here, use socks5h:// so the proxy \
+                    looks them up"
+                ))
+            }
+            _ => {
+                return Err(format!(
+                    "--proxy '{s}' should be socks5h://host:port, e.g. socks5h://127.0.0.1:1080"
+                ))
+            }
+        };
+        let (credentials, server) = match rest.rsplit_once('@') {
+            Some((credentials, server)) => (format!("{credentials}@"), server),
+            None => (String::new(), rest),
+        };
+        let has_port = match server.rsplit_once(']') {
+            Some((_, after)) => after.starts_with(':'),
+            None => server.contains(':'),
+        };
+        let server = if has_port {
+            server.to_owned()
+        } else {
+            format!("{server}:{SOCKS_PORT}")
+        };
+
+        let proxy = ureq::Proxy::new(format!("socks5://{credentials}{server}"))
+            .map_err(|err| format!("--proxy '{s}': {err}"))?;
+        Ok(Self { server, proxy })
+    }
+}
+
+// An address in a host:port, where IPv6 is in brackets
+fn is_ip_literal(host: &str) -> bool {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .is_ok()
+}
+
+// How connections should be made, beyond what ureq lets us choose
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConnectOptions {
+    // send everything through this network interface, e.g. a VPN tunnel
+    pub interface: Option<String>,
+    // send everything from this local address, e.g. one WAN of a dual-WAN router
+    pub source: Option<IpAddr>,
+    // only connect to addresses of this family
+    pub family: Option<IpFamily>,
+    // addresses to use instead of looking names up, see --resolve
+    pub overrides: Vec<ResolveOverride>,
+    // who looks names up, if not the system, see --dns and --doh
+    pub dns: Option<crate::dns::NameServer>,
+    // where every connection goes through, see --proxy
+    pub proxy: Option<SocksProxy>,
+}
+
+impl ConnectOptions {
+    // Whether ureq's own connection will do, or we need to make another.
+    // The family is taken care of when resolving, see `resolver`.
+    pub fn is_default(&self) -> bool {
+        self.interface.is_none() && self.source.is_none()
+    }
+
+    // The addresses `netloc` (host:port) resolves to, leaving out those of
+    // the wrong family
+    pub fn resolve(&self, netloc: &str) -> std::io::Result<Vec<SocketAddr>> {
+        let resolved: Vec<_> = match self.overrides.iter().find(|o| o.matches(netloc)) {
+            Some(pinned) => pinned
+                .addrs
+                .iter()
+                .map(|&ip| SocketAddr::new(ip, pinned.port))
+                .collect(),
+            None => match (&self.dns, netloc.rsplit_once(':')) {
+                (Some(server), Some((host, port))) if !is_ip_literal(host) => {
+                    let port = port.parse().map_err(|_| {
+                        std::io::Error::new(std::io::ErrorKind::InvalidInput, netloc.to_owned())
+                    })?;
+                    let ips = server.lookup(host, self.family, self).map_err(|err| {
+                        std::io::Error::other(format!("looking up {host}: {err}"))
+                    })?;
+                    if ips.is_empty() {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::NotFound,
+                            format!("{} found no address for {host}", server.describe()),
+                        ));
+                    }
+                    ips.into_iter()
+                        .map(|ip| SocketAddr::new(ip, port))
+                        .collect()
+                }
+                _ => netloc.to_socket_addrs()?.collect(),
+            },
+        };
+        let addrs: Vec<_> = resolved
+            .into_iter()
+            .filter(|addr| self.family.is_none_or(|family| family.matches(addr)))
+            .collect();
+
+        match (addrs.is_empty(), self.family) {
+            (true, Some(family)) => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{netloc} has no {} address", family.name()),
+            )),
+            _ => Ok(addrs),
+        }
+    }
+
+    // For the results, where a connection to `host` would go first and who
+    // said so; None for an address, which nobody had to resolve, and behind
+    // --proxy, which resolves names where we can't see
+    pub fn resolved(&self, host: &str) -> Option<crate::results::Resolved> {
+        if is_ip_literal(host) || self.proxy.is_some() {
+            return None;
+        }
+        let netloc = format!("{host}:443");
+        let address = self.resolve(&netloc).ok()?.first()?.ip();
+        let resolver = match &self.dns {
+            _ if self.overrides.iter().any(|o| o.matches(&netloc)) => "--resolve".to_owned(),
+            Some(server) => server.describe(),
+            None => "system".to_owned(),
+        };
+
+        Some(crate::results::Resolved {
+            host: host.to_owned(),
+            address,
+            resolver,
+        })
+    }
+
+    // For ureq, which resolves names itself. Where connections have to be
+    // bound, ureq is sent to a stand-in instead, see `stand_in`.
+    pub fn resolver(&self) -> impl ureq::Resolver + 'static {
+        let connect = self.clone();
+        move |netloc: &str| {
+            if connect.is_default() {
+                connect.resolve(netloc)
+            } else {
+                Ok(vec![stand_in(netloc)?])
+            }
+        }
+    }
+
+    pub fn connect(&self, addr: SocketAddr) -> std::io::Result<TcpStream> {
+        let timeout = Duration::from_millis(CONNECT_TIMEOUT_MILLIS);
+        if self.is_default() {
+            TcpStream::connect_timeout(&addr, timeout)
+        } else {
+            connect_bound(self.interface.as_deref(), self.source, addr, timeout)
+        }
+    }
+
+    // To the first of `netloc`'s addresses that answers, as ureq would
+    pub fn connect_to(&self, netloc: &str) -> std::io::Result<TcpStream> {
+        let mut last_error = None;
+        for addr in self.resolve(netloc)? {
+            match self.connect(addr) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_error = Some(err),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{netloc} has no addresses"),
+            )
+        }))
+    }
+}
+
+/* ureq (2.x) always makes its own connection, unbound and over the default
+   route, and can't be handed one we made. So with --via-interface or
+   --source-address it's given the address of a stand-in listening on
+   loopback instead, and its connection never leaves the machine; the TLS
+   connector then makes the one real connection, bound, to the host and
+   port the stand-in is standing in for. A stand-in takes connections and
+   closes them again, one per host:port for the life of the process.
+*/
+static STAND_INS: Mutex<Vec<(String, SocketAddr)>> = Mutex::new(Vec::new());
+
+fn stand_in(netloc: &str) -> std::io::Result<SocketAddr> {
+    let mut stand_ins = STAND_INS.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some((_, addr)) = stand_ins.iter().find(|(n, _)| n == netloc) {
+        return Ok(*addr);
+    }
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || for _ in listener.incoming() {});
+    stand_ins.push((netloc.to_owned(), addr));
+    Ok(addr)
+}
+
+// The host:port a connection to `addr` was meant for, if `addr` is a
+// stand-in
+pub fn stood_in_for(addr: SocketAddr) -> Option<String> {
+    let stand_ins = STAND_INS.lock().unwrap_or_else(|err| err.into_inner());
+    stand_ins
+        .iter()
+        .find(|(_, a)| *a == addr)
+        .map(|(netloc, _)| netloc.clone())
+}
+
+// A plain or TLS connection for what ureq doesn't speak, ndt7's WebSockets
+// and --jumbo's pipelined requests
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+}
+
+impl Stream {
+    // To `host` at `port`, connecting as `connect` says
+    pub fn open(
+        host: &str,
+        port: u16,
+        tls: bool,
+        connect: &ConnectOptions,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let addr = connect
+            .resolve(&format!("{host}:{port}"))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("{host} has no addresses"))?;
+        let socket = connect.connect(addr)?;
+
+        Ok(if tls {
+            let server_name = rustls::ServerName::try_from(host.trim_matches(['[', ']']))?;
+            let conn = rustls::ClientConnection::new(crate::tls::client_config(), server_name)?;
+            Self::Tls(Box::new(rustls::StreamOwned::new(conn, socket)))
+        } else {
+            Self::Plain(socket)
+        })
+    }
+
+    pub fn socket(&self) -> &TcpStream {
+        match self {
+            Self::Plain(socket) => socket,
+            Self::Tls(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.read(buf),
+            Self::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.write(buf),
+            Self::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.flush(),
+            Self::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+pub fn interface_binding_supported() -> bool {
+    cfg!(any(target_os = "linux", target_os = "android"))
+}
+
+// Fill in a sockaddr for `addr`, returning its length
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn sockaddr(addr: SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+    // SAFETY: sockaddr_storage is big enough and aligned for either
+    let len = unsafe {
+        match addr {
+            SocketAddr::V4(addr) => {
+                let sin = &mut *(storage as *mut _ as *mut libc::sockaddr_in);
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+                std::mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = &mut *(storage as *mut _ as *mut libc::sockaddr_in6);
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_scope_id = addr.scope_id();
+                std::mem::size_of::<libc::sockaddr_in6>()
+            }
+        }
+    };
+
+    len as libc::socklen_t
+}
+
+// Connect with SO_BINDTODEVICE set to `interface` and/or bound to the local
+// address `source`, so the kernel routes the connection that way whatever
+// the routing table says
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn connect_bound(
+    interface: Option<&str>,
+    source: Option<IpAddr>,
+    addr: SocketAddr,
+    timeout: Duration,
+) -> std::io::Result<TcpStream> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    let check = |ret: libc::c_int| {
+        if ret < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    };
+
+    let family = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+
+    // SAFETY: plain syscalls on a socket we own, with buffers sized as we tell the kernel
+    unsafe {
+        let fd = check(libc::socket(
+            family,
+            libc::SOCK_STREAM | libc::SOCK_CLOEXEC,
+            0,
+        ))?;
+        let fd = OwnedFd::from_raw_fd(fd);
+        let raw = std::os::fd::AsRawFd::as_raw_fd(&fd);
+
+        if let Some(interface) = interface {
+            check(libc::setsockopt(
+                raw,
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                interface.as_ptr() as *const libc::c_void,
+                interface.len() as libc::socklen_t,
+            ))
+            .map_err(|err| {
+                std::io::Error::new(
+                    err.kind(),
+                    format!("couldn't bind to interface {interface}: {err}"),
+                )
+            })?;
+        }
+
+        if let Some(source) = source {
+            let mut storage: libc::sockaddr_storage = std::mem::zeroed();
+            let len = sockaddr(SocketAddr::new(source, 0), &mut storage);
+            check(libc::bind(
+                raw,
+                &storage as *const _ as *const libc::sockaddr,
+                len,
+            ))
+            .map_err(|err| {
+                std::io::Error::new(
+                    err.kind(),
+                    format!("couldn't bind to source address {source}: {err}"),
+                )
+            })?;
+        }
+
+        // connect() gives up after the send timeout
+        let timeout = libc::timeval {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+        };
+        check(libc::setsockopt(
+            raw,
+            libc::SOL_SOCKET,
+            libc::SO_SNDTIMEO,
+            &timeout as *const libc::timeval as *const libc::c_void,
+            std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+        ))?;
+
+        let mut storage: libc::sockaddr_storage = std::mem::zeroed();
+        let len = sockaddr(addr, &mut storage);
+        check(libc::connect(
+            raw,
+            &storage as *const _ as *const libc::sockaddr,
+            len,
+        ))?;
+
+        let stream = TcpStream::from(fd);
+        // the send timeout was only meant for connect()
+        stream.set_write_timeout(None)?;
+        Ok(stream)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn connect_bound(
+    _interface: Option<&str>,
+    _source: Option<IpAddr>,
+    _addr: SocketAddr,
+    _timeout: Duration,
+) -> std::io::Result<TcpStream> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "binding to an interface or source address is only supported on Linux and Android",
+    ))
+}
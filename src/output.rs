@@ -0,0 +1,619 @@
+use std::io::Write;
+
+// What we print on stdout while and after a test runs
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputFormat {
+    Human,
+    // human, narrow enough for a phone's terminal
+    Compact,
+    Json,
+    // one row per run, with a header unless --append finds one already there
+    Csv,
+    // one line per interval sample, flushed as soon as it's measured
+    CsvLive,
+    // a line protocol point per run, e.g. for Telegraf's exec input
+    Influx,
+    InfluxLive,
+    // iperf3 client style interval lines and sender/receiver summaries
+    Iperf,
+    // a JSON object per line for everything that happens, the result last
+    Ndjson,
+    // a Nagios/Icinga plugin's status line, with the status as exit code
+    Nagios,
+    // --quiet, nothing but a line of key=value pairs at the end
+    Quiet,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Self::Human),
+            "compact" => Ok(Self::Compact),
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            "csv-live" => Ok(Self::CsvLive),
+            "influx" => Ok(Self::Influx),
+            "influx-live" => Ok(Self::InfluxLive),
+            "iperf" => Ok(Self::Iperf),
+            "ndjson" => Ok(Self::Ndjson),
+            "nagios" => Ok(Self::Nagios),
+            _ => Err(format!(
+                "unknown output format '{s}', expected human, compact, json, csv, csv-live, influx, influx-live, iperf, ndjson or nagios"
+            )),
+        }
+    }
+}
+
+impl OutputFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Human => "human",
+            Self::Compact => "compact",
+            Self::Json => "json",
+            Self::Csv => "csv",
+            Self::CsvLive => "csv-live",
+            Self::Influx => "influx",
+            Self::InfluxLive => "influx-live",
+            Self::Iperf => "iperf",
+            Self::Ndjson => "ndjson",
+            Self::Nagios => "nagios",
+            Self::Quiet => "quiet",
+        }
+    }
+
+    // Whether the per-interval samples are the output, rather than a final result
+    pub fn streams_samples(self) -> bool {
+        matches!(
+            self,
+            Self::CsvLive | Self::InfluxLive | Self::Iperf | Self::Ndjson
+        )
+    }
+
+    // Anything else is for machines, so keep progress and chatter off stdout
+    pub fn is_human(self) -> bool {
+        matches!(self, Self::Human | Self::Compact)
+    }
+}
+
+// A label for a line of human output, padded so the values line up
+pub fn label(format: OutputFormat, label: impl std::fmt::Display) -> String {
+    let width = if format == OutputFormat::Compact {
+        12
+    } else {
+        32
+    };
+    format!("{label:<width$}")
+}
+
+/* A phase's results for --output compact, instead of a row of the table
+   (all in the median's unit, see `bit_rate_unit`, to keep the second line
+   short):
+
+     Download:    94.21 Mbit/s median
+                  avg 91.07, p90 96.12, peak 97.40
+*/
+pub fn compact_phase_lines(name: &str, phase: &crate::results::PhaseSummary) -> [String; 2] {
+    let indent = label(OutputFormat::Compact, "");
+    let (unit, scale) = crate::bit_rate_unit(phase.median_bps);
+    [
+        format!(
+            "{} {:.2} {unit} median",
+            label(OutputFormat::Compact, format!("{name}:")),
+            phase.median_bps / scale
+        ),
+        format!(
+            "{indent} avg {:.2}, p90 {:.2}, peak {:.2}",
+            phase.average_bps / scale,
+            phase.p90_bps / scale,
+            phase.max_bps / scale
+        ),
+    ]
+}
+
+/* iperf3's adaptive units: three significant figures, bytes scaled by 1024
+   and bits by 1000, e.g. "11.2 MBytes" and "94.1 Mbits"
+*/
+fn iperf_unit(value: f64, base: f64, units: [&str; 5]) -> String {
+    let mut value = value;
+    let mut level = 0;
+
+    while value >= base && level < units.len() - 1 {
+        value /= base;
+        level += 1;
+    }
+
+    let number = if value < 9.995 {
+        format!("{value:4.2}")
+    } else if value < 99.95 {
+        format!("{value:4.1}")
+    } else {
+        format!("{value:4.0}")
+    };
+
+    format!("{number} {}", units[level])
+}
+
+pub fn iperf_line(start: f64, end: f64, bytes: f64, suffix: &str) -> String {
+    let seconds = (end - start).max(f64::EPSILON);
+    let transfer = iperf_unit(
+        bytes,
+        1024.0,
+        ["Bytes", "KBytes", "MBytes", "GBytes", "TBytes"],
+    );
+    let bitrate = iperf_unit(
+        bytes * 8.0 / seconds,
+        1000.0,
+        ["bits", "Kbits", "Mbits", "Gbits", "Tbits"],
+    );
+
+    format!("[SUM] {start:6.2}-{end:<6.2} sec  {transfer}  {bitrate}/sec{suffix}")
+        .trim_end()
+        .to_owned()
+}
+
+static IPERF_INTERVAL_HEADER: &str = "[ ID] Interval           Transfer     Bitrate";
+
+/* A one-line chart of `values`, scaled between the smallest and largest,
+   e.g. "▂▃▁▅█▇". Missing values are blank.
+*/
+pub fn sparkline(values: &[Option<f64>]) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+    let present = values.iter().flatten().copied();
+    let min = present.clone().fold(f64::INFINITY, f64::min);
+    let max = present.fold(f64::NEG_INFINITY, f64::max);
+
+    values
+        .iter()
+        .map(|value| match value {
+            None => ' ',
+            // all the same, so a flat line halfway up
+            Some(_) if max - min <= f64::EPSILON => BARS[BARS.len() / 2],
+            Some(value) => {
+                let level = (value - min) / (max - min) * (BARS.len() - 1) as f64;
+                BARS[level.round() as usize]
+            }
+        })
+        .collect()
+}
+
+// Called once before the first sample of the run
+pub fn print_sample_header(format: OutputFormat) {
+    match format {
+        OutputFormat::CsvLive => println!("timestamp,phase,bytes,bits_per_second"),
+        OutputFormat::Iperf => println!(
+            "Connecting to host {}, port 443",
+            crate::CLOUDFLARE_SPEEDTEST_HOST
+        ),
+        _ => {}
+    }
+}
+
+/* One line of --output ndjson: `fields`, less any that are null, with the
+   type of line and, if the clock can be trusted, when it was printed:
+
+     {"phase":"download","second":3,...,"timestamp":"2024-05-01T18:00:03.120Z","type":"sample"}
+*/
+pub fn ndjson_line(kind: &str, fields: serde_json::Value) -> String {
+    let mut line = serde_json::Map::new();
+    line.insert("type".to_owned(), kind.into());
+    if let Some(now) = crate::clock::now() {
+        line.insert(
+            "timestamp".to_owned(),
+            now.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+                .into(),
+        );
+    }
+    if let serde_json::Value::Object(fields) = fields {
+        line.extend(fields.into_iter().filter(|(_, value)| !value.is_null()));
+    }
+
+    serde_json::Value::Object(line).to_string()
+}
+
+pub fn print_ndjson(kind: &str, fields: serde_json::Value) {
+    println!("{}", ndjson_line(kind, fields));
+    std::io::stdout().flush().unwrap();
+}
+
+// Called before the first sample of each phase
+pub fn print_phase_header(format: OutputFormat, phase: &str) {
+    if format == OutputFormat::Ndjson {
+        print_ndjson("phase_start", serde_json::json!({ "phase": phase }));
+    }
+    if format != OutputFormat::Iperf {
+        return;
+    }
+
+    // iperf3 calls the server sending to us reverse mode
+    if phase == "download" {
+        println!(
+            "Reverse mode, remote host {} is sending",
+            crate::CLOUDFLARE_SPEEDTEST_HOST
+        );
+    }
+    println!("{IPERF_INTERVAL_HEADER}");
+}
+
+// The timestamp at the end of a line protocol line, with its separating
+// space. Left off without a clock, so the database stamps it on arrival.
+fn influx_timestamp() -> String {
+    crate::clock::now()
+        .and_then(|now| now.timestamp_nanos_opt())
+        .map(|nanos| format!(" {nanos}"))
+        .unwrap_or_default()
+}
+
+// Tag values can't have unescaped commas, equals signs or spaces
+fn influx_tag(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+// This machine's name for the host tag, so runs from several machines can
+// share a bucket
+pub fn hostname() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+}
+
+// A Nagios plugin's states, which are its exit codes too
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NagiosStatus {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl NagiosStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::Warning => "WARNING",
+            Self::Critical => "CRITICAL",
+            Self::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+/* --output nagios: the plugin status line, with performance data Nagios,
+   Icinga and the like graph, speeds in Mbit/s:
+
+     SPEEDTEST WARNING - Download 91.07 Mbit/s, Upload 19.50 Mbit/s, Latency 11.50ms (failed: download >= 100Mbit) | download=91.07;100:;50:;0; upload=19.50;;;0; latency=11.50ms;;;0; jitter=0.80ms;;;0;
+
+   A failed `critical` assertion, or a failed one in the run's, is CRITICAL,
+   else a failed `warning` one is WARNING, so the same metric can have both.
+*/
+pub fn nagios_line(
+    run: &crate::results::RunResult,
+    warning: &[crate::assertions::Assertion],
+    critical: &[crate::assertions::Assertion],
+) -> (NagiosStatus, String) {
+    let mut status = NagiosStatus::Ok;
+    let mut failed = vec![];
+    for (assertions, alert) in [
+        (critical, NagiosStatus::Critical),
+        (warning, NagiosStatus::Warning),
+    ] {
+        for assertion in assertions {
+            if !assertion.evaluate(run).passed {
+                status = status.max(alert);
+                failed.push(assertion.text());
+            }
+        }
+    }
+    // and those already checked with the run, --assertions among them
+    for outcome in run.assertions.iter().filter(|outcome| !outcome.passed) {
+        status = status.max(NagiosStatus::Critical);
+        if !failed.contains(&outcome.assertion.as_str()) {
+            failed.push(&outcome.assertion);
+        }
+    }
+
+    let mbit =
+        |phase: &Option<crate::results::PhaseSummary>| phase.as_ref().map(|p| p.median_bps / 1e6);
+    // label, value, unit of measure, and what makes a threshold's range
+    // the value's units
+    let metrics = [
+        ("download", mbit(&run.download), "", 1e6),
+        ("upload", mbit(&run.upload), "", 1e6),
+        ("latency", run.latency_ms, "ms", 1.0),
+        (
+            "jitter",
+            run.idle_latency.as_ref().map(|idle| idle.jitter_ms),
+            "ms",
+            1.0,
+        ),
+    ];
+    let range = |assertions: &[crate::assertions::Assertion], metric: &str, scale: f64| {
+        assertions
+            .iter()
+            .find(|assertion| assertion.metric() == metric)
+            .map(|assertion| assertion.nagios_range(scale))
+            .unwrap_or_default()
+    };
+
+    let mut summary = vec![];
+    let mut perfdata = vec![];
+    for (metric, value, unit, scale) in metrics {
+        let Some(value) = value else {
+            continue;
+        };
+        if metric != "jitter" {
+            let mut name = metric.to_owned();
+            name[..1].make_ascii_uppercase();
+            summary.push(match unit {
+                "" => format!("{name} {value:.2} Mbit/s"),
+                _ => format!("{name} {value:.2}{unit}"),
+            });
+        }
+        perfdata.push(format!(
+            "{metric}={value:.2}{unit};{};{};0;",
+            range(warning, metric, scale),
+            range(critical, metric, scale)
+        ));
+    }
+    if summary.is_empty() {
+        return (
+            NagiosStatus::Unknown,
+            "SPEEDTEST UNKNOWN - nothing was measured".to_owned(),
+        );
+    }
+
+    let failed = if failed.is_empty() {
+        String::new()
+    } else {
+        format!(" (failed: {})", failed.join(", "))
+    };
+    (
+        status,
+        format!(
+            "SPEEDTEST {} - {}{failed} | {}",
+            status.name(),
+            summary.join(", "),
+            perfdata.join(" ")
+        ),
+    )
+}
+
+/* --quiet: the run as one line of key=value pairs, for a log or for cut
+   and awk, e.g.
+
+     down=934.2Mbps up=38.1Mbps latency=11ms colo=JNB
+
+   Whatever wasn't measured is left out rather than printed empty.
+*/
+pub fn quiet_line(run: &crate::results::RunResult) -> String {
+    let mbps = |phase: &Option<crate::results::PhaseSummary>| {
+        phase
+            .as_ref()
+            .map(|p| format!("{:.1}Mbps", p.median_bps / 1e6))
+    };
+
+    [
+        ("down", mbps(&run.download)),
+        ("up", mbps(&run.upload)),
+        ("latency", run.latency_ms.map(|ms| format!("{ms:.0}ms"))),
+        ("colo", run.colo.clone()),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| Some(format!("{key}={}", value?)))
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+/* --output influx: one cf_speedtest point per run, e.g.
+   cf_speedtest,colo=JNB,country=ZA,host=pi download_bps=94000000,latency_ms=11.5 1704164645000000000
+   None when nothing was measured, a point needs at least one field
+*/
+pub fn influx_run_line(run: &crate::results::RunResult, host: Option<&str>) -> Option<String> {
+    let mut tags = String::new();
+    for (name, value) in [
+        ("colo", run.colo.as_deref()),
+        ("country", run.country.as_deref()),
+        ("host", host),
+    ] {
+        if let Some(value) = value {
+            tags += &format!(",{name}={}", influx_tag(value));
+        }
+    }
+
+    let fields: Vec<_> = [
+        ("download_bps", run.download.as_ref().map(|p| p.median_bps)),
+        ("upload_bps", run.upload.as_ref().map(|p| p.median_bps)),
+        ("latency_ms", run.latency_ms),
+        (
+            "jitter_ms",
+            run.idle_latency.as_ref().map(|idle| idle.jitter_ms),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| value.map(|value| format!("{name}={value}")))
+    .collect();
+    if fields.is_empty() {
+        return None;
+    }
+
+    // an untrusted clock gets the database's arrival time instead
+    let timestamp = (!run.clock_untrusted)
+        .then(|| run.timestamp.timestamp_nanos_opt())
+        .flatten()
+        .map(|nanos| format!(" {nanos}"))
+        .unwrap_or_default();
+
+    Some(format!(
+        "cf_speedtest{tags} {}{timestamp}",
+        fields.join(",")
+    ))
+}
+
+// Called for each event as soon as the sample after it has been printed.
+// Only the line protocol has somewhere to put them without breaking the
+// sample schema, everything else gets them in the final result.
+pub fn print_event(format: OutputFormat, event: &crate::results::Event) {
+    // the event's kind is the line's type, e.g. thread_started
+    if format == OutputFormat::Ndjson {
+        print_ndjson(
+            &event.kind,
+            serde_json::json!({
+                "phase": event.phase,
+                "at_secs": event.at_secs,
+                "thread": event.thread,
+                "detail": event.detail,
+            }),
+        );
+    }
+    if format != OutputFormat::InfluxLive {
+        return;
+    }
+
+    let mut fields = format!("at_secs={}", event.at_secs);
+    if let Some(thread) = event.thread {
+        fields += &format!(",thread={thread}i");
+    }
+    if let Some(detail) = &event.detail {
+        fields += &format!(
+            ",detail=\"{}\"",
+            detail.replace('\\', "\\\\").replace('"', "\\\"")
+        );
+    }
+
+    println!(
+        "cf_speedtest_event,phase={},kind={} {fields}{}",
+        event.phase,
+        event.kind,
+        influx_timestamp()
+    );
+    std::io::stdout().flush().unwrap();
+}
+
+// One line for --verbose, e.g. "[download 12.84s] thread 3 thread_failed: ..."
+pub fn describe_event(event: &crate::results::Event) -> String {
+    let mut line = format!("[{} {:.2}s]", event.phase, event.at_secs);
+    if let Some(thread) = event.thread {
+        line += &format!(" thread {thread}");
+    }
+    line += &format!(" {}", event.kind);
+    if let Some(detail) = &event.detail {
+        line += &format!(": {detail}");
+    }
+
+    line
+}
+
+// Each round trip measured under load, for --output ndjson
+pub fn print_latency(format: OutputFormat, phase: &str, round_trip: std::time::Duration) {
+    if format == OutputFormat::Ndjson {
+        print_ndjson(
+            "latency",
+            serde_json::json!({
+                "phase": phase,
+                "round_trip_ms": round_trip.as_secs_f64() * 1000.0,
+            }),
+        );
+    }
+}
+
+// Called once a phase is over with all of its samples, the summary leaving
+// out the first `warmup` seconds as the result's does
+pub fn print_phase_summary(
+    format: OutputFormat,
+    phase: &str,
+    measurements: &[usize],
+    warmup: usize,
+) {
+    if format == OutputFormat::Ndjson {
+        print_ndjson(
+            "phase_end",
+            serde_json::json!({
+                "phase": phase,
+                "summary": crate::summarize_after_warmup(measurements, warmup),
+            }),
+        );
+    }
+    if format != OutputFormat::Iperf {
+        return;
+    }
+
+    // we only see our own end, so sender and receiver agree
+    let seconds = measurements.len() as f64;
+    let bytes = measurements.iter().map(|&bytes| bytes as f64).sum::<f64>();
+    println!("- - - - - - - - - - - - - - - - - - - - - - - - -");
+    println!("{IPERF_INTERVAL_HEADER}");
+    for side in ["sender", "receiver"] {
+        println!(
+            "{}",
+            iperf_line(0.0, seconds, bytes, &format!("                  {side}"))
+        );
+    }
+    println!();
+}
+
+// Called once per interval with how many bytes moved in it, `second` counts
+// intervals from the start of the phase
+pub fn print_sample(format: OutputFormat, phase: &str, second: usize, bytes: usize) {
+    let bits_per_second = bytes as u64 * 8;
+
+    match format {
+        OutputFormat::Human => {
+            let (byte_speed, _) = crate::get_appropriate_byte_unit(bytes as u64);
+            let bit_speed = crate::format_bits_per_sec(bits_per_second as f64);
+            let mut label = phase.to_owned();
+            label[..1].make_ascii_uppercase();
+
+            println!(
+                "{:<10}{bit_speed:>16}       ({byte_speed:>10.*}/s)",
+                format!("{label}:"),
+                16,
+            );
+        }
+        OutputFormat::Compact => {
+            let bit_speed = crate::format_bits_per_sec(bits_per_second as f64);
+            let mut label = phase.to_owned();
+            label[..1].make_ascii_uppercase();
+
+            println!("{:<10}{bit_speed:>14}", format!("{label}:"));
+        }
+        // without a clock the timestamp column is left empty
+        OutputFormat::CsvLive => println!(
+            "{},{phase},{bytes},{bits_per_second}",
+            crate::clock::now()
+                .map(|now| now.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+                .unwrap_or_default()
+        ),
+        OutputFormat::InfluxLive => println!(
+            "cf_speedtest_sample,phase={phase} bytes={bytes}i,bits_per_second={bits_per_second}i{}",
+            influx_timestamp()
+        ),
+        OutputFormat::Iperf => println!(
+            "{}",
+            iperf_line(second as f64, second as f64 + 1.0, bytes as f64, "")
+        ),
+        OutputFormat::Ndjson => print_ndjson(
+            "sample",
+            serde_json::json!({
+                "phase": phase,
+                "second": second,
+                "bytes": bytes,
+                "bits_per_second": bits_per_second,
+            }),
+        ),
+        OutputFormat::Json
+        | OutputFormat::Csv
+        | OutputFormat::Influx
+        | OutputFormat::Nagios
+        | OutputFormat::Quiet => {}
+    }
+
+    std::io::stdout().flush().unwrap();
+}
@@ -0,0 +1,110 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Without --limit-burst, the bucket holds this long's worth of traffic
+static DEFAULT_BURST_MILLIS: f64 = 50.0;
+// and never less than one full read, or large reads would always wait
+static MIN_BURST_BYTES: f64 = 16384.0;
+
+// A rate on the command line, e.g. 50Mbit or 2.5gbps (a bare number is bits/s)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub bits_per_sec: f64,
+}
+
+impl std::str::FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rate '{s}', expected e.g. 50Mbit or 1.5Gbit");
+
+        let lower = s.trim().to_ascii_lowercase();
+        let number_end = lower
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(lower.len());
+        let (number, suffix) = lower.split_at(number_end);
+        let number: f64 = number.parse().map_err(|_| invalid())?;
+        let scale = match suffix.trim().trim_end_matches("/s") {
+            "" | "bit" | "bps" => 1.0,
+            "k" | "kbit" | "kbps" => 1e3,
+            "m" | "mbit" | "mbps" => 1e6,
+            "g" | "gbit" | "gbps" => 1e9,
+            _ => return Err(invalid()),
+        };
+
+        if number <= 0.0 {
+            return Err(format!("rate '{s}' must be more than zero"));
+        }
+
+        Ok(Self {
+            bits_per_sec: number * scale,
+        })
+    }
+}
+
+struct BucketState {
+    // may go negative: a read bigger than what's left is let through and
+    // the debt paid off by sleeping
+    tokens: f64,
+    refilled: Instant,
+}
+
+/* A token bucket shared by every worker of a phase, so a limited run moves
+   data at a steady overall rate instead of each thread bursting on its own.
+   Tokens are bytes; they trickle in at `rate` up to `burst`, and every read
+   or write takes what it moved.
+*/
+pub struct TokenBucket {
+    // bytes per second
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Rate, burst_bytes: Option<usize>) -> Self {
+        let rate = rate.bits_per_sec / 8.0;
+        let burst = burst_bytes.map_or_else(
+            || (rate * DEFAULT_BURST_MILLIS / 1000.0).max(MIN_BURST_BYTES),
+            |burst| burst as f64,
+        );
+
+        Self {
+            rate,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    // Account for `bytes` just moved, sleeping for as long as that puts us
+    // over the rate. Callers queue up behind each other's debt, so however
+    // many threads share the bucket the total stays at the rate.
+    pub fn take(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(state.refilled).as_secs_f64() * self.rate;
+            state.tokens = (state.tokens + refill).min(self.burst) - bytes as f64;
+            state.refilled = now;
+
+            if state.tokens < 0.0 {
+                Duration::from_secs_f64(-state.tokens / self.rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+
+    // The most a single read or write should move, so one thread can't run
+    // up a debt the others then have to wait out
+    pub fn max_chunk(&self) -> usize {
+        self.burst.max(1.0) as usize
+    }
+}
@@ -0,0 +1,352 @@
+use crate::throughput::Direction;
+use serde::{Deserialize, Serialize};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// What a phase does when nothing says otherwise
+static DEFAULT_THREADS: u32 = 8;
+static DEFAULT_BYTES_PER_REQUEST: usize = 50 * 1024 * 1024;
+// what --jumbo downloads at a time, in pipelined pieces
+static JUMBO_BYTES_PER_REQUEST: usize = 1024 * 1024 * 1024;
+static DEFAULT_SECONDS: u64 = 12;
+// what another thread has to add for --adaptive-threads to keep it
+static DEFAULT_SATURATION_GAIN_PERCENT: f64 = 5.0;
+
+// How long --auto-tune's probe downloads for, and with how many threads
+static PROBE_SECONDS: u64 = 2;
+static PROBE_THREADS: u32 = 4;
+
+// Roughly how fast a link is, as far as picking threads and request sizes goes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkClass {
+    // under 50 Mbit/s: a few threads on small requests, so nothing is left
+    // half-sent at the deadline
+    Slow,
+    // up to 2 Gbit/s, what the defaults were always meant for
+    Gigabit,
+    // over 2 Gbit/s: enough threads to spread over many Cloudflare metals,
+    // each throttles to a gigabit
+    MultiGigabit,
+}
+
+impl LinkClass {
+    pub fn from_bits_per_sec(bps: f64) -> Self {
+        if bps < 50e6 {
+            Self::Slow
+        } else if bps < 2e9 {
+            Self::Gigabit
+        } else {
+            Self::MultiGigabit
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Slow => "under 50 Mbit/s",
+            Self::Gigabit => "up to 2 Gbit/s",
+            Self::MultiGigabit => "multi-gigabit",
+        }
+    }
+
+    fn threads(self) -> u32 {
+        match self {
+            Self::Slow => 2,
+            Self::Gigabit => DEFAULT_THREADS,
+            Self::MultiGigabit => 16,
+        }
+    }
+
+    fn bytes_per_request(self) -> usize {
+        match self {
+            Self::Slow => 5 * 1024 * 1024,
+            Self::Gigabit => DEFAULT_BYTES_PER_REQUEST,
+            Self::MultiGigabit => 100 * 1024 * 1024,
+        }
+    }
+}
+
+// A quick download to see what class of link we're on, for --auto-tune.
+// Returns the class and the speed it was based on in bits/s.
+pub fn probe_link(
+    target: crate::throughput::Target,
+    connect: &crate::net::ConnectOptions,
+    pacer: Option<std::sync::Arc<crate::pacing::TokenBucket>>,
+) -> (LinkClass, f64) {
+    let config = PhaseConfig {
+        threads: PROBE_THREADS,
+        seconds: PROBE_SECONDS,
+        bytes_per_request: LinkClass::Slow.bytes_per_request(),
+        ramp: Ramp::Immediate,
+        reuse_connections: false,
+        expected_speed: 0,
+        saturation_gain: None,
+        verify_content: false,
+        extend_slow_link: false,
+    };
+    let phase = crate::throughput::Phase::start(target, &config, connect, pacer);
+    let samples = phase.sample_for(config.seconds, |_| {});
+    phase.join(std::time::Duration::ZERO);
+
+    let bps = samples.iter().max().copied().unwrap_or_default() as f64 * 8.0;
+    (LinkClass::from_bits_per_sec(bps), bps)
+}
+
+// How a phase brings its threads up
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Ramp {
+    // a little apart, so each lands on a different Cloudflare metal (each
+    // throttles to a gigabit)
+    Staggered,
+    // all at once, for links where the ramp-up would eat the test
+    Immediate,
+}
+
+// Which phase runs first, or interleaved: short bursts of each taking turns,
+// since some providers shape by which way traffic went recently and real
+// use mixes the two
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PhaseOrder {
+    DownloadFirst,
+    UploadFirst,
+    Interleaved,
+}
+
+impl PhaseOrder {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::DownloadFirst => "download-first",
+            Self::UploadFirst => "upload-first",
+            Self::Interleaved => "interleaved",
+        }
+    }
+}
+
+impl std::str::FromStr for PhaseOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "download-first" => Ok(Self::DownloadFirst),
+            "upload-first" => Ok(Self::UploadFirst),
+            "interleaved" => Ok(Self::Interleaved),
+            _ => Err(format!(
+                "unknown --order '{s}', expected download-first, upload-first or interleaved"
+            )),
+        }
+    }
+}
+
+// One [download] or [upload] section, anything left out comes from the
+// command line
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct PhaseSection {
+    threads: Option<u32>,
+    duration_seconds: Option<u64>,
+    bytes_per_request: Option<usize>,
+    ramp: Option<Ramp>,
+    reuse_connections: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct PhaseConfigFile {
+    #[serde(default)]
+    download: PhaseSection,
+    #[serde(default)]
+    upload: PhaseSection,
+}
+
+// Everything a phase needs to know about how to run
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PhaseConfig {
+    pub threads: u32,
+    // including the extra time given to ramp up many threads
+    pub seconds: u64,
+    pub bytes_per_request: usize,
+    pub ramp: Ramp,
+    // keep each thread's connection open between requests, rather than
+    // making a new one for every request
+    pub reuse_connections: bool,
+    // bytes/s we expect, so reads are sized for the link from the start
+    // rather than after the first sample (0 if we don't know)
+    pub expected_speed: usize,
+    // retire threads once more of them speed the phase up by less than
+    // this many percent, None to keep them all
+    pub saturation_gain: Option<f64>,
+    // hash the start of every download, see `integrity`
+    pub verify_content: bool,
+    // run on and with fewer threads if the link turns out very slow, see
+    // `throughput::slow_link_seconds`; a manifest from before there was
+    // such a thing replays without
+    #[serde(default)]
+    pub extend_slow_link: bool,
+}
+
+impl PhaseConfig {
+    // What a phase does when nothing has been asked for, as --test-duration-seconds
+    // and friends default to
+    pub fn default_for(direction: Direction) -> Self {
+        Self {
+            threads: DEFAULT_THREADS,
+            seconds: crate::get_test_time(DEFAULT_SECONDS, DEFAULT_THREADS, None),
+            bytes_per_request: DEFAULT_BYTES_PER_REQUEST,
+            ramp: Ramp::Staggered,
+            reuse_connections: direction == Direction::Upload,
+            expected_speed: 0,
+            saturation_gain: None,
+            verify_content: false,
+            extend_slow_link: true,
+        }
+    }
+
+    // The section wins over the command line (for one direction, then both),
+    // which wins over what suits the link (if we probed it), which wins over
+    // the defaults
+    fn resolve(
+        config: &PhaseOptions,
+        direction: Direction,
+        section: &PhaseSection,
+        probed: Option<(LinkClass, f64)>,
+    ) -> Self {
+        let (threads, bytes_per_request, seconds) = match direction {
+            Direction::Download => (
+                config.download_threads,
+                config.bytes_to_download,
+                config.download_duration,
+            ),
+            Direction::Upload => (
+                config.upload_threads,
+                config.bytes_to_upload,
+                config.upload_duration,
+            ),
+        };
+        let class = probed.map(|(class, _)| class);
+        let threads = section
+            .threads
+            .or(threads)
+            .or(class.map(LinkClass::threads))
+            .unwrap_or(DEFAULT_THREADS);
+
+        Self {
+            threads,
+            seconds: crate::get_test_time(
+                section
+                    .duration_seconds
+                    .or(seconds)
+                    .unwrap_or(config.test_duration_seconds),
+                threads,
+                if config.strict_duration {
+                    Some(0)
+                } else {
+                    config.max_ramp_seconds
+                },
+            ),
+            bytes_per_request: section
+                .bytes_per_request
+                .or(bytes_per_request)
+                .or((config.jumbo && direction == Direction::Download)
+                    .then_some(JUMBO_BYTES_PER_REQUEST))
+                .or(class.map(LinkClass::bytes_per_request))
+                .unwrap_or(DEFAULT_BYTES_PER_REQUEST),
+            ramp: section.ramp.unwrap_or(Ramp::Staggered),
+            // what each direction has always done: uploads keep their
+            // connection, downloads start afresh
+            reuse_connections: section
+                .reuse_connections
+                .unwrap_or(direction == Direction::Upload),
+            // the probe only downloads, an upload could be far slower
+            expected_speed: match (direction, probed) {
+                (Direction::Download, Some((_, bps))) => (bps / 8.0) as usize,
+                _ => 0,
+            },
+            saturation_gain: config.adaptive_threads.then(|| {
+                config
+                    .saturation_gain
+                    .unwrap_or(DEFAULT_SATURATION_GAIN_PERCENT)
+            }),
+            // what an upload gets back isn't what we're measuring
+            verify_content: config.verify_content && direction == Direction::Download,
+            extend_slow_link: !config.strict_duration,
+        }
+    }
+}
+
+// What the command line asks of the phases, before a --phase-config
+// section or --auto-tune have their say; the defaults ask for nothing
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhaseOptions {
+    pub download_threads: Option<u32>,
+    pub upload_threads: Option<u32>,
+    pub bytes_to_download: Option<usize>,
+    pub bytes_to_upload: Option<usize>,
+    pub download_duration: Option<u64>,
+    pub upload_duration: Option<u64>,
+    // for either direction without a duration of its own
+    pub test_duration_seconds: u64,
+    // the most seconds more threads add to a phase, see `get_test_time`
+    pub max_ramp_seconds: Option<u64>,
+    // exactly the duration asked for, nothing added
+    pub strict_duration: bool,
+    pub jumbo: bool,
+    pub adaptive_threads: bool,
+    pub saturation_gain: Option<f64>,
+    pub verify_content: bool,
+}
+
+impl Default for PhaseOptions {
+    fn default() -> Self {
+        Self {
+            download_threads: None,
+            upload_threads: None,
+            bytes_to_download: None,
+            bytes_to_upload: None,
+            download_duration: None,
+            upload_duration: None,
+            test_duration_seconds: DEFAULT_SECONDS,
+            max_ramp_seconds: None,
+            strict_duration: false,
+            jumbo: false,
+            adaptive_threads: false,
+            saturation_gain: None,
+            verify_content: false,
+        }
+    }
+}
+
+/* Download and upload configured independently, since what suits one
+   direction of an asymmetric link (2Gbit down, 50Mbit up on DOCSIS) is
+   all wrong for the other. --phase-config is TOML with a section for
+   either or both:
+
+     [download]
+     threads = 16
+     bytes-per-request = 104857600
+     ramp = "immediate"
+
+     [upload]
+     threads = 2
+     duration-seconds = 20
+     reuse-connections = false
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhaseConfigs {
+    pub download: PhaseConfig,
+    pub upload: PhaseConfig,
+}
+
+impl PhaseConfigs {
+    // `probed` is what --auto-tune found out about the link, if it ran
+    pub fn parse(
+        config: &PhaseOptions,
+        text: &str,
+        probed: Option<(LinkClass, f64)>,
+    ) -> Result<Self> {
+        let file: PhaseConfigFile = toml::from_str(text)?;
+        Ok(Self {
+            download: PhaseConfig::resolve(config, Direction::Download, &file.download, probed),
+            upload: PhaseConfig::resolve(config, Direction::Upload, &file.upload, probed),
+        })
+    }
+}
@@ -0,0 +1,161 @@
+use crate::args::UserArgs;
+use crate::throughput::Direction;
+use serde::Serialize;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// What the data usage is given per when there's no rate to expect
+static BITS_PER_SECOND_100_MBIT: f64 = 100e6;
+
+#[derive(Serialize)]
+pub struct PhasePlan {
+    pub url: String,
+    pub threads: u32,
+    pub seconds: u64,
+    // how long it runs instead on a link under 1 Mbit/s, unless
+    // --strict-duration holds it to `seconds`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slow_link_seconds: Option<u64>,
+    pub bytes_per_request: usize,
+    // bits/s we expect the phase to run at, see `expected_bps`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_bps: Option<f64>,
+}
+
+impl PhasePlan {
+    // Threads keep requesting until the deadline and finish what they
+    // asked for by then, so the data used is its rate for the whole phase
+    // and a request more for every thread
+    fn bytes_at(&self, bps: f64) -> u64 {
+        (bps / 8.0) as u64 * self.seconds + u64::from(self.threads) * self.bytes_per_request as u64
+    }
+
+    // What the phase should use at the rate we expect, if we expect one
+    pub fn estimated_bytes(&self) -> Option<u64> {
+        self.expected_bps.map(|bps| self.bytes_at(bps))
+    }
+}
+
+// What a phase in `direction` will likely run at: no more than --limit,
+// and otherwise what an --auto-tune probe pinned in a --replay manifest
+// found, or else what the last run in --history measured
+fn expected_bps(
+    config: &UserArgs,
+    direction: Direction,
+    phase: &crate::phases::PhaseConfig,
+    last_run: Option<&crate::results::RunResult>,
+) -> Option<f64> {
+    let probed = (phase.expected_speed > 0).then(|| phase.expected_speed as f64 * 8.0);
+    let recorded = last_run.and_then(|run| match direction {
+        Direction::Download => run.download.as_ref(),
+        Direction::Upload => run.upload.as_ref(),
+    });
+    let expected = probed.or(recorded.map(|phase| phase.median_bps));
+    match (expected, config.limit) {
+        (Some(bps), Some(limit)) => Some(bps.min(limit.bits_per_sec)),
+        (expected, limit) => expected.or(limit.map(|limit| limit.bits_per_sec)),
+    }
+}
+
+// Everything a run would do, worked out from the command line
+#[derive(Serialize)]
+pub struct TestPlan {
+    pub backend: String,
+    pub host: String,
+    // tried in order if the backend can't be reached
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fallbacks: Vec<String>,
+    pub download: Option<PhasePlan>,
+    pub upload: Option<PhasePlan>,
+    pub order: String,
+    pub output: String,
+    pub history_db: Option<String>,
+}
+
+impl TestPlan {
+    pub fn from_args(config: &UserArgs) -> Result<Self> {
+        let phases = config.phase_configs(None)?;
+        let provider = config.provider();
+        // only read, a dry run doesn't make a history that isn't there
+        let history_path = config.history_path();
+        let last_run = if config.history && history_path.exists() {
+            crate::history::History::open(&history_path)?.latest("cf_speedtest")?
+        } else {
+            None
+        };
+        let plan = |url: &str, direction, phase: &crate::phases::PhaseConfig| PhasePlan {
+            url: url.to_owned(),
+            threads: provider.streams().unwrap_or(phase.threads),
+            seconds: phase.seconds,
+            slow_link_seconds: phase
+                .extend_slow_link
+                .then(|| phase.seconds.max(crate::throughput::SLOW_LINK_SECONDS)),
+            bytes_per_request: phase.bytes_per_request,
+            expected_bps: expected_bps(config, direction, phase, last_run.as_ref()),
+        };
+        let download_url = config
+            .download_url
+            .clone()
+            .unwrap_or_else(|| provider.url(Direction::Download));
+        let upload_url = config
+            .upload_url
+            .clone()
+            .unwrap_or_else(|| provider.url(Direction::Upload));
+        let (download, upload) = config.phases();
+        let download = download.then(|| plan(&download_url, Direction::Download, &phases.download));
+        let upload = upload.then(|| plan(&upload_url, Direction::Upload, &phases.upload));
+        let custom = config.download_url.is_some() || config.upload_url.is_some();
+
+        Ok(Self {
+            backend: if custom {
+                "custom"
+            } else {
+                provider.kind().name()
+            }
+            .to_owned(),
+            host: provider.host(),
+            fallbacks: config
+                .fallback
+                .iter()
+                .map(|endpoint| endpoint.provider().describe())
+                .collect(),
+            download,
+            upload,
+            order: config.order.name().to_owned(),
+            output: config.output_format().name().to_owned(),
+            history_db: config
+                .history
+                .then(|| config.history_path().display().to_string()),
+        })
+    }
+
+    fn phases(&self) -> impl Iterator<Item = &PhasePlan> {
+        [self.download.as_ref(), self.upload.as_ref()]
+            .into_iter()
+            .flatten()
+    }
+
+    // What the whole run should use, if we expect a rate for every phase
+    pub fn estimated_bytes(&self) -> Option<u64> {
+        self.phases().map(PhasePlan::estimated_bytes).sum()
+    }
+
+    // What it would use for every 100 Mbit/s of link speed, for when we
+    // don't know what to expect
+    pub fn bytes_per_100_mbit(&self) -> u64 {
+        self.phases()
+            .map(|phase| phase.bytes_at(BITS_PER_SECOND_100_MBIT))
+            .sum()
+    }
+}
+
+// Resolve the test host without transferring anything
+pub fn resolve_host(host: &str, connect: &crate::net::ConnectOptions) -> Result<Vec<String>> {
+    let addrs = connect
+        .resolve(&format!("{host}:443"))?
+        .iter()
+        .map(|addr| addr.ip().to_string())
+        .collect();
+
+    Ok(addrs)
+}
@@ -0,0 +1,133 @@
+use std::sync::OnceLock;
+
+/* Optional socket operations a hardened container, seccomp filter or
+   systemd sandbox may refuse. Everything else we do is plain unprivileged
+   TCP: latency is measured over HTTP rather than ICMP, and we leave the
+   congestion control (TCP_CONGESTION) to the system, so those never need
+   asking for.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    // binding sockets to an interface, needs CAP_NET_RAW on older kernels
+    BindToDevice,
+    // reading the kernel's per-connection stats
+    TcpInfo,
+}
+
+impl Capability {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::BindToDevice => "SO_BINDTODEVICE",
+            Self::TcpInfo => "TCP_INFO",
+        }
+    }
+
+    // What can't be done or measured without it
+    pub fn needed_for(self) -> &'static str {
+        match self {
+            Self::BindToDevice => "testing through a specific interface",
+            Self::TcpInfo => "socket RTT, retransmits and loss",
+        }
+    }
+
+    // Whether we may use it here, and if not why not. Probed once on a
+    // throwaway socket, a refusal is an error code rather than a crash.
+    pub fn available(self) -> Result<(), String> {
+        static BIND_TO_DEVICE: OnceLock<Result<(), String>> = OnceLock::new();
+        static TCP_INFO: OnceLock<Result<(), String>> = OnceLock::new();
+
+        let cell = match self {
+            Self::BindToDevice => &BIND_TO_DEVICE,
+            Self::TcpInfo => &TCP_INFO,
+        };
+        cell.get_or_init(|| probe(self)).clone()
+    }
+}
+
+pub static ALL: [Capability; 2] = [Capability::BindToDevice, Capability::TcpInfo];
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn probe(capability: Capability) -> Result<(), String> {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    // SAFETY: plain syscalls on a socket we own, with buffers sized as we tell the kernel
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().to_string());
+        }
+        let fd = OwnedFd::from_raw_fd(fd);
+
+        let ret = match capability {
+            Capability::BindToDevice => {
+                let interface = "lo";
+                libc::setsockopt(
+                    fd.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_BINDTODEVICE,
+                    interface.as_ptr() as *const libc::c_void,
+                    interface.len() as libc::socklen_t,
+                )
+            }
+            #[cfg(target_os = "linux")]
+            Capability::TcpInfo => {
+                let mut info: libc::tcp_info = std::mem::zeroed();
+                let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+                libc::getsockopt(
+                    fd.as_raw_fd(),
+                    libc::IPPROTO_TCP,
+                    libc::TCP_INFO,
+                    &mut info as *mut libc::tcp_info as *mut libc::c_void,
+                    &mut len,
+                )
+            }
+            // the libc crate has no tcp_info for Android, so we never read it there
+            #[cfg(not(target_os = "linux"))]
+            Capability::TcpInfo => {
+                return Err(format!("{} isn't read on Android", capability.name()))
+            }
+        };
+
+        if ret != 0 {
+            return Err(format!(
+                "{} not permitted: {}",
+                capability.name(),
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn probe(capability: Capability) -> Result<(), String> {
+    Err(format!(
+        "{} is only available on Linux and Android",
+        capability.name()
+    ))
+}
+
+// What this run won't measure because we may not, one line each
+pub fn skipped_measurements() -> Vec<String> {
+    [Capability::TcpInfo]
+        .into_iter()
+        .filter_map(|capability| {
+            capability
+                .available()
+                .err()
+                .map(|why| format!("{} ({why})", capability.needed_for()))
+        })
+        .collect()
+}
+
+// An error explaining why `what` can't be done here, if it can't
+pub fn require(capability: Capability, what: &str) -> Result<(), String> {
+    capability.available().map_err(|why| {
+        format!(
+            "{what} needs {}, which isn't allowed here ({why}). \
+            Run with CAP_NET_RAW, or outside the sandbox that blocks it",
+            capability.name()
+        )
+    })
+}
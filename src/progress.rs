@@ -0,0 +1,155 @@
+/* Human output on a terminal: a bar per phase that updates in place with
+   the last second's speed, the average so far and how long is left,
+   instead of a line per second scrolling past. What stays on screen when
+   a phase ends is its bar with the average. Piped or redirected, e.g.
+   into a log, the per-second lines are printed as before. With --output
+   compact the bar is shorter and leaves out the average, to fit a phone.
+   Either way the speed shown is smoothed, see `Smoothing`.
+*/
+use crate::throughput::Direction;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::io::IsTerminal;
+use std::time::Duration;
+
+// Redraws happen on samples, but not faster than this (indicatif's default
+// is 20 a second, needlessly busy over SSH)
+static REFRESH_PER_SEC: u8 = 4;
+
+// Whether to show bars rather than lines
+pub fn enabled() -> bool {
+    std::io::stdout().is_terminal()
+}
+
+// e.g. "94.21 Mbit/s, 91.07 Mbit/s average, 7s left"
+pub fn status(last_bytes: usize, total_bytes: u128, seconds_done: u64, seconds: u64) -> String {
+    let rate = |bytes: u128| crate::format_bits_per_sec(bytes as f64 * 8.0);
+    let average = total_bytes / u128::from(seconds_done.max(1));
+
+    format!(
+        "{}, {} average, {}s left",
+        rate(last_bytes as u128),
+        rate(average),
+        seconds.saturating_sub(seconds_done)
+    )
+}
+
+/* --smoothing: the speed shown each second as an exponentially weighted
+   moving average, so a bursty link's figure doesn't jump from one end of
+   the scale to the other. Each second counts for 1 - `weight` of what's
+   shown and everything before for the rest; a weight of 0 shows the raw
+   samples. Only what's shown, the results use the samples as measured.
+*/
+pub struct Smoothing {
+    weight: f64,
+    shown: Option<f64>,
+}
+
+impl Smoothing {
+    pub fn new(weight: f64) -> Self {
+        Self {
+            weight,
+            shown: None,
+        }
+    }
+
+    // What to show for a second that moved `bytes`, starting from the
+    // first second as it is
+    pub fn next(&mut self, bytes: usize) -> usize {
+        let shown = match self.shown {
+            Some(shown) => shown * self.weight + bytes as f64 * (1.0 - self.weight),
+            None => bytes as f64,
+        };
+        self.shown = Some(shown);
+
+        shown.round() as usize
+    }
+}
+
+pub struct PhaseProgress {
+    bar: ProgressBar,
+    compact: bool,
+    seconds: u64,
+    total_bytes: u128,
+    samples: u64,
+}
+
+impl PhaseProgress {
+    // `seconds` is how long the phase is meant to run
+    pub fn start(direction: Direction, seconds: u64, compact: bool) -> Self {
+        let mut label = direction.name().to_owned();
+        label[..1].make_ascii_uppercase();
+
+        let bar = ProgressBar::with_draw_target(
+            Some(seconds),
+            ProgressDrawTarget::stdout_with_hz(REFRESH_PER_SEC),
+        );
+        let template = if compact {
+            "{prefix:<10}[{bar:12}] {msg}"
+        } else {
+            "{prefix:<10}[{bar:30}] {msg}"
+        };
+        bar.set_style(
+            ProgressStyle::with_template(template)
+                .unwrap()
+                .progress_chars("=> "),
+        );
+        bar.set_prefix(format!("{label}:"));
+        bar.set_message("starting...");
+        // so the bar shows up before the first second is over
+        bar.enable_steady_tick(Duration::from_millis(1000 / u64::from(REFRESH_PER_SEC)));
+
+        Self {
+            bar,
+            compact,
+            seconds,
+            total_bytes: 0,
+            samples: 0,
+        }
+    }
+
+    // A second that moved `bytes`, shown as `shown`
+    pub fn sample(&mut self, bytes: usize, shown: usize) {
+        self.total_bytes += bytes as u128;
+        self.samples += 1;
+        // a phase can overrun a little while threads ramp up
+        if self.samples > self.seconds {
+            self.bar.set_length(self.samples);
+        }
+        self.bar.set_position(self.samples);
+        self.bar.set_message(if self.compact {
+            format!(
+                "{}, {}s left",
+                crate::format_bits_per_sec(shown as f64 * 8.0),
+                self.seconds.saturating_sub(self.samples)
+            )
+        } else {
+            status(shown, self.total_bytes, self.samples, self.seconds)
+        });
+    }
+
+    // The phase runs for `seconds` in all after all, see `Observer::phase_extended`
+    pub fn extend(&mut self, seconds: u64) {
+        self.seconds = seconds;
+        self.bar.set_length(seconds.max(self.samples));
+    }
+
+    // Print a line above the bar without tearing it
+    pub fn println(&self, line: impl AsRef<str>) {
+        self.bar.println(line);
+    }
+
+    // Run `f`, e.g. something printing to stderr, with the bar out of the way
+    pub fn suspend<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.bar.suspend(f)
+    }
+
+    // Leave the bar on screen with the phase's average
+    pub fn finish(self) {
+        let average = self.total_bytes / u128::from(self.samples.max(1));
+        self.bar.finish_with_message(format!(
+            "{} average over {}s",
+            crate::format_bits_per_sec(average as f64 * 8.0),
+            self.samples
+        ));
+    }
+}
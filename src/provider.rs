@@ -0,0 +1,327 @@
+/* Who a run measures against. Cloudflare's edge is the default, a LibreSpeed
+   server is what a lot of people already run on their own network, and
+   M-Lab's ndt7 servers give a second opinion from outside Cloudflare. Either
+   way the same phases run, only the requests and the idle latency probe
+   differ.
+*/
+use crate::latency::IdleLatency;
+use crate::throughput::{Direction, Target, ThroughputTarget};
+use std::sync::Arc;
+use ureq::Agent;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// LibreSpeed's garbage.php sends data in chunks of this size, and won't
+// send more than LIBRESPEED_MAX_CHUNKS of them per request
+static LIBRESPEED_CHUNK_BYTES: usize = 1024 * 1024;
+static LIBRESPEED_MAX_CHUNKS: usize = 1024;
+
+// Which provider --provider asks for
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProviderKind {
+    Cloudflare,
+    LibreSpeed,
+    Ndt7,
+}
+
+impl std::str::FromStr for ProviderKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "cloudflare" => Ok(Self::Cloudflare),
+            "librespeed" => Ok(Self::LibreSpeed),
+            "ndt7" => Ok(Self::Ndt7),
+            _ => Err(format!(
+                "unknown provider '{s}', expected cloudflare, librespeed or ndt7"
+            )),
+        }
+    }
+}
+
+impl ProviderKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Cloudflare => "cloudflare",
+            Self::LibreSpeed => "librespeed",
+            Self::Ndt7 => "ndt7",
+        }
+    }
+}
+
+// Which datacenter answered and which country it puts us in, for
+// providers that say
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Located {
+    pub colo: Option<String>,
+    pub country: Option<String>,
+}
+
+pub trait Provider: Send + Sync + std::fmt::Debug {
+    fn kind(&self) -> ProviderKind;
+
+    // The host transfers go to, for resolving without transferring
+    fn host(&self) -> String;
+
+    // Where a phase's requests go, for --dry-run
+    fn url(&self, direction: Direction) -> String;
+
+    fn target(&self, direction: Direction) -> Target;
+
+    fn idle_latency(&self, agent: &Agent) -> Result<IdleLatency>;
+
+    fn locate(&self, _agent: &Agent) -> Result<Located> {
+        Ok(Located::default())
+    }
+
+    // The server measured against when it isn't Cloudflare, for the result
+    fn server(&self) -> Option<String> {
+        None
+    }
+
+    // How many connections a phase must use, for protocols that insist
+    fn streams(&self) -> Option<u32> {
+        None
+    }
+
+    fn describe(&self) -> String {
+        match self.server() {
+            Some(server) => format!("{} at {server}", self.kind().name()),
+            None => self.kind().name().to_owned(),
+        }
+    }
+}
+
+/* A provider as given on the command line: cloudflare, ndt7, or
+   librespeed=<server URL>
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Endpoint {
+    pub kind: ProviderKind,
+    pub server: Option<String>,
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (kind, server) = match s.split_once('=') {
+            Some((kind, server)) => (kind.parse()?, Some(server.to_owned())),
+            None => (s.parse()?, None),
+        };
+
+        match (kind, &server) {
+            (ProviderKind::Cloudflare | ProviderKind::Ndt7, Some(_)) => Err(format!(
+                "invalid endpoint '{s}', {} doesn't take a server",
+                kind.name()
+            )),
+            (ProviderKind::LibreSpeed, None) => Err(format!(
+                "invalid endpoint '{s}', expected librespeed=<server URL>"
+            )),
+            (_, Some(server))
+                if !server.starts_with("http://") && !server.starts_with("https://") =>
+            {
+                Err(format!(
+                    "invalid endpoint '{s}', the server must be an http:// or https:// URL"
+                ))
+            }
+            _ => Ok(Self { kind, server }),
+        }
+    }
+}
+
+impl Endpoint {
+    pub fn provider(&self) -> Arc<dyn Provider> {
+        match (self.kind, &self.server) {
+            (ProviderKind::LibreSpeed, Some(server)) => Arc::new(LibreSpeed::new(server)),
+            (ProviderKind::Ndt7, _) => Arc::new(Ndt7::default()),
+            _ => Arc::new(Cloudflare),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Cloudflare;
+
+impl Provider for Cloudflare {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::Cloudflare
+    }
+
+    fn host(&self) -> String {
+        crate::CLOUDFLARE_SPEEDTEST_HOST.to_owned()
+    }
+
+    fn url(&self, direction: Direction) -> String {
+        match direction {
+            Direction::Download => crate::CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL,
+            Direction::Upload => crate::CLOUDFLARE_SPEEDTEST_UPLOAD_URL,
+        }
+        .to_owned()
+    }
+
+    fn target(&self, direction: Direction) -> Target {
+        Target::Requests(match direction {
+            Direction::Download => Arc::new(crate::throughput::CloudflareDownload),
+            Direction::Upload => Arc::new(crate::throughput::CloudflareUpload),
+        })
+    }
+
+    fn idle_latency(&self, agent: &Agent) -> Result<IdleLatency> {
+        crate::get_download_server_http_latency(agent)
+    }
+
+    fn locate(&self, agent: &Agent) -> Result<Located> {
+        let trace = crate::get_cdn_cgi_trace(agent)?;
+        let headers = crate::get_download_server_info(agent)?;
+
+        Ok(Located {
+            colo: headers.get("cf-meta-colo").cloned(),
+            country: Some(crate::get_country_from_trace(&trace)?),
+        })
+    }
+}
+
+/* A LibreSpeed server, given as the URL its web page is served from. Its
+   backend sends random data from backend/garbage.php?ckSize=<MiB> and
+   swallows uploads POSTed to backend/empty.php, which also answers
+   latency probes.
+*/
+#[derive(Debug)]
+pub struct LibreSpeed {
+    base: String,
+}
+
+impl LibreSpeed {
+    pub fn new(server: &str) -> Self {
+        Self {
+            base: format!("{}/", server.trim_end_matches('/')),
+        }
+    }
+
+    fn backend(&self, file: &str) -> String {
+        format!("{}backend/{file}", self.base)
+    }
+}
+
+impl Provider for LibreSpeed {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::LibreSpeed
+    }
+
+    fn host(&self) -> String {
+        let rest = self
+            .base
+            .split_once("://")
+            .map_or(self.base.as_str(), |(_, rest)| rest);
+        let netloc = rest.split('/').next().unwrap_or_default();
+        // a bracketed IPv6 address keeps its colons (and brackets), a port doesn't
+        match netloc.split_once(']') {
+            Some((v6, _)) => format!("{v6}]"),
+            None => netloc.split(':').next().unwrap_or_default().to_owned(),
+        }
+    }
+
+    fn url(&self, direction: Direction) -> String {
+        match direction {
+            Direction::Download => self.backend("garbage.php"),
+            Direction::Upload => self.backend("empty.php"),
+        }
+    }
+
+    fn target(&self, direction: Direction) -> Target {
+        Target::Requests(Arc::new(LibreSpeedTarget {
+            direction,
+            url: self.url(direction),
+        }))
+    }
+
+    fn idle_latency(&self, agent: &Agent) -> Result<IdleLatency> {
+        crate::get_http_latency(agent, &self.backend("empty.php"))
+    }
+
+    fn server(&self) -> Option<String> {
+        Some(self.base.clone())
+    }
+}
+
+pub struct LibreSpeedTarget {
+    direction: Direction,
+    url: String,
+}
+
+// How many of garbage.php's chunks make up at least `bytes`
+pub fn librespeed_chunks(bytes: usize) -> usize {
+    bytes
+        .div_ceil(LIBRESPEED_CHUNK_BYTES)
+        .clamp(1, LIBRESPEED_MAX_CHUNKS)
+}
+
+impl ThroughputTarget for LibreSpeedTarget {
+    fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    fn build_request(&self, agent: &Agent, bytes: usize) -> ureq::Request {
+        let request = match self.direction {
+            Direction::Download => agent
+                .get(&self.url)
+                .query("ckSize", &librespeed_chunks(bytes).to_string()),
+            Direction::Upload => agent
+                .post(&self.url)
+                .set("Content-Type", "application/octet-stream"),
+        };
+        request.set("User-Agent", crate::OUR_USER_AGENT)
+    }
+}
+
+/* M-Lab's ndt7 servers, see `ndt7`. The nearest one is found when the run
+   starts, and a single stream each way is what M-Lab measures with.
+*/
+#[derive(Debug, Default)]
+pub struct Ndt7 {
+    located: crate::ndt7::Located,
+}
+
+impl Provider for Ndt7 {
+    fn kind(&self) -> ProviderKind {
+        ProviderKind::Ndt7
+    }
+
+    fn host(&self) -> String {
+        crate::ndt7::LOCATE_HOST.to_owned()
+    }
+
+    fn url(&self, direction: Direction) -> String {
+        format!("wss://<nearest M-Lab server>/ndt/v7/{}", direction.name())
+    }
+
+    fn target(&self, direction: Direction) -> Target {
+        Target::Stream(Arc::new(crate::ndt7::Ndt7Target {
+            direction,
+            machine: self.located.machine(),
+            urls: crate::ndt7::Located::default(),
+        }))
+    }
+
+    fn idle_latency(&self, agent: &Agent) -> Result<IdleLatency> {
+        let server = self.located.get(agent, None)?;
+        crate::ndt7::idle_latency(agent, &server.machine)
+    }
+
+    fn locate(&self, agent: &Agent) -> Result<Located> {
+        let server = self.located.get(agent, None)?;
+        Ok(Located {
+            colo: server.site,
+            country: None,
+        })
+    }
+
+    fn server(&self) -> Option<String> {
+        self.located.machine()
+    }
+
+    fn streams(&self) -> Option<u32> {
+        Some(1)
+    }
+}
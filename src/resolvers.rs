@@ -0,0 +1,153 @@
+/* --compare-resolvers: is switching to DNS over HTTPS worth it? Each round
+   sets up a fresh connection to the test server twice, once finding its
+   address with the system resolver (getaddrinfo, with whatever caching the
+   OS does) and once by asking Cloudflare's DoH server, or the one --doh
+   gives, and times the lookup, TCP connect, TLS handshake and first byte
+   of a request. The DoH lookups go through `dns::NameServer` as --doh's
+   do: one connection kept alive between rounds and answers kept for their
+   TTL, as a browser would. The two take turns so both see the same
+   network conditions.
+*/
+use crate::dns::NameServer;
+use crate::latency::ForeignProbe;
+use crate::net::{ConnectOptions, IpFamily};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// What DoH is compared with when --doh doesn't say
+static CLOUDFLARE_DOH_URL: &str = "https://cloudflare-dns.com/dns-query";
+// Time between rounds, so we're not mistaken for a flood
+static ROUND_INTERVAL_MILLIS: u64 = 200;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolver {
+    System,
+    Doh,
+}
+
+impl Resolver {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::System => "System resolver",
+            Self::Doh => "DNS over HTTPS",
+        }
+    }
+}
+
+// One connection to the test server, the lookup included
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Setup {
+    pub resolve: Duration,
+    pub connection: ForeignProbe,
+}
+
+impl Setup {
+    pub fn total(&self) -> Duration {
+        self.resolve + self.connection.tcp + self.connection.tls + self.connection.http
+    }
+}
+
+// Cloudflare's DoH server
+pub fn cloudflare() -> NameServer {
+    NameServer::Doh(CLOUDFLARE_DOH_URL.to_owned())
+}
+
+fn measure(resolver: Resolver, connect: &ConnectOptions, doh: &NameServer) -> Result<Setup> {
+    let host = crate::CLOUDFLARE_SPEEDTEST_HOST;
+    let start = Instant::now();
+    let addr = match resolver {
+        Resolver::System => connect.resolve(&format!("{host}:443"))?.into_iter().next(),
+        Resolver::Doh => doh
+            .lookup(host, Some(connect.family.unwrap_or(IpFamily::V4)), connect)?
+            .into_iter()
+            .next()
+            .map(|ip| SocketAddr::new(ip, 443)),
+    };
+    let resolve = start.elapsed();
+    let addr = addr.ok_or_else(|| format!("{} found no address for {host}", resolver.name()))?;
+
+    Ok(Setup {
+        resolve,
+        connection: crate::latency::probe_addr(connect, addr)?,
+    })
+}
+
+// How one resolver did over every round
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ResolverSummary {
+    pub resolver: Resolver,
+    pub probes: usize,
+    pub failures: usize,
+    // medians, None if every probe failed
+    pub resolve_ms: Option<f64>,
+    pub setup_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+impl ResolverSummary {
+    pub fn new(resolver: Resolver, setups: &[std::result::Result<Setup, String>]) -> Self {
+        let ok: Vec<&Setup> = setups.iter().filter_map(|s| s.as_ref().ok()).collect();
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+
+        Self {
+            resolver,
+            probes: setups.len(),
+            failures: setups.len() - ok.len(),
+            resolve_ms: crate::median(ok.iter().map(|s| ms(s.resolve)).collect()),
+            setup_ms: crate::median(ok.iter().map(|s| ms(s.total())).collect()),
+            last_error: setups.iter().rev().find_map(|s| s.clone().err()),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ResolverComparison {
+    pub system: ResolverSummary,
+    pub doh: ResolverSummary,
+    // DoH's median setup minus the system resolver's, so negative when
+    // switching would make connections faster
+    pub difference_ms: Option<f64>,
+}
+
+impl ResolverComparison {
+    pub fn new(system: ResolverSummary, doh: ResolverSummary) -> Self {
+        Self {
+            difference_ms: doh.setup_ms.zip(system.setup_ms).map(|(d, s)| d - s),
+            system,
+            doh,
+        }
+    }
+}
+
+// `rounds` connections with each resolver, taking turns; `connect` is
+// taken as it is but for its resolver, which is the system's or `doh`
+pub fn compare(connect: &ConnectOptions, doh: &NameServer, rounds: usize) -> ResolverComparison {
+    let connect = &ConnectOptions {
+        dns: None,
+        ..connect.clone()
+    };
+    let mut system_setups = vec![];
+    let mut doh_setups = vec![];
+
+    for round in 0..rounds {
+        if round > 0 {
+            std::thread::sleep(Duration::from_millis(ROUND_INTERVAL_MILLIS));
+        }
+        for (resolver, setups) in [
+            (Resolver::System, &mut system_setups),
+            (Resolver::Doh, &mut doh_setups),
+        ] {
+            setups.push(measure(resolver, connect, doh).map_err(|err| err.to_string()));
+        }
+    }
+
+    ResolverComparison::new(
+        ResolverSummary::new(Resolver::System, &system_setups),
+        ResolverSummary::new(Resolver::Doh, &doh_setups),
+    )
+}
@@ -0,0 +1,224 @@
+use crate::assertions::AssertionOutcome;
+use crate::integrity::ContentReport;
+use crate::latency::{IdleLatency, LoadedLatency};
+use crate::sockets::SocketSummary;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+// Summary of a single test phase, all speeds in bits per second
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PhaseSummary {
+    pub median_bps: f64,
+    pub average_bps: f64,
+    pub p90_bps: f64,
+    pub min_bps: f64,
+    pub max_bps: f64,
+    // how many threads saturated the link, when the ramp up showed it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub saturated_threads: Option<u32>,
+    // and the most that more threads than that sped things up by, in percent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub saturation_gain_percent: Option<f64>,
+    // bytes moved in each second of the phase, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub samples: Vec<usize>,
+    // how many of the first samples --warmup left out of the figures above
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub warmup_seconds: Option<usize>,
+    // how many threads were still transferring when the phase ended
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threads: Option<u32>,
+}
+
+impl PhaseSummary {
+    // Other tools only report a single figure per phase,
+    // so use it for every statistic
+    pub fn single(bps: f64) -> Self {
+        Self {
+            median_bps: bps,
+            average_bps: bps,
+            p90_bps: bps,
+            min_bps: bps,
+            max_bps: bps,
+            saturated_threads: None,
+            saturation_gain_percent: None,
+            warmup_seconds: None,
+            threads: None,
+            samples: vec![],
+        }
+    }
+
+    /* Whether it was one connection's limits or the link's capacity that
+       held the phase back: a link one connection saturates is as fast as
+       it gets, one that needs several has a ceiling per connection (a
+       small TCP window on a long path, or per-flow shaping) that
+       single-connection downloads will run into.
+    */
+    pub fn describe_saturation(&self) -> Option<String> {
+        let threads = self.saturated_threads?;
+        let gained = match self.saturation_gain_percent {
+            Some(gain) if gain >= 0.05 => format!("only {gain:.1}%"),
+            Some(_) => "nothing".to_owned(),
+            None => "too little".to_owned(),
+        };
+        let why = if threads == 1 {
+            "one connection fills the link"
+        } else {
+            "one connection alone can't fill the link"
+        };
+        let connections = if threads == 1 {
+            "connection"
+        } else {
+            "parallel connections"
+        };
+
+        Some(format!(
+            "link saturated at {threads} {connections}, adding more gained {gained} ({why})"
+        ))
+    }
+}
+
+/* Something the tool itself did during a phase (a thread starting, a request
+   failing and being retried, the deadline passing), so spikes in the samples
+   can be told apart from the network's own behaviour. `at_secs` counts from
+   the start of the phase, like the per-second samples do.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Event {
+    pub phase: String,
+    pub at_secs: f64,
+    pub kind: String,
+    pub thread: Option<u32>,
+    pub detail: Option<String>,
+}
+
+/* Something about a run its numbers don't show but whoever reads them
+   should know, e.g. that it went through a VPN or the CPU couldn't keep
+   up. `kind` is for scripts and stays put, `message` is for people.
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Warning {
+    pub kind: String,
+    pub message: String,
+}
+
+impl Warning {
+    pub fn new(kind: &str, message: impl Into<String>) -> Self {
+        Self {
+            kind: kind.to_owned(),
+            message: message.into(),
+        }
+    }
+}
+
+// The outcome of one speed test run, either ours or imported from another tool
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RunResult {
+    pub timestamp: DateTime<Utc>,
+    pub source: String,
+    pub colo: Option<String>,
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub city: Option<String>,
+    pub latency_ms: Option<f64>,
+    // what latency_ms is the minimum of, with jitter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_latency: Option<IdleLatency>,
+    pub download: Option<PhaseSummary>,
+    pub upload: Option<PhaseSummary>,
+    // latency while each phase was running, compared to latency_ms
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_loaded_latency: Option<LoadedLatency>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_loaded_latency: Option<LoadedLatency>,
+    // the interface given to --via-interface, i.e. a tunneled run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub via_interface: Option<String>,
+    // the server measured against when it wasn't Cloudflare's, e.g. with
+    // --provider librespeed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    // per-connection totals, only known for runs measured by us just now
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sockets: Option<SocketSummary>,
+    // what --verify-content found in the downloads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<ContentReport>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<Event>,
+    // served from history by --cached rather than measured just now
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cached: bool,
+    // cut short, e.g. by Ctrl+C, so the phases hold what ran until then
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub interrupted: bool,
+    // the system clock wasn't set, so `timestamp` is meaningless
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub clock_untrusted: bool,
+    // how the run fared against --assertions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub assertions: Vec<AssertionOutcome>,
+    // measurements left out because the sandbox wouldn't allow them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<String>,
+    // power and network the machine was on, see `host`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<crate::host::HostState>,
+    // anything that makes the results less trustworthy, see `warnings`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<Warning>,
+    // the address the test server's name resolved to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved: Option<Resolved>,
+}
+
+// Where a name took us, and who said so
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Resolved {
+    pub host: String,
+    pub address: std::net::IpAddr,
+    // "system", "--resolve", or the --dns server or --doh URL
+    pub resolver: String,
+}
+
+// What --output json prints instead of a result when there isn't one, so
+// a failure parses like a success does
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ErrorReport {
+    // always "error"
+    pub status: &'static str,
+    // stays put whatever the message says, e.g. "test_failed"
+    pub category: &'static str,
+    pub message: String,
+    pub exit_code: i32,
+    // what the run measured before it failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partial: Option<RunResult>,
+}
+
+impl ErrorReport {
+    pub fn new(category: &'static str, message: impl Into<String>, exit_code: i32) -> Self {
+        Self {
+            status: "error",
+            category,
+            message: message.into(),
+            exit_code,
+            partial: None,
+        }
+    }
+}
+
+// A run that failed after its phases, with what they measured
+#[derive(Debug)]
+pub struct PartialRun {
+    pub run: RunResult,
+    pub error: Box<dyn std::error::Error>,
+}
+
+impl std::fmt::Display for PartialRun {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for PartialRun {}
@@ -0,0 +1,221 @@
+use crate::results::RunResult;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+pub static DEFAULT_ROTATE_KEEP: usize = 5;
+
+// What a result file holds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SinkFormat {
+    // the latest result, replacing what was there
+    Json,
+    // a row per run, with a header when the file is new
+    Csv,
+    // a JSON object per run, one per line
+    Jsonl,
+    // the latest result as gauges for node_exporter's textfile collector
+    Prometheus,
+}
+
+/* Somewhere besides the console a run's result goes, given on the command
+   line as format:path, e.g. json:/tmp/last.json. Any number of them can be
+   written alongside whatever --output prints.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileSink {
+    pub format: SinkFormat,
+    pub path: PathBuf,
+}
+
+impl std::str::FromStr for FileSink {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (format, path) = s
+            .split_once(':')
+            .filter(|(_, path)| !path.is_empty())
+            .ok_or_else(|| format!("invalid output file '{s}', expected format:path"))?;
+        let format = match format {
+            "json" => SinkFormat::Json,
+            "csv" => SinkFormat::Csv,
+            "jsonl" => SinkFormat::Jsonl,
+            "prometheus" => SinkFormat::Prometheus,
+            _ => {
+                return Err(format!(
+                    "unknown output file format '{format}', expected json, csv, jsonl or prometheus"
+                ))
+            }
+        };
+
+        Ok(Self {
+            format,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+impl FileSink {
+    // Files that grow a run at a time are rotated first if `rotation` says
+    // it's time, the others only ever hold the latest run
+    pub fn write(&self, run: &RunResult, rotation: Option<&Rotation>) -> Result<()> {
+        if let (SinkFormat::Csv | SinkFormat::Jsonl, Some(rotation)) = (self.format, rotation) {
+            rotation.rotate_if_due(&self.path)?;
+        }
+
+        match self.format {
+            SinkFormat::Json => replace(&self.path, &(serde_json::to_string(run)? + "\n")),
+            SinkFormat::Csv => crate::export::append_csv(&self.path, run),
+            SinkFormat::Jsonl => append(&self.path, &(serde_json::to_string(run)? + "\n")),
+            SinkFormat::Prometheus => replace(&self.path, &prometheus_metrics(run)),
+        }
+    }
+}
+
+// Swap the file's contents in one go, so whoever reads it never sees half
+fn replace(path: &Path, contents: &str) -> Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    std::fs::write(&partial, contents)
+        .and_then(|()| std::fs::rename(&partial, path))
+        .map_err(|err| format!("couldn't write {}: {err}", path.display()).into())
+}
+
+// Add to the end of the file in a single write, so runs finishing together
+// don't interleave
+pub fn append(path: &Path, contents: &str) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| format!("couldn't open {}: {err}", path.display()))?;
+    std::io::Write::write_all(&mut file, contents.as_bytes())?;
+
+    Ok(())
+}
+
+/* Keeps a file that a daemon adds to forever from filling the disk: once
+   it's too big or too old it's renamed to <file>.1, the previous <file>.1
+   to <file>.2 and so on, and whatever falls off the end after `keep` is
+   deleted. The next write then starts a new file, with a new CSV header.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rotation {
+    pub max_bytes: Option<u64>,
+    // from when the file was created, which not every filesystem records;
+    // where it isn't, only the size counts
+    pub max_age: Option<Duration>,
+    pub keep: usize,
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{n}"));
+    PathBuf::from(rotated)
+}
+
+impl Rotation {
+    pub fn is_due(&self, path: &Path) -> bool {
+        let Ok(metadata) = std::fs::metadata(path) else {
+            return false;
+        };
+
+        let too_big = self.max_bytes.is_some_and(|max| metadata.len() >= max);
+        let too_old = self.max_age.is_some_and(|max| {
+            metadata
+                .created()
+                .ok()
+                .and_then(|created| created.elapsed().ok())
+                .is_some_and(|age| age >= max)
+        });
+
+        too_big || too_old
+    }
+
+    // Returns whether the file was rotated
+    pub fn rotate_if_due(&self, path: &Path) -> Result<bool> {
+        if !self.is_due(path) {
+            return Ok(false);
+        }
+
+        let rotate = || -> std::io::Result<()> {
+            if self.keep == 0 {
+                return std::fs::remove_file(path);
+            }
+
+            match std::fs::remove_file(rotated_path(path, self.keep)) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+            for n in (1..self.keep).rev() {
+                let from = rotated_path(path, n);
+                if from.exists() {
+                    std::fs::rename(&from, rotated_path(path, n + 1))?;
+                }
+            }
+            std::fs::rename(path, rotated_path(path, 1))
+        };
+
+        rotate().map_err(|err| format!("couldn't rotate {}: {err}", path.display()))?;
+        Ok(true)
+    }
+}
+
+fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// A run in the Prometheus text exposition format, figures it doesn't have
+// left out
+pub fn prometheus_metrics(run: &RunResult) -> String {
+    let mut labels = vec![];
+    if let Some(colo) = &run.colo {
+        labels.push(format!("colo=\"{}\"", label_value(colo)));
+    }
+    if let Some(interface) = &run.via_interface {
+        labels.push(format!("interface=\"{}\"", label_value(interface)));
+    }
+    let labels = if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    };
+
+    let metrics = [
+        (
+            "cf_speedtest_download_bits_per_second",
+            "Median download speed of the latest run",
+            run.download.as_ref().map(|p| p.median_bps),
+        ),
+        (
+            "cf_speedtest_upload_bits_per_second",
+            "Median upload speed of the latest run",
+            run.upload.as_ref().map(|p| p.median_bps),
+        ),
+        (
+            "cf_speedtest_latency_seconds",
+            "Idle latency of the latest run",
+            run.latency_ms.map(|ms| ms / 1000.0),
+        ),
+        (
+            "cf_speedtest_last_run_timestamp_seconds",
+            "When the latest run happened",
+            (!run.clock_untrusted).then(|| run.timestamp.timestamp() as f64),
+        ),
+    ];
+
+    let mut text = String::new();
+    for (name, help, value) in metrics {
+        if let Some(value) = value {
+            let _ = writeln!(text, "# HELP {name} {help}\n# TYPE {name} gauge");
+            let _ = writeln!(text, "{name}{labels} {value}");
+        }
+    }
+
+    text
+}
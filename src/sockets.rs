@@ -0,0 +1,253 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Connections that moved less are too short to have got out of slow start
+static MIN_STREAM_BYTES: u64 = 1024 * 1024;
+
+// What one connection did over its lifetime
+#[derive(Clone, Debug, PartialEq)]
+pub struct SocketRecord {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub duration: Duration,
+    // from the kernel where it tells us (linux), as the socket is closed
+    pub rtt: Option<Duration>,
+    pub retransmits: Option<u32>,
+    pub segments_sent: Option<u32>,
+}
+
+// Where connections report themselves when they close, and can be found
+// while they are open
+#[derive(Clone, Default)]
+pub struct SocketRegistry {
+    records: Arc<Mutex<Vec<SocketRecord>>>,
+    // handles on the connections still open, by tracker
+    open: Arc<Mutex<HashMap<u64, TcpStream>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl SocketRegistry {
+    pub fn record(&self, record: SocketRecord) {
+        self.records.lock().unwrap().push(record);
+    }
+
+    // Everything recorded so far, leaving the registry empty
+    pub fn take(&self) -> Vec<SocketRecord> {
+        std::mem::take(&mut self.records.lock().unwrap())
+    }
+
+    // Shut down every connection that's still open, so whoever is blocked
+    // reading or writing one gives up. Returns how many there were.
+    pub fn shut_down_open(&self) -> usize {
+        let open = self.open.lock().unwrap();
+        for socket in open.values() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+
+        open.len()
+    }
+}
+
+// Counts the bytes through one socket and records them in the registry on drop
+pub struct SocketTracker {
+    registry: SocketRegistry,
+    id: u64,
+    opened: Instant,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl SocketTracker {
+    pub fn new(registry: SocketRegistry, socket: &TcpStream) -> Self {
+        let id = registry.next_id.fetch_add(1, Ordering::Relaxed);
+        if let Ok(handle) = socket.try_clone() {
+            registry.open.lock().unwrap().insert(id, handle);
+        }
+
+        Self {
+            registry,
+            id,
+            opened: Instant::now(),
+            bytes_sent: 0,
+            bytes_received: 0,
+        }
+    }
+
+    // Call before the socket is closed, so the kernel still knows about it
+    pub fn finish(&self, socket: &TcpStream) {
+        let info = tcp_info(socket);
+        // our handle would keep the connection open after its owner closes it
+        self.registry.open.lock().unwrap().remove(&self.id);
+
+        self.registry.record(SocketRecord {
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            duration: self.opened.elapsed(),
+            rtt: info.map(|info| info.rtt),
+            retransmits: info.map(|info| info.retransmits),
+            segments_sent: info.map(|info| info.segments_sent),
+        });
+    }
+}
+
+// The parts of TCP_INFO we keep
+#[derive(Clone, Copy)]
+struct TcpInfo {
+    // smoothed
+    rtt: Duration,
+    retransmits: u32,
+    // data segments, as retransmits are: pure ACKs would water a download's
+    // retransmit rate down to nothing
+    segments_sent: u32,
+}
+
+#[cfg(target_os = "linux")]
+fn tcp_info(socket: &TcpStream) -> Option<TcpInfo> {
+    use std::os::fd::AsRawFd;
+
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+
+    // SAFETY: info is a valid tcp_info of `len` bytes for the kernel to fill in
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if ret != 0 {
+        return None;
+    }
+
+    Some(TcpInfo {
+        rtt: Duration::from_micros(info.tcpi_rtt.into()),
+        retransmits: info.tcpi_total_retrans,
+        // kernels before 4.6 only count every segment
+        segments_sent: match info.tcpi_data_segs_out {
+            0 => info.tcpi_segs_out,
+            data => data,
+        },
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn tcp_info(_socket: &TcpStream) -> Option<TcpInfo> {
+    None
+}
+
+// min/median/mean/max of some per-connection figure
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Distribution {
+    pub min: f64,
+    pub median: f64,
+    pub mean: f64,
+    pub max: f64,
+}
+
+impl Distribution {
+    fn of(values: Vec<f64>) -> Option<Self> {
+        Some(Self {
+            min: values.iter().copied().fold(f64::INFINITY, f64::min),
+            median: crate::median(values.clone())?,
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            max: values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        })
+    }
+}
+
+// Totals and distributions across every connection of a run
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SocketSummary {
+    pub connections: usize,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub duration_secs: Distribution,
+    pub bytes_per_connection: Distribution,
+    // only on platforms that expose TCP_INFO
+    pub rtt_ms: Option<Distribution>,
+    pub retransmits: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub segments_sent: Option<u64>,
+    // the fastest any one connection downloaded and uploaded, in bits per
+    // second over its lifetime, see `bdp`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_download_bps: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_upload_bps: Option<f64>,
+}
+
+impl SocketSummary {
+    pub fn from_records(records: &[SocketRecord]) -> Option<Self> {
+        let duration_secs =
+            Distribution::of(records.iter().map(|r| r.duration.as_secs_f64()).collect())?;
+        let bytes_per_connection = Distribution::of(
+            records
+                .iter()
+                .map(|r| (r.bytes_sent + r.bytes_received) as f64)
+                .collect(),
+        )?;
+        let rtt_ms = Distribution::of(
+            records
+                .iter()
+                .filter_map(|r| r.rtt)
+                .map(|rtt| rtt.as_secs_f64() * 1000.0)
+                .collect(),
+        );
+        let retransmits = records
+            .iter()
+            .filter_map(|r| r.retransmits)
+            .map(u64::from)
+            .reduce(|a, b| a + b);
+        let segments_sent = records
+            .iter()
+            .filter_map(|r| r.segments_sent)
+            .map(u64::from)
+            .reduce(|a, b| a + b);
+
+        // a connection went whichever way most of its bytes did
+        let fastest = |download: bool| {
+            records
+                .iter()
+                .filter(|r| (r.bytes_received > r.bytes_sent) == download)
+                .map(|r| (r.bytes_received.max(r.bytes_sent), r.duration))
+                .filter(|&(bytes, _)| bytes >= MIN_STREAM_BYTES)
+                .map(|(bytes, duration)| {
+                    bytes as f64 * 8.0 / duration.as_secs_f64().max(f64::EPSILON)
+                })
+                .max_by(f64::total_cmp)
+        };
+
+        Some(Self {
+            connections: records.len(),
+            bytes_sent: records.iter().map(|r| r.bytes_sent).sum(),
+            bytes_received: records.iter().map(|r| r.bytes_received).sum(),
+            duration_secs,
+            bytes_per_connection,
+            rtt_ms,
+            retransmits,
+            segments_sent,
+            stream_download_bps: fastest(true),
+            stream_upload_bps: fastest(false),
+        })
+    }
+
+    // Share of the segments we sent that had to be sent again, as a percentage.
+    // The closest thing to packet loss we can see from our end of a TCP stream,
+    // though only for data we send (so mostly the upload).
+    pub fn retransmit_percent(&self) -> Option<f64> {
+        match (self.retransmits, self.segments_sent) {
+            (Some(retransmits), Some(sent)) if sent > 0 => {
+                Some(retransmits as f64 / sent as f64 * 100.0)
+            }
+            _ => None,
+        }
+    }
+}
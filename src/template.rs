@@ -0,0 +1,145 @@
+use crate::results::RunResult;
+use serde_json::Value;
+
+// A piece of a parsed template
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    // a dotted path into the result, and how many decimals to show
+    Field {
+        path: String,
+        precision: Option<usize>,
+    },
+}
+
+/* A line like "{{download_mbps}} / {{upload_mbps}} @ {{latency_ms:.1}}ms",
+   filled in from the result object. Fields are the result's JSON fields,
+   with dots for nested ones (e.g. download.p90_bps), plus a few shorthands
+   (see `with_shorthands`) like download_sparkline with --history. Fields
+   the run doesn't have come out empty.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template(Vec<Part>);
+
+impl std::str::FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut rest = s;
+
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_owned()));
+            }
+
+            let end = rest[start..]
+                .find("}}")
+                .ok_or_else(|| format!("unclosed {{{{ in template '{s}'"))?;
+            let field = rest[start + 2..start + end].trim();
+            let (path, precision) = match field.split_once(":.") {
+                Some((path, precision)) => (
+                    path.trim(),
+                    Some(precision.parse().map_err(|_| {
+                        format!("invalid precision in {{{{{field}}}}}, expected e.g. :.2")
+                    })?),
+                ),
+                None => (field, None),
+            };
+            if path.is_empty() {
+                return Err(format!("empty {{{{}}}} in template '{s}'"));
+            }
+
+            parts.push(Part::Field {
+                path: path.to_owned(),
+                precision,
+            });
+            rest = &rest[start + end + 2..];
+        }
+
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_owned()));
+        }
+
+        Ok(Self(parts))
+    }
+}
+
+// The result as JSON, with the figures people usually want in a one-liner.
+// `sparkline` is the recent download history, when there is one.
+fn with_shorthands(run: &RunResult, sparkline: Option<&str>) -> Value {
+    let mut value = serde_json::to_value(run).unwrap_or_default();
+    let mbps = |bps: Option<f64>| bps.map(|bps| bps / 1e6);
+    let shorthands = [
+        (
+            "download_mbps",
+            mbps(run.download.as_ref().map(|p| p.median_bps)),
+        ),
+        (
+            "upload_mbps",
+            mbps(run.upload.as_ref().map(|p| p.median_bps)),
+        ),
+        (
+            "download_p90_mbps",
+            mbps(run.download.as_ref().map(|p| p.p90_bps)),
+        ),
+        (
+            "upload_p90_mbps",
+            mbps(run.upload.as_ref().map(|p| p.p90_bps)),
+        ),
+    ];
+
+    if let Value::Object(object) = &mut value {
+        for (name, figure) in shorthands {
+            object.insert(name.to_owned(), figure.into());
+        }
+        object.insert("download_sparkline".to_owned(), sparkline.into());
+    }
+
+    value
+}
+
+fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |value, key| value.get(key))
+}
+
+// One field of the result by dotted path, as --field prints it: numbers and
+// strings bare, anything else as JSON, nothing if the run doesn't have it
+pub fn select(run: &RunResult, sparkline: Option<&str>, path: &str) -> String {
+    match lookup(&with_shorthands(run, sparkline), path) {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(other) => other.to_string(),
+    }
+}
+
+fn render_value(value: &Value, precision: Option<usize>) -> String {
+    match (value, precision) {
+        (Value::Null, _) => String::new(),
+        (Value::String(s), _) => s.clone(),
+        (Value::Number(n), Some(precision)) => {
+            format!("{:.*}", precision, n.as_f64().unwrap_or_default())
+        }
+        (Value::Number(n), None) => match n.as_f64() {
+            Some(f) if n.is_f64() && f.fract() != 0.0 => format!("{f:.2}"),
+            _ => n.to_string(),
+        },
+        (other, _) => other.to_string(),
+    }
+}
+
+impl Template {
+    pub fn render(&self, run: &RunResult, sparkline: Option<&str>) -> String {
+        let value = with_shorthands(run, sparkline);
+
+        self.0
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Field { path, precision } => lookup(&value, path)
+                    .map(|value| render_value(value, *precision))
+                    .unwrap_or_default(),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,1066 @@
+use crate::integrity::{self, ContentSample, ContentSamples};
+use crate::net::ConnectOptions;
+use crate::pacing::TokenBucket;
+use crate::phases::{PhaseConfig, Ramp};
+use crate::results::Event;
+use crate::sockets::{SocketRecord, SocketRegistry};
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use ureq::{Agent, AgentBuilder};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+static NEW_METAL_SLEEP_MILLIS: u32 = 250;
+// How long workers get to notice their connection was shut down, once the
+// join timeout has passed, before we leave them behind
+static JOIN_GRACE_MILLIS: u64 = 1000;
+// How often a phase reads its byte counter and checks for its deadline
+pub static TICK_MILLIS: u64 = 100;
+
+/* Dial-up, a congested satellite beam and the like move so little in a
+   normal phase that its figures come down to a handful of bursts. A phase
+   still under SLOW_LINK_BPS over the last second once
+   SLOW_LINK_CHECK_SECONDS are in (not since the start, which slow start
+   and the staggered threads hold back on any link) is given
+   at least SLOW_LINK_SECONDS to measure for, and keeps only
+   SLOW_LINK_THREADS of its threads, since more connections on a link that
+   slow only take turns and each new one spends longer in slow start.
+*/
+static SLOW_LINK_CHECK_SECONDS: u64 = 3;
+pub static SLOW_LINK_BPS: f64 = 1e6;
+pub static SLOW_LINK_SECONDS: u64 = 30;
+pub static SLOW_LINK_THREADS: u32 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+impl Direction {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Download => "download",
+            Self::Upload => "upload",
+        }
+    }
+}
+
+/* Something we can saturate with parallel transfers. The Cloudflare targets
+   below are what cf_speedtest uses, implement this to measure your own
+   endpoints with the same machinery.
+
+   Download requests are sent with no body and their response is sunk,
+   upload requests are sent `bytes` of filler and their response is sunk.
+*/
+pub trait ThroughputTarget: Send + Sync {
+    fn direction(&self) -> Direction;
+
+    // The request for a single transfer of `bytes`
+    fn build_request(&self, agent: &Agent, bytes: usize) -> ureq::Request;
+
+    // Reject a response before its body is counted, e.g. a CDN error page
+    // served with 200. Error statuses are rejected before we get here.
+    fn validate_response(&self, _response: &ureq::Response) -> Result<()> {
+        Ok(())
+    }
+}
+
+/* A target that doesn't transfer over plain HTTP/1.1 requests (ndt7's
+   WebSockets, HTTP/3, pipelined requests) and moves its bytes itself
+   instead, `bytes` at a time where it makes requests, counting them in
+   `counters` until the phase is over.
+*/
+pub trait StreamTarget: Send + Sync {
+    fn direction(&self) -> Direction;
+
+    fn stream(&self, bytes: usize, counters: &Counters) -> Result<()>;
+}
+
+// What a phase transfers against, one way or the other
+#[derive(Clone)]
+pub enum Target {
+    Requests(Arc<dyn ThroughputTarget>),
+    Stream(Arc<dyn StreamTarget>),
+}
+
+impl Target {
+    pub fn direction(&self) -> Direction {
+        match self {
+            Self::Requests(target) => target.direction(),
+            Self::Stream(target) => target.direction(),
+        }
+    }
+
+    // Transfer `bytes` once, reusing `agent`'s connection if there is one
+    pub fn transfer(&self, agent: Option<&Agent>, bytes: usize, counters: &Counters) -> Result<()> {
+        match (self, agent) {
+            (Self::Requests(target), Some(agent)) => {
+                transfer_via(agent, target.as_ref(), bytes, counters)
+            }
+            (Self::Requests(target), None) => transfer(target.as_ref(), bytes, counters),
+            (Self::Stream(target), _) => target.stream(bytes, counters),
+        }
+    }
+}
+
+pub struct CloudflareDownload;
+
+impl ThroughputTarget for CloudflareDownload {
+    fn direction(&self) -> Direction {
+        Direction::Download
+    }
+
+    fn build_request(&self, agent: &Agent, bytes: usize) -> ureq::Request {
+        agent
+            .get(&format!(
+                "{}&bytes={bytes}",
+                crate::CLOUDFLARE_SPEEDTEST_DOWNLOAD_URL
+            ))
+            .set("User-Agent", crate::OUR_USER_AGENT)
+    }
+}
+
+pub struct CloudflareUpload;
+
+impl ThroughputTarget for CloudflareUpload {
+    fn direction(&self) -> Direction {
+        Direction::Upload
+    }
+
+    fn build_request(&self, agent: &Agent, _bytes: usize) -> ureq::Request {
+        agent
+            .post(crate::CLOUDFLARE_SPEEDTEST_UPLOAD_URL)
+            .set("Content-Type", "text/plain;charset=UTF-8")
+            .set("User-Agent", crate::OUR_USER_AGENT)
+    }
+}
+
+// What the transfers go over, HTTP/1.1 unless --http-version says otherwise
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HttpVersion {
+    Http1,
+    // over QUIC, only with the http3 feature
+    Http3,
+}
+
+impl std::str::FromStr for HttpVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "1.1" | "1" => Ok(Self::Http1),
+            "3" => Ok(Self::Http3),
+            _ => Err(format!("unknown HTTP version '{s}', expected 1.1 or 3")),
+        }
+    }
+}
+
+impl HttpVersion {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Http1 => "HTTP/1.1",
+            Self::Http3 => "HTTP/3",
+        }
+    }
+}
+
+/* Your own server, e.g. a LibreSpeed install or a Worker, from a URL
+   template given on the command line. {bytes} in it is replaced by the
+   size of each request, which a download server should send back.
+*/
+pub struct UrlTarget {
+    pub direction: Direction,
+    pub template: String,
+}
+
+impl UrlTarget {
+    pub fn url(&self, bytes: usize) -> String {
+        self.template.replace("{bytes}", &bytes.to_string())
+    }
+}
+
+impl ThroughputTarget for UrlTarget {
+    fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    fn build_request(&self, agent: &Agent, bytes: usize) -> ureq::Request {
+        let request = match self.direction {
+            Direction::Download => agent.get(&self.url(bytes)),
+            Direction::Upload => agent
+                .post(&self.url(bytes))
+                .set("Content-Type", "application/octet-stream"),
+        };
+        request.set("User-Agent", crate::OUR_USER_AGENT)
+    }
+}
+
+// Where a phase notes what the tool itself did, as it happens
+#[derive(Clone)]
+pub struct EventLog {
+    phase: &'static str,
+    start: Instant,
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl EventLog {
+    pub fn new(phase: &'static str) -> Self {
+        Self {
+            phase,
+            start: Instant::now(),
+            events: Arc::default(),
+        }
+    }
+
+    pub fn record(&self, kind: &str, thread: Option<u32>, detail: Option<String>) {
+        let event = Event {
+            phase: self.phase.to_owned(),
+            at_secs: self.start.elapsed().as_secs_f64(),
+            kind: kind.to_owned(),
+            thread,
+            detail,
+        };
+        crate::crash::remember(&event);
+        self.events.lock().unwrap().push(event);
+    }
+
+    // Everything recorded so far, oldest first
+    pub fn all(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        Self::new("")
+    }
+}
+
+// Why a worker stopped
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopReason {
+    // the phase's time was up
+    Deadline,
+    // a transfer failed, with why
+    Error(String),
+    // more threads stopped making the phase faster, so this one was let go
+    Retired,
+}
+
+/* A worker starting or stopping, for showing what each of a phase's
+   connections is up to. Read off the phase's events, where they're
+   thread_started, thread_stopped and thread_failed.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub enum WorkerEvent {
+    Started {
+        worker: u32,
+        at_secs: f64,
+    },
+    Stopped {
+        worker: u32,
+        at_secs: f64,
+        reason: StopReason,
+    },
+}
+
+impl WorkerEvent {
+    // None for events that aren't about a single worker
+    pub fn from_event(event: &Event) -> Option<Self> {
+        let worker = event.thread?;
+        let at_secs = event.at_secs;
+        let detail = || event.detail.clone().unwrap_or_default();
+
+        match event.kind.as_str() {
+            "thread_started" => Some(Self::Started { worker, at_secs }),
+            "thread_stopped" => Some(Self::Stopped {
+                worker,
+                at_secs,
+                reason: match event.detail.as_deref() {
+                    Some("retired") => StopReason::Retired,
+                    _ => StopReason::Deadline,
+                },
+            }),
+            "thread_failed" => Some(Self::Stopped {
+                worker,
+                at_secs,
+                reason: StopReason::Error(detail()),
+            }),
+            _ => None,
+        }
+    }
+}
+
+// State shared between the workers of a phase and whoever is sampling them
+#[derive(Clone, Default)]
+pub struct Counters {
+    // 64 bits even where usize is 32, which a few seconds of a fast link
+    // would wrap
+    pub total_bytes: Arc<AtomicU64>,
+    pub current_speed: Arc<AtomicUsize>,
+    // each worker has one of its own, so some can be stopped before the rest
+    pub exit_signal: Arc<AtomicBool>,
+    pub sockets: SocketRegistry,
+    pub events: EventLog,
+    pub connect: ConnectOptions,
+    // shared by all the workers when the run is rate limited
+    pub pacer: Option<Arc<TokenBucket>>,
+    // workers that have started and not yet stopped
+    pub running_threads: Arc<AtomicUsize>,
+    // workers numbered this or higher stop after their current transfer,
+    // 0 while they're all wanted
+    pub retire_from: Arc<AtomicU32>,
+    // with --verify-content, what the start of each download hashed to
+    pub content: Option<ContentSamples>,
+}
+
+impl Counters {
+    // Count `len` more bytes transferred in the phase
+    pub fn add_bytes(&self, len: usize) {
+        self.total_bytes.fetch_add(len as u64, Ordering::SeqCst);
+    }
+}
+
+// Counts a worker as running for as long as it's held, however it stops
+struct Running(Arc<AtomicUsize>);
+
+impl Running {
+    fn start(threads: &Arc<AtomicUsize>) -> Self {
+        threads.fetch_add(1, Ordering::SeqCst);
+        Self(Arc::clone(threads))
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/* How a running phase is doing, for library users to poll from another
+   thread without knowing how the workers keep count
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricsSnapshot {
+    pub direction: Direction,
+    // since the phase started
+    pub elapsed: Duration,
+    // transferred so far in the phase
+    pub bytes: u64,
+    // bytes/s over the last second
+    pub bytes_per_sec: usize,
+    // workers still running
+    pub threads: usize,
+}
+
+impl MetricsSnapshot {
+    // Wide enough for any total a phase could reach
+    pub fn bits(&self) -> u128 {
+        self.bytes as u128 * 8
+    }
+
+    pub fn bits_per_sec(&self) -> f64 {
+        self.bytes_per_sec as f64 * 8.0
+    }
+}
+
+// A handle on a phase's counters that can outlive the phase itself
+#[derive(Clone)]
+pub struct PhaseMonitor {
+    direction: Direction,
+    started: Instant,
+    counters: Counters,
+}
+
+impl PhaseMonitor {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            direction: self.direction,
+            elapsed: self.started.elapsed(),
+            bytes: self.counters.total_bytes.load(Ordering::Relaxed),
+            bytes_per_sec: self.counters.current_speed.load(Ordering::Relaxed),
+            threads: self.counters.running_threads.load(Ordering::SeqCst),
+        }
+    }
+}
+
+// What a phase leaves behind besides its samples
+pub struct PhaseRecords {
+    pub sockets: Vec<SocketRecord>,
+    pub events: Vec<Event>,
+    // how many threads it took to saturate the link, if we found out
+    pub saturation: Option<Saturation>,
+    pub content: Vec<ContentSample>,
+    // how many workers were running when sampling stopped
+    pub threads: Option<u32>,
+}
+
+// How long a phase meant to run for `seconds` gets, given that it was
+// moving `bytes_per_sec` SLOW_LINK_CHECK_SECONDS in. None if the link isn't
+// slow.
+pub fn slow_link_seconds(seconds: u64, bytes_per_sec: usize) -> Option<u64> {
+    let bps = bytes_per_sec as f64 * 8.0;
+    (bps < SLOW_LINK_BPS).then_some(seconds.max(SLOW_LINK_SECONDS))
+}
+
+// Without --adaptive-threads, how little more threads have to add for the
+// link to count as saturated in the report
+pub static REPORT_SATURATION_GAIN_PERCENT: f64 = 2.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Saturation {
+    pub threads: u32,
+    // the most any more threads sped the phase up by, negative if they only
+    // slowed it down
+    pub gain_percent: f64,
+}
+
+/* Turns readings of a phase's byte counter, taken every TICK_MILLIS, into
+   bytes per second. A sample is due once a second has passed since the
+   last and is what moved since then over exactly how long that was, so a
+   late tick (a busy machine, a sleep that overran) doesn't pass for a fast
+   second. Readings go by the monotonic clock, which NTP or a user setting
+   the time can't step.
+*/
+pub struct Sampler {
+    // the last sample's reading
+    last: (Instant, u64),
+    // the last second's worth of readings, for the current speed
+    recent: std::collections::VecDeque<(Instant, u64)>,
+}
+
+impl Sampler {
+    pub fn new(start: Instant) -> Self {
+        Self {
+            last: (start, 0),
+            recent: [(start, 0)].into(),
+        }
+    }
+
+    fn rate(from: (Instant, u64), to: (Instant, u64)) -> usize {
+        let secs = to.0.saturating_duration_since(from.0).as_secs_f64();
+        let bytes = to.1.saturating_sub(from.1) as f64;
+        (bytes / secs.max(f64::EPSILON)) as usize
+    }
+
+    // A reading of `bytes` in total at `now`, and the sample if one's due
+    pub fn tick(&mut self, now: Instant, bytes: u64) -> Option<usize> {
+        self.recent.push_back((now, bytes));
+        while self
+            .recent
+            .get(1)
+            .is_some_and(|&(at, _)| now.saturating_duration_since(at) >= Duration::from_secs(1))
+        {
+            self.recent.pop_front();
+        }
+
+        if now.saturating_duration_since(self.last.0) < Duration::from_secs(1) {
+            return None;
+        }
+        let sample = Self::rate(self.last, (now, bytes));
+        self.last = (now, bytes);
+        Some(sample)
+    }
+
+    // Bytes per second over about the last second, for progress and buffer
+    // sizes; smoother than the samples, being updated every tick
+    pub fn current(&self) -> usize {
+        match (self.recent.front(), self.recent.back()) {
+            (Some(&first), Some(&last)) if first.0 < last.0 => Self::rate(first, last),
+            _ => 0,
+        }
+    }
+
+    // The second cut short when the phase stopped, if it's worth a sample:
+    // half a second or more, or all the phase had
+    pub fn finish(&self, now: Instant, bytes: u64, samples: usize) -> Option<usize> {
+        let secs = now.saturating_duration_since(self.last.0).as_secs_f64();
+        (secs >= 0.5 || (samples == 0 && secs > 0.0)).then(|| Self::rate(self.last, (now, bytes)))
+    }
+}
+
+/* Works out when more threads stop making a phase faster. Each sample is
+   compared to the best so far: more threads and at least `min_gain_percent`
+   faster makes it the new best, but two samples in a row with more threads
+   and no such gain mean the link was saturated by the best's thread count.
+   Two, since a thread that's just started is still in TCP slow start.
+*/
+pub struct SaturationDetector {
+    min_gain_percent: f64,
+    best_threads: usize,
+    best_bytes: usize,
+    misses: u32,
+    miss_gain: f64,
+}
+
+impl SaturationDetector {
+    pub fn new(min_gain_percent: f64) -> Self {
+        Self {
+            min_gain_percent,
+            best_threads: 0,
+            best_bytes: 0,
+            misses: 0,
+            miss_gain: f64::NEG_INFINITY,
+        }
+    }
+
+    // The thread count the link saturated at, once it's clear
+    pub fn observe(&mut self, threads: usize, bytes: usize) -> Option<Saturation> {
+        if threads <= self.best_threads {
+            return None;
+        }
+
+        let needed = self.best_bytes as f64 * (1.0 + self.min_gain_percent / 100.0);
+        if self.best_threads == 0 || bytes as f64 >= needed {
+            self.best_threads = threads;
+            self.best_bytes = bytes;
+            self.misses = 0;
+            self.miss_gain = f64::NEG_INFINITY;
+            return None;
+        }
+
+        let gain = (bytes as f64 / self.best_bytes as f64 - 1.0) * 100.0;
+        self.miss_gain = self.miss_gain.max(gain);
+        self.misses += 1;
+        (self.misses >= 2).then_some(Saturation {
+            threads: self.best_threads as u32,
+            gain_percent: self.miss_gain,
+        })
+    }
+}
+
+impl std::io::Read for UploadHelper {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // upload is finished, or we are exiting
+        if self.byte_ctr.load(Ordering::SeqCst) >= self.bytes_to_send
+            || self.exit_signal.load(Ordering::SeqCst)
+        {
+            return Ok(0);
+        }
+
+        let len = match &self.pacer {
+            Some(pacer) => buf.len().min(pacer.max_chunk()),
+            None => buf.len(),
+        };
+        buf[..len].fill(1);
+        if let Some(pacer) = &self.pacer {
+            pacer.take(len);
+        }
+
+        self.byte_ctr.fetch_add(len, Ordering::SeqCst);
+        self.total_uploaded_counter
+            .fetch_add(len as u64, Ordering::SeqCst);
+        Ok(len)
+    }
+}
+
+struct UploadHelper {
+    bytes_to_send: usize,
+    byte_ctr: Arc<AtomicUsize>,
+    total_uploaded_counter: Arc<AtomicU64>,
+    exit_signal: Arc<AtomicBool>,
+    pacer: Option<Arc<TokenBucket>>,
+}
+
+fn get_appropriate_buff_size(speed: usize) -> u64 {
+    match speed {
+        0..=1000 => 4,
+        1001..=10000 => 32,
+        10001..=100000 => 512,
+        100001..=1000000 => 4096,
+        _ => 16384,
+    }
+}
+
+// The agent used for the actual transfers, its connections report to `sockets`
+pub fn build_agent(sockets: &SocketRegistry, connect: &ConnectOptions) -> Agent {
+    let custom_connector = crate::tls::InterceptingTlsConnector::with_socket_stats(sockets.clone())
+        .via(connect.clone());
+
+    let mut builder = AgentBuilder::new()
+        .resolver(connect.resolver())
+        .tls_connector(Arc::new(custom_connector))
+        .timeout_connect(std::time::Duration::from_millis(
+            crate::net::CONNECT_TIMEOUT_MILLIS,
+        ))
+        .redirects(0);
+    if let Some(proxy) = &connect.proxy {
+        builder = builder.proxy(proxy.ureq());
+    }
+
+    builder.build()
+}
+
+// Transfer `bytes` to or from the target on a connection of its own,
+// counting them as they go
+pub fn transfer(target: &dyn ThroughputTarget, bytes: usize, counters: &Counters) -> Result<()> {
+    let agent = build_agent(&counters.sockets, &counters.connect);
+    transfer_via(&agent, target, bytes, counters)
+}
+
+// The same, reusing whatever connection `agent` has open
+pub fn transfer_via(
+    agent: &Agent,
+    target: &dyn ThroughputTarget,
+    bytes: usize,
+    counters: &Counters,
+) -> Result<()> {
+    match target.direction() {
+        Direction::Download => download(agent, target, bytes, counters),
+        Direction::Upload => upload(agent, target, bytes, counters),
+    }
+}
+
+fn upload(
+    agent: &Agent,
+    target: &dyn ThroughputTarget,
+    bytes: usize,
+    counters: &Counters,
+) -> Result<()> {
+    let upload_helper = UploadHelper {
+        bytes_to_send: bytes,
+        byte_ctr: Arc::new(AtomicUsize::new(0)),
+        total_uploaded_counter: counters.total_bytes.clone(),
+        exit_signal: counters.exit_signal.clone(),
+        pacer: counters.pacer.clone(),
+    };
+
+    let resp = match target.build_request(agent, bytes).send(upload_helper) {
+        Ok(resp) => resp,
+        Err(err) => {
+            counters
+                .events
+                .record("request_failed", None, Some(err.to_string()));
+            return Ok(());
+        }
+    };
+    target.validate_response(&resp)?;
+
+    // read the POST response body into the void if response is okay, which
+    // also frees the connection for the next request
+    let _ = std::io::copy(&mut resp.into_reader(), &mut std::io::sink());
+
+    Ok(())
+}
+
+fn download(
+    agent: &Agent,
+    target: &dyn ThroughputTarget,
+    bytes: usize,
+    counters: &Counters,
+) -> Result<()> {
+    let resp = match target.build_request(agent, bytes).call() {
+        Ok(resp) => resp,
+        Err(err) => {
+            counters
+                .events
+                .record("request_failed", None, Some(err.to_string()));
+            return Ok(());
+        }
+    };
+    target.validate_response(&resp)?;
+
+    let url = resp.get_url().to_owned();
+    let mut resp_reader = resp.into_reader();
+    let mut total_bytes_sank: usize = 0;
+    let sample_bytes = match counters.content {
+        Some(_) => integrity::SAMPLE_BYTES.min(bytes),
+        None => 0,
+    };
+    let mut sampler = integrity::Sampler::new(sample_bytes);
+
+    loop {
+        // exit if we have passed deadline
+        if counters.exit_signal.load(Ordering::Relaxed) {
+            // a response we stopped reading is only worth comparing if we
+            // got as far as hashing all of its sample
+            if let Some(content) = &counters.content {
+                if sample_bytes > 0 && sampler.sampled() == sample_bytes {
+                    content.record(sampler.finish(false));
+                }
+            }
+            return Ok(());
+        }
+
+        // if we are fast, take big chunks
+        // if we are slow, take small chunks
+        let mut current_recv_buff =
+            get_appropriate_buff_size(counters.current_speed.load(Ordering::Relaxed));
+        if let Some(pacer) = &counters.pacer {
+            current_recv_buff = current_recv_buff.min(pacer.max_chunk() as u64);
+        }
+
+        // copy bytes into the void, hashing the sample on the way
+        let bytes_sank = std::io::copy(
+            &mut resp_reader.by_ref().take(current_recv_buff),
+            &mut sampler,
+        )? as usize;
+
+        if bytes_sank == 0 {
+            if total_bytes_sank == 0 {
+                counters.events.record(
+                    "request_failed",
+                    None,
+                    Some(format!("{url} sent an empty response")),
+                );
+            }
+            if let Some(content) = &counters.content {
+                content.record(sampler.finish(total_bytes_sank < bytes));
+            }
+
+            return Ok(());
+        }
+
+        if let Some(pacer) = &counters.pacer {
+            pacer.take(bytes_sank);
+        }
+
+        total_bytes_sank += bytes_sank;
+        counters.add_bytes(bytes_sank);
+    }
+}
+
+// A running test phase: worker threads transferring against one target
+pub struct Phase {
+    direction: Direction,
+    started: Instant,
+    // see PhaseConfig::saturation_gain, threads are only retired if it's set
+    saturation_gain: Option<f64>,
+    saturation: OnceLock<Saturation>,
+    counters: Counters,
+    handles: Vec<JoinHandle<()>>,
+    // each worker's exit_signal, in order
+    exit_signals: Vec<Arc<AtomicBool>>,
+    // how long sampling runs for, more than asked on a slow link
+    seconds: AtomicU64,
+    extend_slow_link: bool,
+    // when sampling stopped and the workers were told to
+    deadline: OnceLock<Instant>,
+    // and how many of them were running then
+    threads: OnceLock<u32>,
+    // ends sampling before `seconds` are up if triggered
+    interrupt: crate::Interrupt,
+}
+
+impl Phase {
+    // Spawn `config.threads` workers, each repeatedly transferring
+    // `config.bytes_per_request`, together no faster than `pacer` allows if
+    // there is one
+    pub fn start(
+        target: Target,
+        config: &PhaseConfig,
+        connect: &ConnectOptions,
+        pacer: Option<Arc<TokenBucket>>,
+    ) -> Self {
+        let direction = target.direction();
+        let counters = Counters {
+            events: EventLog::new(direction.name()),
+            connect: connect.clone(),
+            pacer,
+            current_speed: Arc::new(AtomicUsize::new(config.expected_speed)),
+            content: config.verify_content.then(ContentSamples::default),
+            ..Counters::default()
+        };
+        let mut handles = vec![];
+        let mut exit_signals = vec![];
+
+        for i in 0..config.threads {
+            let target = target.clone();
+            let counters = Counters {
+                exit_signal: Arc::default(),
+                ..counters.clone()
+            };
+            exit_signals.push(Arc::clone(&counters.exit_signal));
+            let config = config.clone();
+            let handle = std::thread::spawn(move || {
+                if i > 0 && config.ramp == Ramp::Staggered {
+                    // sleep a little to hit a new cloudflare metal
+                    // (each metal will throttle to 1 gigabit)
+                    std::thread::sleep(std::time::Duration::from_millis(
+                        (i * NEW_METAL_SLEEP_MILLIS).into(),
+                    ));
+                }
+
+                let _running = Running::start(&counters.running_threads);
+                counters.events.record("thread_started", Some(i), None);
+                let kept_agent = config
+                    .reuse_connections
+                    .then(|| build_agent(&counters.sockets, &counters.connect));
+                loop {
+                    let retire_from = counters.retire_from.load(Ordering::SeqCst);
+                    if retire_from > 0 && i >= retire_from {
+                        counters.events.record(
+                            "thread_stopped",
+                            Some(i),
+                            Some("retired".to_owned()),
+                        );
+                        return;
+                    }
+
+                    let bytes = config.bytes_per_request;
+                    if let Err(e) = target.transfer(kept_agent.as_ref(), bytes, &counters) {
+                        counters
+                            .events
+                            .record("thread_failed", Some(i), Some(e.to_string()));
+                        return;
+                    }
+
+                    // exit if we have passed the deadline, or were let go
+                    // in the middle of the transfer
+                    if counters.exit_signal.load(Ordering::Relaxed) {
+                        let retire_from = counters.retire_from.load(Ordering::SeqCst);
+                        let reason = if retire_from > 0 && i >= retire_from {
+                            "retired"
+                        } else {
+                            "deadline"
+                        };
+                        counters
+                            .events
+                            .record("thread_stopped", Some(i), Some(reason.to_owned()));
+                        return;
+                    }
+                }
+            });
+            handles.push(handle);
+        }
+
+        Self {
+            direction,
+            started: Instant::now(),
+            saturation_gain: config.saturation_gain,
+            saturation: OnceLock::new(),
+            counters,
+            handles,
+            exit_signals,
+            seconds: AtomicU64::new(config.seconds),
+            extend_slow_link: config.extend_slow_link,
+            deadline: OnceLock::new(),
+            threads: OnceLock::new(),
+            interrupt: crate::Interrupt::default(),
+        }
+    }
+
+    // Stop sampling at the next second if `interrupt` is triggered
+    pub fn interrupted_by(self, interrupt: &crate::Interrupt) -> Self {
+        Self {
+            interrupt: interrupt.clone(),
+            ..self
+        }
+    }
+
+    pub fn monitor(&self) -> PhaseMonitor {
+        PhaseMonitor {
+            direction: self.direction,
+            started: self.started,
+            counters: self.counters.clone(),
+        }
+    }
+
+    // How long the phase is sampled for, which a slow link extends
+    pub fn seconds(&self) -> u64 {
+        self.seconds.load(Ordering::SeqCst)
+    }
+
+    // Tell workers numbered `from` and up to stop, mid-transfer if need be
+    fn stop_workers(&self, from: u32) {
+        for exit_signal in self.exit_signals.iter().skip(from as usize) {
+            exit_signal.store(true, Ordering::SeqCst);
+        }
+    }
+
+    // Measure bytes transferred per second for `seconds`, then tell the
+    // workers to stop. Each sample is handed to `on_sample` as it is taken.
+    pub fn sample_for(&self, seconds: u64, mut on_sample: impl FnMut(usize)) -> Vec<usize> {
+        // timed on the monotonic clock, the wall clock may not be set or may
+        // be stepped by NTP in the middle of a phase
+        let start = Instant::now();
+        let mut deadline = start + Duration::from_secs(seconds);
+        self.seconds.store(seconds, Ordering::SeqCst);
+        let check_slow_link = start + Duration::from_secs(SLOW_LINK_CHECK_SECONDS);
+        let mut slow_link_checked = !self.extend_slow_link;
+        let start_bytes = self.counters.total_bytes.load(Ordering::Relaxed);
+        let mut sampler = Sampler::new(start);
+        let mut measurements = vec![];
+        let mut saturation = Some(SaturationDetector::new(
+            self.saturation_gain
+                .unwrap_or(REPORT_SATURATION_GAIN_PERCENT),
+        ));
+
+        for tick in 1.. {
+            // ticks are due at whole multiples from the start, so oversleeping
+            // one doesn't push the rest back
+            let due = start + Duration::from_millis(TICK_MILLIS * tick);
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+            let now = Instant::now();
+            let bytes = self.counters.total_bytes.load(Ordering::Relaxed) - start_bytes;
+
+            let sample = sampler.tick(now, bytes);
+            self.counters
+                .current_speed
+                .store(sampler.current(), Ordering::SeqCst);
+
+            if !slow_link_checked && now >= check_slow_link && now < deadline {
+                slow_link_checked = true;
+                let current = sampler.current();
+                if let Some(extended) = slow_link_seconds(seconds, current) {
+                    deadline = start + Duration::from_secs(extended);
+                    self.slow_link(extended, current);
+                }
+            }
+
+            // exit if we have passed the deadline, or been told to stop
+            // early
+            let stop = if now >= deadline {
+                Some("deadline_reached")
+            } else if self.interrupt.is_triggered() {
+                Some("interrupted")
+            } else {
+                None
+            };
+            let sample = match stop {
+                Some(_) => sample.or_else(|| sampler.finish(now, bytes, measurements.len())),
+                None => sample,
+            };
+
+            if let Some(bytes_per_sec) = sample {
+                measurements.push(bytes_per_sec);
+                on_sample(bytes_per_sec);
+                self.observe_saturation(&mut saturation, bytes_per_sec);
+            }
+
+            if let Some(kind) = stop {
+                let running = self.counters.running_threads.load(Ordering::SeqCst);
+                let _ = self.threads.set(running as u32);
+                self.stop_workers(0);
+                let _ = self.deadline.set(now);
+                self.counters.events.record(kind, None, None);
+                break;
+            }
+        }
+
+        measurements
+    }
+
+    // Give a slow link, moving `bytes_per_sec`, `seconds` and fewer threads
+    fn slow_link(&self, seconds: u64, bytes_per_sec: usize) {
+        self.seconds.store(seconds, Ordering::SeqCst);
+        let threads = self.exit_signals.len() as u32;
+        if threads > SLOW_LINK_THREADS {
+            self.counters
+                .retire_from
+                .store(SLOW_LINK_THREADS, Ordering::SeqCst);
+            self.stop_workers(SLOW_LINK_THREADS);
+        }
+
+        self.counters.events.record(
+            "slow_link",
+            None,
+            Some(format!(
+                "{}, measuring for {seconds}s with {} threads",
+                crate::format_bits_per_sec(bytes_per_sec as f64 * 8.0),
+                threads.min(SLOW_LINK_THREADS)
+            )),
+        );
+    }
+
+    // Compare a second's sample with the thread count, until the link is
+    // found to be saturated
+    fn observe_saturation(&self, saturation: &mut Option<SaturationDetector>, bytes: usize) {
+        let threads = self.counters.running_threads.load(Ordering::SeqCst);
+        if let Some(saturated) = saturation
+            .as_mut()
+            .and_then(|detector| detector.observe(threads, bytes))
+        {
+            *saturation = None;
+            let _ = self.saturation.set(saturated);
+            let threads = saturated.threads;
+            let detail = if self.saturation_gain.is_some() {
+                self.counters.retire_from.store(threads, Ordering::SeqCst);
+                format!("{threads} threads, retiring the rest")
+            } else {
+                format!("{threads} threads")
+            };
+            self.counters.events.record("saturated", None, Some(detail));
+        }
+    }
+
+    // Events recorded so far, oldest first
+    pub fn events(&self) -> Vec<Event> {
+        self.counters.events.all()
+    }
+
+    // Where others can record events during the phase, e.g. the latency probe
+    pub fn event_log(&self) -> EventLog {
+        self.counters.events.clone()
+    }
+
+    // Wait for the workers to finish their current transfer, returning what
+    // each of the phase's connections did and the phase's events. A slow
+    // request in flight could hold the run up long after the deadline, so
+    // after `timeout` their connections are shut down, and any worker that
+    // still won't stop is left behind. How long it all took past the
+    // deadline is recorded as a threads_joined event.
+    pub fn join(self, timeout: Duration) -> PhaseRecords {
+        let deadline = self.deadline.get().copied().unwrap_or_else(Instant::now);
+        let events = &self.counters.events;
+        let running =
+            |handles: &[JoinHandle<()>]| handles.iter().filter(|h| !h.is_finished()).count();
+        let wait_until = |until: Instant| {
+            while running(&self.handles) > 0 && Instant::now() < until {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        wait_until(deadline + timeout);
+        if running(&self.handles) > 0 {
+            let connections = self.counters.sockets.shut_down_open();
+            events.record(
+                "join_timeout",
+                None,
+                Some(format!("shut down {connections} open connections")),
+            );
+
+            wait_until(Instant::now() + Duration::from_millis(JOIN_GRACE_MILLIS));
+            let stuck = running(&self.handles);
+            if stuck > 0 {
+                events.record(
+                    "threads_abandoned",
+                    None,
+                    Some(format!("{stuck} threads didn't stop")),
+                );
+            }
+        }
+
+        for handle in self.handles {
+            // the rest are detached, they'll end with their request
+            if handle.is_finished() {
+                handle.join().expect("Couldn't join test thread");
+            }
+        }
+        events.record(
+            "threads_joined",
+            None,
+            Some(format!(
+                "{:.2}s after the deadline",
+                deadline.elapsed().as_secs_f64()
+            )),
+        );
+
+        PhaseRecords {
+            sockets: self.counters.sockets.take(),
+            events: self.counters.events.all(),
+            saturation: self.saturation.get().copied(),
+            content: self
+                .counters
+                .content
+                .as_ref()
+                .map(ContentSamples::take)
+                .unwrap_or_default(),
+            threads: self.threads.get().copied(),
+        }
+    }
+}
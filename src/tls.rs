@@ -1,3 +1,5 @@
+use crate::net::ConnectOptions;
+use crate::sockets::{SocketRegistry, SocketTracker};
 use rustls::ClientConfig;
 use rustls::OwnedTrustAnchor;
 use rustls::RootCertStore;
@@ -7,21 +9,34 @@ use ureq::TlsConnector;
 use std::io::{Read, Write};
 use std::net::TcpStream;
 
-#[derive(Debug)]
 pub struct RawIo {
     inner: TcpStream,
+    tracker: Option<SocketTracker>,
+}
+
+impl std::fmt::Debug for RawIo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawIo").field("inner", &self.inner).finish()
+    }
 }
 
 impl Read for RawIo {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let num_bytes = self.inner.read(buf)?;
+        if let Some(tracker) = &mut self.tracker {
+            tracker.bytes_received += num_bytes as u64;
+        }
         Ok(num_bytes)
     }
 }
 
 impl Write for RawIo {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.inner.write(buf)
+        let num_bytes = self.inner.write(buf)?;
+        if let Some(tracker) = &mut self.tracker {
+            tracker.bytes_sent += num_bytes as u64;
+        }
+        Ok(num_bytes)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
@@ -29,6 +44,14 @@ impl Write for RawIo {
     }
 }
 
+impl Drop for RawIo {
+    fn drop(&mut self) {
+        if let Some(tracker) = &self.tracker {
+            tracker.finish(&self.inner);
+        }
+    }
+}
+
 impl ureq::ReadWrite for RawIo {
     fn socket(&self) -> Option<&TcpStream> {
         Some(&self.inner)
@@ -37,34 +60,63 @@ impl ureq::ReadWrite for RawIo {
 
 pub struct InterceptingTlsConnector {
     inner: Arc<ClientConfig>,
+    sockets: Option<SocketRegistry>,
+    connect: ConnectOptions,
+}
+
+// The TLS setup for everything we do, shared with code that drives rustls itself
+pub fn client_config() -> Arc<ClientConfig> {
+    let mut root_store = RootCertStore::empty();
+    root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+
+    // Force ChaCha20 because some platforms dont have
+    // aes acceleration, and it's fast anyway, so why not
+    let my_cipher_suites = vec![rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256];
+
+    let config = rustls::ClientConfig::builder()
+        .with_cipher_suites(&my_cipher_suites)
+        .with_safe_default_kx_groups()
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    Arc::new(config)
+}
+
+impl Default for InterceptingTlsConnector {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl InterceptingTlsConnector {
     pub fn new() -> Self {
-        let mut root_store = RootCertStore::empty();
-        root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
-            OwnedTrustAnchor::from_subject_spki_name_constraints(
-                ta.subject,
-                ta.spki,
-                ta.name_constraints,
-            )
-        }));
-
-        // Force ChaCha20 because some platforms dont have
-        // aes acceleration, and it's fast anyway, so why not
-        let my_cipher_suites = vec![rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256];
-
-        let config = rustls::ClientConfig::builder()
-            .with_cipher_suites(&my_cipher_suites)
-            .with_safe_default_kx_groups()
-            .with_safe_default_protocol_versions()
-            .unwrap()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
         Self {
-            inner: Arc::new(config),
+            inner: client_config(),
+            sockets: None,
+            connect: ConnectOptions::default(),
+        }
+    }
+
+    // Record what every connection did in `sockets` when it closes
+    pub fn with_socket_stats(sockets: SocketRegistry) -> Self {
+        Self {
+            sockets: Some(sockets),
+            ..Self::new()
         }
     }
+
+    // Make connections as `connect` says instead of using the one ureq made
+    pub fn via(self, connect: ConnectOptions) -> Self {
+        Self { connect, ..self }
+    }
 }
 
 impl std::io::Read for InterceptingIo {
@@ -101,14 +153,34 @@ impl TlsConnector for InterceptingTlsConnector {
         dns_name: &str,
         io: Box<dyn ureq::ReadWrite>,
     ) -> std::result::Result<Box<dyn ureq::ReadWrite + 'static>, ureq::Error> {
+        let socket = io.socket().unwrap();
+        let inner = if self.connect.is_default() {
+            socket.try_clone().unwrap()
+        } else {
+            // ureq's connection went to a stand-in on loopback, make the
+            // real one, bound, see `net::stand_in`. Plain http:// never
+            // comes through here, which is why validate turns it away when
+            // connections are bound.
+            let peer = socket.peer_addr()?;
+            let netloc = crate::net::stood_in_for(peer).ok_or_else(|| {
+                std::io::Error::other(format!("{peer} isn't standing in for a server"))
+            })?;
+            drop(io);
+            self.connect.connect_to(&netloc)?
+        };
+
         let raw_io = RawIo {
-            inner: io.socket().unwrap().try_clone().unwrap(),
+            tracker: self
+                .sockets
+                .clone()
+                .map(|sockets| SocketTracker::new(sockets, &inner)),
+            inner,
         };
 
         let tls_io = self
             .inner
             .connect(dns_name, Box::new(raw_io))
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
 
         Ok(Box::new(InterceptingIo { io: tls_io }))
     }
@@ -0,0 +1,303 @@
+/* --tui: the test as a full-screen view rather than a line per second,
+   with a graph of each phase's throughput, a sparkline of latency under
+   load, how many threads are running and how long the phase has to go.
+   It's an `Observer` like the console output, drawn from its own thread
+   a few times a second so the clock keeps moving between samples. The
+   terminal is in raw mode meanwhile, so Ctrl+C arrives as a key press
+   rather than a signal: it and q interrupt the test the same way.
+*/
+use crate::throughput::Direction;
+use crate::{Interrupt, Metrics, Observer, Speedtest};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::symbols::Marker;
+use ratatui::text::Line;
+use ratatui::widgets::{Axis, Block, Chart, Dataset, GraphType, Paragraph, Sparkline};
+use ratatui::{DefaultTerminal, Frame};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+// How often the screen is redrawn and keys are checked for
+static REDRAW_MILLIS: u64 = 250;
+// How many round trips the latency sparkline shows, the latest last
+static LATENCY_HISTORY: usize = 200;
+// The least a throughput graph goes up to, a dial-up line doesn't lie flat
+// along the bottom of it
+static TOP_MIN_MBPS: f64 = 0.01;
+
+// What the observer has seen, for the drawing thread
+#[derive(Default)]
+struct State {
+    // Mbit/s in each second of each phase
+    download: Vec<f64>,
+    upload: Vec<f64>,
+    // round trips under load in milliseconds, both phases
+    latency_ms: Vec<f64>,
+    phase: Option<Direction>,
+    // how long the phase running now was extended to on a slow link
+    extended: Option<u64>,
+    waiting_for_threads: bool,
+}
+
+pub struct Tui {
+    state: Arc<Mutex<State>>,
+    done: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Tui {
+    // Take over the terminal until `finish`
+    pub fn start(speedtest: &Speedtest) -> std::io::Result<Self> {
+        let terminal = ratatui::try_init()?;
+        let state = Arc::new(Mutex::new(State::default()));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let screen = Screen {
+            state: Arc::clone(&state),
+            metrics: speedtest.metrics.clone(),
+            interrupt: speedtest.interrupt.clone(),
+            download_seconds: speedtest.download.as_ref().map(|p| p.seconds),
+            upload_seconds: speedtest.upload.as_ref().map(|p| p.seconds),
+        };
+        let done_clone = Arc::clone(&done);
+        let handle = std::thread::spawn(move || screen.run(terminal, &done_clone));
+
+        Ok(Self {
+            state,
+            done,
+            handle: Some(handle),
+        })
+    }
+
+    // Give the terminal back as it was, for the results to be printed
+    pub fn finish(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.done.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        ratatui::restore();
+    }
+}
+
+// A panic mid-test mustn't leave the terminal in raw mode
+impl Drop for Tui {
+    fn drop(&mut self) {
+        if self.handle.is_some() {
+            self.stop();
+        }
+    }
+}
+
+impl Observer for Tui {
+    fn phase_started(&mut self, direction: Direction) {
+        let mut state = self.state.lock().unwrap();
+        state.phase = Some(direction);
+        state.extended = None;
+        state.waiting_for_threads = false;
+    }
+
+    fn sample(&mut self, direction: Direction, _second: usize, bytes: usize) {
+        let mbps = bytes as f64 * 8.0 / 1e6;
+        let mut state = self.state.lock().unwrap();
+        match direction {
+            Direction::Download => state.download.push(mbps),
+            Direction::Upload => state.upload.push(mbps),
+        }
+    }
+
+    fn latency(&mut self, _direction: Direction, round_trip: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.latency_ms.push(round_trip.as_secs_f64() * 1000.0);
+    }
+
+    fn phase_extended(&mut self, _direction: Direction, seconds: u64) {
+        self.state.lock().unwrap().extended = Some(seconds);
+    }
+
+    fn waiting_for_threads(&mut self, _direction: Direction) {
+        self.state.lock().unwrap().waiting_for_threads = true;
+    }
+
+    fn phase_finished(&mut self, _direction: Direction, _samples: &[usize]) {
+        self.state.lock().unwrap().phase = None;
+    }
+}
+
+// The drawing thread's side
+struct Screen {
+    state: Arc<Mutex<State>>,
+    metrics: Metrics,
+    interrupt: Interrupt,
+    download_seconds: Option<u64>,
+    upload_seconds: Option<u64>,
+}
+
+impl Screen {
+    fn run(self, mut terminal: DefaultTerminal, done: &AtomicBool) {
+        while !done.load(Ordering::SeqCst) {
+            if terminal.draw(|frame| self.draw(frame)).is_err() {
+                return;
+            }
+
+            if !event::poll(Duration::from_millis(REDRAW_MILLIS)).unwrap_or(false) {
+                continue;
+            }
+            if let Ok(Event::Key(key)) = event::read() {
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if key.kind == KeyEventKind::Press && (ctrl_c || key.code == KeyCode::Char('q')) {
+                    self.interrupt.trigger();
+                }
+            }
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let state = self.state.lock().unwrap();
+        let [status, download, upload, latency] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Fill(1),
+            Constraint::Fill(1),
+            Constraint::Length(6),
+        ])
+        .areas(frame.area());
+
+        frame.render_widget(
+            Paragraph::new(self.status_line(&state)).block(Block::bordered().title("cf_speedtest")),
+            status,
+        );
+        for (area, name, samples, seconds, color) in [
+            (
+                download,
+                "Download",
+                &state.download,
+                self.seconds(&state, Direction::Download),
+                Color::Cyan,
+            ),
+            (
+                upload,
+                "Upload",
+                &state.upload,
+                self.seconds(&state, Direction::Upload),
+                Color::Magenta,
+            ),
+        ] {
+            let points = chart_points(samples);
+            frame.render_widget(throughput_chart(name, &points, seconds, color), area);
+        }
+
+        let recent = &state.latency_ms[state.latency_ms.len().saturating_sub(LATENCY_HISTORY)..];
+        let latency_title = match recent.last() {
+            Some(ms) => format!("Latency under load ({ms:.1}ms)"),
+            None => "Latency under load".to_owned(),
+        };
+        let bars: Vec<u64> = recent.iter().map(|&ms| ms.round() as u64).collect();
+        frame.render_widget(
+            Sparkline::default()
+                .block(Block::bordered().title(latency_title))
+                .data(&bars)
+                .style(Style::default().fg(Color::Yellow)),
+            latency,
+        );
+    }
+
+    // How long a phase runs for, as planned unless it's running now and
+    // was extended
+    fn seconds(&self, state: &State, direction: Direction) -> Option<u64> {
+        let planned = match direction {
+            Direction::Download => self.download_seconds,
+            Direction::Upload => self.upload_seconds,
+        };
+        match state.extended {
+            Some(seconds) if state.phase == Some(direction) => Some(seconds),
+            _ => planned,
+        }
+    }
+
+    // e.g. "Download       94.21 Mbit/s   8 threads   5s elapsed, 7s to go   (q to stop)"
+    fn status_line(&self, state: &State) -> Line<'static> {
+        let Some(direction) = state.phase else {
+            return Line::from("Between phases...");
+        };
+        if state.waiting_for_threads {
+            return Line::from(format!(
+                "Waiting for {} threads to finish...",
+                direction.name()
+            ));
+        }
+
+        let Some(now) = self.metrics.snapshot() else {
+            return Line::from("Starting...");
+        };
+        let elapsed = now.elapsed.as_secs();
+        let seconds = self.seconds(state, direction).unwrap_or_default();
+        let label = match direction {
+            Direction::Download => "Download",
+            Direction::Upload => "Upload",
+        };
+
+        Line::from(format!(
+            "{label:<10}{:>14}   {} threads   {elapsed}s elapsed, {}s to go   (q to stop)",
+            crate::format_bits_per_sec(now.bits_per_sec()),
+            now.threads,
+            seconds.saturating_sub(elapsed),
+        ))
+    }
+}
+
+// (second, Mbit/s) for each second so far, the first ending at 1s
+fn chart_points(samples: &[f64]) -> Vec<(f64, f64)> {
+    samples
+        .iter()
+        .enumerate()
+        .map(|(second, &mbps)| (second as f64 + 1.0, mbps))
+        .collect()
+}
+
+fn throughput_chart<'a>(
+    name: &'a str,
+    points: &'a [(f64, f64)],
+    seconds: Option<u64>,
+    color: Color,
+) -> Chart<'a> {
+    let title = match (points.last(), seconds) {
+        (Some((_, mbps)), _) => format!("{name} ({})", crate::format_bits_per_sec(mbps * 1e6)),
+        (None, None) => format!("{name} (not measured)"),
+        (None, Some(_)) => name.to_owned(),
+    };
+    // an axis needs some length, even for a phase that isn't measured
+    let seconds = seconds.unwrap_or_default().max(points.len() as u64).max(1) as f64;
+    // headroom over the fastest second so the line doesn't ride the border,
+    // and some height for a phase that's moved nothing yet
+    let top = points
+        .iter()
+        .map(|&(_, mbps)| mbps)
+        .fold(TOP_MIN_MBPS, f64::max)
+        * 1.2;
+
+    let dataset = Dataset::default()
+        .marker(Marker::Braille)
+        .graph_type(GraphType::Line)
+        .style(Style::default().fg(color))
+        .data(points);
+
+    Chart::new(vec![dataset])
+        .block(Block::bordered().title(title))
+        .x_axis(
+            Axis::default()
+                .bounds([0.0, seconds])
+                .labels(["0s".to_owned(), format!("{seconds}s")]),
+        )
+        .y_axis(
+            Axis::default()
+                .bounds([0.0, top])
+                .labels(["0".to_owned(), crate::format_bits_per_sec(top * 1e6)]),
+        )
+}
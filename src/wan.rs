@@ -0,0 +1,92 @@
+use crate::args::UserArgs;
+use std::net::IpAddr;
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+// One WAN link of a multi-homed router, and how to send traffic over it
+#[derive(Clone, Debug, PartialEq)]
+pub struct WanProfile {
+    pub name: String,
+    pub interface: Option<String>,
+    pub source_address: Option<IpAddr>,
+}
+
+impl WanProfile {
+    // `config` with every connection going over this link
+    pub fn apply(&self, config: &UserArgs) -> UserArgs {
+        UserArgs {
+            via_interface: self.interface.clone(),
+            source_address: self.source_address,
+            ..config.clone()
+        }
+    }
+}
+
+/* One link per line, a name followed by how to reach it:
+
+     # name   how
+     fibre    interface=eth1
+     lte      source=192.168.8.2
+     backup   interface=wwan0 source=10.64.0.2
+
+   `#` starts a comment. Names are what the report calls each link.
+*/
+pub fn parse_profiles(text: &str) -> Result<Vec<WanProfile>> {
+    let mut profiles: Vec<WanProfile> = vec![];
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut tokens = line.split_whitespace();
+        let Some(name) = tokens.next() else {
+            continue;
+        };
+        let error = |msg: String| format!("line {}: {msg}", number + 1);
+
+        let mut profile = WanProfile {
+            name: name.to_owned(),
+            interface: None,
+            source_address: None,
+        };
+        for token in tokens {
+            match token.split_once('=') {
+                Some(("interface", interface)) if !interface.is_empty() => {
+                    profile.interface = Some(interface.to_owned())
+                }
+                Some(("source", address)) => {
+                    profile.source_address = Some(
+                        address
+                            .parse()
+                            .map_err(|_| error(format!("'{address}' is not an IP address")))?,
+                    )
+                }
+                _ => {
+                    return Err(error(format!(
+                        "unknown setting '{token}', expected interface=<name> or source=<address>"
+                    ))
+                    .into())
+                }
+            }
+        }
+
+        if profile.interface.is_none() && profile.source_address.is_none() {
+            return Err(error(format!("link '{name}' needs an interface= or source=")).into());
+        }
+        if profiles.iter().any(|other| other.name == profile.name) {
+            return Err(error(format!("link '{name}' is listed twice")).into());
+        }
+        profiles.push(profile);
+    }
+
+    if profiles.len() < 2 {
+        return Err("a failover test needs at least two WAN links".into());
+    }
+
+    Ok(profiles)
+}
+
+pub fn load_profiles(path: &Path) -> Result<Vec<WanProfile>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("couldn't read {}: {err}", path.display()))?;
+    parse_profiles(&text).map_err(|err| format!("{}: {err}", path.display()).into())
+}
@@ -0,0 +1,153 @@
+/* What goes in a run's `warnings`, gathered as the run goes and reported
+   once at the end rather than in the middle of the per-second lines:
+
+     provider_fallback  the first server didn't answer, another was used
+     vpn                traffic left through a tunnel (or Cloudflare WARP)
+                        without --via-interface asking for it
+     metered            the connection is metered, with --on-metered warn
+     cpu_bound          we kept every core busy, so the machine may have
+                        been the bottleneck rather than the line
+     colo_change        a different colo than the last run in history, so
+                        the two may not compare
+     retries            requests or test threads that failed along the way
+     proxy              the test went through --proxy
+*/
+use crate::results::{Event, Warning};
+use std::time::Duration;
+
+// Share of every core's time we may use before calling the run CPU-bound
+static CPU_BOUND_SHARE: f64 = 0.9;
+
+// Interface names VPN clients give their tunnels
+static TUNNEL_PREFIXES: [&str; 9] = [
+    "tun",
+    "tap",
+    "wg",
+    "utun",
+    "tailscale",
+    "nordlynx",
+    "proton",
+    "zt",
+    "ipsec",
+];
+
+pub fn is_tunnel(interface: &str) -> bool {
+    TUNNEL_PREFIXES
+        .iter()
+        .any(|prefix| interface.starts_with(prefix))
+}
+
+// The default route going through a tunnel, or Cloudflare saying WARP is on
+// in the cdn-cgi trace (warp=on or warp=plus)
+pub fn vpn(default_interface: Option<&str>, warp: Option<&str>) -> Option<Warning> {
+    if let Some(interface) = default_interface.filter(|interface| is_tunnel(interface)) {
+        return Some(Warning::new(
+            "vpn",
+            format!("the default route goes through {interface}, which looks like a VPN"),
+        ));
+    }
+    matches!(warp, Some("on") | Some("plus")).then(|| {
+        Warning::new(
+            "vpn",
+            "Cloudflare WARP is on, the test measured the tunnel rather than the line",
+        )
+    })
+}
+
+pub fn metered() -> Warning {
+    Warning::new(
+        "metered",
+        "the connection is metered, a test can use hundreds of megabytes of it (--on-metered skip to skip it)",
+    )
+}
+
+// CPU time used by this process so far, all threads together
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn cpu_time() -> Option<Duration> {
+    // SAFETY: getrusage only fills in the struct we hand it
+    let usage = unsafe {
+        let mut usage: libc::rusage = std::mem::zeroed();
+        if libc::getrusage(libc::RUSAGE_SELF, &mut usage) != 0 {
+            return None;
+        }
+        usage
+    };
+    let time = |tv: libc::timeval| {
+        Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+    };
+
+    Some(time(usage.ru_utime) + time(usage.ru_stime))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn cpu_time() -> Option<Duration> {
+    None
+}
+
+// `cpu` used over `elapsed` of wall time on a machine with `cores`
+pub fn cpu_bound(cpu: Duration, elapsed: Duration, cores: usize) -> Option<Warning> {
+    let available = elapsed.as_secs_f64() * cores as f64;
+    if available <= 0.0 {
+        return None;
+    }
+
+    let share = cpu.as_secs_f64() / available;
+    (share >= CPU_BOUND_SHARE).then(|| {
+        Warning::new(
+            "cpu_bound",
+            format!(
+                "the test kept {:.0}% of {cores} CPU core(s) busy, this machine may have limited the results",
+                share.min(1.0) * 100.0
+            ),
+        )
+    })
+}
+
+pub fn colo_change(colo: Option<&str>, previous: Option<&str>) -> Option<Warning> {
+    let (colo, previous) = (colo?, previous?);
+    (colo != previous).then(|| {
+        Warning::new(
+            "colo_change",
+            format!(
+                "tested against {colo}, the last run went to {previous}, so they may not compare"
+            ),
+        )
+    })
+}
+
+// Requests and threads that failed, from a run's events
+pub fn retries(events: &[Event]) -> Vec<Warning> {
+    let count = |kind: &str| events.iter().filter(|event| event.kind == kind).count();
+    let mut warnings = vec![];
+
+    match count("request_failed") {
+        0 => {}
+        1 => warnings.push(Warning::new("retries", "a request failed and was retried")),
+        n => warnings.push(Warning::new(
+            "retries",
+            format!("{n} requests failed and were retried"),
+        )),
+    }
+    match count("thread_failed") {
+        0 => {}
+        1 => warnings.push(Warning::new(
+            "retries",
+            "a test thread failed, the others carried on",
+        )),
+        n => warnings.push(Warning::new(
+            "retries",
+            format!("{n} test threads failed, the others carried on"),
+        )),
+    }
+
+    warnings
+}
+
+// Behind --proxy the results are the proxy's path to Cloudflare, with the
+// hop to the proxy in front, which needn't say much about this line
+pub fn proxy(server: &str) -> Warning {
+    Warning::new(
+        "proxy",
+        format!("the test went through the SOCKS proxy at {server}, the results measure the path through it rather than this machine's line"),
+    )
+}
@@ -0,0 +1,86 @@
+// cf_speedtest used as a library, without its command line
+
+use cf_speedtest::calibrate::start_source;
+use cf_speedtest::phases::{PhaseConfig, PhaseConfigs, PhaseOptions};
+use cf_speedtest::throughput::Direction;
+use cf_speedtest::{Observer, Speedtest};
+use std::time::Duration;
+
+#[derive(Default)]
+struct Recorder {
+    started: Vec<Direction>,
+    samples: usize,
+    finished: Vec<Direction>,
+}
+
+impl Observer for Recorder {
+    fn phase_started(&mut self, direction: Direction) {
+        self.started.push(direction);
+    }
+
+    fn sample(&mut self, _direction: Direction, _second: usize, _bytes: usize) {
+        self.samples += 1;
+    }
+
+    fn phase_finished(&mut self, direction: Direction, _samples: &[usize]) {
+        self.finished.push(direction);
+    }
+}
+
+// A short test against a server on loopback rather than a provider
+fn loopback_speedtest() -> Speedtest {
+    let addr = start_source("200Mbit".parse().unwrap()).unwrap();
+    let phase = |direction| PhaseConfig {
+        threads: 2,
+        seconds: 2,
+        ..PhaseConfig::default_for(direction)
+    };
+    Speedtest {
+        download: Some(phase(Direction::Download)),
+        upload: Some(phase(Direction::Upload)),
+        download_url: Some(format!("http://{addr}/down?bytes={{bytes}}")),
+        upload_url: Some(format!("http://{addr}/up")),
+        ..Speedtest::default()
+    }
+}
+
+#[test]
+fn test_run_phases() {
+    let speedtest = loopback_speedtest();
+    let mut recorder = Recorder::default();
+    let measured = speedtest.run_phases(Duration::ZERO, &mut recorder);
+
+    assert_eq!(recorder.started, [Direction::Download, Direction::Upload]);
+    assert_eq!(recorder.finished, recorder.started);
+    assert!(recorder.samples >= 4);
+    assert!(measured.download.unwrap().median_bps > 0.0);
+    assert!(measured.upload.unwrap().median_bps > 0.0);
+    assert!(!measured.interrupted);
+}
+
+#[test]
+fn test_interrupt() {
+    let speedtest = loopback_speedtest();
+    speedtest.interrupt.trigger();
+    let measured = speedtest.run_phases(Duration::ZERO, &mut ());
+
+    assert!(measured.interrupted);
+    assert!(speedtest.metrics.snapshot().is_none());
+}
+
+#[test]
+fn test_phase_configs() {
+    // the defaults are what the command runs with no options
+    let defaults = PhaseConfigs::parse(&PhaseOptions::default(), "", None).unwrap();
+    let speedtest = Speedtest::default();
+    assert_eq!(Some(defaults.download), speedtest.download);
+    assert_eq!(Some(defaults.upload), speedtest.upload);
+
+    let options = PhaseOptions {
+        download_threads: Some(6),
+        ..PhaseOptions::default()
+    };
+    let phases = PhaseConfigs::parse(&options, "[upload]\nthreads = 3\n", None).unwrap();
+    assert_eq!(phases.download.threads, 6);
+    assert_eq!(phases.upload.threads, 3);
+}